@@ -1,12 +1,12 @@
-/// Example: Adding documents to the Pollinet knowledge base
-/// 
-/// This demonstrates:
-/// 1. Initializing the RAG system
-/// 2. Creating the Qdrant collection
-/// 3. Adding documents with metadata
-/// 4. Testing retrieval
-/// 
-/// Run with: cargo run --example add_documents
+//! Example: Adding documents to the Pollinet knowledge base
+//! 
+//! This demonstrates:
+//! 1. Initializing the RAG system
+//! 2. Creating the Qdrant collection
+//! 3. Adding documents with metadata
+//! 4. Testing retrieval
+//! 
+//! Run with: cargo run --example add_documents
 
 use pollinet_knowledge_bot::{config::Config, rag::RAGSystem};
 use std::collections::HashMap;
@@ -59,8 +59,8 @@ async fn main() -> anyhow::Result<()> {
     // metadata.insert("category".to_string(), "general".to_string());
     // metadata.insert("version".to_string(), "2.0".to_string());
     //
-    // let chunks = rag.add_document("pollinet_overview", overview, metadata).await?;
-    // println!("   ✓ Added with {} chunks\n", chunks);
+    // let stats = rag.add_document("pollinet_overview", overview, metadata).await?;
+    // println!("   ✓ {} chunks ({} new, {} updated, {} unchanged)\n", stats.total(), stats.inserted, stats.updated, stats.skipped);
 
     // Example 2: Technology Details (from whitepaper)
     // println!("📄 Adding Technology Details...");
@@ -115,8 +115,8 @@ async fn main() -> anyhow::Result<()> {
     // metadata.insert("category".to_string(), "technical".to_string());
     // metadata.insert("section".to_string(), "technology".to_string());
     //
-    // let chunks = rag.add_document("pollinet_technology", technology, metadata).await?;
-    // println!("   ✓ Added with {} chunks\n", chunks);
+    // let stats = rag.add_document("pollinet_technology", technology, metadata).await?;
+    // println!("   ✓ {} chunks ({} new, {} updated, {} unchanged)\n", stats.total(), stats.inserted, stats.updated, stats.skipped);
 
     // Example 3: Tokenomics (from pollinet.xyz/tokenomics)
     // println!("📄 Adding Tokenomics Information...");
@@ -148,8 +148,8 @@ async fn main() -> anyhow::Result<()> {
     // metadata.insert("source".to_string(), "tokenomics".to_string());
     // metadata.insert("category".to_string(), "economics".to_string());
     //
-    // let chunks = rag.add_document("pollinet_tokenomics", tokenomics, metadata).await?;
-    // println!("   ✓ Added with {} chunks\n", chunks);
+    // let stats = rag.add_document("pollinet_tokenomics", tokenomics, metadata).await?;
+    // println!("   ✓ {} chunks ({} new, {} updated, {} unchanged)\n", stats.total(), stats.inserted, stats.updated, stats.skipped);
 
     // Example 4: FAQ (based on whitepaper)
     // println!("📄 Adding FAQ...");
@@ -205,8 +205,8 @@ async fn main() -> anyhow::Result<()> {
     // metadata.insert("source".to_string(), "faq".to_string());
     // metadata.insert("category".to_string(), "support".to_string());
     //
-    // let chunks = rag.add_document("pollinet_faq", faq, metadata).await?;
-    // println!("   ✓ Added with {} chunks\n", chunks);
+    // let stats = rag.add_document("pollinet_faq", faq, metadata).await?;
+    // println!("   ✓ {} chunks ({} new, {} updated, {} unchanged)\n", stats.total(), stats.inserted, stats.updated, stats.skipped);
 
     // Example 5: Security and Future Extensions
     // println!("📄 Adding Security & Future Information...");
@@ -247,8 +247,8 @@ async fn main() -> anyhow::Result<()> {
     // metadata.insert("source".to_string(), "whitepaper".to_string());
     // metadata.insert("category".to_string(), "security".to_string());
     //
-    // let chunks = rag.add_document("pollinet_security", security, metadata).await?;
-    // println!("   ✓ Added with {} chunks\n", chunks);
+    // let stats = rag.add_document("pollinet_security", security, metadata).await?;
+    // println!("   ✓ {} chunks ({} new, {} updated, {} unchanged)\n", stats.total(), stats.inserted, stats.updated, stats.skipped);
 
     // New: Pollinet Official URLs and Resources
     println!("📄 Adding Pollinet URLs and Resources...");
@@ -287,8 +287,14 @@ async fn main() -> anyhow::Result<()> {
     metadata.insert("category".to_string(), "resources".to_string());
     metadata.insert("type".to_string(), "urls".to_string());
 
-    let chunks = rag.add_document("pollinet_urls", urls, metadata).await?;
-    println!("   ✓ Added with {} chunks\n", chunks);
+    let stats = rag.add_document("pollinet_urls", urls, metadata).await?;
+    println!(
+        "   ✓ {} chunks ({} new, {} updated, {} unchanged)\n",
+        stats.total(),
+        stats.inserted,
+        stats.updated,
+        stats.skipped
+    );
 
     // New: Pollinet Team Information
     println!("📄 Adding Pollinet Team Information...");
@@ -317,8 +323,14 @@ async fn main() -> anyhow::Result<()> {
     metadata.insert("category".to_string(), "team".to_string());
     metadata.insert("type".to_string(), "team_info".to_string());
 
-    let chunks = rag.add_document("pollinet_team", team, metadata).await?;
-    println!("   ✓ Added with {} chunks\n", chunks);
+    let stats = rag.add_document("pollinet_team", team, metadata).await?;
+    println!(
+        "   ✓ {} chunks ({} new, {} updated, {} unchanged)\n",
+        stats.total(),
+        stats.inserted,
+        stats.updated,
+        stats.skipped
+    );
 
     // Test retrieval
     println!("🔍 Testing retrieval...\n");
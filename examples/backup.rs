@@ -0,0 +1,47 @@
+//! Example: Backing up and restoring the knowledge base
+//! 
+//! `export` writes every chunk, with its embedding, as JSON lines; `import`
+//! loads such a file back without re-embedding anything, so a fresh database
+//! can be restored without paying for embeddings again.
+//! 
+//! Run with:
+//!   cargo run --example backup -- export knowledge.jsonl
+//!   cargo run --example backup -- import knowledge.jsonl
+
+use anyhow::Context;
+use pollinet_knowledge_bot::{config::Config, rag::RAGSystem};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let usage = "Usage: cargo run --example backup -- <export|import> <file>";
+    let mut args = std::env::args().skip(1);
+    let command = args.next().context(usage)?;
+    let path = args.next().context(usage)?;
+
+    let config = Config::from_env()?;
+    let rag = RAGSystem::new(config).await?;
+    rag.initialize_collection().await?;
+
+    match command.as_str() {
+        "export" => {
+            let file = File::create(&path).with_context(|| format!("Failed to create {}", path))?;
+            let written = rag.export_json(BufWriter::new(file)).await?;
+            println!("✅ Exported {} records to {}", written, path);
+        }
+        "import" => {
+            let file = File::open(&path).with_context(|| format!("Failed to open {}", path))?;
+            let stats = rag.import_json(BufReader::new(file)).await?;
+            println!(
+                "✅ Imported {} chunks and {} parent chunks from {}",
+                stats.chunks, stats.parents, path
+            );
+        }
+        _ => anyhow::bail!(usage),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Example: Ingesting a directory of markdown/text files
+//! 
+//! Walks the given directory (recursively), adding every `.md` and `.txt`
+//! file as a document named after its relative path. Files whose content
+//! hasn't changed since the last run are skipped, so this can be re-run on
+//! every docs release.
+//! 
+//! Run with: cargo run --example ingest_dir -- ./docs
+
+use anyhow::Context;
+use pollinet_knowledge_bot::{config::Config, rag::RAGSystem};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let dir = std::env::args()
+        .nth(1)
+        .context("Usage: cargo run --example ingest_dir -- <directory>")?;
+
+    let config = Config::from_env()?;
+    let rag = RAGSystem::new(config).await?;
+    rag.initialize_collection().await?;
+
+    let ingested = rag.add_documents_from_dir(&dir, &["md", "txt"]).await?;
+    println!("✅ Ingested {} changed files from {}", ingested, dir);
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+//! Example: Ingesting a PDF (e.g. the Pollinet whitepaper)
+//! 
+//! Extracts the PDF's text with `pdf-extract` and adds it to
+//! the knowledge base with `source=pdf`. The document name defaults to the
+//! file name without its extension.
+//! 
+//! Run with: cargo run --example ingest_pdf -- whitepaper.pdf [name]
+
+use anyhow::Context;
+use pollinet_knowledge_bot::{config::Config, rag::RAGSystem};
+use std::path::PathBuf;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let path: PathBuf = args
+        .next()
+        .context("Usage: cargo run --example ingest_pdf -- <file.pdf> [name]")?
+        .into();
+    let name = match args.next() {
+        Some(name) => name,
+        None => path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .context("PDF file name is not valid UTF-8")?
+            .to_string(),
+    };
+
+    let config = Config::from_env()?;
+    let rag = RAGSystem::new(config).await?;
+    rag.initialize_collection().await?;
+
+    let chunks = rag.add_document_from_pdf(path, &name).await?;
+    println!("✅ Added '{}' ({} chunks)", name, chunks);
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+-- Schema shared by every deployment. The embeddings table itself is created
+-- by RAGSystem::initialize_collection, since its name, vector dimension and
+-- index type come from configuration.
+--
+-- Statements are idempotent so databases created before migrations existed
+-- are adopted as-is.
+
+CREATE EXTENSION IF NOT EXISTS vector;
+
+-- Conversation history (PERSIST_CONVERSATIONS)
+CREATE TABLE IF NOT EXISTS conversations (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Rows from before per-user history have a NULL user_id and stay whole-chat conversations
+ALTER TABLE conversations ADD COLUMN IF NOT EXISTS user_id BIGINT;
+
+CREATE INDEX IF NOT EXISTS conversations_chat_idx ON conversations (chat_id, id);
+
+-- Answer ratings (FEEDBACK_BUTTONS)
+CREATE TABLE IF NOT EXISTS feedback (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    user_id BIGINT,
+    query TEXT NOT NULL,
+    answer TEXT NOT NULL,
+    rating SMALLINT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    rated_at TIMESTAMP
+);
+
+-- Chats the bot has answered in, for /broadcast
+CREATE TABLE IF NOT EXISTS chats (
+    id BIGINT PRIMARY KEY,
+    type TEXT NOT NULL,
+    first_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_seen TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Per-question token usage (LOG_QUERIES)
+CREATE TABLE IF NOT EXISTS query_logs (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT,
+    query TEXT NOT NULL,
+    prompt_tokens BIGINT NOT NULL,
+    completion_tokens BIGINT NOT NULL,
+    model TEXT NOT NULL,
+    used_fallback BOOLEAN NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS query_logs_created_at_idx ON query_logs (created_at);
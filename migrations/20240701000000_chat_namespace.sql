@@ -0,0 +1,2 @@
+-- Knowledge base namespace a chat is answered from (NULL = default)
+ALTER TABLE chat_settings ADD COLUMN IF NOT EXISTS namespace TEXT;
@@ -0,0 +1,11 @@
+-- Parse a metadata date (RFC 3339, YYYY-MM-DD or Twitter's
+-- "Wed Oct 10 20:19:24 +0000 2018") as UTC, or NULL when it isn't one, so a
+-- malformed value can't fail a whole query (GET /latest)
+CREATE OR REPLACE FUNCTION try_timestamptz(value TEXT) RETURNS TIMESTAMPTZ
+LANGUAGE plpgsql STABLE SET TimeZone = 'UTC' AS $$
+BEGIN
+    RETURN value::timestamptz;
+EXCEPTION WHEN others THEN
+    RETURN NULL;
+END;
+$$;
@@ -1,7 +1,7 @@
-/// Telegram bot module
-/// 
-/// This module sets up and runs the Telegram bot using the teloxide framework.
-/// It connects all the pieces: configuration, RAG system, handlers, and conversation management.
+//! Telegram bot module
+//! 
+//! This module sets up and runs the Telegram bot using the teloxide framework.
+//! It connects all the pieces: configuration, RAG system, handlers, and conversation management.
 
 use anyhow::{Context, Result};
 use std::sync::Arc;
@@ -9,21 +9,16 @@ use std::time::Duration;
 use teloxide::{prelude::*, types::Me, utils::command::BotCommands};
 use tokio::time::sleep;
 use reqwest;
-use axum::{
-    extract::State,
-    http::StatusCode,
-    response::Json,
-    routing::{get, post},
-    Router,
-};
-use serde_json::{json, Value};
 
-use crate::config::Config;
+use crate::config::{Config, EmbeddingProviderKind};
 use crate::handlers::{
-    handle_clear_command, handle_edited_message, handle_help_command, handle_message, 
-    handle_start_command, ConversationManager,
+    accepts_command, handle_addknowledge_command, handle_broadcast_command, handle_callback_query,
+    handle_clear_command, handle_edited_message, handle_help_command, handle_message,
+    handle_settings_command, handle_start_command, ConversationManager,
 };
+use crate::http_server::{create_router, metrics_router, AppState};
 use crate::rag::RAGSystem;
+use crate::rate_limit::RateLimiter;
 
 /// Bot commands that users can use
 #[derive(BotCommands, Clone)]
@@ -35,6 +30,12 @@ pub enum Command {
     Help,
     #[command(description = "Clear conversation history")]
     Clear,
+    #[command(description = "Add text to the knowledge base (admins only)")]
+    AddKnowledge(String),
+    #[command(description = "Send an announcement to every chat (admins only)")]
+    Broadcast(String),
+    #[command(description = "Show or change this chat's settings (changes are admins only)")]
+    Settings(String),
 }
 
 /// Initialize and run the Telegram bot with a pre-initialized RAG system
@@ -42,9 +43,15 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
     log::info!("Initializing bot...");
 
     // Initialize conversation manager
-    let conversation_manager = Arc::new(ConversationManager::new(
-        config.max_conversation_history * 2, // Store both user and assistant messages
-    ));
+    let max_history = config.max_conversation_history * 2; // Store both user and assistant messages
+    let conversation_manager = Arc::new(if config.persist_conversations {
+        ConversationManager::with_database(max_history, rag_system.db_pool().clone())
+    } else {
+        ConversationManager::new(max_history)
+    });
+
+    let rate_limiter = Arc::new(RateLimiter::from_config(&config));
+    rate_limiter.spawn_cleanup();
 
     // Detect if running on Railway or cloud platform
     let is_railway = std::env::var("RAILWAY_ENVIRONMENT").is_ok() 
@@ -144,6 +151,10 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
     };
     log::info!("Bot started: @{}", me.username());
 
+    // Log a consolidated readiness summary (fails only on hard-required checks)
+    let report = startup_report(&config, &rag_system, &me).await?;
+    log::info!("{}", report);
+
     // Set up command handler
     let handler = dptree::entry()
         // Handle commands
@@ -151,12 +162,13 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
             Update::filter_message()
                 .filter_command::<Command>()
                 .endpoint(
-                    |bot: Bot, msg: Message, cmd: Command, conversation_manager: Arc<ConversationManager>| async move {
-                        match cmd {
-                            Command::Start => handle_start_command(bot, msg).await,
-                            Command::Help => handle_help_command(bot, msg).await,
-                            Command::Clear => handle_clear_command(bot, msg, conversation_manager).await,
+                    |bot: Bot, msg: Message, cmd: Command, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>| async move {
+                        let text = msg.text().unwrap_or_default();
+                        if !accepts_command(text, me.username(), msg.chat.is_private(), rag_system.config().group_bare_commands) {
+                            log::debug!("Ignoring bare command in group: {}", text);
+                            return Ok(());
                         }
+                        run_command(bot, msg, cmd, rag_system, conversation_manager).await
                     },
                 ),
         )
@@ -164,20 +176,32 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
         .branch(
             Update::filter_message()
                 .endpoint(
-                    |bot: Bot, msg: Message, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>| async move {
-                        if let Err(e) = handle_message(bot, msg, me, rag_system, conversation_manager).await {
+                    |bot: Bot, msg: Message, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>, rate_limiter: Arc<RateLimiter>| async move {
+                        if let Err(e) = handle_message(bot, msg, me, rag_system, conversation_manager, rate_limiter).await {
                             log::error!("Error handling message: {:?}", e);
                         }
                         Ok(())
                     },
                 ),
         )
+        // Handle answer buttons (feedback and follow-up suggestions)
+        .branch(
+            Update::filter_callback_query()
+                .endpoint(
+                    |bot: Bot, query: CallbackQuery, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>, rate_limiter: Arc<RateLimiter>| async move {
+                        if let Err(e) = handle_callback_query(bot, query, me, rag_system, conversation_manager, rate_limiter).await {
+                            log::error!("Error handling button click: {:?}", e);
+                        }
+                        Ok(())
+                    },
+                ),
+        )
         // Handle edited messages
         .branch(
             Update::filter_edited_message()
                 .endpoint(
-                    |bot: Bot, msg: Message, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>| async move {
-                        if let Err(e) = handle_edited_message(bot, msg, me, rag_system, conversation_manager).await {
+                    |bot: Bot, msg: Message, me: Me, rag_system: Arc<RAGSystem>, conversation_manager: Arc<ConversationManager>, rate_limiter: Arc<RateLimiter>| async move {
+                        if let Err(e) = handle_edited_message(bot, msg, me, rag_system, conversation_manager, rate_limiter).await {
                             log::error!("Error handling edited message: {:?}", e);
                         }
                         Ok(())
@@ -190,22 +214,38 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
         .dependencies(dptree::deps![
             rag_system.clone(),
             conversation_manager.clone(),
+            rate_limiter.clone(),
             me.clone()
         ])
         .enable_ctrlc_handler()
         .build();
 
+    if let Some(port) = config.metrics_port {
+        tokio::spawn(serve_metrics(port));
+    }
+
     // Check if we should use webhooks or polling
     if let Some(webhook_url) = &config.webhook_url {
         log::info!("Using webhook mode");
-        run_webhook_server(
-            bot, 
-            config.clone(), 
-            webhook_url,
-            rag_system, 
-            conversation_manager, 
-            me
-        ).await?;
+        match register_webhook(&bot, &config, webhook_url).await {
+            Ok(verified_me) => {
+                run_webhook_server(
+                    bot, 
+                    config.clone(), 
+                    webhook_url,
+                    rag_system, 
+                    conversation_manager, 
+                    rate_limiter,
+                    verified_me
+                ).await?;
+            }
+            Err(e) => {
+                // Polling removes the half-configured webhook before fetching updates
+                log::error!("Webhook setup failed: {:#}", e);
+                log::warn!("Falling back to polling mode");
+                dispatcher.dispatch().await;
+            }
+        }
     } else {
         log::info!("Using polling mode (no webhook URL configured)");
         log::info!("Bot is running. Press Ctrl+C to stop.");
@@ -215,6 +255,114 @@ pub async fn run_bot_with_rag(config: Config, rag_system: Arc<RAGSystem>) -> Res
     Ok(())
 }
 
+/// Consolidated readiness summary produced once all systems are initialized
+#[derive(Debug, Clone)]
+pub struct StartupReport {
+    /// Number of chunks stored in the embeddings table
+    pub chunk_count: i64,
+    /// Whether the OpenAI API answered an authenticated request
+    pub openai_reachable: bool,
+    /// Configured OpenAI models and whether the API key can see them
+    pub openai_models: Vec<(String, bool)>,
+    /// Username the bot is running as
+    pub telegram_username: String,
+    /// "webhook" or "polling"
+    pub update_mode: &'static str,
+    /// HTTP port the webhook server binds to (webhook mode only)
+    pub http_port: Option<u16>,
+    /// Optional features enabled by configuration
+    pub active_features: Vec<&'static str>,
+}
+
+impl std::fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "📋 Startup report:")?;
+        writeln!(f, "   Database:  connected ({} chunks)", self.chunk_count)?;
+        if self.openai_reachable {
+            let models = self
+                .openai_models
+                .iter()
+                .map(|(model, available)| {
+                    format!("{} ({})", model, if *available { "available" } else { "NOT FOUND" })
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "   OpenAI:    reachable - {}", models)?;
+        } else {
+            writeln!(f, "   OpenAI:    UNREACHABLE")?;
+        }
+        writeln!(f, "   Telegram:  connected as @{}", self.telegram_username)?;
+        match self.http_port {
+            Some(port) => writeln!(f, "   Updates:   {} (HTTP port {})", self.update_mode, port)?,
+            None => writeln!(f, "   Updates:   {}", self.update_mode)?,
+        }
+        if self.active_features.is_empty() {
+            write!(f, "   Features:  none")
+        } else {
+            write!(f, "   Features:  {}", self.active_features.join(", "))
+        }
+    }
+}
+
+/// Check every subsystem and aggregate the results into a [`StartupReport`]
+///
+/// The database is hard-required, so a failing check returns an error.
+/// OpenAI problems are only reported, since they may be transient.
+pub async fn startup_report(config: &Config, rag_system: &RAGSystem, me: &Me) -> Result<StartupReport> {
+    let chunk_count = rag_system
+        .chunk_count(None)
+        .await
+        .context("Startup check failed: database is not usable")?;
+
+    let (openai_reachable, openai_models) = match rag_system.list_openai_models().await {
+        Ok(available) => {
+            // Self-hosted embedding models aren't listed by OpenAI
+            let embedding_model = (config.embedding_provider == EmbeddingProviderKind::OpenAI)
+                .then_some(&config.embedding_model);
+            let models = embedding_model
+                .into_iter()
+                .chain([&config.gpt_model])
+                .map(|model| (model.clone(), available.contains(model)))
+                .collect();
+            (true, models)
+        }
+        Err(e) => {
+            log::warn!("Startup check: OpenAI API not reachable: {}", e);
+            (false, Vec::new())
+        }
+    };
+
+    let is_webhook_mode = config.webhook_url.is_some();
+
+    Ok(StartupReport {
+        chunk_count,
+        openai_reachable,
+        openai_models,
+        telegram_username: me.username().to_string(),
+        update_mode: if is_webhook_mode { "webhook" } else { "polling" },
+        http_port: is_webhook_mode.then_some(config.webhook_port),
+        active_features: config.active_features(),
+    })
+}
+
+/// Run a parsed command (shared by the polling dispatcher and webhook processor)
+async fn run_command(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    rag_system: Arc<RAGSystem>,
+    conversation_manager: Arc<ConversationManager>,
+) -> Result<()> {
+    match cmd {
+        Command::Start => handle_start_command(bot, msg).await,
+        Command::Help => handle_help_command(bot, msg).await,
+        Command::Clear => handle_clear_command(bot, msg, conversation_manager).await,
+        Command::AddKnowledge(text) => handle_addknowledge_command(bot, msg, text, rag_system).await,
+        Command::Broadcast(text) => handle_broadcast_command(bot, msg, text, rag_system).await,
+        Command::Settings(text) => handle_settings_command(bot, msg, text, rag_system).await,
+    }
+}
+
 /// Retry getting bot info with exponential backoff
 async fn retry_get_me(bot: &Bot) -> Result<Me> {
     let max_retries = 5;
@@ -326,15 +474,11 @@ async fn retry_get_me(bot: &Bot) -> Result<Me> {
     unreachable!()
 }
 
-/// Run bot with webhook server
-async fn run_webhook_server(
-    bot: Bot,
-    config: Config,
-    webhook_url: &str,
-    rag_system: Arc<RAGSystem>,
-    conversation_manager: Arc<ConversationManager>,
-    _me: Me, // May be invalid if initial get_me() failed - we'll get fresh one here
-) -> Result<()> {
+/// Verify connectivity and register the webhook URL (and secret) with Telegram
+/// 
+/// # Returns
+/// Fresh bot info, fetched over the same outbound connection
+async fn register_webhook(bot: &Bot, config: &Config, webhook_url: &str) -> Result<Me> {
     let webhook_path = format!("{}/webhook", webhook_url);
     
     log::info!("Setting webhook URL: {}", webhook_path);
@@ -378,20 +522,25 @@ async fn run_webhook_server(
         .context("Failed to set webhook with Telegram")?;
     
     log::info!("Webhook set successfully");
+    Ok(verified_me)
+}
+
+/// Run bot with webhook server
+async fn run_webhook_server(
+    bot: Bot,
+    config: Config,
+    webhook_url: &str,
+    rag_system: Arc<RAGSystem>,
+    conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
+    verified_me: Me,
+) -> Result<()> {
+    let webhook_path = format!("{}/webhook", webhook_url);
     
-    // Ensure we're using Railway's PORT if available (Railway sets this automatically)
-    // Railway's public networking on port 80 forwards to this internal PORT
-    let actual_port = std::env::var("PORT")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(config.webhook_port);
-    
+    // Railway's PORT, if set, was already resolved into webhook_port
+    // (Railway's public networking on port 80 forwards to it)
+    let actual_port = config.webhook_port;
     log::info!("🔌 Railway public networking: port 80 → internal port {}", actual_port);
-    log::info!("🔌 Railway PORT env var: {:?}", std::env::var("PORT").ok());
-    
-    if actual_port != config.webhook_port {
-        log::info!("Using Railway's PORT env var: {} (config had: {})", actual_port, config.webhook_port);
-    }
     
     let addr = format!("0.0.0.0:{}", actual_port);
     
@@ -412,6 +561,7 @@ async fn run_webhook_server(
                 update,
                 rag_clone.clone(),
                 conv_clone.clone(),
+                rate_limiter.clone(),
                 me_clone.clone(),
             ).await {
                 log::error!("❌ Error processing webhook update: {:?}", e);
@@ -424,13 +574,12 @@ async fn run_webhook_server(
     // Create shared state for the HTTP server
     let state = AppState {
         update_tx: tx,
+        rag_system,
+        conversation_manager,
     };
     
     // Build the router
-    let app = Router::new()
-        .route("/webhook", post(webhook_handler))
-        .route("/health", get(health_check))
-        .with_state(state);
+    let app = create_router(state);
     
     log::info!("🚀 Starting webhook server on {}", addr);
     log::info!("📍 Health check: http://{}/health", addr);
@@ -474,65 +623,21 @@ async fn run_webhook_server(
     Ok(())
 }
 
-/// Application state shared across HTTP handlers
-#[derive(Clone)]
-struct AppState {
-    update_tx: tokio::sync::mpsc::UnboundedSender<Update>,
-}
-
-/// Handle incoming webhook updates from Telegram
-async fn webhook_handler(
-    State(state): State<AppState>,
-    body: axum::body::Body,
-) -> Result<StatusCode, (StatusCode, Json<Value>)> {
-    log::info!("📥 Received webhook update from Telegram");
-    
-    // Add a timeout to prevent hanging requests
-    let start = std::time::Instant::now();
-    
-    // Read the body
-    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            log::error!("Failed to read webhook body: {}", e);
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "Failed to read request body"})),
-            ));
-        }
-    };
-    
-    log::debug!("Webhook body size: {} bytes", bytes.len());
-    
-    // Parse the update
-    let update: Update = match serde_json::from_slice::<Update>(bytes.as_ref()) {
-        Ok(update) => {
-            log::info!("✓ Successfully parsed update ID: {:?}", update.id);
-            update
-        }
+/// Serve `/metrics` on its own port until the process exits
+async fn serve_metrics(port: u16) {
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
         Err(e) => {
-            log::error!("Failed to parse webhook update: {}", e);
-            let preview_len = bytes.len().min(500);
-            log::error!("Raw body (first {} chars): {}", preview_len, String::from_utf8_lossy(&bytes[..preview_len]));
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "Invalid update format"})),
-            ));
+            log::error!("Failed to bind metrics server to {}: {}", addr, e);
+            return;
         }
     };
-    
-    // Send update to processing channel
-    if let Err(e) = state.update_tx.send(update) {
-        log::error!("Failed to send update to processing channel: {}", e);
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "Failed to queue update"})),
-        ));
+
+    log::info!("📈 Metrics: http://{}/metrics", addr);
+    if let Err(e) = axum::serve(listener, metrics_router()).await {
+        log::error!("Metrics server error: {}", e);
     }
-    
-    let elapsed = start.elapsed();
-    log::info!("✓ Update queued for processing (took {:?})", elapsed);
-    Ok(StatusCode::OK)
 }
 
 /// Process webhook update by manually routing to appropriate handlers
@@ -541,6 +646,7 @@ async fn process_webhook_update(
     update: Update,
     rag_system: Arc<RAGSystem>,
     conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
     me: Me,
 ) -> Result<()> {
     log::debug!("Processing update: ID={:?}, kind={:?}", update.id, update.kind);
@@ -549,39 +655,36 @@ async fn process_webhook_update(
     match update.kind {
         teloxide::types::UpdateKind::Message(msg) => {
             log::info!("📨 Received message update");
-            // Check if it's a command
-            if let Some(cmd) = msg.text().and_then(|t| {
-                if t.starts_with('/') {
-                    t.split_whitespace().next().map(|s| s.trim_start_matches('/'))
-                } else {
-                    None
+            if let Some(text) = msg.text().filter(|t| t.starts_with('/')) {
+                let config = rag_system.config();
+                if !accepts_command(text, me.username(), msg.chat.is_private(), config.group_bare_commands) {
+                    log::debug!("Ignoring command not addressed to this bot: {}", text);
+                    return Ok(());
                 }
-            }) {
-                match cmd.to_lowercase().as_str() {
-                    "start" => handle_start_command(bot, msg).await?,
-                    "help" => handle_help_command(bot, msg).await?,
-                    "clear" => handle_clear_command(bot, msg, conversation_manager).await?,
-                    _ => {
-                        // Try to handle as regular message
-                        if let Err(e) = handle_message(bot, msg, me, rag_system, conversation_manager).await {
-                            log::error!("Error handling message: {:?}", e);
-                        }
-                    }
-                }
-            } else {
-                // Regular message
-                if let Err(e) = handle_message(bot, msg, me, rag_system, conversation_manager).await {
-                    log::error!("Error handling message: {:?}", e);
+
+                // Known commands are handled here; anything else is a regular message
+                if let Ok(cmd) = Command::parse(text, me.username()) {
+                    return run_command(bot, msg, cmd, rag_system, conversation_manager).await;
                 }
             }
+
+            if let Err(e) = handle_message(bot, msg, me, rag_system, conversation_manager, rate_limiter).await {
+                log::error!("Error handling message: {:?}", e);
+            }
         }
         teloxide::types::UpdateKind::EditedMessage(msg) => {
             log::info!("✏️ Received edited message update");
             // Handle edited messages
-            if let Err(e) = handle_edited_message(bot, msg, me, rag_system, conversation_manager).await {
+            if let Err(e) = handle_edited_message(bot, msg, me, rag_system, conversation_manager, rate_limiter).await {
                 log::error!("Error handling edited message: {:?}", e);
             }
         }
+        teloxide::types::UpdateKind::CallbackQuery(query) => {
+            log::info!("👍 Received callback query update");
+            if let Err(e) = handle_callback_query(bot, query, me, rag_system, conversation_manager, rate_limiter).await {
+                log::error!("Error handling button click: {:?}", e);
+            }
+        }
         other => {
             log::debug!("Ignoring update type: {:?} for update ID: {:?}", other, update.id);
         }
@@ -590,14 +693,6 @@ async fn process_webhook_update(
     Ok(())
 }
 
-/// Health check endpoint
-async fn health_check() -> Json<Value> {
-    Json(json!({
-        "status": "ok",
-        "service": "pollinet_knowledge_bot"
-    }))
-}
-
 /// Initialize and run the Telegram bot (creates its own RAG system)
 pub async fn run_bot(config: Config) -> Result<()> {
     log::info!("Initializing bot...");
@@ -612,3 +707,49 @@ pub async fn run_bot(config: Config) -> Result<()> {
     run_bot_with_rag(config, rag_system).await
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> StartupReport {
+        StartupReport {
+            chunk_count: 42,
+            openai_reachable: true,
+            openai_models: vec![
+                ("text-embedding-3-small".to_string(), true),
+                ("gpt-4o-mini".to_string(), false),
+            ],
+            telegram_username: "pollinet_bot".to_string(),
+            update_mode: "webhook",
+            http_port: Some(8080),
+            active_features: vec!["hybrid retrieval", "answer cache"],
+        }
+    }
+
+    #[test]
+    fn startup_report_lists_every_subsystem() {
+        let text = report().to_string();
+        assert!(text.contains("connected (42 chunks)"));
+        assert!(text.contains("text-embedding-3-small (available), gpt-4o-mini (NOT FOUND)"));
+        assert!(text.contains("connected as @pollinet_bot"));
+        assert!(text.contains("webhook (HTTP port 8080)"));
+        assert!(text.ends_with("Features:  hybrid retrieval, answer cache"));
+    }
+
+    #[test]
+    fn startup_report_flags_unreachable_openai() {
+        let text = StartupReport {
+            openai_reachable: false,
+            openai_models: Vec::new(),
+            update_mode: "polling",
+            http_port: None,
+            active_features: Vec::new(),
+            ..report()
+        }
+        .to_string();
+        assert!(text.contains("OpenAI:    UNREACHABLE"));
+        assert!(text.contains("Updates:   polling\n"));
+        assert!(text.ends_with("Features:  none"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Per-chat settings module
+//!
+//! This module handles:
+//! - Storing per-chat options (answer language, keyword triggering,
+//!   knowledge base namespace) in the `chat_settings` table
+//! - Parsing the `name value` pairs given to `/settings`
+
+use anyhow::{Context, Result};
+use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Row};
+use std::fmt;
+
+use crate::rag::{checked_namespace, DEFAULT_NAMESPACE};
+
+/// Options an admin can change for one chat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatSettings {
+    /// Language answers are written in (`None` = the model's choice)
+    pub language: Option<String>,
+    /// Whether `TRIGGER_KEYWORDS` make the bot answer in groups
+    pub keyword_trigger: bool,
+    /// Knowledge base namespace the chat is answered from (`None` = the
+    /// default namespace)
+    pub namespace: Option<String>,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            language: None,
+            keyword_trigger: true,
+            namespace: None,
+        }
+    }
+}
+
+impl fmt::Display for ChatSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "language: {}", self.language.as_deref().unwrap_or("auto"))?;
+        writeln!(f, "keywords: {}", if self.keyword_trigger { "on" } else { "off" })?;
+        write!(f, "namespace: {}", self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE))
+    }
+}
+
+/// One change to a chat's settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatSetting {
+    /// `language <name>`, or `language auto` to clear it
+    Language(Option<String>),
+    /// `keywords on|off`
+    KeywordTrigger(bool),
+    /// `namespace <name>`, or `namespace default` to clear it
+    Namespace(Option<String>),
+}
+
+impl ChatSetting {
+    /// Parse a setting name and value as typed after `/settings`
+    pub fn parse(name: &str, value: &str) -> Result<Self> {
+        let value = value.trim();
+        match name.trim().to_lowercase().as_str() {
+            "language" => match value.to_lowercase().as_str() {
+                "" => anyhow::bail!("Missing language (e.g. Spanish, or auto)"),
+                "auto" | "off" | "none" => Ok(ChatSetting::Language(None)),
+                _ => Ok(ChatSetting::Language(Some(value.to_string()))),
+            },
+            "keywords" => match value.to_lowercase().as_str() {
+                "on" | "true" | "yes" => Ok(ChatSetting::KeywordTrigger(true)),
+                "off" | "false" | "no" => Ok(ChatSetting::KeywordTrigger(false)),
+                _ => anyhow::bail!("keywords must be on or off"),
+            },
+            "namespace" => match value {
+                "" => anyhow::bail!("Missing namespace (e.g. docs, or default)"),
+                DEFAULT_NAMESPACE => Ok(ChatSetting::Namespace(None)),
+                _ => Ok(ChatSetting::Namespace(Some(checked_namespace(Some(value))?.to_string()))),
+            },
+            other => anyhow::bail!("Unknown setting: {}", other),
+        }
+    }
+}
+
+/// Settings for a chat, or the defaults if none were ever changed
+pub async fn get_chat_settings(pool: &PgPool, chat_id: i64) -> Result<ChatSettings> {
+    let row = sqlx::query("SELECT language, keyword_trigger, namespace FROM chat_settings WHERE chat_id = $1")
+        .bind(chat_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to load chat settings")?;
+
+    match row {
+        Some(row) => settings_from_row(&row),
+        None => Ok(ChatSettings::default()),
+    }
+}
+
+fn settings_from_row(row: &PgRow) -> Result<ChatSettings> {
+    Ok(ChatSettings {
+        language: row.try_get("language")?,
+        keyword_trigger: row.try_get("keyword_trigger")?,
+        namespace: row.try_get("namespace")?,
+    })
+}
+
+/// Apply one setting change to a chat, leaving its other settings alone
+///
+/// # Returns
+/// The chat's settings after the change
+pub async fn set_chat_setting(pool: &PgPool, chat_id: i64, setting: &ChatSetting) -> Result<ChatSettings> {
+    let query = match setting {
+        ChatSetting::Language(language) => sqlx::query(
+            r#"
+            INSERT INTO chat_settings (chat_id, language) VALUES ($1, $2)
+            ON CONFLICT (chat_id) DO UPDATE
+            SET language = EXCLUDED.language, updated_at = CURRENT_TIMESTAMP
+            RETURNING language, keyword_trigger, namespace
+            "#,
+        )
+        .bind(chat_id)
+        .bind(language.clone()),
+        ChatSetting::KeywordTrigger(enabled) => sqlx::query(
+            r#"
+            INSERT INTO chat_settings (chat_id, keyword_trigger) VALUES ($1, $2)
+            ON CONFLICT (chat_id) DO UPDATE
+            SET keyword_trigger = EXCLUDED.keyword_trigger, updated_at = CURRENT_TIMESTAMP
+            RETURNING language, keyword_trigger, namespace
+            "#,
+        )
+        .bind(chat_id)
+        .bind(*enabled),
+        ChatSetting::Namespace(namespace) => sqlx::query(
+            r#"
+            INSERT INTO chat_settings (chat_id, namespace) VALUES ($1, $2)
+            ON CONFLICT (chat_id) DO UPDATE
+            SET namespace = EXCLUDED.namespace, updated_at = CURRENT_TIMESTAMP
+            RETURNING language, keyword_trigger, namespace
+            "#,
+        )
+        .bind(chat_id)
+        .bind(namespace.clone()),
+    };
+
+    let row = query
+        .fetch_one(pool)
+        .await
+        .context("Failed to save chat setting")?;
+
+    settings_from_row(&row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[test]
+    fn settings_parse_from_name_and_value() {
+        assert_eq!(ChatSetting::parse("Language", " Spanish ").unwrap(), ChatSetting::Language(Some("Spanish".into())));
+        assert_eq!(ChatSetting::parse("language", "auto").unwrap(), ChatSetting::Language(None));
+        assert_eq!(ChatSetting::parse("keywords", "OFF").unwrap(), ChatSetting::KeywordTrigger(false));
+        assert_eq!(ChatSetting::parse("namespace", "docs").unwrap(), ChatSetting::Namespace(Some("docs".into())));
+        assert_eq!(ChatSetting::parse("namespace", "default").unwrap(), ChatSetting::Namespace(None));
+
+        assert!(ChatSetting::parse("keywords", "maybe").is_err());
+        assert!(ChatSetting::parse("namespace", "no spaces").is_err());
+        assert!(ChatSetting::parse("colour", "blue").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn settings_round_trip_through_the_database() {
+        let pool = test_pool().await;
+        let chat_id = -(std::process::id() as i64) * 10 - 5;
+        sqlx::query("DELETE FROM chat_settings WHERE chat_id = $1")
+            .bind(chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(get_chat_settings(&pool, chat_id).await.unwrap(), ChatSettings::default());
+
+        set_chat_setting(&pool, chat_id, &ChatSetting::Language(Some("French".into()))).await.unwrap();
+        let updated = set_chat_setting(&pool, chat_id, &ChatSetting::KeywordTrigger(false)).await.unwrap();
+        // Changing one setting leaves the others alone
+        let settings = get_chat_settings(&pool, chat_id).await.unwrap();
+        assert_eq!(updated, settings);
+        assert_eq!(settings.language.as_deref(), Some("French"));
+        assert!(!settings.keyword_trigger);
+        assert_eq!(settings.namespace, None);
+
+        set_chat_setting(&pool, chat_id, &ChatSetting::Language(None)).await.unwrap();
+        assert_eq!(get_chat_settings(&pool, chat_id).await.unwrap().language, None);
+
+        sqlx::query("DELETE FROM chat_settings WHERE chat_id = $1")
+            .bind(chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Chat registry module
+//!
+//! This module handles:
+//! - Recording every chat the bot answers in the `chats` table
+//! - Broadcasting announcements to all recorded chats
+//! - Forgetting chats that blocked or removed the bot
+
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::Chat;
+use teloxide::{ApiError, RequestError};
+
+use crate::telegram::SendWithRetry;
+
+/// Pause between broadcast messages, keeping well under Telegram's ~30 msg/s limit
+const BROADCAST_DELAY: Duration = Duration::from_millis(50);
+
+/// Outcome of [`broadcast_message`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BroadcastReport {
+    /// Chats the message was delivered to
+    pub sent: usize,
+    /// Chats dropped from the registry because the bot can no longer post there
+    pub removed: usize,
+    /// Chats that failed for other (possibly transient) reasons
+    pub failed: usize,
+}
+
+/// Insert a chat, or bump `last_seen` if it is already known
+pub async fn record_chat(pool: &PgPool, chat: &Chat) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO chats (id, type) VALUES ($1, $2)
+        ON CONFLICT (id) DO UPDATE SET type = EXCLUDED.type, last_seen = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(chat.id.0)
+    .bind(chat_type(chat))
+    .execute(pool)
+    .await
+    .context("Failed to record chat")?;
+
+    Ok(())
+}
+
+/// Ids of every recorded chat, oldest first
+pub async fn list_chat_ids(pool: &PgPool) -> Result<Vec<i64>> {
+    sqlx::query_scalar("SELECT id FROM chats ORDER BY first_seen")
+        .fetch_all(pool)
+        .await
+        .context("Failed to list chats")
+}
+
+/// Forget a chat the bot can no longer post to
+pub async fn remove_chat(pool: &PgPool, chat_id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM chats WHERE id = $1")
+        .bind(chat_id)
+        .execute(pool)
+        .await
+        .context("Failed to remove chat")?;
+
+    Ok(())
+}
+
+/// Send `text` to every recorded chat, one message at a time
+///
+/// Chats that blocked, kicked or no longer exist for the bot are removed
+/// from the registry; transient errors are retried (see [`SendWithRetry`]).
+pub async fn broadcast_message(bot: &Bot, pool: &PgPool, text: &str) -> Result<BroadcastReport> {
+    let chat_ids = list_chat_ids(pool).await?;
+    let mut report = BroadcastReport::default();
+    log::info!("Broadcasting to {} chats", chat_ids.len());
+
+    for chat_id in chat_ids {
+        match bot.send_message(ChatId(chat_id), text).send_with_retry().await {
+            Ok(_) => report.sent += 1,
+            Err(RequestError::Api(error)) if is_unreachable_chat(&error) => {
+                log::info!("Removing chat {} from broadcast list: {}", chat_id, error);
+                remove_chat(pool, chat_id).await?;
+                report.removed += 1;
+            }
+            Err(e) => {
+                log::warn!("Failed to broadcast to chat {}: {}", chat_id, e);
+                report.failed += 1;
+            }
+        }
+
+        tokio::time::sleep(BROADCAST_DELAY).await;
+    }
+
+    log::info!(
+        "Broadcast finished: {} sent, {} removed, {} failed",
+        report.sent,
+        report.removed,
+        report.failed
+    );
+    Ok(report)
+}
+
+/// Errors meaning the bot will never be able to post to the chat again
+fn is_unreachable_chat(error: &ApiError) -> bool {
+    matches!(
+        error,
+        ApiError::BotBlocked
+            | ApiError::ChatNotFound
+            | ApiError::UserDeactivated
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::GroupDeactivated
+            | ApiError::CantInitiateConversation
+    )
+}
+
+fn chat_type(chat: &Chat) -> &'static str {
+    if chat.is_private() {
+        "private"
+    } else if chat.is_supergroup() {
+        "supergroup"
+    } else if chat.is_group() {
+        "group"
+    } else {
+        "channel"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    fn chat(id: i64, kind: &str) -> Chat {
+        let chat = match kind {
+            "private" => serde_json::json!({"id": id, "type": "private", "first_name": "Ada"}),
+            kind => serde_json::json!({"id": id, "type": kind, "title": "Pollinet"}),
+        };
+        serde_json::from_value(chat).expect("valid chat")
+    }
+
+    #[test]
+    fn chat_types_match_telegram_names() {
+        assert_eq!(chat_type(&chat(1, "private")), "private");
+        assert_eq!(chat_type(&chat(-1, "group")), "group");
+        assert_eq!(chat_type(&chat(-100, "supergroup")), "supergroup");
+        assert_eq!(chat_type(&chat(-100, "channel")), "channel");
+    }
+
+    #[test]
+    fn only_permanent_errors_drop_a_chat() {
+        assert!(is_unreachable_chat(&ApiError::BotBlocked));
+        assert!(is_unreachable_chat(&ApiError::BotKickedFromSupergroup));
+        assert!(!is_unreachable_chat(&ApiError::MessageIsTooLong));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn recording_a_chat_twice_keeps_one_row() {
+        let pool = test_pool().await;
+        let id = -(std::process::id() as i64) * 10 - 3;
+        remove_chat(&pool, id).await.unwrap();
+
+        record_chat(&pool, &chat(id, "group")).await.unwrap();
+        // A group upgraded to a supergroup keeps its row with the new type
+        record_chat(&pool, &chat(id, "supergroup")).await.unwrap();
+
+        let ids = list_chat_ids(&pool).await.unwrap();
+        assert_eq!(ids.iter().filter(|&&chat_id| chat_id == id).count(), 1);
+        let kind: String = sqlx::query_scalar("SELECT type FROM chats WHERE id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(kind, "supergroup");
+
+        remove_chat(&pool, id).await.unwrap();
+        assert!(!list_chat_ids(&pool).await.unwrap().contains(&id));
+    }
+}
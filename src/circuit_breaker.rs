@@ -0,0 +1,233 @@
+//! Circuit breaker for OpenAI requests
+//!
+//! This module handles:
+//! - Counting consecutive OpenAI failures within a time window
+//! - Failing fast while the circuit is open, instead of waiting out timeouts
+//! - Letting a single probe request through once the cooldown has passed
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// Returned instead of calling OpenAI while the circuit is open
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitOpen {
+    /// Time left until the next probe request is allowed
+    pub retry_in: Duration,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the AI service is temporarily unavailable (retrying in {}s)",
+            self.retry_in.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    /// Consecutive failures since `window_start`
+    failures: u32,
+    window_start: Option<Instant>,
+    /// Set while the circuit is open: requests are refused until then
+    open_until: Option<Instant>,
+}
+
+/// Consecutive-failure circuit breaker
+///
+/// After `threshold` failures within `window` (with no success in between)
+/// the circuit opens and [`Self::check`] refuses requests for `cooldown`.
+/// The first request after that is let through as a probe: a success closes
+/// the circuit, a failure keeps it open for another cooldown.
+/// A threshold of 0 disables the breaker.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.circuit_breaker_threshold,
+            Duration::from_secs(config.circuit_breaker_window_secs),
+            Duration::from_secs(config.circuit_breaker_cooldown_secs),
+        )
+    }
+
+    /// Whether a request may be sent now
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        self.check_at(Instant::now())
+    }
+
+    /// [`Self::check`] against an explicit clock
+    pub fn check_at(&self, now: Instant) -> Result<(), CircuitOpen> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if now < until => Err(CircuitOpen {
+                retry_in: until - now,
+            }),
+            Some(_) => {
+                // Let this request probe; everyone else waits another cooldown
+                log::info!("OpenAI circuit half-open, sending a probe request");
+                state.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record a request that reached OpenAI and got an answer
+    pub fn record_success(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            log::info!("OpenAI is responding again, closing the circuit");
+        }
+        *state = BreakerState::default();
+    }
+
+    /// Record a request that failed after all its retries
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now());
+    }
+
+    /// [`Self::record_failure`] against an explicit clock
+    pub fn record_failure_at(&self, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            // A failed probe (or a request sent before the circuit opened)
+            state.open_until = Some(now + self.cooldown);
+            return;
+        }
+
+        let window_expired = state
+            .window_start
+            .is_none_or(|start| now.duration_since(start) > self.window);
+        if window_expired {
+            state.failures = 0;
+            state.window_start = Some(now);
+        }
+        state.failures += 1;
+
+        if state.failures >= self.threshold {
+            log::warn!(
+                "OpenAI failed {} times in a row, opening the circuit for {}s",
+                state.failures,
+                self.cooldown.as_secs()
+            );
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+
+    /// Whether requests are currently being refused
+    pub fn is_open(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .open_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(3, 60 * SECOND, 30 * SECOND)
+    }
+
+    #[test]
+    fn repeated_failures_trip_the_breaker_until_the_cooldown() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..2 {
+            breaker.record_failure_at(start);
+            assert!(breaker.check_at(start).is_ok());
+        }
+        breaker.record_failure_at(start);
+
+        let refused = breaker.check_at(start + 10 * SECOND).unwrap_err();
+        assert_eq!(refused.retry_in, 20 * SECOND);
+        assert!(refused.to_string().contains("temporarily unavailable"));
+
+        // After the cooldown one probe goes through; a success closes the circuit
+        let after_cooldown = start + 31 * SECOND;
+        assert!(breaker.check_at(after_cooldown).is_ok());
+        assert!(breaker.check_at(after_cooldown).is_err());
+        breaker.record_success();
+        assert!(breaker.check_at(after_cooldown).is_ok());
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn failed_probes_reopen_the_circuit() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(start);
+        }
+
+        let probe = start + 31 * SECOND;
+        assert!(breaker.check_at(probe).is_ok());
+        breaker.record_failure_at(probe);
+        assert_eq!(breaker.check_at(probe + SECOND).unwrap_err().retry_in, 29 * SECOND);
+    }
+
+    #[test]
+    fn only_failures_within_the_window_count() {
+        let breaker = breaker();
+        let start = Instant::now();
+        breaker.record_failure_at(start);
+        breaker.record_failure_at(start + SECOND);
+        // The window has passed, so this starts a new count
+        breaker.record_failure_at(start + 61 * SECOND);
+        assert!(breaker.check_at(start + 61 * SECOND).is_ok());
+
+        // A success in between resets the count too
+        breaker.record_failure_at(start + 62 * SECOND);
+        breaker.record_success();
+        breaker.record_failure_at(start + 63 * SECOND);
+        assert!(breaker.check_at(start + 63 * SECOND).is_ok());
+    }
+
+    #[test]
+    fn a_zero_threshold_disables_the_breaker() {
+        let breaker = CircuitBreaker::new(0, 60 * SECOND, 30 * SECOND);
+        let now = Instant::now();
+        for _ in 0..10 {
+            breaker.record_failure_at(now);
+        }
+        assert!(breaker.check_at(now).is_ok());
+    }
+}
@@ -0,0 +1,362 @@
+//! Embedding provider module
+//!
+//! This module handles:
+//! - The `EmbeddingProvider` abstraction the RAG system embeds text through
+//! - OpenAI's embeddings endpoint
+//! - Any OpenAI-compatible embeddings server (e.g. a locally hosted model)
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{Config, EmbeddingProviderKind};
+use crate::rag::{api_url, post_with_retry, ApiAuth, OpenAIUsage, UsageCounters};
+
+/// Base URL of the OpenAI API
+pub const OPENAI_API_BASE: &str = "https://api.openai.com/v1";
+
+/// Maximum number of inputs sent in a single embeddings request
+pub const EMBEDDING_BATCH_SIZE: usize = 100;
+
+/// Source of embedding vectors for chunks and queries
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Embed every text, returning one vector per input in the same order
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    /// Model the embeddings come from (part of the embedding cache key)
+    fn model(&self) -> &str;
+
+    /// The same provider using a different model, for re-embedding
+    fn with_model(&self, model: &str) -> Box<dyn EmbeddingProvider>;
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    input: &'a [String],
+    model: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    #[serde(default)]
+    index: usize,
+}
+
+/// Embeddings from any server implementing OpenAI's `/embeddings` API
+#[derive(Clone)]
+pub struct OpenAICompatibleProvider {
+    http_client: reqwest::Client,
+    /// API root, e.g. `http://localhost:8000/v1` (without `/embeddings`)
+    base_url: String,
+    /// Sent as a bearer token when set
+    api_key: Option<String>,
+    /// Send the key in an `api-key` header instead (Azure OpenAI)
+    azure_auth: bool,
+    model: String,
+    max_retries: u32,
+    usage: Option<Arc<UsageCounters>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl OpenAICompatibleProvider {
+    pub fn new(
+        http_client: reqwest::Client,
+        base_url: impl Into<String>,
+        api_key: Option<String>,
+        model: impl Into<String>,
+        max_retries: u32,
+    ) -> Self {
+        Self {
+            http_client,
+            base_url: base_url.into(),
+            api_key,
+            azure_auth: false,
+            model: model.into(),
+            max_retries,
+            usage: None,
+            circuit_breaker: None,
+        }
+    }
+
+    /// Send the API key in Azure's `api-key` header
+    pub fn with_azure_auth(mut self) -> Self {
+        self.azure_auth = true;
+        self
+    }
+
+    /// Count the tokens reported by the server towards `usage`
+    pub(crate) fn with_usage(mut self, usage: Arc<UsageCounters>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Fail fast while `breaker` is open, and report failures to it
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAICompatibleProvider {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let url = api_url(&self.base_url, "embeddings");
+        let auth = match self.api_key.as_deref() {
+            Some(key) if self.azure_auth => ApiAuth::AzureApiKey(key),
+            Some(key) => ApiAuth::Bearer(key),
+            None => ApiAuth::None,
+        };
+        let mut embeddings = Vec::with_capacity(texts.len());
+
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            let request = EmbeddingRequest {
+                input: batch,
+                model: &self.model,
+            };
+
+            let response = post_with_retry(
+                &self.http_client,
+                &url,
+                auth,
+                self.max_retries,
+                self.circuit_breaker.as_deref(),
+                &request,
+            )
+            .await
+            .context("Failed to send embedding request")?;
+
+            let response_text = response
+                .text()
+                .await
+                .context("Failed to read response body")?;
+            let mut response: EmbeddingResponse = serde_json::from_str(&response_text)
+                .context(format!(
+                    "Failed to parse embedding response. Response was: {}",
+                    response_text
+                ))?;
+            if let Some(usage) = &self.usage {
+                usage.record(response.usage.as_ref());
+            }
+
+            if response.data.len() != batch.len() {
+                return Err(anyhow::anyhow!(
+                    "Embedding API returned {} embeddings for {} inputs",
+                    response.data.len(),
+                    batch.len()
+                ));
+            }
+
+            // The API documents ordered results, but each item carries its index
+            response.data.sort_by_key(|d| d.index);
+            embeddings.extend(response.data.into_iter().map(|d| d.embedding));
+        }
+
+        Ok(embeddings)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn with_model(&self, model: &str) -> Box<dyn EmbeddingProvider> {
+        let mut provider = self.clone();
+        provider.model = model.to_string();
+        Box::new(provider)
+    }
+}
+
+/// Embeddings from the OpenAI API
+#[derive(Clone)]
+pub struct OpenAIEmbeddingProvider {
+    inner: OpenAICompatibleProvider,
+}
+
+impl OpenAIEmbeddingProvider {
+    pub fn new(
+        http_client: reqwest::Client,
+        api_key: impl Into<String>,
+        model: impl Into<String>,
+        max_retries: u32,
+    ) -> Self {
+        Self {
+            inner: OpenAICompatibleProvider::new(
+                http_client,
+                OPENAI_API_BASE,
+                Some(api_key.into()),
+                model,
+                max_retries,
+            ),
+        }
+    }
+
+    /// Use a different API root, e.g. a proxy or an Azure OpenAI deployment
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.inner.base_url = base_url.into();
+        self
+    }
+
+    /// Send the API key in Azure's `api-key` header
+    pub fn with_azure_auth(mut self) -> Self {
+        self.inner = self.inner.with_azure_auth();
+        self
+    }
+
+    /// Count the tokens reported by OpenAI towards `usage`
+    pub(crate) fn with_usage(mut self, usage: Arc<UsageCounters>) -> Self {
+        self.inner = self.inner.with_usage(usage);
+        self
+    }
+
+    /// Fail fast while `breaker` is open, and report failures to it
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.inner = self.inner.with_circuit_breaker(breaker);
+        self
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAIEmbeddingProvider {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed(texts).await
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    fn with_model(&self, model: &str) -> Box<dyn EmbeddingProvider> {
+        let mut provider = self.clone();
+        provider.inner.model = model.to_string();
+        Box::new(provider)
+    }
+}
+
+/// Build the embedding provider selected by `EMBEDDING_PROVIDER`
+pub(crate) fn provider_from_config(
+    config: &Config,
+    http_client: reqwest::Client,
+    usage: Arc<UsageCounters>,
+    circuit_breaker: Arc<CircuitBreaker>,
+) -> Box<dyn EmbeddingProvider> {
+    match config.embedding_provider {
+        EmbeddingProviderKind::OpenAI => {
+            let mut provider = OpenAIEmbeddingProvider::new(
+                http_client,
+                config
+                    .embedding_api_key
+                    .clone()
+                    .unwrap_or_else(|| config.openai_api_key.clone()),
+                config.embedding_model.clone(),
+                config.openai_max_retries,
+            )
+            .with_base_url(config.openai_base_url.clone())
+            .with_usage(usage)
+            .with_circuit_breaker(circuit_breaker);
+            if config.openai_azure_auth {
+                provider = provider.with_azure_auth();
+            }
+            Box::new(provider)
+        }
+        EmbeddingProviderKind::OpenAICompatible => Box::new(
+            OpenAICompatibleProvider::new(
+                http_client,
+                config.embedding_base_url.clone().unwrap_or_default(),
+                config.embedding_api_key.clone(),
+                config.embedding_model.clone(),
+                config.openai_max_retries,
+            )
+            .with_usage(usage)
+            .with_circuit_breaker(circuit_breaker),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitOpen;
+    use axum::http::HeaderMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Requests received by [`embeddings_server`]: the `Authorization`
+    /// header and the JSON body
+    type Received = Arc<Mutex<Vec<(Option<String>, serde_json::Value)>>>;
+
+    /// An `/embeddings` endpoint embedding each input as `[its index]`,
+    /// listing the results in reverse order; returns its API root
+    async fn embeddings_server() -> (String, Received) {
+        let received: Received = Arc::default();
+        let requests = Arc::clone(&received);
+        let handler = move |headers: HeaderMap, axum::Json(body): axum::Json<serde_json::Value>| {
+            let auth = headers
+                .get("authorization")
+                .map(|value| value.to_str().unwrap().to_string());
+            let inputs = body["input"].as_array().map(Vec::len).unwrap_or_default();
+            requests.lock().unwrap().push((auth, body));
+            let data: Vec<_> = (0..inputs)
+                .rev()
+                .map(|index| serde_json::json!({"embedding": [index as f32], "index": index}))
+                .collect();
+            async move { axum::Json(serde_json::json!({ "data": data })) }
+        };
+        let app = axum::Router::new().route("/v1/embeddings", axum::routing::post(handler));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn compatible_provider_batches_and_orders_by_index() {
+        let (url, received) = embeddings_server().await;
+        let provider = OpenAICompatibleProvider::new(reqwest::Client::new(), url, None, "local-model", 0);
+        let texts: Vec<String> = (0..EMBEDDING_BATCH_SIZE + 2).map(|i| format!("text {}", i)).collect();
+
+        let embeddings = provider.embed(&texts).await.unwrap();
+        assert_eq!(embeddings.len(), texts.len());
+        assert_eq!(embeddings[0], [0.0]);
+        assert_eq!(embeddings[EMBEDDING_BATCH_SIZE - 1], [(EMBEDDING_BATCH_SIZE - 1) as f32]);
+        assert_eq!(embeddings[EMBEDDING_BATCH_SIZE + 1], [1.0]);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, None);
+        assert_eq!(received[0].1["model"], "local-model");
+    }
+
+    #[tokio::test]
+    async fn provider_from_config_follows_embedding_provider() {
+        let (url, received) = embeddings_server().await;
+        let config = Config::for_tests(&[
+            ("EMBEDDING_PROVIDER", "openai-compatible"),
+            ("EMBEDDING_BASE_URL", url.as_str()),
+            ("EMBEDDING_API_KEY", "local-key"),
+            ("EMBEDDING_MODEL", "nomic-embed-text"),
+        ]);
+        let breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(60)));
+        let provider = provider_from_config(&config, reqwest::Client::new(), Arc::default(), Arc::clone(&breaker));
+        assert_eq!(provider.model(), "nomic-embed-text");
+        assert_eq!(provider.with_model("other").model(), "other");
+
+        provider.embed(&["Pollinet".to_string()]).await.unwrap();
+        assert_eq!(received.lock().unwrap()[0].0.as_deref(), Some("Bearer local-key"));
+
+        // A down server trips the breaker, and then embed fails without a request
+        breaker.record_failure();
+        let error = provider.embed(&["Pollinet".to_string()]).await.unwrap_err();
+        assert!(error.chain().any(|cause| cause.is::<CircuitOpen>()), "{:#}", error);
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+//! Text extraction module
+//!
+//! This module handles:
+//! - Turning fetched HTML pages into readable plain text for ingestion
+//! - Extracting the text of PDF files with `pdf-extract`
+//!
+//! HTML is rendered with `html2text`, keeping block structure as line breaks
+//! and dropping markup, scripts and styles.
+
+use anyhow::{Context, Result};
+use html2text::render::text_renderer::TrivialDecorator;
+use std::path::{Path, PathBuf};
+
+/// Line width HTML is rendered at; wide enough that paragraphs stay on one line
+const TEXT_WIDTH: usize = 10_000;
+
+/// A PDF to extract text from
+#[derive(Debug, Clone)]
+pub enum PdfSource {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for PdfSource {
+    fn from(path: &Path) -> Self {
+        PdfSource::Path(path.to_path_buf())
+    }
+}
+
+impl From<PathBuf> for PdfSource {
+    fn from(path: PathBuf) -> Self {
+        PdfSource::Path(path)
+    }
+}
+
+impl From<Vec<u8>> for PdfSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        PdfSource::Bytes(bytes)
+    }
+}
+
+/// Text of every page of a PDF, pages separated by blank lines
+///
+/// Extraction runs in-process with `pdf-extract` on a blocking thread.
+/// Encrypted PDFs and PDFs without a text layer (e.g. scans) are reported as
+/// errors.
+pub async fn pdf_to_text(source: &PdfSource) -> Result<String> {
+    let (bytes, name) = match source {
+        PdfSource::Path(path) => {
+            let bytes = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read PDF {}", path.display()))?;
+            (bytes, path.display().to_string())
+        }
+        PdfSource::Bytes(bytes) => (bytes.clone(), "upload".to_string()),
+    };
+
+    let pages = tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem_by_pages(&bytes))
+        .await
+        .with_context(|| format!("PDF {} could not be parsed", name))?;
+    let pages = match pages {
+        Ok(pages) => pages,
+        Err(pdf_extract::OutputError::PdfError(pdf_extract::Error::Decryption(_))) => {
+            anyhow::bail!("PDF {} is encrypted", name)
+        }
+        Err(e) => anyhow::bail!("Failed to extract text from PDF {}: {}", name, e),
+    };
+
+    let text = pages
+        .iter()
+        .map(|page| page.trim())
+        .filter(|page| !page.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if text.is_empty() {
+        anyhow::bail!("PDF {} has no extractable text (is it a scan without a text layer?)", name);
+    }
+    Ok(text)
+}
+
+/// The `<title>` of an HTML page, if it has one
+pub fn html_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    // Render the title as a fragment so its entities are decoded
+    let title = collapse_whitespace(&html_to_text(&html[start..end]));
+    (!title.is_empty()).then_some(title)
+}
+
+/// Readable text of an HTML page: one line per block element, with scripts,
+/// styles and the `<head>` removed and entities decoded
+pub fn html_to_text(html: &str) -> String {
+    let text = html2text::config::with_decorator(TrivialDecorator::new())
+        .raw_mode(true)
+        .allow_width_overflow()
+        .string_from_read(html.as_bytes(), TEXT_WIDTH)
+        .unwrap_or_default();
+
+    text.lines()
+        .map(collapse_whitespace)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replace runs of whitespace with single spaces and trim the ends
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html>
+<head><title>Pollinet &amp; Tokenomics</title><style>body { color: red; }</style></head>
+<body>
+  <script>var tracking = true;</script>
+  <h1>Tokenomics</h1>
+  <p>Relayers   earn a <b>fee</b>
+     per transaction.</p>
+</body>
+</html>"#;
+
+    #[test]
+    fn html_is_reduced_to_its_readable_text() {
+        let text = html_to_text(PAGE);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", text);
+        assert!(lines[0].contains("Tokenomics"), "{}", text);
+        assert_eq!(lines[1], "Relayers earn a fee per transaction.");
+        assert!(!text.contains("tracking") && !text.contains("color"));
+    }
+
+    /// A minimal PDF with one Helvetica text line per page (no text layer
+    /// for empty strings)
+    fn pdf_fixture(pages: &[&str]) -> Vec<u8> {
+        let page_ids: Vec<String> = (0..pages.len()).map(|i| format!("{} 0 R", 4 + 2 * i)).collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>", page_ids.join(" "), pages.len()),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+        ];
+        for (i, text) in pages.iter().enumerate() {
+            let stream = if text.is_empty() {
+                String::new()
+            } else {
+                format!("BT /F1 12 Tf 72 720 Td ({}) Tj ET", text)
+            };
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] \
+                 /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                5 + 2 * i
+            ));
+            objects.push(format!("<< /Length {} >>\nstream\n{}\nendstream", stream.len(), stream));
+        }
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend_from_slice(
+            format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref)
+                .as_bytes(),
+        );
+        pdf
+    }
+
+    #[tokio::test]
+    async fn pdf_pages_are_joined_with_blank_lines() {
+        let pdf = pdf_fixture(&["Pollinet whitepaper", "", "Nonce account management"]);
+        let text = pdf_to_text(&PdfSource::Bytes(pdf)).await.unwrap();
+
+        let pages: Vec<&str> = text.split("\n\n").collect();
+        assert_eq!(pages.len(), 2, "{:?}", text);
+        assert!(pages[0].contains("Pollinet whitepaper"), "{:?}", text);
+        assert!(pages[1].contains("Nonce account management"), "{:?}", text);
+    }
+
+    #[tokio::test]
+    async fn pdfs_without_text_are_rejected() {
+        let error = pdf_to_text(&PdfSource::Bytes(pdf_fixture(&[""]))).await.unwrap_err();
+        assert!(error.to_string().contains("no extractable text"), "{}", error);
+
+        assert!(pdf_to_text(&PdfSource::Bytes(b"not a pdf".to_vec())).await.is_err());
+
+        let missing = std::env::temp_dir().join("pollinet-test-missing.pdf");
+        let error = pdf_to_text(&PdfSource::from(missing)).await.unwrap_err();
+        assert!(error.to_string().contains("Failed to read PDF"), "{}", error);
+    }
+
+    #[test]
+    fn titles_are_decoded() {
+        assert_eq!(html_title(PAGE).as_deref(), Some("Pollinet & Tokenomics"));
+        assert_eq!(html_title("<p>No title</p>"), None);
+        assert_eq!(html_title("<title>  </title>"), None);
+    }
+}
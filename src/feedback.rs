@@ -0,0 +1,148 @@
+//! Answer feedback module
+//!
+//! This module handles:
+//! - Storing answered questions in the `feedback` table until they are rated
+//! - The 👍/👎 inline keyboard attached to answers
+//! - Recording ratings from button clicks
+
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup};
+
+/// Prefix of the callback data sent by feedback buttons
+const CALLBACK_PREFIX: &str = "feedback:";
+
+/// Store an answered question awaiting a rating
+///
+/// # Returns
+/// The feedback id to embed in the answer's buttons
+pub async fn create_feedback(
+    pool: &PgPool,
+    chat_id: i64,
+    user_id: Option<i64>,
+    query: &str,
+    answer: &str,
+) -> Result<i64> {
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO feedback (chat_id, user_id, query, answer) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(query)
+    .bind(answer)
+    .fetch_one(pool)
+    .await
+    .context("Failed to store feedback entry")?;
+
+    Ok(id)
+}
+
+/// Record a rating (1 for 👍, -1 for 👎)
+///
+/// # Returns
+/// `false` if the answer was already rated (or doesn't exist)
+pub async fn record_feedback(pool: &PgPool, id: i64, rating: i16) -> Result<bool> {
+    let updated = sqlx::query(
+        "UPDATE feedback SET rating = $2, rated_at = CURRENT_TIMESTAMP WHERE id = $1 AND rating IS NULL",
+    )
+    .bind(id)
+    .bind(rating)
+    .execute(pool)
+    .await
+    .context("Failed to record feedback")?
+    .rows_affected();
+
+    Ok(updated > 0)
+}
+
+/// Inline keyboard with 👍/👎 buttons for a feedback entry
+pub fn feedback_keyboard(id: i64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("👍", format!("{}{}:up", CALLBACK_PREFIX, id)),
+        InlineKeyboardButton::callback("👎", format!("{}{}:down", CALLBACK_PREFIX, id)),
+    ]])
+}
+
+/// `keyboard` without its 👍/👎 row (`None` when nothing else is left)
+pub fn without_feedback_buttons(keyboard: &InlineKeyboardMarkup) -> Option<InlineKeyboardMarkup> {
+    let rows: Vec<Vec<InlineKeyboardButton>> = keyboard
+        .inline_keyboard
+        .iter()
+        .filter(|row| !row.iter().any(is_feedback_button))
+        .cloned()
+        .collect();
+    (!rows.is_empty()).then(|| InlineKeyboardMarkup::new(rows))
+}
+
+fn is_feedback_button(button: &InlineKeyboardButton) -> bool {
+    matches!(
+        &button.kind,
+        InlineKeyboardButtonKind::CallbackData(data) if data.starts_with(CALLBACK_PREFIX)
+    )
+}
+
+/// Parse feedback button callback data into `(feedback id, rating)`
+pub fn parse_feedback_data(data: &str) -> Option<(i64, i16)> {
+    let (id, vote) = data.strip_prefix(CALLBACK_PREFIX)?.split_once(':')?;
+    let rating = match vote {
+        "up" => 1,
+        "down" => -1,
+        _ => return None,
+    };
+    Some((id.parse().ok()?, rating))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[test]
+    fn keyboard_callbacks_parse_back_to_ratings() {
+        let keyboard = feedback_keyboard(42);
+        let data: Vec<_> = keyboard.inline_keyboard[0]
+            .iter()
+            .map(|button| match &button.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => parse_feedback_data(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(data, [Some((42, 1)), Some((42, -1))]);
+
+        assert_eq!(parse_feedback_data("feedback:42:meh"), None);
+        assert_eq!(parse_feedback_data("feedback:x:up"), None);
+        assert_eq!(parse_feedback_data("follow_up:42:up"), None);
+    }
+
+    #[test]
+    fn removing_feedback_buttons_keeps_other_rows() {
+        assert!(without_feedback_buttons(&feedback_keyboard(1)).is_none());
+
+        let mut keyboard = feedback_keyboard(1);
+        keyboard.inline_keyboard.push(vec![InlineKeyboardButton::callback("More", "other")]);
+        let rest = without_feedback_buttons(&keyboard).expect("other row kept");
+        assert_eq!(rest.inline_keyboard.len(), 1);
+        assert_eq!(rest.inline_keyboard[0][0].text, "More");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn answers_can_only_be_rated_once() {
+        let pool = test_pool().await;
+
+        let id = create_feedback(&pool, -100, Some(1), "What is Pollinet?", "A relay network.")
+            .await
+            .unwrap();
+        assert!(record_feedback(&pool, id, 1).await.unwrap());
+        assert!(!record_feedback(&pool, id, -1).await.unwrap());
+
+        let rating: Option<i16> = sqlx::query_scalar("SELECT rating FROM feedback WHERE id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rating, Some(1));
+
+        sqlx::query("DELETE FROM feedback WHERE id = $1").bind(id).execute(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+//! Follow-up suggestions module
+//!
+//! This module handles:
+//! - The inline keyboard of suggested follow-up questions under an answer
+//! - Parsing a button click back into the index of the chosen question
+//!
+//! Button callback data is capped at 64 bytes, so buttons carry only an
+//! index; the questions themselves are kept by the
+//! [`ConversationManager`](crate::handlers::ConversationManager).
+
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// Prefix of the callback data sent by follow-up buttons
+const CALLBACK_PREFIX: &str = "followup:";
+
+/// Inline keyboard with one button per follow-up question
+pub fn follow_up_keyboard(questions: &[String]) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(questions.iter().enumerate().map(|(index, question)| {
+        [InlineKeyboardButton::callback(
+            question.clone(),
+            format!("{}{}", CALLBACK_PREFIX, index),
+        )]
+    }))
+}
+
+/// Whether callback data came from a follow-up button
+pub fn is_follow_up_data(data: &str) -> bool {
+    data.starts_with(CALLBACK_PREFIX)
+}
+
+/// Parse follow-up button callback data into the question's index
+pub fn parse_follow_up_data(data: &str) -> Option<usize> {
+    data.strip_prefix(CALLBACK_PREFIX)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::InlineKeyboardButtonKind;
+
+    #[test]
+    fn buttons_carry_the_question_index() {
+        let questions = ["What is a relay?".to_string(), "How are fees paid?".to_string()];
+        let keyboard = follow_up_keyboard(&questions);
+
+        let buttons: Vec<_> = keyboard
+            .inline_keyboard
+            .iter()
+            .map(|row| match &row[0].kind {
+                InlineKeyboardButtonKind::CallbackData(data) => (row[0].text.as_str(), parse_follow_up_data(data)),
+                _ => (row[0].text.as_str(), None),
+            })
+            .collect();
+        assert_eq!(buttons, [("What is a relay?", Some(0)), ("How are fees paid?", Some(1))]);
+    }
+
+    #[test]
+    fn only_follow_up_data_parses() {
+        assert!(is_follow_up_data("followup:2"));
+        assert!(!is_follow_up_data("feedback:42:up"));
+        assert_eq!(parse_follow_up_data("followup:x"), None);
+        assert_eq!(parse_follow_up_data("feedback:1"), None);
+    }
+}
@@ -1,24 +1,172 @@
-/// Message handlers module
-/// 
-/// This module handles:
-/// - Message routing logic
-/// - Determining when bot should respond (mentions, keywords)
-/// - Managing conversation history per chat
-/// - Coordinating between Telegram and RAG system
+//! Message handlers module
+//! 
+//! This module handles:
+//! - Message routing logic
+//! - Determining when bot should respond (mentions, keywords)
+//! - Managing conversation history per chat
+//! - Coordinating between Telegram and RAG system
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
 use std::collections::HashMap;
-use std::sync::Arc;
-use teloxide::{prelude::*, types::{Me, ParseMode}};
-use tokio::sync::RwLock;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::{
+    net::Download,
+    payloads::SendMessage,
+    prelude::*,
+    requests::JsonRequest,
+    types::{
+        FileMeta, InlineKeyboardMarkup, Me, MediaKind, MediaText, MessageId, MessageKind, ParseMode, User,
+    },
+    ApiError, RequestError,
+};
+use tokio::sync::{RwLock, Semaphore, SemaphorePermit};
+
+use crate::chat_settings::{self, ChatSetting, ChatSettings};
+use crate::chats;
+use crate::circuit_breaker::CircuitOpen;
+use crate::config::Config;
+use crate::feedback;
+use crate::follow_ups;
+use crate::metrics::METRICS;
+use crate::query_logs;
+use crate::rag::{
+    is_refusal, source_names, ConversationMessage, DocumentTooShort, QueryResult, RAGSystem,
+    REFUSAL_SENTINEL,
+};
+use crate::rate_limit::RateLimiter;
+use crate::telegram::SendWithRetry;
+use crate::telemetry::SpanTimer;
+
+/// Reply sent instead of an answer when a user exceeds the rate limit
+const RATE_LIMIT_MESSAGE: &str = "⏳ You're sending questions too quickly. Please slow down and try again in a minute.";
+
+/// Sent when a question has to wait for a free query slot
+const BUSY_MESSAGE: &str = "⏳ I'm busy answering other questions, one moment...";
+
+/// How long a question waits for a query slot before the busy notice is sent
+const QUERY_SLOT_WAIT: Duration = Duration::from_secs(2);
+
+/// Reply sent when a voice message can't be transcribed
+const VOICE_FAILED_MESSAGE: &str = "🎤 Sorry, I couldn't make out that voice message. Could you type your question instead?";
+
+/// Reply sent instead of an answer when moderation flags a query
+const MODERATION_MESSAGE: &str = "🚫 Sorry, I can't help with that message.";
+
+/// Reply sent instead of an answer while the OpenAI circuit breaker is open
+const UNAVAILABLE_MESSAGE: &str =
+    "⏳ The AI service is temporarily unavailable. Please try again in a minute.";
+
+/// Maximum characters Telegram accepts in one message
+pub const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+/// Minimum time between edits of a streamed draft (Telegram rate-limits edits)
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Recent user messages whose answers are remembered for re-answering edits
+const TRACKED_ANSWERS: NonZeroUsize = match NonZeroUsize::new(1000) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// The bot's answer to one user message, kept so an edit of the message
+/// can replace it
+#[derive(Debug, Clone)]
+pub struct AnsweredMessage {
+    /// The bot's reply messages, in order (long answers span several)
+    pub reply_ids: Vec<MessageId>,
+    /// The query as it was stored in conversation history
+    pub query: String,
+    /// The answer as it was stored in conversation history
+    pub answer: String,
+}
+
+/// Identifies one conversation: a whole chat, or one user within a group
+///
+/// Private chats (and keys without a user) use the chat alone, which is
+/// also how history was keyed before per-user conversations existed.
+/// Serialized as `"chat_id"` or `"chat_id:user_id"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConversationKey {
+    pub chat_id: i64,
+    pub user_id: Option<i64>,
+}
+
+impl ConversationKey {
+    /// The conversation shared by a whole chat
+    pub fn chat(chat_id: i64) -> Self {
+        Self { chat_id, user_id: None }
+    }
+
+    /// The conversation a message belongs to: per sender in groups,
+    /// per chat in private chats
+    pub fn for_message(msg: &Message) -> Self {
+        match msg.from() {
+            Some(user) if !msg.chat.is_private() => Self {
+                chat_id: msg.chat.id.0,
+                user_id: Some(user.id.0 as i64),
+            },
+            _ => Self::chat(msg.chat.id.0),
+        }
+    }
+}
+
+impl std::fmt::Display for ConversationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.user_id {
+            Some(user_id) => write!(f, "{}:{}", self.chat_id, user_id),
+            None => write!(f, "{}", self.chat_id),
+        }
+    }
+}
+
+impl std::str::FromStr for ConversationKey {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((chat_id, user_id)) => Ok(Self {
+                chat_id: chat_id.parse()?,
+                user_id: Some(user_id.parse()?),
+            }),
+            None => Ok(Self::chat(s.parse()?)),
+        }
+    }
+}
 
-use crate::rag::{ConversationMessage, RAGSystem};
+impl Serialize for ConversationKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConversationKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
 
 /// Manages conversation history for multiple chats
+///
+/// History lives in memory; with a database attached (`PERSIST_CONVERSATIONS`)
+/// every message is also written to the `conversations` table and histories
+/// are loaded from it on first use, so conversations survive restarts.
 pub struct ConversationManager {
-    /// Maps chat_id to conversation history
-    conversations: Arc<RwLock<HashMap<i64, Vec<ConversationMessage>>>>,
+    /// Maps each conversation to its history
+    conversations: Arc<RwLock<HashMap<ConversationKey, Vec<ConversationMessage>>>>,
     max_history: usize,
+    /// Backing store (None for memory-only deployments)
+    db_pool: Option<PgPool>,
+    /// Answers to recent user messages, keyed by (chat id, user message id)
+    answered: Mutex<LruCache<(ChatId, MessageId), AnsweredMessage>>,
+    /// Follow-up questions offered under recent answers, keyed by
+    /// (chat id, message id of the answer carrying the buttons)
+    follow_ups: Mutex<LruCache<(ChatId, MessageId), Vec<String>>>,
 }
 
 impl ConversationManager {
@@ -26,33 +174,57 @@ impl ConversationManager {
         Self {
             conversations: Arc::new(RwLock::new(HashMap::new())),
             max_history,
+            db_pool: None,
+            answered: Mutex::new(LruCache::new(TRACKED_ANSWERS)),
+            follow_ups: Mutex::new(LruCache::new(TRACKED_ANSWERS)),
         }
     }
 
-    /// Add a user message to conversation history
-    pub async fn add_user_message(&self, chat_id: i64, message: String) {
-        let mut conversations = self.conversations.write().await;
-        let history = conversations.entry(chat_id).or_insert_with(Vec::new);
-        
-        history.push(ConversationMessage {
-            role: "user".to_string(),
-            content: message,
-        });
-
-        // Trim history if it exceeds max
-        if history.len() > self.max_history {
-            let start = history.len() - self.max_history;
-            *history = history[start..].to_vec();
+    /// Create a manager that persists conversations to PostgreSQL
+    /// 
+    /// The `conversations` table is created by the schema migrations.
+    pub fn with_database(max_history: usize, db_pool: PgPool) -> Self {
+        log::info!("Conversation history will be persisted to PostgreSQL");
+        Self {
+            db_pool: Some(db_pool),
+            ..Self::new(max_history)
         }
     }
 
+    /// Add a user message to conversation history
+    pub async fn add_user_message(&self, key: ConversationKey, message: String) {
+        self.add_message(key, "user", message).await;
+    }
+
     /// Add an assistant message to conversation history
-    pub async fn add_assistant_message(&self, chat_id: i64, message: String) {
+    pub async fn add_assistant_message(&self, key: ConversationKey, message: String) {
+        self.add_message(key, "assistant", message).await;
+    }
+
+    async fn add_message(&self, key: ConversationKey, role: &str, message: String) {
+        // Make sure persisted history is cached before appending to it
+        self.load_history(key).await;
+
+        if let Some(pool) = &self.db_pool {
+            let result = sqlx::query(
+                "INSERT INTO conversations (chat_id, user_id, role, content) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(key.chat_id)
+            .bind(key.user_id)
+            .bind(role)
+            .bind(&message)
+            .execute(pool)
+            .await;
+            if let Err(e) = result {
+                log::error!("Failed to persist conversation message for {}: {}", key, e);
+            }
+        }
+
         let mut conversations = self.conversations.write().await;
-        let history = conversations.entry(chat_id).or_insert_with(Vec::new);
+        let history = conversations.entry(key).or_insert_with(Vec::new);
         
         history.push(ConversationMessage {
-            role: "assistant".to_string(),
+            role: role.to_string(),
             content: message,
         });
 
@@ -63,30 +235,276 @@ impl ConversationManager {
         }
     }
 
-    /// Get conversation history for a chat
-    pub async fn get_history(&self, chat_id: i64) -> Vec<ConversationMessage> {
+    /// Populate the in-memory cache for a conversation from the database, if needed
+    async fn load_history(&self, key: ConversationKey) {
+        let Some(pool) = &self.db_pool else {
+            return;
+        };
+        if self.conversations.read().await.contains_key(&key) {
+            return;
+        }
+
+        let rows = sqlx::query(
+            r#"
+            SELECT role, content FROM (
+                SELECT id, role, content FROM conversations
+                WHERE chat_id = $1 AND user_id IS NOT DISTINCT FROM $2
+                ORDER BY id DESC
+                LIMIT $3
+            ) recent
+            ORDER BY id
+            "#,
+        )
+        .bind(key.chat_id)
+        .bind(key.user_id)
+        .bind(self.max_history as i64)
+        .fetch_all(pool)
+        .await;
+
+        let history = match rows {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| ConversationMessage {
+                    role: row.get("role"),
+                    content: row.get("content"),
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("Failed to load conversation history for {}: {}", key, e);
+                return;
+            }
+        };
+
+        self.conversations.write().await.entry(key).or_insert(history);
+    }
+
+    /// Get conversation history for a conversation
+    pub async fn get_history(&self, key: ConversationKey) -> Vec<ConversationMessage> {
+        self.load_history(key).await;
+
         let conversations = self.conversations.read().await;
         conversations
-            .get(&chat_id)
+            .get(&key)
             .cloned()
             .unwrap_or_default()
     }
 
-    /// Clear conversation history for a chat
-    pub async fn clear_history(&self, chat_id: i64) {
+    /// Clear conversation history for a conversation
+    ///
+    /// A whole-chat key also clears every per-user conversation in that chat.
+    pub async fn clear_history(&self, key: ConversationKey) {
+        if let Some(pool) = &self.db_pool {
+            let result = sqlx::query(
+                "DELETE FROM conversations WHERE chat_id = $1 AND ($2::BIGINT IS NULL OR user_id = $2)",
+            )
+            .bind(key.chat_id)
+            .bind(key.user_id)
+            .execute(pool)
+            .await;
+            if let Err(e) = result {
+                log::error!("Failed to delete conversation history for {}: {}", key, e);
+            }
+        }
+
+        let mut conversations = self.conversations.write().await;
+        match key.user_id {
+            Some(_) => {
+                conversations.remove(&key);
+            }
+            None => conversations.retain(|k, _| k.chat_id != key.chat_id),
+        }
+    }
+
+    /// Remember the bot's answer to a user message
+    pub fn record_answer(&self, chat_id: ChatId, message_id: MessageId, answered: AnsweredMessage) {
+        self.answered.lock().unwrap().put((chat_id, message_id), answered);
+    }
+
+    /// The bot's answer to a user message, if it answered it recently
+    pub fn answered_message(&self, chat_id: ChatId, message_id: MessageId) -> Option<AnsweredMessage> {
+        self.answered.lock().unwrap().get(&(chat_id, message_id)).cloned()
+    }
+
+    /// Remember the follow-up questions offered under an answer message
+    pub fn record_follow_ups(&self, chat_id: ChatId, message_id: MessageId, questions: Vec<String>) {
+        self.follow_ups.lock().unwrap().put((chat_id, message_id), questions);
+    }
+
+    /// The `index`th follow-up question offered under an answer message, if
+    /// it is still remembered
+    pub fn follow_up(&self, chat_id: ChatId, message_id: MessageId, index: usize) -> Option<String> {
+        self.follow_ups
+            .lock()
+            .unwrap()
+            .get(&(chat_id, message_id))
+            .and_then(|questions| questions.get(index).cloned())
+    }
+
+    /// Remove a question and its answer from a conversation's history
+    ///
+    /// The most recent matching user turn is removed along with the
+    /// assistant turn right after it, if that is the recorded answer.
+    pub async fn remove_exchange(&self, key: ConversationKey, answered: &AnsweredMessage) {
+        self.load_history(key).await;
+
+        let mut removed_answer = false;
+        {
+            let mut conversations = self.conversations.write().await;
+            let Some(history) = conversations.get_mut(&key) else {
+                return;
+            };
+            let Some(index) = history
+                .iter()
+                .rposition(|m| m.role == "user" && m.content == answered.query)
+            else {
+                return;
+            };
+
+            if history
+                .get(index + 1)
+                .is_some_and(|m| m.role == "assistant" && m.content == answered.answer)
+            {
+                history.remove(index + 1);
+                removed_answer = true;
+            }
+            history.remove(index);
+        }
+
+        if let Some(pool) = &self.db_pool {
+            let mut turns = vec![("user", answered.query.as_str())];
+            if removed_answer {
+                turns.push(("assistant", answered.answer.as_str()));
+            }
+            for (role, content) in turns {
+                let result = sqlx::query(
+                    r#"
+                    DELETE FROM conversations WHERE id = (
+                        SELECT id FROM conversations
+                        WHERE chat_id = $1 AND user_id IS NOT DISTINCT FROM $2 AND role = $3 AND content = $4
+                        ORDER BY id DESC
+                        LIMIT 1
+                    )
+                    "#,
+                )
+                .bind(key.chat_id)
+                .bind(key.user_id)
+                .bind(role)
+                .bind(content)
+                .execute(pool)
+                .await;
+                if let Err(e) = result {
+                    log::error!("Failed to delete edited message from history for {}: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Export the live conversation state of a conversation
+    pub async fn export(&self, key: ConversationKey) -> Vec<ConversationMessage> {
+        self.get_history(key).await
+    }
+
+    /// Replace the conversation state of a conversation (trimmed to max history)
+    pub async fn import(&self, key: ConversationKey, mut messages: Vec<ConversationMessage>) {
+        if messages.len() > self.max_history {
+            messages.drain(..messages.len() - self.max_history);
+        }
+
+        if let Some(pool) = &self.db_pool {
+            if let Err(e) = replace_persisted_history(pool, key, &messages).await {
+                log::error!("Failed to persist imported history for {}: {}", key, e);
+            }
+        }
+
         let mut conversations = self.conversations.write().await;
-        conversations.remove(&chat_id);
+        conversations.insert(key, messages);
+    }
+
+    /// Export the state of every conversation
+    ///
+    /// Only conversations cached in memory are included.
+    pub async fn export_all(&self) -> HashMap<ConversationKey, Vec<ConversationMessage>> {
+        self.conversations.read().await.clone()
+    }
+
+    /// Import the state of several conversations, replacing their histories
+    pub async fn import_all(&self, conversations: HashMap<ConversationKey, Vec<ConversationMessage>>) {
+        for (key, messages) in conversations {
+            self.import(key, messages).await;
+        }
     }
 }
 
+/// Replace a conversation's persisted history in a single transaction
+async fn replace_persisted_history(
+    pool: &PgPool,
+    key: ConversationKey,
+    messages: &[ConversationMessage],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM conversations WHERE chat_id = $1 AND user_id IS NOT DISTINCT FROM $2")
+        .bind(key.chat_id)
+        .bind(key.user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for message in messages {
+        sqlx::query("INSERT INTO conversations (chat_id, user_id, role, content) VALUES ($1, $2, $3, $4)")
+            .bind(key.chat_id)
+            .bind(key.user_id)
+            .bind(&message.role)
+            .bind(&message.content)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The text a user wrote: the message text, or the caption of a photo,
+/// document or other media
+pub fn message_text(message: &Message) -> Option<&str> {
+    message.text().or_else(|| message.caption())
+}
+
+/// The voice note or audio file attached to a message, with its MIME type
+pub fn voice_attachment(message: &Message) -> Option<(&FileMeta, String)> {
+    if let Some(voice) = message.voice() {
+        let mime = voice.mime_type.as_ref().map_or("audio/ogg".to_string(), |m| m.to_string());
+        return Some((&voice.file, mime));
+    }
+    message.audio().map(|audio| {
+        let mime = audio.mime_type.as_ref().map_or("audio/mpeg".to_string(), |m| m.to_string());
+        (&audio.file, mime)
+    })
+}
+
+/// Download a voice note from Telegram and transcribe it
+async fn transcribe_voice(bot: &Bot, rag_system: &RAGSystem, file: &FileMeta, mime: &str) -> Result<String> {
+    let file = bot.get_file(&file.id).await.context("Failed to look up voice file")?;
+    let mut audio = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut audio)
+        .await
+        .context("Failed to download voice file")?;
+
+    rag_system.transcribe_audio(audio, mime).await
+}
+
 /// Check if the bot should respond to a message
 /// 
 /// Bot responds when:
 /// 1. It is mentioned/tagged in the message
-/// 2. Message contains the keyword "Pollinet" (case-insensitive)
+/// 2. Message contains one of `trigger_keywords` (lowercased; matched case-insensitively)
 /// 3. It's a private chat (not a group)
 /// 4. Message is a reply to the bot's message
-pub fn should_respond(bot_username: &str, message: &Message, bot_id: teloxide::types::UserId) -> bool {
+pub fn should_respond(
+    bot_username: &str,
+    message: &Message,
+    bot_id: teloxide::types::UserId,
+    trigger_keywords: &[String],
+) -> bool {
     // Always respond in private chats
     if message.chat.is_private() {
         return true;
@@ -103,7 +521,8 @@ pub fn should_respond(bot_username: &str, message: &Message, bot_id: teloxide::t
     }
 
     // In group chats, check for mentions or keywords
-    if let Some(text) = message.text() {
+    let text = message_text(message);
+    if let Some(text) = text {
         let text_lower = text.to_lowercase();
         let bot_username_lower = bot_username.to_lowercase();
         
@@ -112,17 +531,17 @@ pub fn should_respond(bot_username: &str, message: &Message, bot_id: teloxide::t
             return true;
         }
         
-        // Check for "Pollinet" keyword
-        if text_lower.contains("pollinet") {
+        // Check for trigger keywords (e.g. "Pollinet")
+        if trigger_keywords.iter().any(|keyword| text_lower.contains(keyword.as_str())) {
             return true;
         }
     }
 
     // Check if bot is mentioned in entities
-    if let Some(entities) = message.entities() {
+    if let Some(entities) = message.entities().or_else(|| message.caption_entities()) {
         for entity in entities {
             if matches!(entity.kind, teloxide::types::MessageEntityKind::Mention) {
-                if let Some(text) = message.text() {
+                if let Some(text) = text {
                     let start = entity.offset;
                     let end = start + entity.length;
                     if let Some(mention) = text.get(start..end) {
@@ -150,20 +569,68 @@ pub fn extract_query(bot_username: &str, text: &str) -> String {
     query.trim().to_string()
 }
 
-/// Handle edited messages - delegates to the main message handler
+/// Bot username a `/command@botname` message is addressed to, if any
+pub fn command_target(text: &str) -> Option<&str> {
+    let command = text.split_whitespace().next()?.strip_prefix('/')?;
+    command.split_once('@').map(|(_, target)| target)
+}
+
+/// Check whether this bot should handle a command message
+///
+/// `/cmd@thisbot` is always handled and `/cmd@otherbot` never is. A bare
+/// `/cmd` is handled in private chats, and in groups unless bare group
+/// commands are disabled (Telegram delivers them to every bot in the group).
+pub fn accepts_command(text: &str, bot_username: &str, is_private: bool, allow_bare_in_groups: bool) -> bool {
+    match command_target(text) {
+        Some(target) => target.eq_ignore_ascii_case(bot_username),
+        None => is_private || allow_bare_in_groups,
+    }
+}
+
+/// Word-overlap similarity above which an answer counts as a repeat
+const REPEAT_SIMILARITY_THRESHOLD: f64 = 0.9;
+
+/// Cheap text similarity: Jaccard index over lowercase word sets (0.0 - 1.0)
+pub fn text_similarity(a: &str, b: &str) -> f64 {
+    let words = |s: &str| -> std::collections::HashSet<String> {
+        s.split_whitespace()
+            .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect()
+    };
+
+    let (a, b) = (words(a), words(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+
+    let intersection = a.intersection(&b).count() as f64;
+    let union = a.union(&b).count() as f64;
+    intersection / union
+}
+
+/// Check whether a new answer is a near-verbatim repeat of the previous one
+pub fn is_repeat_answer(previous: &str, answer: &str) -> bool {
+    text_similarity(previous, answer) >= REPEAT_SIMILARITY_THRESHOLD
+}
+
+/// Handle edited messages
 /// 
-/// This allows users to edit their questions and get updated responses
+/// If the bot answered the original message, the stale question and answer
+/// are dropped from history and the bot's reply is edited in place with an
+/// answer to the new text. Otherwise the edit is handled like a new message
+/// (e.g. a mention was added).
 pub async fn handle_edited_message(
     bot: Bot,
     msg: Message,
     me: Me,
     rag_system: Arc<RAGSystem>,
     conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
 ) -> Result<()> {
     log::debug!("Handling edited message from chat {}", msg.chat.id);
-    // Process edited messages the same way as new messages
-    // The bot will respond with a fresh answer to the edited question
-    handle_message(bot, msg, me, rag_system, conversation_manager).await
+    let previous = conversation_manager.answered_message(msg.chat.id, msg.id);
+    respond_to_message(bot, msg, me, rag_system, conversation_manager, rate_limiter, previous).await
 }
 
 /// Main message handler
@@ -181,11 +648,48 @@ pub async fn handle_message(
     me: Me,
     rag_system: Arc<RAGSystem>,
     conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
 ) -> Result<()> {
-    // Get the message text first for logging
-    let text = match msg.text() {
+    respond_to_message(bot, msg, me, rag_system, conversation_manager, rate_limiter, None).await
+}
+
+/// Answer a message, replacing `previous` (the bot's answer to an earlier
+/// version of it) when the message was edited
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "telegram.handle_message",
+        skip_all,
+        fields(
+            request_id = crate::telemetry::next_request_id(),
+            chat_id = msg.chat.id.0,
+            edited = previous.is_some(),
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+async fn respond_to_message(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    rag_system: Arc<RAGSystem>,
+    conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
+    previous: Option<AnsweredMessage>,
+) -> Result<()> {
+    let _timer = SpanTimer::start();
+
+    // Get the message text first for logging; uncaptioned voice notes are
+    // transcribed once we know they're for us (ENABLE_VOICE)
+    let voice = match message_text(&msg) {
+        Some(_) => None,
+        None if rag_system.config().enable_voice => voice_attachment(&msg),
+        None => None,
+    };
+    let text = match message_text(&msg) {
         Some(t) => t,
-        None => return Ok(()), // Ignore non-text messages
+        None if voice.is_some() => "",
+        None => return Ok(()), // Ignore media without a caption
     };
 
     // Log all messages for debugging (you can remove this later)
@@ -196,57 +700,777 @@ pub async fn handle_message(
         text
     );
 
-    // Check if we should respond to this message
-    if !should_respond(&me.username(), &msg, me.id) {
+    // Chats the team hasn't authorized (ALLOWED_CHAT_IDS/BLOCKED_CHAT_IDS)
+    if !rag_system.config().is_chat_allowed(msg.chat.id.0) {
+        log::info!("Dropping message from chat {} (chat policy)", msg.chat.id);
+        return Ok(());
+    }
+
+    // Commands meant for another bot in the group are none of our business
+    if command_target(text).is_some_and(|target| !target.eq_ignore_ascii_case(me.username())) {
+        log::debug!("Skipping command addressed to another bot");
+        return Ok(());
+    }
+
+    // Check if we should respond to this message. This runs before the
+    // per-chat settings are loaded, so ignored group chatter never costs a
+    // database query
+    if !should_respond(me.username(), &msg, me.id, &rag_system.config().trigger_keywords) {
         log::debug!("Skipping message (no mention/keyword/reply)");
         return Ok(());
     }
 
+    // Per-chat settings; a database hiccup shouldn't stop the bot answering
+    let settings = chat_settings::get_chat_settings(rag_system.db_pool(), msg.chat.id.0)
+        .await
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to load settings for chat {}: {:#}", msg.chat.id, e);
+            ChatSettings::default()
+        });
+    let language = settings.language.as_deref();
+    let namespace = settings.namespace.as_deref();
+
+    // Chats with keyword triggering off only answer mentions and replies
+    if !settings.keyword_trigger && !should_respond(me.username(), &msg, me.id, &[]) {
+        log::debug!("Skipping message (keyword triggers are off in this chat)");
+        return Ok(());
+    }
+
     // Extract the actual query
-    let query = extract_query(&me.username(), text);
+    let query = extract_query(me.username(), text);
     
-    if query.is_empty() {
+    if query.is_empty() && voice.is_none() {
         log::debug!("Query is empty after removing mentions");
         return Ok(());
     }
 
+    if previous.as_ref().is_some_and(|previous| previous.query == query) {
+        log::debug!("Edit didn't change the query, keeping the previous answer");
+        return Ok(());
+    }
+
     log::info!("Received query from chat {}: {}", msg.chat.id, query);
 
+    // Remember the chat for broadcasts; a failure here shouldn't block the answer
+    if let Err(e) = chats::record_chat(rag_system.db_pool(), &msg.chat).await {
+        log::warn!("Failed to record chat {}: {:#}", msg.chat.id, e);
+    }
+
+    // Every answer costs an embedding and a completion, so cap each user's rate
+    if let Some(user) = msg.from() {
+        if !rate_limiter.check(user.id.0 as i64, !msg.chat.is_private()) {
+            log::warn!("Rate limit exceeded for user {} in chat {}", user.id, msg.chat.id);
+            answer_request(&bot, &msg, RATE_LIMIT_MESSAGE.to_string(), rag_system.config())
+                .send_with_retry()
+                .await?;
+            return Ok(());
+        }
+    }
+
+    // A voice note's transcript is the query; it's echoed back with the answer
+    let mut query = query;
+    let mut transcript = None;
+    if let Some((file, mime)) = voice {
+        bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+            .send_with_retry()
+            .await?;
+        match transcribe_voice(&bot, &rag_system, file, &mime).await {
+            Ok(text) if !text.is_empty() => {
+                log::info!("Transcribed voice message in chat {}: {}", msg.chat.id, text);
+                query = text.clone();
+                transcript = Some(text);
+            }
+            result => {
+                if let Err(e) = result {
+                    log::warn!("Failed to transcribe voice message: {:#}", e);
+                }
+                answer_request(&bot, &msg, VOICE_FAILED_MESSAGE.to_string(), rag_system.config())
+                    .send_with_retry()
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
+    if rag_system.config().enable_moderation {
+        match rag_system.moderate_text(&query).await {
+            Ok(true) => {
+                log::warn!("Rejected flagged query from chat {}", msg.chat.id);
+                answer_request(&bot, &msg, MODERATION_MESSAGE.to_string(), rag_system.config())
+                    .send_with_retry()
+                    .await?;
+                return Ok(());
+            }
+            Ok(false) => {}
+            // Fail open: a moderation outage shouldn't take the bot down
+            Err(e) => log::warn!("Moderation check failed, answering anyway: {:#}", e),
+        }
+    }
+
     // Send "typing" action to indicate bot is processing
     bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+        .send_with_retry()
         .await?;
 
-    // Get conversation history
-    let chat_id = msg.chat.id.0;
-    let history = conversation_manager.get_history(chat_id).await;
+    // Get conversation history, without the question this edit replaces
+    let conversation = ConversationKey::for_message(&msg);
+    if let Some(previous) = &previous {
+        conversation_manager.remove_exchange(conversation, previous).await;
+    }
+    let history = conversation_manager.get_history(conversation).await;
 
     // Add user message to history
     conversation_manager
-        .add_user_message(chat_id, query.clone())
+        .add_user_message(conversation, query.clone())
         .await;
 
-    // Query the RAG system
-    let response = match rag_system.query(&query, &history).await {
-        Ok(resp) => resp,
+    // Wait for a free query slot so bursts don't stampede OpenAI
+    let _permit = match rag_system.query_slots() {
+        Some(slots) => Some(acquire_query_slot(&bot, &msg, slots, rag_system.config()).await?),
+        None => None,
+    };
+
+    // An edited question's answer replaces the previous reply: its first
+    // message becomes the draft and any further parts are removed
+    let mut draft = None;
+    if let Some(previous) = &previous {
+        let mut reply_ids = previous.reply_ids.iter().copied();
+        draft = reply_ids.next();
+        for id in reply_ids {
+            if let Err(e) = bot.delete_message(msg.chat.id, id).await {
+                log::debug!("Couldn't delete outdated answer part: {}", e);
+            }
+        }
+    }
+
+    // Query the RAG system, streaming into a draft message if enabled
+    let started = Instant::now();
+    let result = if rag_system.config().stream_responses {
+        stream_answer(&bot, &msg, &rag_system, &query, &history, language, namespace, &mut draft).await
+    } else {
+        rag_system.query_in_language(&query, &history, language, None, namespace).await
+    };
+    let result = match result {
+        Ok(result) => {
+            METRICS.record_query(started.elapsed(), result.used_fallback);
+            let config = rag_system.config();
+            if config.log_queries {
+                if let Err(e) = query_logs::log_query(
+                    rag_system.db_pool(),
+                    Some(msg.chat.id.0),
+                    &query,
+                    &result.usage,
+                    &config.gpt_model,
+                    result.used_fallback,
+                )
+                .await
+                {
+                    log::warn!("Failed to log query: {:#}", e);
+                }
+            }
+            result
+        }
+        Err(e) if e.downcast_ref::<CircuitOpen>().is_some() => {
+            log::warn!("Not answering in chat {}: {}", msg.chat.id, e);
+            QueryResult {
+                answer: UNAVAILABLE_MESSAGE.to_string(),
+                ..QueryResult::default()
+            }
+        }
         Err(e) => {
             log::error!("Error querying RAG system: {}", e);
-            "Sorry, I encountered an error while processing your request. Please try again.".to_string()
+            QueryResult {
+                answer: "Sorry, I encountered an error while processing your request. Please try again.".to_string(),
+                ..QueryResult::default()
+            }
         }
     };
+    let mut response = result.answer;
+
+    // Don't silently repeat the previous answer word for word
+    if rag_system.config().avoid_repeat_answers {
+        let previous_answer = history.iter().rev().find(|m| m.role == "assistant");
+        if let Some(previous) = previous_answer {
+            if is_repeat_answer(&previous.content, &response) {
+                log::info!("Answer repeats the previous one in chat {}", msg.chat.id);
+                response = format!("As I mentioned, {}", response);
+            }
+        }
+    }
 
     // Add assistant response to history
     conversation_manager
-        .add_assistant_message(chat_id, response.clone())
+        .add_assistant_message(conversation, response.clone())
         .await;
+    let answer = response.clone();
 
-    // Send the response with HTML formatting
-    bot.send_message(msg.chat.id, response)
-        .parse_mode(ParseMode::Html)
+    // Show what was heard so users can confirm (not stored in history)
+    if let Some(transcript) = &transcript {
+        response = format!("🎤 <i>{}</i>\n\n{}", escape_html(transcript), response);
+    }
+
+    // Cite the documents the answer came from (not stored in history)
+    response.push_str(&format_sources(&result.sources));
+
+    // Admins can see how confident the retrieval was (not stored in history)
+    let config = rag_system.config();
+    let is_admin = msg.from().is_some_and(|user| config.is_admin(user.id.0 as i64));
+    if config.show_scores_to_admins && is_admin {
+        response.push_str(&format_scores(&result.scores));
+    }
+
+    // Send the response with HTML formatting, split to fit Telegram's limit
+    let reply_ids: Vec<MessageId> = match draft {
+        Some(draft) => {
+            // The first part replaces the streamed draft
+            let mut parts = split_message(&response, TELEGRAM_MESSAGE_LIMIT).into_iter();
+            let first = parts.next().unwrap_or_default();
+            let mut edit = bot
+                .edit_message_text(msg.chat.id, draft, first.clone())
+                .parse_mode(ParseMode::Html)
+                .send_with_retry()
+                .await;
+            if let Err(e) = &edit {
+                if is_parse_error(e) {
+                    log::warn!("Answer HTML rejected by Telegram, sending as plain text: {}", e);
+                    edit = bot
+                        .edit_message_text(msg.chat.id, draft, strip_html_tags(&first))
+                        .send_with_retry()
+                        .await;
+                }
+            }
+            match edit {
+                Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+                Err(e) => return Err(e.into()),
+            }
+            let sent = send_parts(&bot, &msg, parts, config).await?;
+            std::iter::once(draft).chain(sent.iter().map(|m| m.id)).collect()
+        }
+        None => send_long_message(&bot, &msg, &response, config)
+            .await?
+            .iter()
+            .map(|m| m.id)
+            .collect(),
+    };
+    let Some(&last_message_id) = reply_ids.last() else {
+        return Ok(());
+    };
+
+    // Remember the reply so an edit of the question can replace it
+    conversation_manager.record_answer(
+        msg.chat.id,
+        msg.id,
+        AnsweredMessage {
+            reply_ids,
+            query: query.clone(),
+            answer: answer.clone(),
+        },
+    );
+
+    // Buttons go under the answer's last part: suggested follow-ups (only
+    // for knowledge base answers), then 👍/👎
+    let mut keyboard = InlineKeyboardMarkup::default();
+    if config.suggest_follow_ups && !result.used_fallback && !result.chunks.is_empty() {
+        match rag_system.suggest_follow_ups(&query, &answer, &result.chunks).await {
+            Ok(suggestions) if !suggestions.questions.is_empty() => {
+                keyboard = follow_ups::follow_up_keyboard(&suggestions.questions);
+                conversation_manager.record_follow_ups(msg.chat.id, last_message_id, suggestions.questions);
+            }
+            Ok(_) => log::debug!("GPT suggested no usable follow-up questions"),
+            Err(e) => log::warn!("Failed to suggest follow-up questions: {:#}", e),
+        }
+    }
+    if config.feedback_buttons {
+        let user_id = msg.from().map(|user| user.id.0 as i64);
+        match feedback::create_feedback(rag_system.db_pool(), msg.chat.id.0, user_id, &query, &answer).await {
+            Ok(id) => keyboard
+                .inline_keyboard
+                .extend(feedback::feedback_keyboard(id).inline_keyboard),
+            Err(e) => log::error!("Failed to store feedback entry: {}", e),
+        }
+    }
+    if !keyboard.inline_keyboard.is_empty() {
+        bot.edit_message_reply_markup(msg.chat.id, last_message_id)
+            .reply_markup(keyboard)
+            .send_with_retry()
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Take a query slot, telling the user they're queued if none frees up quickly
+async fn acquire_query_slot<'a>(
+    bot: &Bot,
+    msg: &Message,
+    slots: &'a Semaphore,
+    config: &Config,
+) -> Result<SemaphorePermit<'a>> {
+    if let Ok(permit) = tokio::time::timeout(QUERY_SLOT_WAIT, slots.acquire()).await {
+        return permit.context("Query slots closed");
+    }
+
+    log::info!("All query slots busy, queueing message in chat {}", msg.chat.id);
+    answer_request(bot, msg, BUSY_MESSAGE.to_string(), config).send_with_retry().await?;
+    bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+        .send_with_retry()
+        .await?;
+    slots.acquire().await.context("Query slots closed")
+}
+
+/// Answer `query` while showing GPT's output progressively in a draft message
+///
+/// The draft is plain text with HTML tags stripped (partial HTML may be
+/// unbalanced) and is left in `draft` for the caller to replace with the
+/// final answer. Edits are throttled to `STREAM_EDIT_INTERVAL`. Falls back to
+/// a non-streamed answer from the same chunks when nothing relevant is
+/// retrieved or the stream breaks off, and to the full knowledge base when
+/// GPT can't answer. Only `namespace` is searched.
+#[allow(clippy::too_many_arguments)]
+async fn stream_answer(
+    bot: &Bot,
+    msg: &Message,
+    rag_system: &RAGSystem,
+    query: &str,
+    history: &[ConversationMessage],
+    language: Option<&str>,
+    namespace: Option<&str>,
+    draft: &mut Option<MessageId>,
+) -> Result<QueryResult> {
+    if let Some(cached) = rag_system.cached_answer(query, history, language, None, namespace) {
+        return Ok(cached);
+    }
+
+    let answer_language = rag_system.answer_language(query, language).await;
+    let mut result = stream_fresh_answer(
+        bot,
+        msg,
+        rag_system,
+        query,
+        history,
+        answer_language.language.as_deref(),
+        namespace,
+        draft,
+    )
+    .await?;
+    result.usage = answer_language.usage.combined(result.usage);
+    rag_system.cache_answer(query, history, language, None, namespace, &result);
+    Ok(result)
+}
+
+/// [`stream_answer`] without consulting the answer cache
+#[allow(clippy::too_many_arguments)]
+async fn stream_fresh_answer(
+    bot: &Bot,
+    msg: &Message,
+    rag_system: &RAGSystem,
+    query: &str,
+    history: &[ConversationMessage],
+    language: Option<&str>,
+    namespace: Option<&str>,
+    draft: &mut Option<MessageId>,
+) -> Result<QueryResult> {
+    let retrieval_query = rag_system.retrieval_query(query, history).await;
+    let chunks = rag_system
+        .retrieve_relevant_chunks_in_namespace(&retrieval_query.answer, namespace)
         .await?;
+    if chunks.is_empty() {
+        return rag_system.answer_from_chunks(query, &chunks, history, language, namespace).await;
+    }
+    let scores: Vec<f32> = chunks.iter().map(|chunk| chunk.similarity).collect();
+
+    let mut stream = match rag_system
+        .generate_response_streaming(query, &chunks, history, language)
+        .await {
+        Ok(pieces) => pieces,
+        Err(e) => {
+            log::warn!("Streaming unavailable, falling back: {}", e);
+            return rag_system.answer_from_chunks(query, &chunks, history, language, namespace).await;
+        }
+    };
+
+    let mut answer = String::new();
+    let mut shown_len = 0;
+    let mut last_edit = Instant::now();
+    while let Some(piece) = stream.recv().await {
+        match piece {
+            Ok(piece) => answer.push_str(&piece),
+            Err(e) => {
+                log::warn!("Streaming failed mid-way, falling back: {}", e);
+                return rag_system.answer_from_chunks(query, &chunks, history, language, namespace).await;
+            }
+        }
+
+        // Don't flash a refusal that is about to be replaced by the fallback
+        let maybe_refusal = REFUSAL_SENTINEL.starts_with(answer.trim_start()) || is_refusal(&answer);
+        if maybe_refusal || answer.len() == shown_len || last_edit.elapsed() < STREAM_EDIT_INTERVAL {
+            continue;
+        }
+
+        let text: String = strip_html_tags(&answer)
+            .chars()
+            .take(TELEGRAM_MESSAGE_LIMIT)
+            .collect();
+        let update = match draft {
+            Some(draft) => bot.edit_message_text(msg.chat.id, *draft, text).await.map(|_| ()),
+            None => answer_request(bot, msg, text, rag_system.config())
+                .await
+                .map(|sent| *draft = Some(sent.id)),
+        };
+        if let Err(e) = update {
+            log::debug!("Skipping streamed draft update: {}", e);
+        }
+        shown_len = answer.len();
+        last_edit = Instant::now();
+    }
+
+    let mut usage = retrieval_query.usage.combined(stream.usage());
+    let refused = is_refusal(&answer) || {
+        let verdict = rag_system.verify_grounding(&answer, &chunks).await;
+        usage = usage.combined(verdict.usage);
+        !verdict.grounded
+    };
+    if refused {
+        log::info!("GPT couldn't answer from context, using ChatGPT fallback with full knowledge base");
+        let fallback = rag_system
+            .generate_fallback_response(query, history, language, namespace)
+            .await?;
+        let usage = usage.combined(fallback.usage);
+        return Ok(QueryResult::fallback(fallback.answer, scores, usage));
+    }
+
+    Ok(QueryResult {
+        answer,
+        sources: source_names(&chunks),
+        used_fallback: false,
+        scores,
+        usage,
+        chunks,
+    })
+}
+
+/// Formatting tags Telegram accepts in HTML messages
+const TELEGRAM_HTML_TAGS: &[&str] = &[
+    "b", "strong", "i", "em", "u", "ins", "s", "strike", "del", "code", "pre", "a",
+    "blockquote", "tg-spoiler", "span",
+];
+
+/// Convert Telegram HTML to plain text: drop formatting tags and unescape
+/// entities
+///
+/// Anything that isn't one of [`TELEGRAM_HTML_TAGS`] is kept as text, so a
+/// stray `<` (the usual reason Telegram rejects an answer) doesn't swallow
+/// the rest of the message.
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        match telegram_tag_len(candidate) {
+            Some(len) => rest = &candidate[len..],
+            None => {
+                text.push('<');
+                rest = &candidate[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Length of the Telegram formatting tag `text` starts with, if any
+fn telegram_tag_len(text: &str) -> Option<usize> {
+    let end = text.find('>')?;
+    let tag = &text[1..end];
+    if tag.contains('<') {
+        return None;
+    }
+    let name = tag.strip_prefix('/').unwrap_or(tag);
+    let name = name.split(char::is_whitespace).next().unwrap_or_default();
+    TELEGRAM_HTML_TAGS
+        .contains(&name.to_ascii_lowercase().as_str())
+        .then_some(end + 1)
+}
+
+/// Escape user- or model-provided text interpolated into an HTML message
+///
+/// Only `<`, `>` and `&` are special in Telegram HTML, so Markdown
+/// characters such as `_`, `*`, `[` and backticks stay literal.
+pub fn escape_html(text: &str) -> String {
+    teloxide::utils::html::escape(text)
+}
+
+/// Render the answer's source documents as an HTML footer (empty if none)
+pub fn format_sources(sources: &[String]) -> String {
+    if sources.is_empty() {
+        return String::new();
+    }
+
+    let sources = sources
+        .iter()
+        .map(|source| escape_html(source))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("\n\n<i>Sources: {}</i>", sources)
+}
+
+/// Render retrieval similarity scores as an HTML footer for admins
+pub fn format_scores(scores: &[f32]) -> String {
+    if scores.is_empty() {
+        return "\n\n<i>🔎 No chunks retrieved</i>".to_string();
+    }
+
+    let scores = scores
+        .iter()
+        .take(3)
+        .map(|score| format!("{:.3}", score))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("\n\n<i>🔎 Top similarity: {}</i>", scores)
+}
+
+/// Send a generated answer for `msg` with HTML formatting
+///
+/// Answers longer than Telegram's message limit are split (see
+/// [`split_message`]) and sent as consecutive messages.
+///
+/// With `reply_as_thread` enabled the answer is sent as a reply to the
+/// triggering message (inside its forum topic, if any). Telegram still
+/// delivers it as a normal message if the original was deleted meanwhile.
+pub async fn send_long_message(
+    bot: &Bot,
+    msg: &Message,
+    text: &str,
+    config: &Config,
+) -> Result<Vec<Message>> {
+    send_parts(bot, msg, split_message(text, TELEGRAM_MESSAGE_LIMIT), config).await
+}
+
+/// Send already-split answer parts in order
+async fn send_parts(
+    bot: &Bot,
+    msg: &Message,
+    parts: impl IntoIterator<Item = String>,
+    config: &Config,
+) -> Result<Vec<Message>> {
+    let mut sent = Vec::new();
+    for part in parts {
+        let message = match answer_request(bot, msg, part.clone(), config)
+            .parse_mode(ParseMode::Html)
+            .send_with_retry()
+            .await
+        {
+            Err(e) if is_parse_error(&e) => {
+                // GPT produced HTML Telegram can't parse; send it as plain text
+                log::warn!("Answer HTML rejected by Telegram, sending as plain text: {}", e);
+                answer_request(bot, msg, strip_html_tags(&part), config)
+                    .send_with_retry()
+                    .await?
+            }
+            result => result?,
+        };
+        sent.push(message);
+    }
+    Ok(sent)
+}
+
+/// Whether Telegram rejected a message because of malformed formatting
+fn is_parse_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::CantParseEntities) => true,
+        RequestError::Api(ApiError::Unknown(description)) => {
+            description.contains("can't parse entities")
+        }
+        _ => false,
+    }
+}
+
+/// Split text into parts of at most `limit` characters
+///
+/// Splits between lines, preferring paragraph breaks, and keeps each
+/// `<pre>` block in one part where it fits. Lines longer than the limit are
+/// cut at character boundaries as a last resort.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    // Group lines into unsplittable segments: single lines, or whole <pre> blocks
+    let mut segments: Vec<String> = Vec::new();
+    let mut in_pre = false;
+    for line in text.split_inclusive('\n') {
+        if in_pre {
+            if let Some(segment) = segments.last_mut() {
+                segment.push_str(line);
+            }
+        } else {
+            segments.push(line.to_string());
+        }
+        in_pre = (in_pre || line.contains("<pre")) && !line.contains("</pre>");
+    }
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for segment in segments {
+        let segment_len = segment.chars().count();
+
+        if current_len + segment_len > limit && current_len > 0 {
+            parts.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+
+        if segment_len > limit {
+            let chars: Vec<char> = segment.chars().collect();
+            for piece in chars.chunks(limit) {
+                parts.push(piece.iter().collect());
+            }
+            continue;
+        }
+
+        current.push_str(&segment);
+        current_len += segment_len;
+
+        // Close the part at a paragraph break once it is reasonably full
+        if segment.trim().is_empty() && current_len > limit * 3 / 4 {
+            parts.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+    }
+    parts.push(current);
+
+    parts
+        .into_iter()
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+/// Build the (unformatted) send request for an answer to `msg`,
+/// threading it as configured
+fn answer_request(bot: &Bot, msg: &Message, text: String, config: &Config) -> JsonRequest<SendMessage> {
+    let mut request = bot.send_message(msg.chat.id, text);
+
+    if config.reply_as_thread {
+        request = request
+            .reply_to_message_id(msg.id)
+            .allow_sending_without_reply(true);
+
+        let is_topic_message = matches!(
+            &msg.kind,
+            teloxide::types::MessageKind::Common(common) if common.is_topic_message
+        );
+        if let Some(thread_id) = msg.thread_id.filter(|_| is_topic_message) {
+            request = request.message_thread_id(thread_id);
+        }
+    }
+
+    request
+}
+
+/// Handle a 👍/👎 click on an answer: record the rating, then remove the buttons
+pub async fn handle_feedback_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    rag_system: Arc<RAGSystem>,
+) -> Result<()> {
+    let Some((id, rating)) = query.data.as_deref().and_then(feedback::parse_feedback_data) else {
+        bot.answer_callback_query(query.id).send_with_retry().await?;
+        return Ok(());
+    };
+
+    let recorded = feedback::record_feedback(rag_system.db_pool(), id, rating).await?;
+    log::info!("Feedback {} for answer {} (recorded: {})", rating, id, recorded);
+
+    let acknowledgement = if recorded {
+        "Thanks for your feedback!"
+    } else {
+        "You already rated this answer"
+    };
+    bot.answer_callback_query(query.id)
+        .text(acknowledgement)
+        .send_with_retry()
+        .await?;
+
+    // Drop the rating buttons, keeping any follow-up suggestions
+    if let Some(message) = query.message {
+        let mut request = bot.edit_message_reply_markup(message.chat.id, message.id);
+        if let Some(keyboard) = message.reply_markup().and_then(feedback::without_feedback_buttons) {
+            request = request.reply_markup(keyboard);
+        }
+        request.send_with_retry().await?;
+    }
 
     Ok(())
 }
 
+/// Route an inline button click: follow-up suggestions are asked as new
+/// questions, everything else is treated as answer feedback
+pub async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    me: Me,
+    rag_system: Arc<RAGSystem>,
+    conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<()> {
+    if query.data.as_deref().is_some_and(follow_ups::is_follow_up_data) {
+        handle_follow_up_callback(bot, query, me, rag_system, conversation_manager, rate_limiter).await
+    } else {
+        handle_feedback_callback(bot, query, rag_system).await
+    }
+}
+
+/// Handle a click on a suggested follow-up: ask it as if the user had
+/// replied to the answer with that question
+async fn handle_follow_up_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    me: Me,
+    rag_system: Arc<RAGSystem>,
+    conversation_manager: Arc<ConversationManager>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<()> {
+    let question = query.message.as_ref().and_then(|message| {
+        let index = follow_ups::parse_follow_up_data(query.data.as_deref()?)?;
+        conversation_manager.follow_up(message.chat.id, message.id, index)
+    });
+    let (Some(answer), Some(question)) = (query.message, question) else {
+        bot.answer_callback_query(query.id)
+            .text("This suggestion has expired, please ask again")
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    };
+    bot.answer_callback_query(query.id).send_with_retry().await?;
+    log::info!("Follow-up chosen in chat {}: {}", answer.chat.id, question);
+
+    let msg = follow_up_message(answer, query.from, question);
+    respond_to_message(bot, msg, me, rag_system, conversation_manager, rate_limiter, None).await
+}
+
+/// A message from `user` asking `question` in reply to the bot's `answer`,
+/// so a clicked follow-up runs through the normal message handling
+fn follow_up_message(answer: Message, user: User, question: String) -> Message {
+    let mut msg = answer.clone();
+    msg.via_bot = None;
+    if let MessageKind::Common(common) = &mut msg.kind {
+        common.from = Some(user);
+        common.sender_chat = None;
+        common.author_signature = None;
+        common.forward = None;
+        common.edit_date = None;
+        common.reply_markup = None;
+        common.reply_to_message = Some(Box::new(answer));
+        common.media_kind = MediaKind::Text(MediaText {
+            text: question,
+            entities: Vec::new(),
+        });
+    }
+    msg
+}
+
 /// Handle the /start command
 pub async fn handle_start_command(bot: Bot, msg: Message) -> Result<()> {
     let welcome_message = "👋 <b>Hello! I'm the Pollinet Knowledge Bot.</b>\n\n\
@@ -261,6 +1485,7 @@ pub async fn handle_start_command(bot: Bot, msg: Message) -> Result<()> {
 
     bot.send_message(msg.chat.id, welcome_message)
         .parse_mode(ParseMode::Html)
+        .send_with_retry()
         .await?;
 
     Ok(())
@@ -272,7 +1497,8 @@ pub async fn handle_help_command(bot: Bot, msg: Message) -> Result<()> {
         <b>Commands:</b>\n\
         /start - Welcome message and introduction\n\
         /help - Show this help message\n\
-        /clear - Clear conversation history\n\n\
+        /clear - Clear your conversation history\n\
+        /settings - Show this chat's settings (admins can change them)\n\n\
         <b>How I work:</b>\n\
         • I use Retrieval-Augmented Generation (RAG) to answer questions\n\
         • I search through Pollinet documents to find relevant information\n\
@@ -286,27 +1512,667 @@ pub async fn handle_help_command(bot: Bot, msg: Message) -> Result<()> {
 
     bot.send_message(msg.chat.id, help_message)
         .parse_mode(ParseMode::Html)
+        .send_with_retry()
+        .await?;
+
+    Ok(())
+}
+
+/// Whether the sender of `msg` is listed in `ADMIN_IDS`
+pub fn is_admin_message(msg: &Message, config: &Config) -> bool {
+    msg.from()
+        .is_some_and(|user| config.is_admin(user.id.0 as i64))
+}
+
+/// Handle the /settings command: show this chat's settings, or change one
+/// with `/settings <name> <value>` (admins only)
+pub async fn handle_settings_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    rag_system: Arc<RAGSystem>,
+) -> Result<()> {
+    let pool = rag_system.db_pool();
+    let text = text.trim();
+    if text.is_empty() {
+        let settings = chat_settings::get_chat_settings(pool, msg.chat.id.0).await?;
+        bot.send_message(msg.chat.id, format!("⚙️ Chat settings:\n{}", settings))
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    }
+
+    if !is_admin_message(&msg, rag_system.config()) {
+        bot.send_message(msg.chat.id, "🔒 Sorry, only bot admins can change settings.")
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    }
+
+    let (name, value) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let setting = match ChatSetting::parse(name, value) {
+        Ok(setting) => setting,
+        Err(e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "{}\nUsage: /settings language <name|auto>, /settings keywords <on|off>, \
+                     /settings namespace <name|default>",
+                    e
+                ),
+            )
+            .send_with_retry()
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let settings = chat_settings::set_chat_setting(pool, msg.chat.id.0, &setting).await?;
+    bot.send_message(msg.chat.id, format!("✅ Settings updated:\n{}", settings))
+        .send_with_retry()
+        .await?;
+
+    Ok(())
+}
+
+/// Handle the /addknowledge command: add the command text (or the replied-to
+/// message) to the chat's knowledge base namespace. Admins only.
+pub async fn handle_addknowledge_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    rag_system: Arc<RAGSystem>,
+) -> Result<()> {
+    if !is_admin_message(&msg, rag_system.config()) {
+        bot.send_message(msg.chat.id, "🔒 Sorry, only bot admins can add knowledge.")
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    }
+
+    let content = match text.trim() {
+        "" => msg
+            .reply_to_message()
+            .and_then(|reply| reply.text())
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        body => body.to_string(),
+    };
+    if content.is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /addknowledge <text>, or reply to a message with /addknowledge",
+        )
+        .send_with_retry()
+        .await?;
+        return Ok(());
+    }
+
+    let document_name = format!("telegram_admin_{}_{}", msg.chat.id, msg.id);
+    let mut metadata = HashMap::new();
+    metadata.insert("source".to_string(), "telegram_admin".to_string());
+    if let Some(user) = msg.from() {
+        metadata.insert("added_by".to_string(), user.id.to_string());
+    }
+
+    let namespace = chat_settings::get_chat_settings(rag_system.db_pool(), msg.chat.id.0)
+        .await?
+        .namespace;
+    let reply = match rag_system
+        .add_document_in_namespace(&document_name, &content, metadata, namespace.as_deref())
+        .await
+    {
+        Ok(stats) => {
+            log::info!("Admin added '{}' ({} chunks) via Telegram", document_name, stats.total());
+            format!(
+                "✅ Added to the knowledge base as <code>{}</code> ({} chunks).",
+                document_name,
+                stats.total()
+            )
+        }
+        Err(e) => match e.downcast_ref::<DocumentTooShort>() {
+            Some(short) if short.no_chunks => format!(
+                "❌ Nothing was added: every chunk of that was under the {}-character minimum.",
+                short.min_chars
+            ),
+            Some(short) => format!(
+                "❌ That's too short to add to the knowledge base (minimum {} characters).",
+                short.min_chars
+            ),
+            None => {
+                log::error!("Failed to add knowledge from Telegram: {:#}", e);
+                "❌ Failed to add that to the knowledge base. Check the logs for details.".to_string()
+            }
+        },
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .parse_mode(ParseMode::Html)
+        .send_with_retry()
         .await?;
 
     Ok(())
 }
 
+/// Handle the /broadcast command: send the command text to every recorded
+/// chat. Admins only.
+pub async fn handle_broadcast_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    rag_system: Arc<RAGSystem>,
+) -> Result<()> {
+    if !is_admin_message(&msg, rag_system.config()) {
+        bot.send_message(msg.chat.id, "🔒 Sorry, only bot admins can send broadcasts.")
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    }
+
+    let text = text.trim();
+    if text.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /broadcast <message>")
+            .send_with_retry()
+            .await?;
+        return Ok(());
+    }
+
+    bot.send_message(msg.chat.id, "📣 Broadcasting… I'll report back when it's done.")
+        .send_with_retry()
+        .await?;
+
+    // Sending to every chat takes a while, and webhook updates are handled
+    // one at a time, so run it in the background instead of stalling the bot
+    let pool = rag_system.db_pool().clone();
+    let text = text.to_string();
+    let admin_chat = msg.chat.id;
+    tokio::spawn(async move {
+        let summary = match chats::broadcast_message(&bot, &pool, &text).await {
+            Ok(report) => format!(
+                "📣 Broadcast sent to {} chats ({} removed, {} failed).",
+                report.sent, report.removed, report.failed
+            ),
+            Err(e) => {
+                log::error!("Broadcast failed: {:#}", e);
+                "❌ Broadcast failed; see the logs for details.".to_string()
+            }
+        };
+        if let Err(e) = bot.send_message(admin_chat, summary).send_with_retry().await {
+            log::error!("Failed to send broadcast report to chat {}: {}", admin_chat, e);
+        }
+    });
+
+    Ok(())
+}
+
 /// Handle the /clear command to reset conversation history
 pub async fn handle_clear_command(
     bot: Bot,
     msg: Message,
     conversation_manager: Arc<ConversationManager>,
 ) -> Result<()> {
-    let chat_id = msg.chat.id.0;
-    conversation_manager.clear_history(chat_id).await;
+    // Only the caller's own history in groups; the whole chat in private
+    conversation_manager.clear_history(ConversationKey::for_message(&msg)).await;
 
     bot.send_message(
         msg.chat.id,
         "✅ <b>Conversation history cleared!</b> Starting fresh.",
     )
     .parse_mode(ParseMode::Html)
+    .send_with_retry()
     .await?;
 
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[test]
+    fn text_similarity_ignores_case_and_punctuation() {
+        assert_eq!(text_similarity("Pollinet relays offline!", "pollinet RELAYS offline"), 1.0);
+        assert_eq!(text_similarity("", ""), 1.0);
+        assert_eq!(text_similarity("alpha beta", "gamma delta"), 0.0);
+        assert!((text_similarity("a b c d", "a b") - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn repeat_answer_needs_near_identical_text() {
+        let previous = "Pollinet lets Solana transactions travel over Bluetooth mesh networks \
+            until a peer with internet relays them.";
+        assert!(is_repeat_answer(previous, &previous.to_uppercase()));
+        assert!(!is_repeat_answer(previous, "Pollinet lets Solana transactions travel offline."));
+    }
+
+    fn message(role: &str, content: &str) -> ConversationMessage {
+        ConversationMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn exported_conversations_import_into_a_fresh_manager() {
+        let manager = ConversationManager::new(10);
+        let key = ConversationKey::chat(7);
+        manager.add_user_message(key, "What is Pollinet?".to_string()).await;
+        manager.add_assistant_message(key, "An offline relay network.".to_string()).await;
+
+        let exported = manager.export_all().await;
+        let restored = ConversationManager::new(10);
+        restored.import_all(exported).await;
+
+        let history = restored.export(key).await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].role, "user");
+        assert_eq!(history[1].content, "An offline relay network.");
+    }
+
+    #[tokio::test]
+    async fn import_keeps_only_the_latest_messages() {
+        let manager = ConversationManager::new(2);
+        let key = ConversationKey::chat(7);
+        manager
+            .import(key, vec![message("user", "1"), message("assistant", "2"), message("user", "3")])
+            .await;
+
+        let contents: Vec<_> = manager.export(key).await.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["2", "3"]);
+    }
+
+    #[test]
+    fn command_target_reads_the_bot_suffix() {
+        assert_eq!(command_target("/help@pollinet_bot now"), Some("pollinet_bot"));
+        assert_eq!(command_target("/help"), None);
+        assert_eq!(command_target("help@pollinet_bot"), None);
+        assert_eq!(command_target(""), None);
+    }
+
+    #[test]
+    fn commands_for_other_bots_are_ignored() {
+        assert!(accepts_command("/help@Pollinet_Bot", "pollinet_bot", false, false));
+        assert!(!accepts_command("/help@other_bot", "pollinet_bot", true, true));
+        assert!(accepts_command("/help", "pollinet_bot", true, false));
+        assert!(accepts_command("/help", "pollinet_bot", false, true));
+        assert!(!accepts_command("/help", "pollinet_bot", false, false));
+    }
+
+    fn group_message(topic: bool) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 10,
+            "message_thread_id": 5,
+            "is_topic_message": topic,
+            "date": 0,
+            "chat": {"id": -100, "type": "supergroup", "title": "Pollinet", "is_forum": topic},
+            "from": {"id": 1, "is_bot": false, "first_name": "Ada"},
+            "text": "What is Pollinet?"
+        }))
+        .expect("valid message")
+    }
+
+    /// Fake Telegram Bot API that records `(method, body)` for every call
+    async fn telegram_server() -> (reqwest::Url, Arc<Mutex<Vec<(String, String)>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&calls);
+        let handler = move |uri: axum::http::Uri, body: axum::body::Bytes| {
+            let method = uri.path().rsplit('/').next().unwrap_or_default().to_lowercase();
+            let result = match method.as_str() {
+                "sendmessage" => serde_json::json!({
+                    "message_id": 11,
+                    "date": 0,
+                    "chat": {"id": -100, "type": "supergroup", "title": "Pollinet"},
+                    "text": BUSY_MESSAGE
+                }),
+                _ => serde_json::json!(true),
+            };
+            received.lock().unwrap().push((method, String::from_utf8_lossy(&body).into_owned()));
+            async move { axum::Json(serde_json::json!({"ok": true, "result": result})) }
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move { axum::serve(listener, axum::Router::new().fallback(handler)).await });
+        (url, calls)
+    }
+
+    #[tokio::test]
+    async fn query_slots_cap_concurrent_queries() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // GPT stub that holds every request until released, tracking how
+        // many are in flight at once
+        let (release, released) = tokio::sync::watch::channel(false);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (current, highest) = (Arc::clone(&in_flight), Arc::clone(&peak));
+        let handler = move || {
+            let (current, highest, mut released) = (Arc::clone(&current), Arc::clone(&highest), released.clone());
+            async move {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                highest.fetch_max(now, Ordering::SeqCst);
+                released.wait_for(|released| *released).await.unwrap();
+                current.fetch_sub(1, Ordering::SeqCst);
+                axum::Json(serde_json::json!({
+                    "choices": [{"message": {"role": "assistant", "content": "English"}}],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 1, "total_tokens": 11}
+                }))
+            }
+        };
+        let app = axum::Router::new().route("/chat/completions", axum::routing::post(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let openai = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (telegram, telegram_calls) = telegram_server().await;
+        let bot = Bot::new("123:test").set_api_url(telegram);
+        let config = Config::for_tests(&[("OPENAI_BASE_URL", openai.as_str()), ("MAX_CONCURRENT_QUERIES", "2")]);
+        let pool = PgPool::connect_lazy(&config.database_url).expect("valid database URL");
+        let rag_system = Arc::new(RAGSystem::with_pool(config, pool));
+
+        let queries: Vec<_> = (0..5)
+            .map(|_| {
+                let (bot, rag_system) = (bot.clone(), Arc::clone(&rag_system));
+                tokio::spawn(async move {
+                    let msg = group_message(false);
+                    let slots = rag_system.query_slots().expect("limit configured");
+                    let _permit = acquire_query_slot(&bot, &msg, slots, rag_system.config()).await.unwrap();
+                    rag_system.detect_language("What is Pollinet?").await.unwrap();
+                })
+            })
+            .collect();
+
+        // The three queued queries tell their chats they're waiting
+        let busy_messages = || {
+            telegram_calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(method, body)| method == "sendmessage" && body.contains("busy answering"))
+                .count()
+        };
+        tokio::time::timeout(QUERY_SLOT_WAIT * 5, async {
+            while busy_messages() < 3 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("queued queries should send the busy message");
+        assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+        release.send(true).unwrap();
+        for query in queries {
+            query.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(busy_messages(), 3);
+
+        let unlimited = Config::for_tests(&[("MAX_CONCURRENT_QUERIES", "0")]);
+        let pool = PgPool::connect_lazy(&unlimited.database_url).expect("valid database URL");
+        assert!(RAGSystem::with_pool(unlimited, pool).query_slots().is_none());
+    }
+
+    #[test]
+    fn answers_reply_to_the_question_when_threading() {
+        let bot = Bot::new("123:test");
+        let config = Config::for_tests(&[("REPLY_AS_THREAD", "true")]);
+
+        let request = answer_request(&bot, &group_message(true), "answer".to_string(), &config);
+        assert_eq!(request.reply_to_message_id, Some(MessageId(10)));
+        assert_eq!(request.allow_sending_without_reply, Some(true));
+        assert!(request.message_thread_id.is_some());
+
+        // Reply threads outside forum topics must not set a topic
+        let request = answer_request(&bot, &group_message(false), "answer".to_string(), &config);
+        assert_eq!(request.reply_to_message_id, Some(MessageId(10)));
+        assert_eq!(request.message_thread_id, None);
+    }
+
+    #[test]
+    fn answers_are_plain_messages_by_default() {
+        let bot = Bot::new("123:test");
+        let config = Config::for_tests(&[]);
+
+        let request = answer_request(&bot, &group_message(true), "answer".to_string(), &config);
+        assert_eq!(request.reply_to_message_id, None);
+        assert_eq!(request.message_thread_id, None);
+    }
+
+    #[test]
+    fn score_footer_shows_the_top_three() {
+        assert_eq!(
+            format_scores(&[0.91234, 0.8, 0.75, 0.5]),
+            "\n\n<i>🔎 Top similarity: 0.912, 0.800, 0.750</i>"
+        );
+        assert_eq!(format_scores(&[]), "\n\n<i>🔎 No chunks retrieved</i>");
+    }
+
+    #[test]
+    fn conversation_keys_round_trip_through_serde() {
+        let keys = [ConversationKey::chat(-1001), ConversationKey { chat_id: -1001, user_id: Some(42) }];
+        let json = serde_json::to_string(&keys).unwrap();
+        assert_eq!(json, r#"["-1001","-1001:42"]"#);
+        assert_eq!(serde_json::from_str::<[ConversationKey; 2]>(&json).unwrap(), keys);
+
+        // Keys are also map keys in conversation exports
+        let map = HashMap::from([(keys[1], vec![message("user", "hi")])]);
+        let json = serde_json::to_string(&map).unwrap();
+        let parsed: HashMap<ConversationKey, Vec<ConversationMessage>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[&keys[1]][0].content, "hi");
+
+        assert!(serde_json::from_str::<ConversationKey>(r#""chat:1""#).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn persisted_history_survives_a_restart() {
+        let pool = test_pool().await;
+        let key = ConversationKey::chat(-(std::process::id() as i64) * 10 - 1);
+        let manager = ConversationManager::with_database(2, pool.clone());
+        manager.clear_history(key).await;
+        for text in ["one", "two", "three"] {
+            manager.add_user_message(key, text.to_string()).await;
+        }
+
+        // A new manager only has the database to go on
+        let restarted = ConversationManager::with_database(2, pool.clone());
+        let contents: Vec<_> = restarted.get_history(key).await.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["two", "three"]);
+
+        restarted.clear_history(key).await;
+        let cleared = ConversationManager::with_database(2, pool);
+        assert!(cleared.get_history(key).await.is_empty());
+    }
+
+    #[test]
+    fn group_members_get_their_own_conversation() {
+        let msg = group_message(false);
+        assert_eq!(ConversationKey::for_message(&msg), ConversationKey { chat_id: -100, user_id: Some(1) });
+
+        let private: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": 1, "type": "private", "first_name": "Ada"},
+            "from": {"id": 1, "is_bot": false, "first_name": "Ada"},
+            "text": "What is Pollinet?"
+        }))
+        .expect("valid message");
+        assert_eq!(ConversationKey::for_message(&private), ConversationKey::chat(1));
+    }
+
+    #[test]
+    fn conversation_keys_display_and_parse() {
+        let key = ConversationKey { chat_id: -100, user_id: Some(1) };
+        assert_eq!(key.to_string(), "-100:1");
+        assert_eq!("-100:1".parse::<ConversationKey>().unwrap(), key);
+        assert_eq!("-100".parse::<ConversationKey>().unwrap(), ConversationKey::chat(-100));
+        assert!("-100:ada".parse::<ConversationKey>().is_err());
+    }
+
+    #[test]
+    fn long_messages_split_within_the_limit() {
+        let text = (1..=40).map(|i| format!("Line {} about Pollinet.", i)).collect::<Vec<_>>().join("\n");
+        let parts = split_message(&text, 100);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.chars().count() <= 100));
+        assert_eq!(parts.join("\n"), text);
+
+        assert_eq!(split_message("short", 100), ["short"]);
+    }
+
+    #[test]
+    fn split_keeps_pre_blocks_whole_and_cuts_on_char_boundaries() {
+        let text = "intro\n<pre>\nlet a = 1;\nlet b = 2;\n</pre>\noutro";
+        let parts = split_message(text, 40);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().any(|part| part.contains("<pre>\nlet a = 1;\nlet b = 2;\n</pre>")));
+
+        let emoji = "🚀".repeat(10);
+        let parts = split_message(&emoji, 4);
+        assert_eq!(parts, ["🚀🚀🚀🚀", "🚀🚀🚀🚀", "🚀🚀"]);
+    }
+
+    #[test]
+    fn escaping_keeps_markdown_characters_literal() {
+        let cases = [
+            ("call get_nonce_account for nonce_v2", "call get_nonce_account for nonce_v2"),
+            ("**bold** in Markdown, 2 * 3 < 7", "**bold** in Markdown, 2 * 3 &lt; 7"),
+            ("[docs](https://pollinet.xyz?a=1&b=2)", "[docs](https://pollinet.xyz?a=1&amp;b=2)"),
+            ("run `pollinet relay <tx>`", "run `pollinet relay &lt;tx&gt;`"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(escape_html(input), expected);
+            assert_eq!(strip_html_tags(&escape_html(input)), input);
+        }
+        assert_eq!(format_sources(&["`a_b*[c]`.md".to_string()]), "\n\n<i>Sources: `a_b*[c]`.md</i>");
+    }
+
+    #[test]
+    fn html_fallback_strips_tags_and_unescapes_entities() {
+        assert_eq!(
+            strip_html_tags("<b>Pollinet</b> relays <i>offline</i> &lt;tx&gt; &amp; &quot;more&quot;"),
+            "Pollinet relays offline <tx> & \"more\""
+        );
+        // &amp; is unescaped last so escaped entities stay literal
+        assert_eq!(strip_html_tags("&amp;lt;"), "&lt;");
+        assert_eq!(
+            strip_html_tags("<a href=\"https://pollinet.xyz\">Docs</a> <tg-spoiler>soon</tg-spoiler>"),
+            "Docs soon"
+        );
+    }
+
+    #[test]
+    fn html_fallback_keeps_stray_angle_brackets() {
+        assert_eq!(strip_html_tags("a < b and c > d"), "a < b and c > d");
+        assert_eq!(
+            strip_html_tags("<b>Fees</b> < 0.001 SOL, <i>always</i> <unknown> kept"),
+            "Fees < 0.001 SOL, always <unknown> kept"
+        );
+        assert_eq!(strip_html_tags("trailing <"), "trailing <");
+    }
+
+    #[test]
+    fn sources_footer_escapes_document_names() {
+        assert_eq!(format_sources(&[]), "");
+        assert_eq!(
+            format_sources(&["a<b>.md".to_string(), "guide.md".to_string()]),
+            "\n\n<i>Sources: a&lt;b&gt;.md, guide.md</i>"
+        );
+    }
+
+    #[test]
+    fn only_listed_users_are_admins() {
+        let config = Config::for_tests(&[("ADMIN_IDS", "1, 99")]);
+        assert!(is_admin_message(&group_message(false), &config));
+
+        let config = Config::for_tests(&[("ADMIN_IDS", "99")]);
+        assert!(!is_admin_message(&group_message(false), &config));
+        assert!(!is_admin_message(&group_message(false), &Config::for_tests(&[])));
+
+        assert!(Config::try_for_tests(&[("ADMIN_IDS", "1,ada")]).is_err());
+    }
+
+    fn group_text_message(text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 11,
+            "date": 0,
+            "chat": {"id": -100, "type": "supergroup", "title": "Pollinet"},
+            "from": {"id": 1, "is_bot": false, "first_name": "Ada"},
+            "text": text
+        }))
+        .expect("valid message")
+    }
+
+    #[test]
+    fn group_messages_trigger_on_configured_keywords() {
+        let bot_id = UserId(500);
+        let keywords = Config::for_tests(&[("TRIGGER_KEYWORDS", "Mesh, relay")]).trigger_keywords;
+        assert_eq!(keywords, ["mesh", "relay"]);
+
+        assert!(should_respond("pollinet_bot", &group_text_message("How does the MESH work?"), bot_id, &keywords));
+        assert!(!should_respond("pollinet_bot", &group_text_message("What is Pollinet?"), bot_id, &keywords));
+        assert!(should_respond("pollinet_bot", &group_text_message("hi @Pollinet_Bot"), bot_id, &[]));
+        assert!(!should_respond("pollinet_bot", &group_text_message("hi everyone"), bot_id, &[]));
+    }
+
+    #[test]
+    fn captions_count_as_message_text() {
+        let photo: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 12,
+            "date": 0,
+            "chat": {"id": -100, "type": "supergroup", "title": "Pollinet"},
+            "from": {"id": 1, "is_bot": false, "first_name": "Ada"},
+            "photo": [{"file_id": "a", "file_unique_id": "b", "width": 1, "height": 1}],
+            "caption": "Is this a Pollinet relay?"
+        }))
+        .expect("valid message");
+
+        assert_eq!(message_text(&photo), Some("Is this a Pollinet relay?"));
+        assert!(should_respond("pollinet_bot", &photo, UserId(500), &["pollinet".to_string()]));
+        assert_eq!(message_text(&group_text_message("hi")), Some("hi"));
+    }
+
+    fn answered(query: &str, answer: &str) -> AnsweredMessage {
+        AnsweredMessage {
+            reply_ids: vec![MessageId(2)],
+            query: query.to_string(),
+            answer: answer.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn edited_questions_drop_their_old_exchange() {
+        let manager = ConversationManager::new(10);
+        let key = ConversationKey::chat(7);
+        manager.add_user_message(key, "What is Pollinet?".to_string()).await;
+        manager.add_assistant_message(key, "A relay network.".to_string()).await;
+        manager.add_user_message(key, "Who runs relays?".to_string()).await;
+        manager.add_assistant_message(key, "Anyone.".to_string()).await;
+
+        manager.remove_exchange(key, &answered("What is Pollinet?", "A relay network.")).await;
+        let contents: Vec<_> = manager.get_history(key).await.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["Who runs relays?", "Anyone."]);
+
+        // An answer that isn't the recorded one stays
+        manager.remove_exchange(key, &answered("Who runs relays?", "Someone else.")).await;
+        let contents: Vec<_> = manager.get_history(key).await.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["Anyone."]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn edited_questions_are_removed_from_the_database() {
+        let pool = test_pool().await;
+        let key = ConversationKey::chat(-(std::process::id() as i64) * 10 - 7);
+        let manager = ConversationManager::with_database(10, pool.clone());
+        manager.clear_history(key).await;
+        manager.add_user_message(key, "What is Pollinet?".to_string()).await;
+        manager.add_assistant_message(key, "A relay network.".to_string()).await;
+
+        manager.remove_exchange(key, &answered("What is Pollinet?", "A relay network.")).await;
+        let restarted = ConversationManager::with_database(10, pool);
+        assert!(restarted.get_history(key).await.is_empty());
+    }
+}
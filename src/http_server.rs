@@ -0,0 +1,1165 @@
+//! HTTP server module
+//!
+//! This module holds the axum routes served in webhook mode, each request
+//! logged with its latency and an `x-request-id`:
+//! - Telegram webhook endpoint
+//! - Liveness (`/health`) and readiness (`/health/ready`) checks
+//! - Prometheus metrics (`/metrics`, unauthenticated)
+//! - Public query API and latest-chunks lookup (optionally protected by
+//!   `QUERY_API_SECRET`), with opt-in CORS for browser clients
+//! - Admin endpoints protected by a Bearer token (`ADMIN_API_SECRET`),
+//!   including document ingestion (text or web page URL), soft deletion,
+//!   restore and purge, knowledge base and token usage stats, and
+//!   per-day/per-chat usage reports
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::{AUTHORIZATION, CONTENT_TYPE}, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+    Extension, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::types::Update;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::circuit_breaker::CircuitOpen;
+use crate::handlers::{ConversationKey, ConversationManager};
+use crate::metrics::METRICS;
+use crate::query_logs;
+use crate::rag::{checked_namespace, ConversationMessage, DocumentTooShort, RAGSystem, DEFAULT_NAMESPACE};
+
+/// Longest question accepted by `POST /query`
+const MAX_QUERY_CHARS: usize = 2000;
+
+/// Chunks returned by `GET /latest` when no `limit` is given
+const DEFAULT_LATEST_LIMIT: usize = 10;
+
+/// Most chunks a single `GET /latest` request may return
+const MAX_LATEST_LIMIT: usize = 100;
+
+/// Age in days a soft-deleted document must reach before
+/// `POST /admin/purge-deleted` removes it, unless the request says otherwise
+const DEFAULT_PURGE_DAYS: u64 = 30;
+
+/// How long browsers may cache a CORS preflight response
+const CORS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Header carrying the id that correlates a request with its log lines
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming `x-request-id` that is reused rather than replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Header Telegram uses to send the webhook secret token
+const TELEGRAM_SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Application state shared across HTTP handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub update_tx: tokio::sync::mpsc::UnboundedSender<Update>,
+    pub rag_system: Arc<RAGSystem>,
+    pub conversation_manager: Arc<ConversationManager>,
+}
+
+/// Build the HTTP router with all routes
+/// 
+/// The routes meant for browsers (`/query`, `/latest`, `/knowledge-stats`)
+/// answer CORS requests, including `OPTIONS` preflights, when
+/// `CORS_ALLOWED_ORIGINS` is set.
+pub fn create_router(state: AppState) -> Router {
+    let mut browser_routes = Router::new()
+        .route("/query", post(query_endpoint))
+        .route("/latest", get(latest_endpoint))
+        .route("/knowledge-stats", get(knowledge_stats_endpoint));
+    if let Some(cors) = cors_layer(&state.rag_system.config().cors_allowed_origins) {
+        browser_routes = browser_routes.layer(cors);
+    }
+
+    Router::new()
+        .route("/webhook", post(webhook_handler))
+        .route("/health", get(health_check))
+        .route("/health/ready", get(readiness_check))
+        .route("/metrics", get(metrics_endpoint))
+        .merge(browser_routes)
+        .route("/usage-report", get(usage_report_endpoint))
+        .route("/documents", post(add_document_endpoint))
+        .route("/documents/:name", delete(delete_document_endpoint))
+        .route("/documents/:name/restore", post(restore_document_endpoint))
+        .route("/ingest-url", post(ingest_url_endpoint))
+        .route("/admin/reembed", post(reembed_endpoint))
+        .route("/admin/purge-deleted", post(purge_deleted_endpoint))
+        .route(
+            "/admin/conversations",
+            get(export_conversations_endpoint).post(import_conversations_endpoint),
+        )
+        .with_state(state)
+        .layer(middleware::from_fn(log_requests))
+}
+
+/// Id correlating an HTTP request's log lines, echoed in `x-request-id`
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Log every request's method, path, status and latency, tagged with its
+/// request id
+///
+/// The id is taken from an incoming `x-request-id` header (so ids from a
+/// proxy carry through) or generated, made available to handlers as a
+/// [`RequestId`] extension, and returned in the response's `x-request-id`.
+/// Health checks and metrics scrapes are logged at debug level only.
+async fn log_requests(mut request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    request.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let started = std::time::Instant::now();
+    let mut response = next.run(request).await;
+
+    let level = if path.starts_with("/health") || path == "/metrics" {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    log::log!(
+        level,
+        "[{}] {} {} -> {} ({} ms)",
+        request_id,
+        method,
+        path,
+        response.status().as_u16(),
+        started.elapsed().as_millis()
+    );
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// CORS policy allowing `origins` (None when empty, leaving CORS disabled)
+fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+            .max_age(CORS_MAX_AGE),
+    )
+}
+
+/// Handle incoming webhook updates from Telegram
+///
+/// teloxide's `axum_to_router` isn't used: in teloxide 0.12 it is built on
+/// axum 0.6, so its router can't be merged into this axum 0.7 one, which
+/// also serves the health, query and admin endpoints on the same port.
+/// Updates are checked against `WEBHOOK_SECRET` here and queued for
+/// `process_webhook_update` in `bot.rs`.
+async fn webhook_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    log::info!("📥 Received webhook update from Telegram");
+    
+    // Telegram echoes the secret registered with set_webhook in this header
+    if let Some(secret) = &state.rag_system.config().webhook_secret {
+        let provided = headers
+            .get(TELEGRAM_SECRET_HEADER)
+            .and_then(|v| v.to_str().ok());
+        if provided != Some(secret.as_str()) {
+            log::warn!("Rejected webhook request with missing or invalid secret token");
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Invalid secret token"})),
+            ));
+        }
+    }
+    
+    // Add a timeout to prevent hanging requests
+    let start = std::time::Instant::now();
+    
+    // Read the body
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Failed to read webhook body: {}", e);
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Failed to read request body"})),
+            ));
+        }
+    };
+    
+    log::debug!("Webhook body size: {} bytes", bytes.len());
+    
+    // Parse the update
+    let update: Update = match serde_json::from_slice::<Update>(bytes.as_ref()) {
+        Ok(update) => {
+            log::info!("✓ Successfully parsed update ID: {:?}", update.id);
+            update
+        }
+        Err(e) => {
+            log::error!("Failed to parse webhook update: {}", e);
+            let preview_len = bytes.len().min(500);
+            log::error!("Raw body (first {} chars): {}", preview_len, String::from_utf8_lossy(&bytes[..preview_len]));
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid update format"})),
+            ));
+        }
+    };
+    
+    // Send update to processing channel
+    if let Err(e) = state.update_tx.send(update) {
+        log::error!("Failed to send update to processing channel: {}", e);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to queue update"})),
+        ));
+    }
+    
+    let elapsed = start.elapsed();
+    log::info!("✓ Update queued for processing (took {:?})", elapsed);
+    Ok(StatusCode::OK)
+}
+
+/// Health check endpoint
+async fn health_check() -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "service": "pollinet_knowledge_bot"
+    }))
+}
+
+/// Readiness probe: checks PostgreSQL and, if `HEALTH_CHECK_OPENAI` is set, OpenAI
+///
+/// Returns 503 with the failing checks so load balancers stop routing to a
+/// broken instance, while `/health` stays a cheap liveness probe.
+async fn readiness_check(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let mut checks = serde_json::Map::new();
+    let mut ready = true;
+
+    match sqlx::query("SELECT 1").execute(state.rag_system.db_pool()).await {
+        Ok(_) => {
+            checks.insert("database".into(), json!("ok"));
+        }
+        Err(e) => {
+            log::warn!("Readiness check: database unavailable: {}", e);
+            checks.insert("database".into(), json!(format!("error: {}", e)));
+            ready = false;
+        }
+    }
+
+    if state.rag_system.config().health_check_openai {
+        match state.rag_system.list_openai_models().await {
+            Ok(_) => {
+                checks.insert("openai".into(), json!("ok"));
+            }
+            Err(e) => {
+                log::warn!("Readiness check: OpenAI unavailable: {}", e);
+                checks.insert("openai".into(), json!(format!("error: {}", e)));
+                ready = false;
+            }
+        }
+    }
+
+    // Informational: the bot still answers (with a notice) while it is open
+    if state.rag_system.openai_circuit_open() {
+        checks.insert("openai_circuit".into(), json!("open"));
+    }
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ok" } else { "unavailable" },
+            "checks": checks,
+        })),
+    )
+}
+
+/// Router serving only `/metrics`, for a separate `METRICS_PORT`
+pub fn metrics_router() -> Router {
+    Router::new().route("/metrics", get(metrics_endpoint))
+}
+
+/// Prometheus metrics in the text exposition format (unauthenticated)
+async fn metrics_endpoint() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+/// Check the `Authorization: Bearer <secret>` header against `ADMIN_API_SECRET`
+///
+/// Admin endpoints are disabled entirely when no secret is configured.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+    let Some(secret) = &state.rag_system.config().admin_api_secret else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "Admin endpoints are disabled (ADMIN_API_SECRET not set)"})),
+        ));
+    };
+
+    check_bearer(headers, secret)
+}
+
+/// Check the `Authorization: Bearer <secret>` header against `QUERY_API_SECRET`
+///
+/// The public query API is open when no secret is configured.
+fn require_query_access(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+    match &state.rag_system.config().query_api_secret {
+        Some(secret) => check_bearer(headers, secret),
+        None => Ok(()),
+    }
+}
+
+fn check_bearer(headers: &HeaderMap, secret: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    if provided != Some(secret) {
+        log::warn!("Rejected API request with missing or invalid token");
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Invalid or missing Bearer token"})),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Body of a `POST /query` request
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    query: String,
+    #[serde(default)]
+    history: Vec<ConversationMessage>,
+    /// Chunks to retrieve instead of `TOP_K_CHUNKS` (capped at [`crate::rag::MAX_TOP_K`])
+    #[serde(default)]
+    top_k: Option<usize>,
+    /// Knowledge base namespace to answer from (default: `default`)
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+/// Answer a question over HTTP (for embedding Q&A outside Telegram)
+async fn query_endpoint(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_query_access(&state, &headers)?;
+
+    let query = request.query.trim();
+    if query.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Query must not be empty"})),
+        ));
+    }
+    if query.chars().count() > MAX_QUERY_CHARS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Query must be at most {} characters", MAX_QUERY_CHARS)})),
+        ));
+    }
+    if request.top_k == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "top_k must be at least 1"})),
+        ));
+    }
+    validate_namespace(request.namespace.as_deref())?;
+
+    if state.rag_system.config().enable_moderation {
+        match state.rag_system.moderate_text(query).await {
+            Ok(true) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "Query was rejected by moderation"})),
+                ));
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("Moderation check failed, answering anyway: {:#}", e),
+        }
+    }
+
+    let _permit = match state.rag_system.query_slots() {
+        Some(slots) => Some(slots.acquire().await.map_err(|_| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"error": "Server is shutting down"})),
+            )
+        })?),
+        None => None,
+    };
+
+    let started = std::time::Instant::now();
+    let result = state
+        .rag_system
+        .query_detailed(query, &request.history, request.top_k, request.namespace.as_deref())
+        .await
+        .map_err(|e| {
+            if let Some(open) = e.downcast_ref::<CircuitOpen>() {
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(json!({"error": open.to_string()})),
+                );
+            }
+            log::error!("[{}] Error answering HTTP query: {}", request_id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to answer query"})),
+            )
+        })?;
+    METRICS.record_query(started.elapsed(), result.used_fallback);
+
+    let config = state.rag_system.config();
+    if config.log_queries {
+        if let Err(e) = query_logs::log_query(
+            state.rag_system.db_pool(),
+            None,
+            query,
+            &result.usage,
+            &config.gpt_model,
+            result.used_fallback,
+        )
+        .await
+        {
+            log::warn!("Failed to log query: {:#}", e);
+        }
+    }
+
+    Ok(Json(json!({
+        "answer": result.answer,
+        "sources": result.sources,
+        "used_fallback": result.used_fallback,
+    })))
+}
+
+/// Most recent chunks matching metadata filters, without a similarity query
+/// 
+/// `since` (`YYYY-MM-DD` or RFC 3339), `limit` and `namespace` are reserved;
+/// every other query parameter is a metadata filter, e.g.
+/// `/latest?category=pollinet_announcement&since=2024-01-01`.
+async fn latest_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(mut params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_query_access(&state, &headers)?;
+
+    let since = match params.remove("since") {
+        Some(since) => Some(parse_since(since.trim()).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "since must be a date formatted as YYYY-MM-DD or RFC 3339"})),
+            )
+        })?),
+        None => None,
+    };
+    let limit = match params.remove("limit") {
+        Some(limit) => match limit.trim().parse::<usize>() {
+            Ok(limit) if limit >= 1 => limit.min(MAX_LATEST_LIMIT),
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "limit must be a positive integer"})),
+                ));
+            }
+        },
+        None => DEFAULT_LATEST_LIMIT,
+    };
+    let namespace = params.remove("namespace");
+    validate_namespace(namespace.as_deref())?;
+
+    let chunks = state
+        .rag_system
+        .query_by_metadata(&params, since, limit, namespace.as_deref())
+        .await
+        .map_err(|e| {
+            log::error!("Failed to look up latest chunks: {:#}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to look up latest chunks"})),
+            )
+        })?;
+
+    Ok(Json(json!({ "chunks": chunks })))
+}
+
+/// Parse a `YYYY-MM-DD` (midnight UTC) or RFC 3339 date
+fn parse_since(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    if is_iso_date(value) {
+        let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+        return Some(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|date| date.with_timezone(&chrono::Utc))
+}
+
+/// Statistics of one knowledge base namespace (`?namespace=`, default
+/// `default`) and OpenAI token consumption since startup
+async fn knowledge_stats_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<NamespaceParams>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    validate_namespace(params.namespace.as_deref())?;
+
+    let stats = state
+        .rag_system
+        .stats(params.namespace.as_deref())
+        .await
+        .map_err(|e| {
+            log::error!("Failed to collect knowledge stats: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to collect knowledge stats"})),
+            )
+        })?;
+
+    Ok(Json(json!({
+        "namespace": params.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE),
+        "total_chunks": stats.total_chunks,
+        "total_documents": stats.total_documents,
+        "chunks_by_source": stats.chunks_by_source,
+        "chunks_by_category": stats.chunks_by_category,
+        "oldest_document": stats.oldest_document,
+        "newest_document": stats.newest_document,
+        "token_usage": state.rag_system.token_usage(),
+    })))
+}
+
+/// Query string of a `GET /usage-report` request
+#[derive(Debug, Deserialize)]
+struct UsageReportParams {
+    /// First day to include, as `YYYY-MM-DD`
+    since: Option<String>,
+}
+
+/// Token usage of logged queries aggregated by day and by chat
+async fn usage_report_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<UsageReportParams>,
+) -> Result<Json<query_logs::UsageReport>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    if !state.rag_system.config().log_queries {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Query logging is disabled (set LOG_QUERIES=true)"})),
+        ));
+    }
+    let since = params.since.as_deref().map(str::trim);
+    if since.is_some_and(|since| !is_iso_date(since)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "since must be a date formatted as YYYY-MM-DD"})),
+        ));
+    }
+
+    let report = query_logs::usage_report(state.rag_system.db_pool(), since)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to build usage report: {:#}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to build usage report"})),
+            )
+        })?;
+
+    Ok(Json(report))
+}
+
+/// Whether `value` looks like `YYYY-MM-DD`
+fn is_iso_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Body of a `POST /documents` request
+#[derive(Debug, Deserialize)]
+struct AddDocumentRequest {
+    name: String,
+    content: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+/// Add (or replace) a document in the knowledge base
+async fn add_document_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<AddDocumentRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let name = request.name.trim();
+    if name.is_empty() || request.content.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Document name and content must not be empty"})),
+        ));
+    }
+    validate_namespace(request.namespace.as_deref())?;
+
+    let stats = state
+        .rag_system
+        .add_document_in_namespace(name, &request.content, request.metadata, request.namespace.as_deref())
+        .await
+        .map_err(|e| {
+            if let Some(short) = e.downcast_ref::<DocumentTooShort>() {
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(json!({"error": short.to_string()})),
+                );
+            }
+            log::error!("Failed to add document '{}' via HTTP: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to add document"})),
+            )
+        })?;
+
+    log::info!("Added document '{}' via HTTP ({} chunks)", name, stats.total());
+    Ok(Json(json!({
+        "document": name,
+        "chunks": stats.total(),
+        "inserted": stats.inserted,
+        "updated": stats.updated,
+        "skipped": stats.skipped,
+        "deduplicated": stats.deduplicated,
+    })))
+}
+
+/// Body of a `POST /ingest-url` request
+#[derive(Debug, Deserialize)]
+struct IngestUrlRequest {
+    url: String,
+}
+
+/// Fetch a web page and add its text to the knowledge base
+async fn ingest_url_endpoint(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<IngestUrlRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let url = request.url.trim();
+    let chunks = state
+        .rag_system
+        .add_document_from_url(url)
+        .await
+        .map_err(|e| {
+            // The error chain can name internal hosts and addresses, so it
+            // only goes to the log
+            log::error!("[{}] Failed to ingest URL '{}' via HTTP: {:#}", request_id, url, e);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({"error": "Failed to ingest URL"})),
+            )
+        })?;
+
+    log::info!("Ingested URL '{}' via HTTP ({} chunks)", url, chunks);
+    Ok(Json(json!({"document": url, "chunks": chunks})))
+}
+
+/// Query parameters selecting a knowledge base namespace (`?namespace=`)
+#[derive(Debug, Deserialize)]
+struct NamespaceParams {
+    namespace: Option<String>,
+}
+
+/// 400 for a namespace name [`checked_namespace`] rejects
+fn validate_namespace(namespace: Option<&str>) -> Result<(), (StatusCode, Json<Value>)> {
+    checked_namespace(namespace)
+        .map(|_| ())
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(json!({"error": e.to_string()}))))
+}
+
+/// Remove a document and all of its chunks from the knowledge base
+/// (soft delete; see `POST /documents/:name/restore`)
+async fn delete_document_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(params): Query<NamespaceParams>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    validate_namespace(params.namespace.as_deref())?;
+
+    let deleted = state
+        .rag_system
+        .delete_document(&name, params.namespace.as_deref())
+        .await
+        .map_err(|e| {
+            log::error!("Failed to delete document '{}' via HTTP: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to delete document"})),
+            )
+        })?;
+
+    if deleted == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("Document '{}' not found", name), "deleted": 0})),
+        ));
+    }
+
+    Ok(Json(json!({"deleted": deleted})))
+}
+
+/// Undo the deletion of a document that hasn't been purged yet
+async fn restore_document_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(params): Query<NamespaceParams>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+    validate_namespace(params.namespace.as_deref())?;
+
+    let restored = state
+        .rag_system
+        .restore_document(&name, params.namespace.as_deref())
+        .await
+        .map_err(|e| {
+            log::error!("Failed to restore document '{}' via HTTP: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to restore document"})),
+            )
+        })?;
+
+    if restored == 0 {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("No deleted document '{}' to restore", name), "restored": 0})),
+        ));
+    }
+
+    Ok(Json(json!({"restored": restored})))
+}
+
+/// Query parameters of `POST /admin/purge-deleted`
+#[derive(Debug, Deserialize)]
+struct PurgeDeletedParams {
+    /// Only purge documents deleted at least this many days ago
+    #[serde(default = "default_purge_days")]
+    older_than_days: u64,
+}
+
+fn default_purge_days() -> u64 {
+    DEFAULT_PURGE_DAYS
+}
+
+/// Permanently remove soft-deleted chunks
+async fn purge_deleted_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<PurgeDeletedParams>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let older_than = Duration::from_secs(params.older_than_days.saturating_mul(24 * 60 * 60));
+    let purged = state.rag_system.purge_deleted(older_than).await.map_err(|e| {
+        log::error!("Failed to purge deleted chunks: {:#}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to purge deleted chunks"})),
+        )
+    })?;
+
+    Ok(Json(json!({"purged": purged, "older_than_days": params.older_than_days})))
+}
+
+/// Request body for `POST /admin/reembed`
+#[derive(Debug, Deserialize)]
+struct ReembedRequest {
+    model: String,
+}
+
+/// Re-embed the whole knowledge base with a different embedding model
+async fn reembed_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ReembedRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let model = request.model.trim();
+    if model.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "model must not be empty"})),
+        ));
+    }
+
+    let reembedded = state.rag_system.reembed_all(model).await.map_err(|e| {
+        log::error!("Failed to re-embed knowledge base with {}: {:#}", model, e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Failed to re-embed knowledge base"})),
+        )
+    })?;
+
+    Ok(Json(json!({"reembedded": reembedded, "model": model})))
+}
+
+/// Export the conversation state of every chat as JSON
+async fn export_conversations_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<HashMap<ConversationKey, Vec<ConversationMessage>>>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let conversations = state.conversation_manager.export_all().await;
+    log::info!("Exported conversation state for {} conversations", conversations.len());
+    Ok(Json(conversations))
+}
+
+/// Import conversation state (as produced by the export endpoint)
+async fn import_conversations_endpoint(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(conversations): Json<HashMap<ConversationKey, Vec<ConversationMessage>>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    require_admin(&state, &headers)?;
+
+    let imported = conversations.len();
+    state.conversation_manager.import_all(conversations).await;
+    log::info!("Imported conversation state for {} conversations", imported);
+    Ok(Json(json!({"imported_conversations": imported})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use sqlx::PgPool;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// Serve the router on a local port without connecting to the database,
+    /// returning its base URL and the receiving end of the webhook queue
+    async fn test_server(vars: &[(&str, &str)]) -> (String, UnboundedReceiver<Update>) {
+        let config = Config::for_tests(vars);
+        let pool = PgPool::connect_lazy(&config.database_url).expect("valid database URL");
+        serve(config, pool).await
+    }
+
+    async fn serve(config: Config, pool: PgPool) -> (String, UnboundedReceiver<Update>) {
+        let (update_tx, updates) = tokio::sync::mpsc::unbounded_channel();
+        let state = AppState {
+            update_tx,
+            rag_system: Arc::new(RAGSystem::with_pool(config, pool)),
+            conversation_manager: Arc::new(ConversationManager::new(10)),
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, create_router(state)).await });
+        (url, updates)
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_token_must_match_the_secret() {
+        assert!(check_bearer(&bearer("s3cret"), "s3cret").is_ok());
+
+        let (status, _) = check_bearer(&bearer("wrong"), "s3cret").unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(check_bearer(&HeaderMap::new(), "s3cret").is_err());
+
+        let mut basic = HeaderMap::new();
+        basic.insert(AUTHORIZATION, HeaderValue::from_static("Basic s3cret"));
+        assert!(check_bearer(&basic, "s3cret").is_err());
+    }
+
+    #[test]
+    fn query_requests_default_optional_fields() {
+        let request: QueryRequest = serde_json::from_value(json!({"query": "What is Pollinet?"})).unwrap();
+        assert!(request.history.is_empty());
+        assert_eq!(request.top_k, None);
+        assert_eq!(request.namespace, None);
+
+        let request: QueryRequest = serde_json::from_value(json!({
+            "query": "And relays?",
+            "history": [{"role": "user", "content": "What is Pollinet?"}],
+            "top_k": 3,
+        }))
+        .unwrap();
+        assert_eq!(request.history[0].content, "What is Pollinet?");
+        assert_eq!(request.top_k, Some(3));
+    }
+
+    #[tokio::test]
+    async fn document_ingestion_validates_before_touching_the_database() {
+        let (url, _updates) = test_server(&[("ADMIN_API_SECRET", "s3cret")]).await;
+        let client = reqwest::Client::new();
+        let post = |body: Value, token: &str| {
+            client.post(format!("{}/documents", url)).bearer_auth(token).json(&body).send()
+        };
+
+        let response = post(json!({"name": "faq", "content": "Pollinet"}), "wrong").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = post(json!({"name": " ", "content": "Pollinet"}), "s3cret").await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let response = post(json!({"name": "faq", "content": "Pollinet", "namespace": "a b"}), "s3cret")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let body: Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("namespace"));
+    }
+
+    #[tokio::test]
+    async fn failed_url_ingestion_hides_the_cause() {
+        let (url, _updates) = test_server(&[("ADMIN_API_SECRET", "s3cret")]).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/ingest-url", url))
+            .bearer_auth("s3cret")
+            // Nothing listens on port 1
+            .json(&json!({"url": "http://127.0.0.1:1/internal"}))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body, json!({"error": "Failed to ingest URL"}));
+    }
+
+    #[test]
+    fn since_accepts_dates_and_rfc3339() {
+        assert_eq!(parse_since("2024-01-01").unwrap().to_rfc3339(), "2024-01-01T00:00:00+00:00");
+        assert_eq!(parse_since("2024-01-01T12:00:00+02:00").unwrap().to_rfc3339(), "2024-01-01T10:00:00+00:00");
+        assert!(parse_since("2024-13-01").is_none());
+        assert!(parse_since("yesterday").is_none());
+    }
+
+    #[tokio::test]
+    async fn latest_rejects_bad_dates_and_limits() {
+        let (url, _updates) = test_server(&[]).await;
+        for query in ["since=2024-02-30", "since=last-week", "limit=0", "limit=many", "namespace=a b"] {
+            let response = reqwest::get(format!("{}/latest?{}", url, query)).await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_carry_a_request_id() {
+        let (url, _updates) = test_server(&[]).await;
+        let client = reqwest::Client::new();
+
+        let generated = client.get(format!("{}/health", url)).send().await.unwrap();
+        let id = generated.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok());
+
+        let echoed = client
+            .get(format!("{}/health", url))
+            .header(REQUEST_ID_HEADER, "support-ticket-42")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(echoed.headers()[REQUEST_ID_HEADER], "support-ticket-42");
+
+        // Unknown routes and oversized ids still get a fresh id
+        let replaced = client
+            .get(format!("{}/missing", url))
+            .header(REQUEST_ID_HEADER, "x".repeat(MAX_REQUEST_ID_LEN + 1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(replaced.status(), reqwest::StatusCode::NOT_FOUND);
+        let id = replaced.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok());
+    }
+
+    /// `Access-Control-Allow-Origin` of a preflight for `path` from `origin`
+    async fn preflight_allowed_origin(url: &str, path: &str, origin: &str) -> Option<String> {
+        let response = reqwest::Client::new()
+            .request(reqwest::Method::OPTIONS, format!("{}{}", url, path))
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .send()
+            .await
+            .unwrap();
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn allowed_origins_get_cors_headers() {
+        let (url, _updates) = test_server(&[("CORS_ALLOWED_ORIGINS", "https://pollinet.xyz")]).await;
+
+        let allowed = preflight_allowed_origin(&url, "/query", "https://pollinet.xyz").await;
+        assert_eq!(allowed.as_deref(), Some("https://pollinet.xyz"));
+        assert_eq!(preflight_allowed_origin(&url, "/query", "https://evil.example").await, None);
+        // Admin routes never answer browsers
+        assert_eq!(preflight_allowed_origin(&url, "/documents", "https://pollinet.xyz").await, None);
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/latest?limit=0", url))
+            .header("origin", "https://pollinet.xyz")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://pollinet.xyz");
+    }
+
+    #[tokio::test]
+    async fn cors_is_off_by_default() {
+        let (url, _updates) = test_server(&[]).await;
+        assert_eq!(preflight_allowed_origin(&url, "/query", "https://pollinet.xyz").await, None);
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_are_disabled_without_a_secret() {
+        let (url, _updates) = test_server(&[]).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/documents", url))
+            .bearer_auth("anything")
+            .json(&json!({"name": "faq", "content": "Pollinet"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn document_deletion_requires_a_token_and_a_valid_namespace() {
+        let (url, _updates) = test_server(&[("ADMIN_API_SECRET", "s3cret")]).await;
+        let client = reqwest::Client::new();
+
+        let response = client.delete(format!("{}/documents/faq", url)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .delete(format!("{}/documents/faq?namespace=a.b", url))
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn webhook_updates_need_the_secret_token() {
+        let (url, mut updates) = test_server(&[("WEBHOOK_SECRET", "hook-secret")]).await;
+        let client = reqwest::Client::new();
+        let update = json!({
+            "update_id": 7,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": {"id": 1, "type": "private", "first_name": "Ada"},
+                "from": {"id": 1, "is_bot": false, "first_name": "Ada"},
+                "text": "What is Pollinet?"
+            }
+        });
+
+        let response = client.post(format!("{}/webhook", url)).json(&update).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let response = client
+            .post(format!("{}/webhook", url))
+            .header(TELEGRAM_SECRET_HEADER, "hook-secret")
+            .json(&update)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(updates.try_recv().unwrap().id, 7);
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_without_authentication() {
+        let (url, _updates) = test_server(&[("ADMIN_API_SECRET", "s3cret")]).await;
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("pollinet_queries_total"));
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_unreachable_dependencies() {
+        // Nothing listens on port 1, so both checks fail fast
+        let config = Config::for_tests(&[("HEALTH_CHECK_OPENAI", "true"), ("OPENAI_BASE_URL", "http://127.0.0.1:1/v1")]);
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_secs(2))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/pollinet")
+            .unwrap();
+        let (url, _updates) = serve(config, pool).await;
+
+        let response = reqwest::get(format!("{}/health", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let response = reqwest::get(format!("{}/health/ready", url)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert!(body["checks"]["database"].as_str().unwrap().starts_with("error"));
+        assert!(body["checks"]["openai"].as_str().unwrap().starts_with("error"));
+    }
+}
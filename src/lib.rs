@@ -1,11 +1,32 @@
-/// Pollinet Knowledge Bot Library
-/// 
-/// This library provides the core functionality for the Pollinet Telegram bot
-/// including RAG (Retrieval-Augmented Generation) capabilities, configuration,
-/// handlers, and bot setup.
+//! Pollinet Knowledge Bot Library
+//! 
+//! This library provides the core functionality for the Pollinet Telegram bot
+//! including RAG (Retrieval-Augmented Generation) capabilities, embedding
+//! providers, configuration, handlers, answer feedback, follow-up
+//! suggestions, chat tracking and broadcasts, per-chat settings, web page and
+//! PDF text extraction, query cost logging, rate limiting, an OpenAI circuit
+//! breaker, metrics, logger setup, tracing helpers, retried Telegram
+//! requests, HTTP server, and bot setup.
 
 pub mod bot;
+pub mod chat_settings;
+pub mod chats;
+pub mod circuit_breaker;
 pub mod config;
+pub mod embeddings;
+pub mod extract;
+pub mod feedback;
+pub mod follow_ups;
 pub mod handlers;
+pub mod http_server;
+pub mod logging;
+pub mod metrics;
+pub mod query_logs;
 pub mod rag;
+pub mod rate_limit;
+pub mod telegram;
+pub mod telemetry;
 
+
+#[cfg(test)]
+pub(crate) mod test_support;
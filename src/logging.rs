@@ -0,0 +1,66 @@
+//! Logger setup module
+//!
+//! This module handles:
+//! - Human-readable logs via `pretty_env_logger` (the default)
+//! - One JSON object per line for log aggregators (`LOG_FORMAT=json`), via
+//!   a `tracing-subscriber` JSON formatter
+//!
+//! Both honor `RUST_LOG`. `LOG_FORMAT` is read straight from the environment
+//! because the logger starts before the configuration is loaded.
+
+use tracing_subscriber::fmt::format::{Format, Json, JsonFields};
+use tracing_subscriber::fmt::{format::FmtSpan, SubscriberBuilder};
+use tracing_subscriber::EnvFilter;
+
+/// Install the global logger selected by `LOG_FORMAT` (`pretty` or `json`)
+pub fn init() {
+    match std::env::var("LOG_FORMAT").unwrap_or_default().trim().to_lowercase().as_str() {
+        // Also installs a `log` -> `tracing` bridge, so `log::` macros land here too
+        "json" => json_subscriber().init(),
+        "" | "pretty" => pretty_env_logger::init(),
+        other => {
+            pretty_env_logger::init();
+            log::warn!("Unknown LOG_FORMAT '{}' (expected pretty or json), using pretty", other);
+        }
+    }
+}
+
+/// The `LOG_FORMAT=json` subscriber, filtered by `RUST_LOG`
+///
+/// Each line carries `timestamp` (RFC 3339, UTC), `level`, `target`,
+/// `filename`, `line_number` and `fields.message`. Inside a span (the
+/// `tracing` feature) it also carries the span's fields under `span`, and
+/// every enclosing span under `spans`, so `request_id` and `chat_id` reach
+/// each line; a span's `elapsed_ms` is logged when it closes.
+pub fn json_subscriber() -> SubscriberBuilder<JsonFields, Format<Json>, EnvFilter> {
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_file(true)
+        .with_line_number(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::CapturedOutput;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[test]
+    fn json_lines_parse_as_json() {
+        let captured = CapturedOutput::default();
+        let guard = json_subscriber().with_writer(captured.clone()).set_default();
+        // Errors pass the default filter when RUST_LOG is unset
+        log::error!("Failed to reach {}", "OpenAI");
+        drop(guard);
+
+        let line: serde_json::Value = serde_json::from_str(&captured.lines()[0]).unwrap();
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["fields"]["message"], "Failed to reach OpenAI");
+        assert_eq!(line["target"], "pollinet_knowledge_bot::logging::tests");
+        assert!(line["timestamp"].is_string());
+    }
+}
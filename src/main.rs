@@ -1,23 +1,23 @@
-/// Pollinet Knowledge Bot
-/// 
-/// A Telegram bot that uses RAG (Retrieval-Augmented Generation) to answer
-/// questions about Pollinet using official documentation and knowledge base.
-/// 
-/// The bot:
-/// - Responds to mentions and keyword "Pollinet" in group chats
-/// - Uses PostgreSQL with pgvector for semantic search
-/// - Generates contextual answers using GPT-4o-mini
-/// - Maintains conversation history for better context
-/// - Never hallucinates - only answers from retrieved context
+//! Pollinet Knowledge Bot
+//! 
+//! A Telegram bot that uses RAG (Retrieval-Augmented Generation) to answer
+//! questions about Pollinet using official documentation and knowledge base.
+//! 
+//! The bot:
+//! - Responds to mentions and keyword "Pollinet" in group chats
+//! - Uses PostgreSQL with pgvector for semantic search
+//! - Generates contextual answers using GPT-4o-mini
+//! - Maintains conversation history for better context
+//! - Never hallucinates - only answers from retrieved context
 
 use anyhow::Result;
-use pollinet_knowledge_bot::{bot, config, rag};
+use pollinet_knowledge_bot::{bot, config, logging, rag};
 use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logger
-    pretty_env_logger::init();
+    // Initialize logger (LOG_FORMAT=json for one JSON object per line)
+    logging::init();
     
     // Set up panic handler to log panics
     std::panic::set_hook(Box::new(|panic_info| {
@@ -28,8 +28,14 @@ async fn main() -> Result<()> {
     log::info!("Starting Pollinet Knowledge Bot...");
     log::info!("Environment: PORT={:?}", std::env::var("PORT").ok());
 
-    // Load configuration from environment
-    let cfg = match config::Config::from_env() {
+    // Load configuration from CONFIG_FILE (if set) and the environment,
+    // including a .env file
+    dotenv::dotenv().ok();
+    let cfg = match std::env::var("CONFIG_FILE") {
+        Ok(path) if !path.is_empty() => config::Config::from_file(path),
+        _ => config::Config::from_env(),
+    };
+    let cfg = match cfg {
         Ok(cfg) => cfg,
         Err(e) => {
             log::error!("Failed to load configuration: {}", e);
@@ -61,6 +67,14 @@ async fn main() -> Result<()> {
         return Err(e);
     }
 
+    // Seed the knowledge base so fresh deployments aren't empty
+    if let Some(path) = &cfg.seed_documents_path {
+        if let Err(e) = rag_system.seed_documents(std::path::Path::new(path)).await {
+            // The bot can still answer from whatever is already stored
+            log::error!("Failed to seed documents from {}: {:#}", path, e);
+        }
+    }
+
     log::info!("✅ All systems initialized, starting bot...");
     
     // Run bot (this should block forever for webhook mode)
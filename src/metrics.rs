@@ -0,0 +1,117 @@
+//! Prometheus metrics module
+//!
+//! This module handles:
+//! - Process-wide counters for answered queries, fallbacks and OpenAI errors
+//! - A histogram of query latency
+//! - Rendering everything in the Prometheus text exposition format for `/metrics`
+
+use prometheus::{Histogram, HistogramOpts, IntCounter, Registry, TextEncoder};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the query latency histogram buckets
+const LATENCY_BUCKETS: [f64; 8] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// Metrics shared by the whole process
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The collectors behind `/metrics`, in their own registry
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    queries: IntCounter,
+    fallbacks: IntCounter,
+    openai_errors: IntCounter,
+    query_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let queries = IntCounter::new("pollinet_queries_total", "Questions answered")
+            .expect("valid metric");
+        let fallbacks = IntCounter::new(
+            "pollinet_fallback_answers_total",
+            "Questions answered by the full-knowledge-base fallback",
+        )
+        .expect("valid metric");
+        let openai_errors = IntCounter::new(
+            "pollinet_openai_errors_total",
+            "OpenAI requests that failed after retries",
+        )
+        .expect("valid metric");
+        let query_duration = Histogram::with_opts(
+            HistogramOpts::new("pollinet_query_duration_seconds", "Time taken to answer a question")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+        )
+        .expect("valid metric");
+
+        // Names are fixed and distinct, so registering can't collide
+        let registry = Registry::new();
+        registry.register(Box::new(queries.clone())).expect("unique metric");
+        registry.register(Box::new(fallbacks.clone())).expect("unique metric");
+        registry.register(Box::new(openai_errors.clone())).expect("unique metric");
+        registry.register(Box::new(query_duration.clone())).expect("unique metric");
+
+        Self {
+            registry,
+            queries,
+            fallbacks,
+            openai_errors,
+            query_duration,
+        }
+    }
+
+    /// Count an answered query and how long answering it took
+    pub fn record_query(&self, latency: Duration, used_fallback: bool) {
+        self.queries.inc();
+        if used_fallback {
+            self.fallbacks.inc();
+        }
+        self.query_duration.observe(latency.as_secs_f64());
+    }
+
+    /// Count an OpenAI request that failed after all retries
+    pub fn record_openai_error(&self) {
+        self.openai_errors.inc();
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|e| {
+                log::error!("Failed to encode metrics: {}", e);
+                String::new()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of an unlabelled sample in rendered metrics
+    fn sample(rendered: &str, name: &str) -> f64 {
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("{} missing from:\n{}", name, rendered))
+    }
+
+    #[test]
+    fn rendered_metrics_count_queries_and_latency() {
+        let metrics = Metrics::new();
+        metrics.record_query(Duration::from_millis(300), false);
+        metrics.record_query(Duration::from_secs(3), true);
+        metrics.record_openai_error();
+
+        let rendered = metrics.render();
+        assert_eq!(sample(&rendered, "pollinet_queries_total"), 2.0);
+        assert_eq!(sample(&rendered, "pollinet_fallback_answers_total"), 1.0);
+        assert_eq!(sample(&rendered, "pollinet_openai_errors_total"), 1.0);
+        assert_eq!(sample(&rendered, "pollinet_query_duration_seconds_count"), 2.0);
+        assert!(rendered.contains("pollinet_query_duration_seconds_bucket{le=\"0.5\"} 1"), "{}", rendered);
+        assert!(rendered.contains("# TYPE pollinet_query_duration_seconds histogram"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Query log module
+//!
+//! This module handles:
+//! - Recording the token cost of every answered question in the `query_logs` table
+//! - Aggregating those costs by day and by chat for usage reports
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+
+use crate::rag::TokenUsage;
+
+/// How far back a usage report looks when no start date is given
+const DEFAULT_REPORT_DAYS: i32 = 30;
+
+/// Token totals for one day or one chat
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageTotals {
+    pub queries: i64,
+    /// Queries answered by the full-knowledge-base fallback
+    pub fallback_queries: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+/// Token usage for one day (`YYYY-MM-DD`)
+#[derive(Debug, Clone, Serialize)]
+pub struct DailyUsage {
+    pub day: String,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Token usage for one chat (`None` for HTTP API queries)
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatUsage {
+    pub chat_id: Option<i64>,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Aggregated query costs since a start date
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    /// First day included in the report (`YYYY-MM-DD`)
+    pub since: String,
+    /// Oldest day first
+    pub by_day: Vec<DailyUsage>,
+    /// Most expensive chat first
+    pub by_chat: Vec<ChatUsage>,
+}
+
+/// Record what answering one question cost
+pub async fn log_query(
+    pool: &PgPool,
+    chat_id: Option<i64>,
+    query: &str,
+    usage: &TokenUsage,
+    model: &str,
+    used_fallback: bool,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO query_logs (chat_id, query, prompt_tokens, completion_tokens, model, used_fallback)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+    )
+    .bind(chat_id)
+    .bind(query)
+    .bind(usage.prompt_tokens as i64)
+    .bind(usage.completion_tokens as i64)
+    .bind(model)
+    .bind(used_fallback)
+    .execute(pool)
+    .await
+    .context("Failed to store query log")?;
+
+    Ok(())
+}
+
+/// Aggregate logged queries from `since` (`YYYY-MM-DD`, default 30 days ago)
+pub async fn usage_report(pool: &PgPool, since: Option<&str>) -> Result<UsageReport> {
+    let since: String = sqlx::query_scalar("SELECT COALESCE($1::date, CURRENT_DATE - $2)::text")
+        .bind(since)
+        .bind(DEFAULT_REPORT_DAYS)
+        .fetch_one(pool)
+        .await
+        .context("Invalid report start date")?;
+
+    let by_day = sqlx::query(
+        r#"
+        SELECT to_char(created_at, 'YYYY-MM-DD') AS day,
+               COUNT(*) AS queries,
+               COUNT(*) FILTER (WHERE used_fallback) AS fallback_queries,
+               COALESCE(SUM(prompt_tokens), 0)::BIGINT AS prompt_tokens,
+               COALESCE(SUM(completion_tokens), 0)::BIGINT AS completion_tokens
+        FROM query_logs
+        WHERE created_at >= $1::date
+        GROUP BY day
+        ORDER BY day
+        "#,
+    )
+    .bind(&since)
+    .fetch_all(pool)
+    .await
+    .context("Failed to aggregate query logs by day")?
+    .into_iter()
+    .map(|row| {
+        Ok(DailyUsage {
+            day: row.try_get("day")?,
+            totals: usage_totals(&row)?,
+        })
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+    let by_chat = sqlx::query(
+        r#"
+        SELECT chat_id,
+               COUNT(*) AS queries,
+               COUNT(*) FILTER (WHERE used_fallback) AS fallback_queries,
+               COALESCE(SUM(prompt_tokens), 0)::BIGINT AS prompt_tokens,
+               COALESCE(SUM(completion_tokens), 0)::BIGINT AS completion_tokens
+        FROM query_logs
+        WHERE created_at >= $1::date
+        GROUP BY chat_id
+        ORDER BY SUM(prompt_tokens + completion_tokens) DESC
+        "#,
+    )
+    .bind(&since)
+    .fetch_all(pool)
+    .await
+    .context("Failed to aggregate query logs by chat")?
+    .into_iter()
+    .map(|row| {
+        Ok(ChatUsage {
+            chat_id: row.try_get("chat_id")?,
+            totals: usage_totals(&row)?,
+        })
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+    Ok(UsageReport {
+        since,
+        by_day,
+        by_chat,
+    })
+}
+
+fn usage_totals(row: &sqlx::postgres::PgRow) -> Result<UsageTotals, sqlx::Error> {
+    Ok(UsageTotals {
+        queries: row.try_get("queries")?,
+        fallback_queries: row.try_get("fallback_queries")?,
+        prompt_tokens: row.try_get("prompt_tokens")?,
+        completion_tokens: row.try_get("completion_tokens")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn usage_report_sums_logged_queries_per_chat() {
+        let pool = test_pool().await;
+        let chat_id = -(std::process::id() as i64) * 10 - 5;
+        let usage = |prompt_tokens, completion_tokens| TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        };
+        log_query(&pool, Some(chat_id), "What is Pollinet?", &usage(100, 20), "gpt-4o-mini", false)
+            .await
+            .unwrap();
+        log_query(&pool, Some(chat_id), "And relays?", &usage(50, 10), "gpt-4o-mini", true)
+            .await
+            .unwrap();
+
+        let report = usage_report(&pool, None).await.unwrap();
+        let chat = report
+            .by_chat
+            .iter()
+            .find(|chat| chat.chat_id == Some(chat_id))
+            .expect("chat in report");
+        assert_eq!(
+            (chat.totals.queries, chat.totals.fallback_queries, chat.totals.prompt_tokens, chat.totals.completion_tokens),
+            (2, 1, 150, 30)
+        );
+        assert!(!report.by_day.is_empty());
+
+        // An invalid start date is an error rather than an empty report
+        assert!(usage_report(&pool, Some("2024-13-01")).await.is_err());
+
+        sqlx::query("DELETE FROM query_logs WHERE chat_id = $1")
+            .bind(chat_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -1,19 +1,34 @@
-/// RAG (Retrieval-Augmented Generation) module with PostgreSQL + pgvector
-/// 
-/// This module handles:
-/// - Document chunking and embedding
-/// - Vector storage in PostgreSQL with pgvector extension
-/// - Semantic retrieval of relevant chunks
-/// - Prompt building with context and conversation history
-/// - GPT-4o-mini integration for response generation
+//! RAG (Retrieval-Augmented Generation) module with PostgreSQL + pgvector
+//! 
+//! This module handles:
+//! - Document chunking and embedding
+//! - Vector storage in PostgreSQL with pgvector extension
+//! - Semantic retrieval of relevant chunks
+//! - Prompt building with context and conversation history
+//! - GPT-4o-mini integration for response generation
 
 use anyhow::{Context, Result};
+use lru::LruCache;
 use pgvector::Vector;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{PgPool, Row};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Semaphore};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::config::Config;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{Config, DistanceMetric, RetrievalMode, VectorIndexType};
+use crate::embeddings::{provider_from_config, EmbeddingProvider, EMBEDDING_BATCH_SIZE};
+use crate::extract::{self, PdfSource};
+use crate::metrics::METRICS;
+use crate::telemetry::{self, SpanTimer};
 
 /// Represents a chunk of a document
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +38,298 @@ pub struct DocumentChunk {
     pub metadata: HashMap<String, String>,
 }
 
+/// How documents are split into chunks before embedding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// Fixed-size character windows with overlap
+    #[default]
+    FixedSize,
+    /// Whole sentences packed up to the chunk size, never cut mid-sentence
+    Sentence,
+}
+
+/// A document to ingest, as read from a seed file (one JSON object per line)
+#[derive(Debug, Clone, Deserialize)]
+pub struct SeedDocument {
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// One line of a knowledge base export (see [`RAGSystem::export_json`])
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord {
+    /// A row of the embeddings table, with its vector as stored
+    Chunk {
+        id: String,
+        content: String,
+        embedding: Option<Vec<f32>>,
+        metadata: Option<serde_json::Value>,
+        content_hash: Option<String>,
+        parent_id: Option<String>,
+        created_at: Option<String>,
+        /// Set for soft-deleted chunks (missing in older exports)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deleted_at: Option<String>,
+        /// Set outside the default namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// A parent chunk (parent-child chunking); these carry no embedding
+    Parent {
+        id: String,
+        document: String,
+        content: String,
+        created_at: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+}
+
+/// Rows restored by [`RAGSystem::import_json`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportStats {
+    pub chunks: usize,
+    pub parents: usize,
+}
+
+/// A chunk returned by retrieval, with its metadata and similarity
+#[derive(Debug, Clone, Serialize)]
+pub struct RetrievedChunk {
+    pub content: String,
+    /// Empty when the row's metadata is NULL or not a JSON object
+    pub metadata: HashMap<String, String>,
+    /// Cosine similarity to the query (`ts_rank` in keyword-only mode),
+    /// blended with the recency boost when `RECENCY_WEIGHT` is set
+    pub similarity: f32,
+    /// Parent chunk this chunk was cut from (parent-child chunking)
+    #[serde(skip)]
+    pub parent_id: Option<String>,
+}
+
+/// A chunk returned by [`RAGSystem::query_by_metadata`], newest first
+#[derive(Debug, Clone, Serialize)]
+pub struct DatedChunk {
+    pub content: String,
+    pub metadata: HashMap<String, String>,
+    /// RFC 3339 date from the `created_at` metadata, or when the chunk was stored
+    pub created_at: Option<String>,
+}
+
+/// Outcome of [`RAGSystem::query_detailed`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryResult {
+    pub answer: String,
+    /// Documents the answer was grounded in (empty for fallback answers)
+    pub sources: Vec<String>,
+    /// Whether the answer came from the full-knowledge-base fallback
+    pub used_fallback: bool,
+    /// Similarity scores of the retrieved chunks (empty when none were retrieved)
+    pub scores: Vec<f32>,
+    /// Tokens spent by every GPT call made for this answer
+    pub usage: TokenUsage,
+    /// Chunks the answer was generated from (empty for fallback answers)
+    #[serde(skip)]
+    pub chunks: Vec<RetrievedChunk>,
+}
+
+impl QueryResult {
+    /// Result for an answer produced by the fallback path
+    pub fn fallback(answer: String, scores: Vec<f32>, usage: TokenUsage) -> Self {
+        Self {
+            answer,
+            sources: Vec::new(),
+            used_fallback: true,
+            scores,
+            usage,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+/// A generated answer and the tokens GPT spent on it
+#[derive(Debug, Clone, Default)]
+pub struct GeneratedAnswer {
+    pub answer: String,
+    /// GPT replied with [`REFUSAL_SENTINEL`]: the context doesn't cover the question
+    pub refused: bool,
+    pub usage: TokenUsage,
+}
+
+/// Outcome of [`RAGSystem::check_grounding`]
+#[derive(Debug, Clone, Copy)]
+pub struct GroundingVerdict {
+    /// Every claim in the answer is supported by the context
+    pub grounded: bool,
+    /// Tokens spent on the verification call
+    pub usage: TokenUsage,
+}
+
+/// Outcome of [`RAGSystem::suggest_follow_ups`]
+#[derive(Debug, Clone, Default)]
+pub struct FollowUps {
+    /// At most [`MAX_FOLLOW_UPS`] short questions
+    pub questions: Vec<String>,
+    pub usage: TokenUsage,
+}
+
+/// Outcome of [`RAGSystem::answer_language`]
+#[derive(Debug, Clone, Default)]
+pub struct AnswerLanguage {
+    /// Language to answer in (`None` = the model's choice)
+    pub language: Option<String>,
+    /// Tokens spent detecting it
+    pub usage: TokenUsage,
+}
+
+/// Answer pieces from [`RAGSystem::generate_response_streaming`]
+pub struct ResponseStream {
+    pieces: mpsc::Receiver<Result<String>>,
+    usage: Arc<Mutex<TokenUsage>>,
+}
+
+impl ResponseStream {
+    /// Next piece of the answer; `None` once the stream has ended
+    pub async fn recv(&mut self) -> Option<Result<String>> {
+        self.pieces.recv().await
+    }
+
+    /// Tokens spent on the answer (reported once the stream has ended)
+    pub fn usage(&self) -> TokenUsage {
+        *self.usage.lock().unwrap()
+    }
+}
+
+/// What [`RAGSystem::add_document`] did with each chunk of a document
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct IngestStats {
+    /// Chunks that were new
+    pub inserted: usize,
+    /// Existing chunks whose text changed and were re-embedded
+    pub updated: usize,
+    /// Existing chunks with identical text (no embedding call)
+    pub skipped: usize,
+    /// Chunks not stored because another document already has a
+    /// near-identical one (`DEDUP_SIMILARITY`)
+    pub deduplicated: usize,
+}
+
+impl IngestStats {
+    /// Number of chunks the document now has (deduplicated ones excluded)
+    pub fn total(&self) -> usize {
+        self.inserted + self.updated + self.skipped
+    }
+}
+
+/// Error returned by [`RAGSystem::add_document`] when a document has too
+/// little text to be worth storing (`MIN_DOCUMENT_CHARS`, `MIN_CHUNK_CHARS`)
+#[derive(Debug, Clone)]
+pub struct DocumentTooShort {
+    pub document: String,
+    /// Characters of content after trimming whitespace
+    pub chars: usize,
+    /// Minimum the content (or, with `no_chunks`, a chunk) had to reach
+    pub min_chars: usize,
+    /// The document passed `MIN_DOCUMENT_CHARS`, but every chunk was
+    /// shorter than `MIN_CHUNK_CHARS`
+    pub no_chunks: bool,
+}
+
+impl std::fmt::Display for DocumentTooShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.no_chunks {
+            return write!(
+                f,
+                "document '{}' has no chunk reaching MIN_CHUNK_CHARS ({} characters)",
+                self.document, self.min_chars
+            );
+        }
+        write!(
+            f,
+            "document '{}' is too short ({} characters, minimum {})",
+            self.document, self.chars, self.min_chars
+        )
+    }
+}
+
+impl std::error::Error for DocumentTooShort {}
+
+/// Per-document overview of what is stored in the knowledge base
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentSummary {
+    pub document_name: String,
+    pub chunk_count: i64,
+    /// `source` metadata value, if the document was given one
+    pub source: Option<String>,
+    /// Most recent chunk write, formatted as `YYYY-MM-DD HH:MM:SS`
+    pub last_updated: String,
+}
+
+/// Aggregate statistics about the knowledge base contents
+#[derive(Debug, Clone, Serialize)]
+pub struct KnowledgeStats {
+    pub total_chunks: i64,
+    pub total_documents: usize,
+    /// Chunk counts per `source` metadata value ("unknown" when missing)
+    pub chunks_by_source: HashMap<String, i64>,
+    /// Chunk counts per `category` metadata value ("unknown" when missing)
+    pub chunks_by_category: HashMap<String, i64>,
+    /// Least recently updated document
+    pub oldest_document: Option<DocumentSummary>,
+    /// Most recently updated document
+    pub newest_document: Option<DocumentSummary>,
+}
+
+/// OpenAI token counts, for a single answer or as running totals since startup
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Add the counts OpenAI reported for one request
+    pub(crate) fn record(&mut self, usage: Option<&OpenAIUsage>) {
+        let Some(usage) = usage else {
+            return;
+        };
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+    }
+
+    /// Sum of two usages
+    pub fn combined(self, other: TokenUsage) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            total_tokens: self.total_tokens + other.total_tokens,
+        }
+    }
+}
+
+/// Lock-free accumulator behind [`RAGSystem::token_usage`]
+#[derive(Debug, Default)]
+pub(crate) struct UsageCounters {
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    total_tokens: AtomicU64,
+}
+
+impl UsageCounters {
+    pub(crate) fn record(&self, usage: Option<&OpenAIUsage>) {
+        let Some(usage) = usage else {
+            return;
+        };
+        self.prompt_tokens.fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+        self.completion_tokens.fetch_add(usage.completion_tokens, Ordering::Relaxed);
+        self.total_tokens.fetch_add(usage.total_tokens, Ordering::Relaxed);
+    }
+}
+
 /// Represents a message in conversation history
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConversationMessage {
@@ -30,21 +337,17 @@ pub struct ConversationMessage {
     pub content: String,
 }
 
-/// OpenAI API request/response structures
-#[derive(Debug, Serialize)]
-struct OpenAIEmbeddingRequest {
-    input: String,
-    model: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct OpenAIEmbeddingResponse {
-    data: Vec<OpenAIEmbeddingData>,
-}
+// OpenAI API request/response structures
 
-#[derive(Debug, Deserialize)]
-struct OpenAIEmbeddingData {
-    embedding: Vec<f32>,
+/// Token counts reported by OpenAI for a single request
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct OpenAIUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    #[serde(default)]
+    total_tokens: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -53,11 +356,22 @@ struct OpenAIChatRequest {
     messages: Vec<ConversationMessage>,
     temperature: f32,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<OpenAIStreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAIStreamOptions {
+    /// Ask for a final chunk carrying the request's token usage
+    include_usage: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct OpenAIChatResponse {
     choices: Vec<OpenAIChatChoice>,
+    usage: Option<OpenAIUsage>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,18 +379,207 @@ struct OpenAIChatChoice {
     message: ConversationMessage,
 }
 
+/// One server-sent event of a streamed chat completion
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamChunk {
+    choices: Vec<OpenAIChatStreamChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamChoice {
+    delta: OpenAIChatStreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAIModerationRequest<'a> {
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModerationResponse {
+    results: Vec<OpenAIModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: HashMap<String, bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAITranscriptionResponse {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModelsResponse {
+    data: Vec<OpenAIModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModel {
+    id: String,
+}
+
+/// Default system prompt for context-grounded answers (`{context}` is
+/// replaced by the retrieved chunks); override with `SYSTEM_PROMPT[_PATH]`
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful knowledge base assistant for Pollinet. \
+    Your role is to answer questions ONLY using the provided context from Pollinet documents. \
+    \
+    IMPORTANT RULES:\n\
+    1. Answer questions using ONLY the information from the Context sections below.\n\
+    2. Never make assumptions or provide information not explicitly stated in the context.\n\
+    3. Be concise and accurate.\n\
+    4. Using blue jean writing style.\n\
+    5. Ask the user follow up questions after a response if needed.\n\
+    6. Give brief response whenby default else it requires more details then give longer responses.\n\
+    7. You can use information from previous conversation to provide better context, \
+    but only if it's based on the provided knowledge.\n\
+    8. Format your responses using HTML:\n\
+       - Use <b>bold</b> for emphasis and section headers\n\
+       - Use bullet points (• or emoji bullets like 🔗, ✅) for lists\n\
+       - Use <code>code</code> for technical terms\n\
+       - Use <i>italic</i> for subtle emphasis\n\
+       - Structure responses clearly with headers and spacing\n\
+    \n\
+    Context from Pollinet documents:\n\
+    {context}\n\
+    ---";
+
+/// Answers kept in memory when the answer cache is enabled
+const ANSWER_CACHE_SIZE: NonZeroUsize = match NonZeroUsize::new(512) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// Instructions for the post-generation grounding check
+const GROUNDING_CHECK_PROMPT: &str = "You verify answers against their source context. \
+    Reply SUPPORTED if every factual claim in the answer is stated in or directly follows from \
+    the context, otherwise reply UNSUPPORTED. Greetings, formatting and follow-up questions are \
+    not claims. Reply with the single word only.";
+
+/// Instructions for suggesting follow-up questions to an answer
+const FOLLOW_UP_PROMPT: &str = "Suggest 2 or 3 short follow-up questions the user might ask next \
+    that the context can answer. Write them in the same language as the answer, one per line, \
+    with no numbering or other text.";
+
+/// Most follow-up questions offered under one answer
+pub const MAX_FOLLOW_UPS: usize = 3;
+
+/// Longer suggestions are dropped; they make unwieldy buttons
+const MAX_FOLLOW_UP_CHARS: usize = 80;
+
+/// Instructions for the hypothetical answer embedded in HyDE retrieval
+const HYDE_PROMPT: &str = "Write a short passage (2-4 sentences) from Pollinet's documentation \
+    that answers the user's question. Pollinet is an SDK for offline Solana transactions over \
+    Bluetooth Low Energy mesh networks. If unsure, write a plausible answer anyway; it is only \
+    used to search the documentation.";
+
+/// HyDE hypothetical answers kept in memory
+const HYDE_CACHE_SIZE: NonZeroUsize = match NonZeroUsize::new(256) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// Instructions for turning a follow-up into a standalone retrieval query
+const QUERY_REWRITE_PROMPT: &str = "Rewrite the latest user message as a single self-contained \
+    question, resolving pronouns and references using the conversation. Keep the user's wording \
+    where possible and do not answer the question. If it is already self-contained, return it \
+    unchanged. Reply with the question only.";
+
+/// Rows read per page while exporting the knowledge base
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+/// Chunks fetched per query while filling the fallback token budget
+const FALLBACK_PAGE_SIZE: i64 = 50;
+
+/// Token allowance for the separator placed between fallback chunks
+const FALLBACK_SEPARATOR_TOKENS: usize = 4;
+
+/// Largest web page [`RAGSystem::add_document_from_url`] will download
+const MAX_WEB_PAGE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Speech-to-text model used for voice messages
+const WHISPER_MODEL: &str = "whisper-1";
+
+/// Most chunks a single query may ask for via [`RAGSystem::retrieve_relevant_chunks_with_k`]
+pub const MAX_TOP_K: usize = 50;
+
+/// Namespace of documents added without one (and of every pre-namespace row)
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Namespaces become part of chunk ids, so keep them short
+const MAX_NAMESPACE_LEN: usize = 64;
+
+/// Longer "language names" are GPT answering the question instead
+const MAX_LANGUAGE_NAME_LEN: usize = 40;
+
+/// Instructions for naming the language a question is written in
+const LANGUAGE_DETECTION_PROMPT: &str = "Identify the language the user's message is written in. \
+    Reply with the language's English name only (e.g. English, Spanish, French).";
+
+/// Default system prompt for the full-knowledge-base fallback (`{context}`
+/// is replaced by all documents); override with `FALLBACK_PROMPT[_PATH]`
+pub const DEFAULT_FALLBACK_PROMPT: &str = "You are a helpful assistant for Pollinet, a decentralized SDK enabling \
+    offline Solana transactions via Bluetooth Low Energy (BLE) mesh networks. \
+    \n\n\
+    COMPLETE POLLINET KNOWLEDGE BASE:\n\
+    {context}\n\
+    ---\n\n\
+    When answering questions:\n\
+    1. First try to answer using the knowledge base above\n\
+    2. If the question is about Pollinet, blockchain, Solana, Web3, DePIN, or related crypto topics, \
+       answer using the knowledge base or your understanding of these topics\n\
+    3. If the question is COMPLETELY UNRELATED (weather, cooking, sports, entertainment, general trivia, etc.), \
+       respond EXACTLY with: 'I'm sorry, but I only answer questions related to Pollinet, blockchain, \
+       Solana, and Web3 technologies. Please ask me something about Pollinet!'\n\
+    4. If you're unsure whether a question is related, err on the side of answering if there's \
+       any connection to blockchain/crypto/technology\n\
+    5. Keep responses concise and accurate\n\
+    6. Using blue jean writing style.\n\
+    7. Ask the user follow up questions after a response if needed.\n\
+    8. Give brief response whenby default else it requires more details then give longer responses.\n\
+    9. ALWAYS format responses using HTML:\n\
+       - Use <b>bold</b> for emphasis and section headers\n\
+       - Use bullet points with emoji bullets (🔗, •, ✅, etc.) for lists\n\
+       - Use <code>code</code> for technical terms and code snippets\n\
+       - Use <i>italic</i> for subtle emphasis\n\
+       - Structure responses with clear sections using <b>headers</b>";
+
 /// Main RAG system structure
 pub struct RAGSystem {
     config: Config,
     db_pool: PgPool,
     http_client: reqwest::Client,
+    /// Where chunk and query embeddings come from
+    embedder: Box<dyn EmbeddingProvider>,
+    /// Embeddings keyed by a hash of model + text (None when disabled)
+    embedding_cache: Option<Mutex<LruCache<String, Vec<f32>>>>,
+    /// HyDE hypothetical answers keyed by a hash of model + query
+    hyde_cache: Mutex<LruCache<String, String>>,
+    /// Answers keyed by a hash of query + recent history, with when they were
+    /// cached (None when `ANSWER_CACHE_TTL_SECS` is 0)
+    answer_cache: Option<Mutex<LruCache<String, (Instant, QueryResult)>>>,
+    /// Limits concurrent queries (None when `MAX_CONCURRENT_QUERIES` is 0)
+    query_slots: Option<Semaphore>,
+    /// Tokens consumed by chat and embedding requests
+    usage: Arc<UsageCounters>,
+    /// Fails OpenAI requests fast during outages (shared with the embedder)
+    circuit_breaker: Arc<CircuitBreaker>,
 }
 
 impl RAGSystem {
     /// Initialize the RAG system
     pub async fn new(config: Config) -> Result<Self> {
         // Check if using connection pooler (pgBouncer)
-        let use_pooler = config.database_url.contains(":6543") || config.database_url.contains("pgbouncer=true");
+        let use_pooler = uses_connection_pooler(&config.database_url);
         
         let mut pool_options = sqlx::postgres::PgPoolOptions::new()
             .max_connections(50);
@@ -99,37 +602,108 @@ impl RAGSystem {
             .await
             .context("Failed to connect to PostgreSQL")?;
 
+        Ok(Self::with_pool(config, db_pool))
+    }
+
+    /// Build the RAG system around an existing connection pool
+    pub(crate) fn with_pool(config: Config, db_pool: PgPool) -> Self {
         let http_client = reqwest::Client::new();
 
-        Ok(Self {
+        let embedding_cache = NonZeroUsize::new(config.embedding_cache_size)
+            .map(|capacity| Mutex::new(LruCache::new(capacity)));
+
+        let answer_cache = (config.answer_cache_ttl_secs > 0)
+            .then(|| Mutex::new(LruCache::new(ANSWER_CACHE_SIZE)));
+
+        let query_slots = (config.max_concurrent_queries > 0)
+            .then(|| Semaphore::new(config.max_concurrent_queries));
+
+        let usage = Arc::<UsageCounters>::default();
+        let circuit_breaker = Arc::new(CircuitBreaker::from_config(&config));
+        let embedder = provider_from_config(
+            &config,
+            http_client.clone(),
+            Arc::clone(&usage),
+            Arc::clone(&circuit_breaker),
+        );
+
+        Self {
             config,
             db_pool,
             http_client,
-        })
+            embedder,
+            embedding_cache,
+            hyde_cache: Mutex::new(LruCache::new(HYDE_CACHE_SIZE)),
+            answer_cache,
+            query_slots,
+            usage,
+            circuit_breaker,
+        }
+    }
+
+    /// Replace the embedding provider chosen from config
+    /// 
+    /// The provider's vectors must match `EMBEDDING_DIMENSIONS`.
+    pub fn with_embedding_provider(mut self, embedder: Box<dyn EmbeddingProvider>) -> Self {
+        self.embedder = embedder;
+        self
+    }
+
+    /// Configuration this RAG system was created with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Apply pending schema migrations from `migrations/`
+    /// 
+    /// Behind a connection pooler the migrator's session advisory lock can't
+    /// be relied on, so locking is disabled there; run a single instance
+    /// while deploying schema changes in that setup.
+    pub async fn run_migrations(&self) -> Result<()> {
+        let mut migrator = sqlx::migrate!("./migrations");
+        if uses_connection_pooler(&self.config.database_url) {
+            migrator.set_locking(false);
+        }
+
+        migrator
+            .run(&self.db_pool)
+            .await
+            .context("Failed to run database migrations")?;
+        Ok(())
     }
 
-    /// Initialize the database table if it doesn't exist
+    /// Run migrations and create the embeddings table if it doesn't exist
+    /// 
+    /// The embeddings table is created here rather than in a migration
+    /// because its name, vector dimension and index come from configuration.
     pub async fn initialize_collection(&self) -> Result<()> {
         log::info!("Initializing database table...");
 
-        // Enable pgvector extension
-        sqlx::query("CREATE EXTENSION IF NOT EXISTS vector")
-            .execute(&self.db_pool)
-            .await
-            .context("Failed to create vector extension")?;
+        // Shared tables and the pgvector extension
+        self.run_migrations().await?;
+
+        // An existing table must match the configured embedding dimension,
+        // otherwise every insert and query would fail with a vague pgvector error
+        if let Some(existing) = self.embedding_column_dimensions().await? {
+            check_embedding_dimensions(existing, self.config.embedding_dimensions)?;
+        }
+
+        // Likewise for the distance: CREATE INDEX IF NOT EXISTS would keep an
+        // index built for another metric, which the search could not use
+        check_index_metric(&self.vector_index_opclasses().await?, self.config.distance_metric)?;
 
-        // Create table for embeddings (1536 dimensions for text-embedding-ada-002)
+        // Create table for embeddings
         let create_table_query = format!(
             r#"
             CREATE TABLE IF NOT EXISTS {} (
                 id TEXT PRIMARY KEY,
                 content TEXT NOT NULL,
-                embedding vector(1536),
+                embedding vector({}),
                 metadata JSONB,
                 created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
             )
             "#,
-            self.config.embeddings_table
+            self.config.embeddings_table, self.config.embedding_dimensions
         );
 
         sqlx::query(&create_table_query)
@@ -137,478 +711,5270 @@ impl RAGSystem {
             .await
             .context("Failed to create embeddings table")?;
 
-        // Create index for vector similarity search
-        let create_index_query = format!(
+        self.create_vector_index(&self.db_pool).await?;
+        let table = &self.config.embeddings_table;
+
+        // Per-chunk hash so unchanged chunks aren't re-embedded on re-ingestion
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS content_hash TEXT",
+            table
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to add content hash column")?;
+
+        // Parent-child chunking: children point at the larger parent they
+        // were cut from, which is what gets sent to GPT
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS parent_id TEXT",
+            table
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to add parent id column")?;
+
+        // Soft deletes: deleted chunks stay restorable until purged
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP",
+            table
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to add deleted_at column")?;
+
+        // Namespaces let one deployment serve several knowledge bases
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL DEFAULT '{}'",
+            table, DEFAULT_NAMESPACE
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to add namespace column")?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {}_namespace_idx ON {} (namespace)",
+            table, table
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to create namespace index")?;
+
+        sqlx::query(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                id TEXT PRIMARY KEY,
+                document TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )
+            "#,
+            self.parents_table()
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to create parent chunks table")?;
+
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL DEFAULT '{}'",
+            self.parents_table(),
+            DEFAULT_NAMESPACE
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to add parent chunks namespace column")?;
+
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {}_document_idx ON {} (document)",
+            self.parents_table(),
+            self.parents_table()
+        ))
+        .execute(&self.db_pool)
+        .await
+        .context("Failed to create parent chunks index")?;
+
+        // Full-text search column and index for keyword/hybrid retrieval
+        let add_tsv_query = format!(
             r#"
-            CREATE INDEX IF NOT EXISTS {}_embedding_idx 
-            ON {} USING ivfflat (embedding vector_cosine_ops)
-            WITH (lists = 100)
+            ALTER TABLE {} ADD COLUMN IF NOT EXISTS content_tsv tsvector
+            GENERATED ALWAYS AS (to_tsvector('english', content)) STORED
             "#,
-            self.config.embeddings_table, self.config.embeddings_table
+            table
         );
+        sqlx::query(&add_tsv_query)
+            .execute(&self.db_pool)
+            .await
+            .context("Failed to add full-text search column")?;
 
-        sqlx::query(&create_index_query)
+        let create_tsv_index_query = format!(
+            "CREATE INDEX IF NOT EXISTS {}_content_tsv_idx ON {} USING gin (content_tsv)",
+            table, table
+        );
+        sqlx::query(&create_tsv_index_query)
             .execute(&self.db_pool)
             .await
-            .context("Failed to create vector index")?;
+            .context("Failed to create full-text search index")?;
 
         log::info!("Database table initialized successfully");
         Ok(())
     }
 
-    /// Generate embeddings for text using OpenAI API
-    async fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
-        let request = OpenAIEmbeddingRequest {
-            input: text.to_string(),
-            model: self.config.embedding_model.clone(),
-        };
+    /// The text embedded for a chunk: the chunk itself, prefixed with its
+    /// document name and `section`/`heading` metadata when
+    /// `CONTEXTUAL_CHUNK_HEADERS` is set (the stored content stays unprefixed)
+    fn embedding_text(&self, chunk: &str, document_name: &str, metadata: &HashMap<String, String>) -> String {
+        if !self.config.contextual_chunk_headers {
+            return chunk.to_string();
+        }
+        contextual_header(document_name, metadata) + chunk
+    }
 
-        let response = self
-            .http_client
-            .post("https://api.openai.com/v1/embeddings")
-            .header("Authorization", format!("Bearer {}", self.config.openai_api_key))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send embedding request")?;
+    /// Table holding the parent chunks of parent-child chunking
+    fn parents_table(&self) -> String {
+        format!("{}_parents", self.config.embeddings_table)
+    }
 
-        // Check HTTP status
-        let status = response.status();
-        if !status.is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unable to read error response".to_string());
-            return Err(anyhow::anyhow!(
-                "OpenAI API error (status {}): {}",
-                status,
-                error_text
-            ));
+    /// Create the configured vector similarity index if it doesn't exist
+    async fn create_vector_index<'e>(&self, executor: impl sqlx::PgExecutor<'e>) -> Result<()> {
+        let table = &self.config.embeddings_table;
+        let create_index_query = match self.config.index_type {
+            VectorIndexType::IvfFlat => format!(
+                r#"
+                CREATE INDEX IF NOT EXISTS {}_embedding_idx 
+                ON {} USING ivfflat (embedding {})
+                WITH (lists = 100)
+                "#,
+                table, table, index_opclass(self.config.distance_metric)
+            ),
+            VectorIndexType::Hnsw => format!(
+                r#"
+                CREATE INDEX IF NOT EXISTS {}_embedding_hnsw_idx
+                ON {} USING hnsw (embedding {})
+                WITH (m = {}, ef_construction = {})
+                "#,
+                table,
+                table,
+                index_opclass(self.config.distance_metric),
+                self.config.hnsw_m,
+                self.config.hnsw_ef_construction
+            ),
+        };
+
+        if let Err(e) = sqlx::query(&create_index_query).execute(executor).await {
+            if e.to_string().contains("access method \"hnsw\" does not exist") {
+                anyhow::bail!(
+                    "INDEX_TYPE=hnsw requires pgvector 0.5.0 or newer; upgrade the extension \
+                    (ALTER EXTENSION vector UPDATE) or use INDEX_TYPE=ivfflat"
+                );
+            }
+            return Err(anyhow::Error::new(e).context("Failed to create vector index"));
         }
+        log::info!(
+            "Using {:?} vector index ({:?} distance)",
+            self.config.index_type,
+            self.config.distance_metric
+        );
+        Ok(())
+    }
 
-        let response_text = response
-            .text()
-            .await
-            .context("Failed to read response body")?;
+    /// Operator classes of the pgvector indexes on the embeddings table
+    async fn vector_index_opclasses(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT opc.opcname::text FROM pg_index i
+            JOIN pg_opclass opc ON opc.oid = i.indclass[0]
+            WHERE i.indrelid = to_regclass($1) AND opc.opcname LIKE 'vector\_%'
+            "#,
+        )
+        .bind(&self.config.embeddings_table)
+        .fetch_all(&self.db_pool)
+        .await
+        .context("Failed to read vector index operator classes")
+    }
 
-        let response: OpenAIEmbeddingResponse = serde_json::from_str(&response_text)
-            .context(format!(
-                "Failed to parse embedding response. Response was: {}",
-                response_text
-            ))?;
+    /// Declared dimension of the `embedding` column, if the table exists
+    pub async fn embedding_column_dimensions(&self) -> Result<Option<i32>> {
+        // pgvector stores the dimension as the column's type modifier
+        let dimensions: Option<i32> = sqlx::query_scalar(
+            r#"
+            SELECT atttypmod FROM pg_attribute
+            WHERE attrelid = to_regclass($1) AND attname = 'embedding' AND NOT attisdropped
+            "#,
+        )
+        .bind(&self.config.embeddings_table)
+        .fetch_optional(&self.db_pool)
+        .await
+        .context("Failed to read embedding column type")?;
 
-        response
-            .data
-            .into_iter()
-            .next()
-            .map(|d| d.embedding)
-            .context("No embedding returned")
+        Ok(dimensions.filter(|d| *d > 0))
     }
 
-    /// Split text into chunks for embedding
-    /// Simple chunking by character count with overlap
-    fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
-        let mut chunks = Vec::new();
-        let text = text.trim();
-        
-        if text.len() <= chunk_size {
-            chunks.push(text.to_string());
-            return chunks;
+    /// Re-embed every stored chunk with `new_model`
+    /// 
+    /// All chunks are embedded first, outside any transaction, so a failed
+    /// API call leaves the table untouched. A short transaction then drops
+    /// the vector index and retypes the `embedding` column if the new
+    /// model's dimension differs, writes the fresh embeddings and rebuilds
+    /// the index. `EMBEDDING_MODEL` must be switched to `new_model` (and the
+    /// bot restarted) for queries to use the new embeddings.
+    /// 
+    /// # Returns
+    /// Number of chunks re-embedded
+    pub async fn reembed_all(&self, new_model: &str) -> Result<usize> {
+        let table = &self.config.embeddings_table;
+
+        let rows: Vec<(String, String, Option<serde_json::Value>)> =
+            sqlx::query_as(&format!("SELECT id, content, metadata FROM {} ORDER BY id", table))
+                .fetch_all(&self.db_pool)
+                .await
+                .context("Failed to load chunks for re-embedding")?;
+        let total = rows.len();
+        if total == 0 {
+            log::info!("No chunks to re-embed");
+            return Ok(0);
         }
 
-        let mut start = 0;
-        while start < text.len() {
-            let end = (start + chunk_size).min(text.len());
-            let chunk = &text[start..end];
-            chunks.push(chunk.to_string());
-            
-            if end == text.len() {
-                break;
+        log::info!("Re-embedding {} chunks with {}", total, new_model);
+        let current_dimensions = self.embedding_column_dimensions().await?;
+        let embedder = self.embedder.with_model(new_model);
+
+        // Embed everything before touching the table, so the slow API calls
+        // never hold a transaction (and its locks) open
+        let mut embeddings = Vec::with_capacity(total);
+        for batch in rows.chunks(EMBEDDING_BATCH_SIZE) {
+            let texts: Vec<String> = batch
+                .iter()
+                .map(|(_, content, metadata)| {
+                    let metadata = metadata_from_json(metadata.clone());
+                    let document = metadata.get("document").map(String::as_str).unwrap_or_default();
+                    self.embedding_text(content, document, &metadata)
+                })
+                .collect();
+            embeddings.extend(embedder.embed(&texts).await?);
+            log::info!("Re-embedded {}/{} chunks", embeddings.len(), total);
+        }
+
+        let mut tx = self.db_pool.begin().await?;
+        let new_dimensions = embeddings.first().map(|e| e.len()).unwrap_or_default();
+        if current_dimensions != Some(new_dimensions as i32) {
+            log::info!(
+                "Changing embedding column from {:?} to {} dimensions",
+                current_dimensions,
+                new_dimensions
+            );
+            for index in ["embedding_idx", "embedding_hnsw_idx"] {
+                sqlx::query(&format!("DROP INDEX IF EXISTS {}_{}", table, index))
+                    .execute(&mut *tx)
+                    .await
+                    .context("Failed to drop vector index")?;
             }
-            
-            start += chunk_size - overlap;
+            sqlx::query(&format!(
+                "ALTER TABLE {} ALTER COLUMN embedding TYPE vector({}) USING NULL",
+                table, new_dimensions
+            ))
+            .execute(&mut *tx)
+            .await
+            .context("Failed to change embedding column dimension")?;
         }
 
-        chunks
+        for ((id, _, _), embedding) in rows.iter().zip(embeddings) {
+            sqlx::query(&format!("UPDATE {} SET embedding = $1 WHERE id = $2", table))
+                .bind(Vector::from(embedding))
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to store re-embedded chunk")?;
+        }
+        self.create_vector_index(&mut *tx).await?;
+
+        tx.commit()
+            .await
+            .context("Failed to commit re-embedded chunks")?;
+
+        log::warn!(
+            "Re-embedded {} chunks with {}; set EMBEDDING_MODEL={} (and EMBEDDING_DIMENSIONS) and restart",
+            total,
+            new_model,
+            new_model
+        );
+        Ok(total)
     }
 
-    /// Add a document to the knowledge base
+    /// Write every chunk (with its embedding) and parent chunk to `writer`
+    /// as JSON lines, for backup or migration without re-embedding
     /// 
-    /// # Arguments
-    /// * `document_name` - Name/identifier for the document
-    /// * `content` - Full text content of the document
-    /// * `metadata` - Additional metadata (e.g., source, date, etc.)
-    pub async fn add_document(
-        &self,
-        document_name: &str,
-        content: &str,
-        metadata: HashMap<String, String>,
-    ) -> Result<usize> {
-        log::info!("Adding document: {}", document_name);
+    /// # Returns
+    /// Number of records written
+    pub async fn export_json<W: std::io::Write>(&self, mut writer: W) -> Result<usize> {
+        let mut written = 0;
 
-        // Chunk the document (1000 chars with 200 char overlap)
-        let chunks = Self::chunk_text(content, 1000, 200);
-        log::info!("Split into {} chunks", chunks.len());
-
-        for (idx, chunk_text) in chunks.iter().enumerate() {
-            // Generate embedding for this chunk
-            let embedding = self.generate_embedding(chunk_text).await?;
-
-            // Create point ID
-            let point_id = format!("{}_{}", document_name, idx);
-
-            // Prepare metadata
-            let mut chunk_metadata = metadata.clone();
-            chunk_metadata.insert("document".to_string(), document_name.to_string());
-            chunk_metadata.insert("chunk_index".to_string(), idx.to_string());
-
-            // Convert metadata to JSON
-            let metadata_json = serde_json::to_value(&chunk_metadata)
-                .context("Failed to serialize metadata")?;
-
-            // Insert into database
-            let insert_query = format!(
+        // Page by id so huge tables never have to fit in memory
+        let mut last_id = String::new();
+        loop {
+            let rows = sqlx::query(&format!(
                 r#"
-                INSERT INTO {} (id, content, embedding, metadata)
-                VALUES ($1, $2, $3, $4)
-                ON CONFLICT (id) DO UPDATE 
-                SET content = $2, embedding = $3, metadata = $4
+                SELECT id, content, embedding, metadata, content_hash, parent_id,
+                       created_at::text AS created_at, deleted_at::text AS deleted_at,
+                       NULLIF(namespace, '{}') AS namespace
+                FROM {}
+                WHERE id > $1
+                ORDER BY id
+                LIMIT $2
                 "#,
-                self.config.embeddings_table
-            );
+                DEFAULT_NAMESPACE, self.config.embeddings_table
+            ))
+            .bind(&last_id)
+            .bind(EXPORT_PAGE_SIZE)
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to read chunks for export")?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            last_id = last.get("id");
 
-            sqlx::query(&insert_query)
-                .bind(&point_id)
-                .bind(chunk_text)
-                .bind(Vector::from(embedding))
-                .bind(metadata_json)
-                .execute(&self.db_pool)
-                .await
-                .context("Failed to insert embedding")?;
+            for row in rows {
+                let record = ExportRecord::Chunk {
+                    id: row.get("id"),
+                    content: row.get("content"),
+                    embedding: row
+                        .get::<Option<Vector>, _>("embedding")
+                        .map(|vector| vector.to_vec()),
+                    metadata: row.get("metadata"),
+                    content_hash: row.get("content_hash"),
+                    parent_id: row.get("parent_id"),
+                    created_at: row.get("created_at"),
+                    deleted_at: row.get("deleted_at"),
+                    namespace: row.get("namespace"),
+                };
+                write_export_record(&mut writer, &record)?;
+                written += 1;
+            }
+        }
+
+        let parents = sqlx::query(&format!(
+            r#"
+            SELECT id, document, content, created_at::text AS created_at,
+                   NULLIF(namespace, '{}') AS namespace
+            FROM {}
+            ORDER BY id
+            "#,
+            DEFAULT_NAMESPACE,
+            self.parents_table()
+        ))
+        .fetch_all(&self.db_pool)
+        .await
+        .context("Failed to read parent chunks for export")?;
+        for row in parents {
+            let record = ExportRecord::Parent {
+                id: row.get("id"),
+                document: row.get("document"),
+                content: row.get("content"),
+                created_at: row.get("created_at"),
+                namespace: row.get("namespace"),
+            };
+            write_export_record(&mut writer, &record)?;
+            written += 1;
         }
 
-        log::info!("Document added successfully with {} chunks", chunks.len());
-        Ok(chunks.len())
+        writer.flush().context("Failed to flush knowledge base export")?;
+        log::info!("Exported {} knowledge base records", written);
+        Ok(written)
     }
 
-    /// Retrieve relevant document chunks for a query
-    /// 
-    /// # Arguments
-    /// * `query` - User's question or query
+    /// Restore records written by [`RAGSystem::export_json`], keeping their
+    /// embeddings as they are (nothing is re-embedded)
     /// 
-    /// # Returns
-    /// Vector of relevant text chunks
-    pub async fn retrieve_relevant_chunks(&self, query: &str) -> Result<Vec<String>> {
-        log::info!("Retrieving relevant chunks for query: {}", query);
+    /// Rows with an existing id are overwritten. Everything is imported in
+    /// one transaction, so a bad line leaves the knowledge base untouched.
+    pub async fn import_json<R: std::io::BufRead>(&self, reader: R) -> Result<ImportStats> {
+        let dimensions = self.embedding_column_dimensions().await?;
+        let mut tx = self.db_pool.begin().await?;
+        let mut stats = ImportStats::default();
 
-        // Generate embedding for the query
-        let query_embedding = self.generate_embedding(query).await?;
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line.context("Failed to read knowledge base export")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ExportRecord = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid export record on line {}", line_number + 1))?;
 
-        // Search for similar vectors using cosine similarity
-        let search_query = format!(
-            r#"
-            SELECT content
-            FROM {}
-            ORDER BY embedding <=> $1
-            LIMIT $2
-            "#,
-            self.config.embeddings_table
-        );
+            match record {
+                ExportRecord::Chunk {
+                    id,
+                    content,
+                    embedding,
+                    metadata,
+                    content_hash,
+                    parent_id,
+                    created_at,
+                    deleted_at,
+                    namespace,
+                } => {
+                    if let (Some(embedding), Some(dimensions)) = (&embedding, dimensions) {
+                        if embedding.len() as i32 != dimensions {
+                            anyhow::bail!(
+                                "Chunk '{}' has a {}-dimensional embedding but the table stores {} dimensions",
+                                id,
+                                embedding.len(),
+                                dimensions
+                            );
+                        }
+                    }
+                    sqlx::query(&format!(
+                        r#"
+                        INSERT INTO {} (id, content, embedding, metadata, content_hash, parent_id, created_at,
+                                        deleted_at, namespace)
+                        VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7::timestamp, CURRENT_TIMESTAMP), $8::timestamp,
+                                COALESCE($9, '{}'))
+                        ON CONFLICT (id) DO UPDATE
+                        SET content = $2, embedding = $3, metadata = $4, content_hash = $5,
+                            parent_id = $6, created_at = COALESCE($7::timestamp, CURRENT_TIMESTAMP),
+                            deleted_at = $8::timestamp, namespace = COALESCE($9, '{}')
+                        "#,
+                        self.config.embeddings_table,
+                        DEFAULT_NAMESPACE,
+                        DEFAULT_NAMESPACE
+                    ))
+                    .bind(&id)
+                    .bind(content)
+                    .bind(embedding.map(Vector::from))
+                    .bind(metadata)
+                    .bind(content_hash)
+                    .bind(parent_id)
+                    .bind(created_at)
+                    .bind(deleted_at)
+                    .bind(namespace)
+                    .execute(&mut *tx)
+                    .await
+                    .with_context(|| format!("Failed to import chunk '{}'", id))?;
+                    stats.chunks += 1;
+                }
+                ExportRecord::Parent {
+                    id,
+                    document,
+                    content,
+                    created_at,
+                    namespace,
+                } => {
+                    sqlx::query(&format!(
+                        r#"
+                        INSERT INTO {} (id, document, content, created_at, namespace)
+                        VALUES ($1, $2, $3, COALESCE($4::timestamp, CURRENT_TIMESTAMP), COALESCE($5, '{}'))
+                        ON CONFLICT (id) DO UPDATE
+                        SET document = $2, content = $3, created_at = COALESCE($4::timestamp, CURRENT_TIMESTAMP),
+                            namespace = COALESCE($5, '{}')
+                        "#,
+                        self.parents_table(),
+                        DEFAULT_NAMESPACE,
+                        DEFAULT_NAMESPACE
+                    ))
+                    .bind(&id)
+                    .bind(document)
+                    .bind(content)
+                    .bind(created_at)
+                    .bind(namespace)
+                    .execute(&mut *tx)
+                    .await
+                    .with_context(|| format!("Failed to import parent chunk '{}'", id))?;
+                    stats.parents += 1;
+                }
+            }
+        }
 
-        let rows = sqlx::query(&search_query)
-            .bind(Vector::from(query_embedding))
-            .bind(self.config.top_k_chunks as i64)
-            .fetch_all(&self.db_pool)
+        tx.commit()
             .await
-            .context("Failed to search for similar vectors")?;
+            .context("Failed to commit knowledge base import")?;
+        self.clear_answer_cache();
 
-        let chunks: Vec<String> = rows
-            .into_iter()
-            .map(|row| row.get::<String, _>("content"))
-            .collect();
+        log::info!(
+            "Imported {} chunks and {} parent chunks",
+            stats.chunks,
+            stats.parents
+        );
+        Ok(stats)
+    }
 
-        log::info!("Retrieved {} relevant chunks", chunks.len());
-        Ok(chunks)
+    /// Count the chunks currently stored in `namespace` (default:
+    /// [`DEFAULT_NAMESPACE`])
+    pub async fn chunk_count(&self, namespace: Option<&str>) -> Result<i64> {
+        let query = format!(
+            "SELECT COUNT(*) FROM {} WHERE {}",
+            self.config.embeddings_table,
+            live_chunks_in("$1")
+        );
+
+        let count: i64 = sqlx::query_scalar(&query)
+            .bind(checked_namespace(namespace)?)
+            .fetch_one(&self.db_pool)
+            .await
+            .context("Failed to count stored chunks")?;
+
+        Ok(count)
     }
 
-    /// Generate a response using GPT-4o-mini with retrieved context
-    /// 
-    /// # Arguments
-    /// * `query` - User's question
-    /// * `context_chunks` - Retrieved relevant document chunks
-    /// * `conversation_history` - Previous messages in the conversation
+    /// Semaphore bounding concurrent queries, if `MAX_CONCURRENT_QUERIES` is set
     /// 
-    /// # Returns
-    /// Generated response from GPT-4o-mini
-    pub async fn generate_response(
+    /// Callers hold a permit while answering so bursts queue instead of
+    /// stampeding the OpenAI API.
+    pub fn query_slots(&self) -> Option<&Semaphore> {
+        self.query_slots.as_ref()
+    }
+
+    /// Database connection pool shared with other components
+    pub fn db_pool(&self) -> &PgPool {
+        &self.db_pool
+    }
+
+    /// Whether OpenAI requests are currently refused by the circuit breaker
+    pub fn openai_circuit_open(&self) -> bool {
+        self.circuit_breaker.is_open()
+    }
+
+    /// OpenAI tokens consumed by this process so far
+    pub fn token_usage(&self) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: self.usage.prompt_tokens.load(Ordering::Relaxed),
+            completion_tokens: self.usage.completion_tokens.load(Ordering::Relaxed),
+            total_tokens: self.usage.total_tokens.load(Ordering::Relaxed),
+        }
+    }
+
+    /// List the model IDs available to the configured OpenAI API key
+    ///
+    /// Cheap authenticated call used to confirm OpenAI is reachable.
+    pub async fn list_openai_models(&self) -> Result<Vec<String>> {
+        list_models(&self.http_client, &self.config.openai_base_url, self.openai_auth()).await
+    }
+
+    /// POST a JSON body to `path` under the configured OpenAI base URL,
+    /// retrying transient failures (see [`post_with_retry`])
+    async fn post_with_retry<T: Serialize + ?Sized>(
         &self,
-        query: &str,
-        context_chunks: &[String],
-        conversation_history: &[ConversationMessage],
-    ) -> Result<String> {
-        log::info!("Generating response using GPT-4o-mini");
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response> {
+        post_with_retry(
+            &self.http_client,
+            &api_url(&self.config.openai_base_url, path),
+            self.openai_auth(),
+            self.config.openai_max_retries,
+            Some(&self.circuit_breaker),
+            body,
+        )
+        .await
+    }
 
-        // Build context from retrieved chunks
-        let context = if context_chunks.is_empty() {
-            "No relevant information found in the knowledge base.".to_string()
-        } else {
-            context_chunks
-                .iter()
-                .enumerate()
-                .map(|(i, chunk)| format!("[Context {}]\n{}", i + 1, chunk))
-                .collect::<Vec<_>>()
-                .join("\n\n")
-        };
+    /// How the OpenAI API key is sent (`api-key` header for Azure)
+    fn openai_auth(&self) -> ApiAuth<'_> {
+        self.config.openai_auth()
+    }
 
-        // Build system message with instructions
-        let system_message = ConversationMessage {
-            role: "system".to_string(),
-            content: format!(
-                "You are a helpful knowledge base assistant for Pollinet. \
-                Your role is to answer questions ONLY using the provided context from Pollinet documents. \
-                \
-                IMPORTANT RULES:\n\
-                1. Answer questions using ONLY the information from the Context sections below.\n\
-                2. If the answer is not in the provided context, respond EXACTLY with: \
-                \"I don't have that information yet.\"\n\
-                3. Never make assumptions or provide information not explicitly stated in the context.\n\
-                4. Be concise and accurate.\n\
-                5. Remove all non-english symbols.\n\
-                6. Using blue jean writing style.\n\
-                7. Ask the user follow up questions after a response if needed.\n\
-                8. Give brief response whenby default else it requires more details then give longer responses.\n\
-                9. You can use information from previous conversation to provide better context, \
-                but only if it's based on the provided knowledge.\n\
-                10. Format your responses using HTML:\n\
-                   - Use <b>bold</b> for emphasis and section headers\n\
-                   - Use bullet points (• or emoji bullets like 🔗, ✅) for lists\n\
-                   - Use <code>code</code> for technical terms\n\
-                   - Use <i>italic</i> for subtle emphasis\n\
-                   - Structure responses clearly with headers and spacing\n\
-                \n\
-                Context from Pollinet documents:\n\
-                {}\n\
-                ---",
-                context
-            ),
+    /// Transcribe an audio file (e.g. a Telegram voice note) with Whisper
+    ///
+    /// `mime` is the audio's MIME type, such as `audio/ogg`; it also picks the
+    /// file extension Whisper uses to detect the format.
+    pub async fn transcribe_audio(&self, bytes: Vec<u8>, mime: &str) -> Result<String> {
+        let url = api_url(&self.config.openai_base_url, "audio/transcriptions");
+        // `send` consumes the form, so each attempt uploads a fresh copy
+        let build = || -> Result<reqwest::RequestBuilder> {
+            let file = reqwest::multipart::Part::bytes(bytes.clone())
+                .file_name(format!("audio.{}", audio_extension(mime)))
+                .mime_str(mime)
+                .context("Invalid audio MIME type")?;
+            let form = reqwest::multipart::Form::new()
+                .text("model", WHISPER_MODEL)
+                .part("file", file);
+            Ok(self.http_client.post(&url).multipart(form))
         };
 
-        // Build messages array: system + history + current query
-        let mut messages = vec![system_message];
-        
-        // Add conversation history (limited to max_conversation_history)
-        let history_start = conversation_history.len()
-            .saturating_sub(self.config.max_conversation_history);
-        messages.extend_from_slice(&conversation_history[history_start..]);
-        
-        // Add current query
-        messages.push(ConversationMessage {
-            role: "user".to_string(),
-            content: query.to_string(),
-        });
+        let response = send_with_retry(
+            self.openai_auth(),
+            self.config.openai_max_retries,
+            Some(&self.circuit_breaker),
+            build,
+        )
+        .await
+        .context("Failed to send transcription request")?;
 
-        // Call OpenAI API
-        let request = OpenAIChatRequest {
-            model: self.config.gpt_model.clone(),
-            messages,
-            temperature: 0.3, // Low temperature for factual responses
-            max_tokens: 500,
-        };
+        let response: OpenAITranscriptionResponse = response
+            .json()
+            .await
+            .context("Failed to parse transcription response")?;
+
+        Ok(response.text.trim().to_string())
+    }
 
+    /// Check text against OpenAI's moderation endpoint
+    /// 
+    /// Only categories listed in `MODERATION_CATEGORIES` count (any flagged
+    /// category when the list is empty).
+    /// 
+    /// # Returns
+    /// `true` if the text should be rejected
+    pub async fn moderate_text(&self, text: &str) -> Result<bool> {
         let response = self
-            .http_client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", self.config.openai_api_key))
-            .json(&request)
-            .send()
+            .post_with_retry("moderations", &OpenAIModerationRequest { input: text })
             .await
-            .context("Failed to send chat completion request")?;
+            .context("Failed to send moderation request")?;
 
-        let response: OpenAIChatResponse = response
+        let response: OpenAIModerationResponse = response
             .json()
             .await
-            .context("Failed to parse chat completion response")?;
+            .context("Failed to parse moderation response")?;
 
-        let answer = response
-            .choices
-            .into_iter()
-            .next()
-            .map(|c| c.message.content)
-            .context("No response from GPT")?;
+        let Some(result) = response.results.into_iter().next() else {
+            return Ok(false);
+        };
 
-        log::info!("Response generated successfully");
-        Ok(answer)
+        let blocking = &self.config.moderation_categories;
+        let hits: Vec<&str> = result
+            .categories
+            .iter()
+            .filter(|(category, hit)| **hit && (blocking.is_empty() || blocking.contains(*category)))
+            .map(|(category, _)| category.as_str())
+            .collect();
+
+        let rejected = if blocking.is_empty() {
+            result.flagged
+        } else {
+            !hits.is_empty()
+        };
+        if rejected {
+            log::warn!("Moderation flagged text for: {}", hits.join(", "));
+        }
+        Ok(rejected)
     }
 
-    /// Retrieve ALL documents from database (for comprehensive fallback context)
-    async fn retrieve_all_documents(&self) -> Result<Vec<String>> {
-        log::info!("Retrieving all documents for comprehensive context (limit: {})", 
-                   self.config.max_fallback_chunks);
+    /// Generate embeddings for text, reusing cached embeddings for text
+    /// that was already embedded with the same model
+    ///
+    /// The text (a query, or a query plus HyDE answer) is cleaned with
+    /// [`normalize_text`] first, like document content is.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rag.generate_embedding",
+            skip_all,
+            fields(cache_hit = tracing::field::Empty, elapsed_ms = tracing::field::Empty)
+        )
+    )]
+    async fn generate_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        let _timer = SpanTimer::start();
+        let text = &normalize_text(text);
+        let Some(cache) = &self.embedding_cache else {
+            return self.request_embedding(text).await;
+        };
 
-        let query = format!(
-            "SELECT content FROM {} ORDER BY created_at LIMIT $1",
-            self.config.embeddings_table
-        );
+        let key = content_hash(&format!("{}\n{}", self.embedder.model(), text));
+        let cached = cache.lock().unwrap().get(&key).cloned();
+        telemetry::record("cache_hit", cached.is_some() as u64);
+        if let Some(embedding) = cached {
+            log::debug!("Embedding cache hit");
+            return Ok(embedding);
+        }
 
-        let rows = sqlx::query(&query)
-            .bind(self.config.max_fallback_chunks as i64)
-            .fetch_all(&self.db_pool)
-            .await
-            .context("Failed to retrieve all documents")?;
+        let embedding = self.request_embedding(text).await?;
+        cache.lock().unwrap().put(key, embedding.clone());
+        Ok(embedding)
+    }
 
-        let chunks: Vec<String> = rows
+    /// Embed a single text with the configured provider
+    async fn request_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        self.embedder
+            .embed(&[text.to_string()])
+            .await?
             .into_iter()
-            .map(|row| row.get::<String, _>("content"))
-            .collect();
+            .next()
+            .context("No embedding returned")
+    }
 
-        log::info!("Retrieved {} total chunks for context", chunks.len());
-        Ok(chunks)
+    /// Generate embeddings for many texts, sending up to
+    /// `EMBEDDING_BATCH_SIZE` inputs per provider request
+    ///
+    /// # Returns
+    /// One embedding per input text, in the same order
+    async fn generate_embeddings_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embedder.embed(texts).await
     }
 
-    /// Generate a fallback response using ChatGPT with full knowledge base context
-    /// Used when no relevant information is found via similarity search
-    async fn generate_fallback_response(
-        &self,
-        query: &str,
-        conversation_history: &[ConversationMessage],
-    ) -> Result<String> {
-        log::info!("Generating fallback response using ChatGPT with full Pollinet knowledge base");
+    /// Split text into chunks for embedding
+    /// Simple chunking by character count with overlap
+    ///
+    /// Sizes are counted in characters (not bytes), so multibyte text such as
+    /// emoji or accented letters is never split inside a character.
+    fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let text = text.trim();
 
-        // Retrieve all documents for comprehensive context
-        let all_chunks = self.retrieve_all_documents().await?;
-        
-        // Build comprehensive context
-        let full_context = if all_chunks.is_empty() {
-            "No documents in knowledge base yet.".to_string()
-        } else {
-            all_chunks.join("\n\n---\n\n")
-        };
+        // Byte offset of every character, plus the end of the text
+        let boundaries: Vec<usize> = text
+            .char_indices()
+            .map(|(idx, _)| idx)
+            .chain(std::iter::once(text.len()))
+            .collect();
+        let char_count = boundaries.len() - 1;
 
-        // Build system message with full Pollinet knowledge base
-        let system_message = ConversationMessage {
-            role: "system".to_string(),
-            content: format!(
-                "You are a helpful assistant for Pollinet, a decentralized SDK enabling \
-                offline Solana transactions via Bluetooth Low Energy (BLE) mesh networks. \
-                \n\n\
-                COMPLETE POLLINET KNOWLEDGE BASE:\n\
-                {}\n\
-                ---\n\n\
-                When answering questions:\n\
-                1. First try to answer using the knowledge base above\n\
-                2. If the question is about Pollinet, blockchain, Solana, Web3, DePIN, or related crypto topics, \
-                   answer using the knowledge base or your understanding of these topics\n\
-                3. If the question is COMPLETELY UNRELATED (weather, cooking, sports, entertainment, general trivia, etc.), \
-                   respond EXACTLY with: 'I'm sorry, but I only answer questions related to Pollinet, blockchain, \
-                   Solana, and Web3 technologies. Please ask me something about Pollinet!'\n\
-                4. If you're unsure whether a question is related, err on the side of answering if there's \
-                   any connection to blockchain/crypto/technology\n\
-                5. Keep responses concise and accurate\n\
-                6. Remove all non-english symbols.\n\
-                7. Using blue jean writing style.\n\
-                8. Ask the user follow up questions after a response if needed.\n\
-                9. Give brief response whenby default else it requires more details then give longer responses.\n\
-                10. ALWAYS format responses using HTML:\n\
-                   - Use <b>bold</b> for emphasis and section headers\n\
-                   - Use bullet points with emoji bullets (🔗, •, ✅, etc.) for lists\n\
-                   - Use <code>code</code> for technical terms and code snippets\n\
-                   - Use <i>italic</i> for subtle emphasis\n\
-                   - Structure responses with clear sections using <b>headers</b>",
-                full_context
-            ),
-        };
+        if char_count <= chunk_size {
+            chunks.push(text.to_string());
+            return chunks;
+        }
 
-        // Build messages array
-        let mut messages = vec![system_message];
-        
-        // Add conversation history
-        let history_start = conversation_history.len()
-            .saturating_sub(self.config.max_conversation_history);
-        messages.extend_from_slice(&conversation_history[history_start..]);
-        
-        // Add current query
-        messages.push(ConversationMessage {
-            role: "user".to_string(),
-            content: query.to_string(),
-        });
+        let mut start = 0;
+        while start < char_count {
+            let end = (start + chunk_size).min(char_count);
+            let chunk = &text[boundaries[start]..boundaries[end]];
+            chunks.push(chunk.to_string());
 
-        // Call OpenAI API
-        let request = OpenAIChatRequest {
-            model: self.config.gpt_model.clone(),
-            messages,
-            temperature: 0.7, // Higher temperature for general responses
-            max_tokens: 500,
-        };
+            if end == char_count {
+                break;
+            }
 
-        let response = self
-            .http_client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", self.config.openai_api_key))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send chat completion request")?;
+            start += chunk_size - overlap;
+        }
 
-        let response: OpenAIChatResponse = response
-            .json()
-            .await
-            .context("Failed to parse chat completion response")?;
+        chunks
+    }
 
-        let answer = response
-            .choices
-            .into_iter()
-            .next()
-            .map(|c| c.message.content)
-            .context("No response from GPT")?;
+    /// Split text into chunks of whole sentences
+    ///
+    /// Sentences are packed into a chunk until the next one would exceed
+    /// `chunk_size` characters; the following chunk starts with as many
+    /// trailing sentences of the previous one as fit in `overlap`. A single
+    /// sentence longer than `chunk_size` is split with [`Self::chunk_text`].
+    fn chunk_text_by_sentence(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut current: Vec<&str> = Vec::new();
+        let mut current_len = 0;
 
-        Ok(answer)
+        for sentence in split_sentences(text) {
+            let sentence_len = sentence.chars().count();
+
+            if sentence_len > chunk_size {
+                if !current.is_empty() {
+                    chunks.push(current.join(" "));
+                    current.clear();
+                    current_len = 0;
+                }
+                chunks.extend(Self::chunk_text(sentence, chunk_size, overlap));
+                continue;
+            }
+
+            // +1 for the joining space
+            if !current.is_empty() && current_len + 1 + sentence_len > chunk_size {
+                chunks.push(current.join(" "));
+
+                // Carry trailing sentences over as overlap
+                let mut carried = Vec::new();
+                let mut carried_len = 0;
+                for previous in current.iter().rev() {
+                    let len = previous.chars().count() + 1;
+                    if carried_len + len > overlap || carried_len + len + sentence_len > chunk_size {
+                        break;
+                    }
+                    carried.insert(0, *previous);
+                    carried_len += len;
+                }
+                current = carried;
+                current_len = carried_len.saturating_sub(1);
+            }
+
+            if !current.is_empty() {
+                current_len += 1;
+            }
+            current.push(sentence);
+            current_len += sentence_len;
+        }
+
+        if !current.is_empty() {
+            chunks.push(current.join(" "));
+        }
+
+        chunks
     }
 
-    /// Main query method that combines retrieval and generation
+    /// Add a document to the knowledge base
+    /// 
+    /// Uses the default [`ChunkStrategy`] (fixed-size character windows).
     /// 
     /// # Arguments
-    /// * `query` - User's question
-    /// * `conversation_history` - Previous messages
+    /// * `document_name` - Name/identifier for the document
+    /// * `content` - Full text content of the document
+    /// * `metadata` - Additional metadata (e.g., source, date, etc.)
     /// 
     /// # Returns
-    /// Generated answer based on retrieved context or fallback to general ChatGPT
-    pub async fn query(
+    /// How many chunks were inserted, re-embedded, or skipped as unchanged
+    pub async fn add_document(
         &self,
-        query: &str,
-        conversation_history: &[ConversationMessage],
-    ) -> Result<String> {
-        // Step 1: Retrieve relevant chunks
-        let chunks = self.retrieve_relevant_chunks(query).await?;
+        document_name: &str,
+        content: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<IngestStats> {
+        self.add_document_with_strategy(document_name, content, metadata, ChunkStrategy::default(), None)
+            .await
+    }
 
-        // Step 2: Check if we have relevant context
+    /// [`RAGSystem::add_document`] into `namespace` (`None` is
+    /// [`DEFAULT_NAMESPACE`])
+    /// 
+    /// A document name identifies a document within its namespace, so the
+    /// same name can hold different content in two namespaces.
+    pub async fn add_document_in_namespace(
+        &self,
+        document_name: &str,
+        content: &str,
+        metadata: HashMap<String, String>,
+        namespace: Option<&str>,
+    ) -> Result<IngestStats> {
+        self.add_document_with_strategy(document_name, content, metadata, ChunkStrategy::default(), namespace)
+            .await
+    }
+
+    /// Add a document to the knowledge base, choosing how it is chunked
+    /// 
+    /// Chunks whose stored `content_hash` matches their text keep their
+    /// embedding; only their metadata is refreshed.
+    /// 
+    /// With `PARENT_CHUNK_SIZE` set, the document is first cut into parent
+    /// chunks of that size, and each parent is split into the embedded
+    /// (child) chunks using `strategy`.
+    /// 
+    /// With `DEDUP_SIMILARITY` set, a new or changed chunk is dropped when a
+    /// chunk of another document is at least that similar to it.
+    /// 
+    /// The content is cleaned with [`normalize_text`] first; documents
+    /// shorter than `MIN_DOCUMENT_CHARS` fail with [`DocumentTooShort`] and
+    /// chunks shorter than `MIN_CHUNK_CHARS` are dropped (also failing with
+    /// [`DocumentTooShort`] if that leaves none).
+    /// 
+    /// `namespace` defaults to [`DEFAULT_NAMESPACE`]; deduplication only
+    /// compares against chunks of the same namespace.
+    pub async fn add_document_with_strategy(
+        &self,
+        document_name: &str,
+        content: &str,
+        metadata: HashMap<String, String>,
+        strategy: ChunkStrategy,
+        namespace: Option<&str>,
+    ) -> Result<IngestStats> {
+        let namespace = checked_namespace(namespace)?;
+        log::info!(
+            "Adding document: {} ({:?} chunking, namespace {})",
+            document_name,
+            strategy,
+            namespace
+        );
+
+        let content = normalize_text(content);
+        let chars = content.chars().count();
+        if chars < self.config.min_document_chars.max(1) {
+            return Err(DocumentTooShort {
+                document: document_name.to_string(),
+                chars,
+                min_chars: self.config.min_document_chars.max(1),
+                no_chunks: false,
+            }
+            .into());
+        }
+
+        // Chunk the document using the configured size and overlap
+        let (chunk_size, overlap) = (self.config.chunk_size, self.config.chunk_overlap);
+        let split = |text: &str| match strategy {
+            ChunkStrategy::FixedSize => Self::chunk_text(text, chunk_size, overlap),
+            ChunkStrategy::Sentence => Self::chunk_text_by_sentence(text, chunk_size, overlap),
+        };
+
+        // Parent chunks, and the parent id of every (child) chunk
+        let mut parents: Vec<(String, String)> = Vec::new();
+        let mut parent_ids: Vec<Option<String>> = Vec::new();
+        let chunks = if self.config.parent_chunk_size > 0 {
+            let mut children = Vec::new();
+            for (idx, parent) in Self::chunk_text(&content, self.config.parent_chunk_size, overlap)
+                .into_iter()
+                .enumerate()
+            {
+                let parent_id = namespaced_id(namespace, format!("{}_parent_{}", document_name, idx));
+                for child in split(&parent) {
+                    children.push(child);
+                    parent_ids.push(Some(parent_id.clone()));
+                }
+                parents.push((parent_id, parent));
+            }
+            log::info!("Split into {} parent chunks", parents.len());
+            children
+        } else {
+            let chunks = split(&content);
+            parent_ids.resize(chunks.len(), None);
+            chunks
+        };
+
+        // Drop chunks too small to carry meaning (e.g. a trailing fragment)
+        let min_chunk_chars = self.config.min_chunk_chars.max(1);
+        let (chunks, parent_ids): (Vec<String>, Vec<Option<String>>) = chunks
+            .into_iter()
+            .zip(parent_ids)
+            .filter(|(chunk, _)| chunk.trim().chars().count() >= min_chunk_chars)
+            .unzip();
+        parents.retain(|(id, _)| parent_ids.iter().any(|parent| parent.as_ref() == Some(id)));
         if chunks.is_empty() {
-            log::info!("No relevant chunks found, using ChatGPT fallback with full knowledge base");
-            
-            // Use ChatGPT with full knowledge base as fallback
-            let fallback_response = self
-                .generate_fallback_response(query, conversation_history)
-                .await?;
-            
-            return Ok(fallback_response);
+            return Err(DocumentTooShort {
+                document: document_name.to_string(),
+                chars,
+                min_chars: min_chunk_chars,
+                no_chunks: true,
+            }
+            .into());
         }
+        log::info!("Split into {} chunks", chunks.len());
 
-        // Step 3: Generate response with context from knowledge base
-        let response = self
-            .generate_response(query, &chunks, conversation_history)
-            .await?;
+        let ids: Vec<String> = (0..chunks.len())
+            .map(|idx| namespaced_id(namespace, format!("{}_{}", document_name, idx)))
+            .collect();
+        // What gets embedded; hashing it means toggling contextual headers
+        // re-embeds the chunks on the next ingestion
+        let embed_texts: Vec<String> = chunks
+            .iter()
+            .map(|chunk| self.embedding_text(chunk, document_name, &metadata))
+            .collect();
+        let hashes: Vec<String> = embed_texts.iter().map(|text| content_hash(text)).collect();
 
-        // Check if GPT said it doesn't know
-        if response.contains("I don't have that information yet") {
-            log::info!("GPT couldn't answer from context, using ChatGPT fallback with full knowledge base");
-            
-            // Use ChatGPT with full knowledge base as fallback
-            let fallback_response = self
-                .generate_fallback_response(query, conversation_history)
-                .await?;
-            
-            return Ok(fallback_response);
+        // Hashes of the chunks already stored under these ids
+        let existing_query = format!(
+            "SELECT id, content_hash FROM {} WHERE id = ANY($1)",
+            self.config.embeddings_table
+        );
+        let existing: HashMap<String, Option<String>> = sqlx::query_as(&existing_query)
+            .bind(&ids)
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to load existing chunk hashes")?
+            .into_iter()
+            .collect();
+
+        let unchanged: Vec<bool> = ids
+            .iter()
+            .zip(&hashes)
+            .map(|(id, hash)| existing.get(id).is_some_and(|stored| stored.as_ref() == Some(hash)))
+            .collect();
+
+        // Embed the new and changed chunks up front (batched) before touching the database
+        let to_embed: Vec<String> = embed_texts
+            .into_iter()
+            .zip(&unchanged)
+            .filter(|(_, unchanged)| !**unchanged)
+            .map(|(text, _)| text)
+            .collect();
+        let mut embeddings = self
+            .generate_embeddings_batch(&to_embed)
+            .await
+            .with_context(|| format!("Failed to embed document '{}'", document_name))?
+            .into_iter();
+
+        // One transaction per document, so a failure never leaves it half-written
+        let mut tx = self
+            .db_pool
+            .begin()
+            .await
+            .context("Failed to start document transaction")?;
+
+        let mut stats = IngestStats::default();
+        for (idx, chunk_text) in chunks.iter().enumerate() {
+            let point_id = &ids[idx];
+
+            // Prepare metadata
+            let mut chunk_metadata = metadata.clone();
+            chunk_metadata.insert("document".to_string(), document_name.to_string());
+            chunk_metadata.insert("chunk_index".to_string(), idx.to_string());
+
+            // Convert metadata to JSON
+            let metadata_json = serde_json::to_value(&chunk_metadata)
+                .context("Failed to serialize metadata")?;
+
+            if unchanged[idx] {
+                let refresh_query = format!(
+                    r#"
+                    UPDATE {} SET metadata = $2, parent_id = $3, deleted_at = NULL
+                    WHERE id = $1 AND (metadata IS DISTINCT FROM $2 OR parent_id IS DISTINCT FROM $3
+                                       OR deleted_at IS NOT NULL)
+                    "#,
+                    self.config.embeddings_table
+                );
+                sqlx::query(&refresh_query)
+                    .bind(point_id)
+                    .bind(metadata_json)
+                    .bind(&parent_ids[idx])
+                    .execute(&mut *tx)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to update chunk {} of document '{}'", idx, document_name)
+                    })?;
+                stats.skipped += 1;
+                continue;
+            }
+
+            let embedding = embeddings
+                .next()
+                .context("Embedding provider returned too few embeddings")?;
+
+            if let Some(threshold) = self.config.dedup_similarity {
+                let nearest = self
+                    .nearest_chunk_elsewhere(&mut tx, &embedding, document_name, namespace)
+                    .await?;
+                if let Some((duplicate_of, similarity)) =
+                    nearest.filter(|(_, similarity)| *similarity >= threshold)
+                {
+                    log::info!(
+                        "Skipping chunk {} of '{}': {:.3} similar to {}",
+                        idx,
+                        document_name,
+                        similarity,
+                        duplicate_of
+                    );
+                    // Don't leave a previous version of this chunk behind
+                    sqlx::query(&format!("DELETE FROM {} WHERE id = $1", self.config.embeddings_table))
+                        .bind(point_id)
+                        .execute(&mut *tx)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to remove duplicate chunk {} of document '{}'", idx, document_name)
+                        })?;
+                    stats.deduplicated += 1;
+                    continue;
+                }
+            }
+
+            // Insert into database
+            let insert_query = format!(
+                r#"
+                INSERT INTO {} (id, content, embedding, metadata, content_hash, parent_id, namespace)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (id) DO UPDATE 
+                SET content = $2, embedding = $3, metadata = $4, content_hash = $5,
+                    parent_id = $6, namespace = $7, created_at = CURRENT_TIMESTAMP, deleted_at = NULL
+                "#,
+                self.config.embeddings_table
+            );
+
+            sqlx::query(&insert_query)
+                .bind(point_id)
+                .bind(chunk_text)
+                .bind(Vector::from(embedding))
+                .bind(metadata_json)
+                .bind(&hashes[idx])
+                .bind(&parent_ids[idx])
+                .bind(namespace)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| {
+                    format!("Failed to insert chunk {} of document '{}'", idx, document_name)
+                })?;
+
+            if existing.contains_key(point_id) {
+                stats.updated += 1;
+            } else {
+                stats.inserted += 1;
+            }
+        }
+
+        // Drop chunks left over from a previous, longer version of this document
+        let stale_query = format!(
+            r#"
+            DELETE FROM {}
+            WHERE metadata->>'document' = $1 AND (metadata->>'chunk_index')::int >= $2 AND namespace = $3
+            "#,
+            self.config.embeddings_table
+        );
+
+        let stale = sqlx::query(&stale_query)
+            .bind(document_name)
+            .bind(chunks.len() as i32)
+            .bind(namespace)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to remove stale chunks of document '{}'", document_name))?
+            .rows_affected();
+
+        // Parents carry no embedding, so they are simply rewritten
+        sqlx::query(&format!(
+            "DELETE FROM {} WHERE document = $1 AND namespace = $2",
+            self.parents_table()
+        ))
+        .bind(document_name)
+        .bind(namespace)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to remove parent chunks of document '{}'", document_name))?;
+        for (parent_id, parent) in &parents {
+            sqlx::query(&format!(
+                "INSERT INTO {} (id, document, content, namespace) VALUES ($1, $2, $3, $4)",
+                self.parents_table()
+            ))
+            .bind(parent_id)
+            .bind(document_name)
+            .bind(parent)
+            .bind(namespace)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to insert parent chunks of document '{}'", document_name))?;
+        }
+
+        tx.commit()
+            .await
+            .with_context(|| format!("Failed to commit document '{}'", document_name))?;
+
+        if stale > 0 {
+            log::info!("Removed {} stale chunks from previous version", stale);
+        }
+        self.clear_answer_cache();
+
+        log::info!(
+            "Document added successfully: {} inserted, {} updated, {} unchanged, {} deduplicated",
+            stats.inserted,
+            stats.updated,
+            stats.skipped,
+            stats.deduplicated
+        );
+        Ok(stats)
+    }
+
+    /// Most similar stored chunk that belongs to a different document in
+    /// `namespace`, with its similarity to `embedding` (see [`similarity_sql`])
+    async fn nearest_chunk_elsewhere(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        embedding: &[f32],
+        document_name: &str,
+        namespace: &str,
+    ) -> Result<Option<(String, f32)>> {
+        let nearest: Option<(String, f64)> = sqlx::query_as(&format!(
+            r#"
+            SELECT id, ({})::float8 AS similarity
+            FROM {}
+            WHERE metadata->>'document' IS DISTINCT FROM $2 AND {}
+            ORDER BY embedding {} $1
+            LIMIT 1
+            "#,
+            similarity_sql(self.config.distance_metric, "$1"),
+            self.config.embeddings_table,
+            live_chunks_in("$3"),
+            distance_operator(self.config.distance_metric)
+        ))
+        .bind(Vector::from(embedding.to_vec()))
+        .bind(document_name)
+        .bind(namespace)
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to search for duplicate chunks")?;
+
+        Ok(nearest.map(|(id, similarity)| (id, similarity as f32)))
+    }
+
+    /// Check whether any chunk in `namespace` has `metadata->>key = value`
+    /// (e.g. `tweet_id`), without an embedding call
+    ///
+    /// Soft-deleted chunks count, so a deleted item isn't ingested again.
+    pub async fn document_exists_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+        namespace: Option<&str>,
+    ) -> Result<bool> {
+        let query = format!(
+            "SELECT EXISTS(SELECT 1 FROM {} WHERE metadata->>$1 = $2 AND namespace = $3)",
+            self.config.embeddings_table
+        );
+
+        let exists: bool = sqlx::query_scalar(&query)
+            .bind(key)
+            .bind(value)
+            .bind(checked_namespace(namespace)?)
+            .fetch_one(&self.db_pool)
+            .await
+            .context("Failed to check for existing document")?;
+
+        Ok(exists)
+    }
+
+    /// List the documents in `namespace`, most recently updated first
+    ///
+    /// Rows are grouped by the `document` metadata key.
+    pub async fn list_documents(&self, namespace: Option<&str>) -> Result<Vec<DocumentSummary>> {
+        let query = format!(
+            r#"
+            SELECT metadata->>'document' AS document_name,
+                   COUNT(*) AS chunk_count,
+                   MAX(metadata->>'source') AS source,
+                   to_char(MAX(created_at), 'YYYY-MM-DD HH24:MI:SS') AS last_updated
+            FROM {}
+            WHERE metadata ? 'document' AND {}
+            GROUP BY metadata->>'document'
+            ORDER BY MAX(created_at) DESC
+            "#,
+            self.config.embeddings_table,
+            live_chunks_in("$1")
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(checked_namespace(namespace)?)
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to list documents")?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(DocumentSummary {
+                    document_name: row.try_get("document_name")?,
+                    chunk_count: row.try_get("chunk_count")?,
+                    source: row.try_get("source")?,
+                    last_updated: row
+                        .try_get::<Option<String>, _>("last_updated")?
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    /// Most recent chunks in `namespace` whose metadata contains every pair
+    /// in `filters` (e.g. `category=pollinet_announcement`), without a
+    /// similarity query
+    /// 
+    /// A chunk's date is its `created_at` metadata when that parses, else
+    /// when it was stored. With `since`, older and undated chunks are skipped.
+    pub async fn query_by_metadata(
+        &self,
+        filters: &HashMap<String, String>,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        limit: usize,
+        namespace: Option<&str>,
+    ) -> Result<Vec<DatedChunk>> {
+        // try_timestamptz (a migration) yields NULL for malformed metadata
+        // dates, so one bad value can't fail the whole lookup
+        let (filter_clause, since_param) = if filters.is_empty() {
+            ("", 3)
+        } else {
+            ("AND metadata @> $3", 4)
+        };
+        let since_clause = match since {
+            Some(_) => format!("WHERE chunk_date >= to_timestamp(${})", since_param),
+            None => String::new(),
+        };
+        let lookup_query = format!(
+            r#"
+            SELECT content, metadata, EXTRACT(EPOCH FROM chunk_date)::float8 AS created_epoch
+            FROM (
+                SELECT id, content, metadata,
+                       COALESCE(try_timestamptz(metadata->>'created_at'), created_at AT TIME ZONE 'UTC')
+                           AS chunk_date
+                FROM {}
+                WHERE {} {}
+            ) AS chunks
+            {}
+            ORDER BY chunk_date DESC NULLS LAST, id
+            LIMIT $2
+            "#,
+            self.config.embeddings_table, live_chunks_in("$1"), filter_clause, since_clause
+        );
+
+        let mut lookup = sqlx::query(&lookup_query)
+            .bind(checked_namespace(namespace)?)
+            .bind(limit as i64);
+        if !filters.is_empty() {
+            let filters_json =
+                serde_json::to_value(filters).context("Failed to serialize metadata filters")?;
+            lookup = lookup.bind(filters_json);
+        }
+        if let Some(since) = since {
+            lookup = lookup.bind(since.timestamp() as f64);
+        }
+        let rows = lookup
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to look up chunks by metadata")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let created_epoch: Option<f64> = row.try_get("created_epoch").ok().flatten();
+                DatedChunk {
+                    content: row.get("content"),
+                    metadata: metadata_from_json(row.try_get("metadata").ok().flatten()),
+                    created_at: created_epoch
+                        .and_then(|created| chrono::DateTime::from_timestamp(created as i64, 0))
+                        .map(|created| created.to_rfc3339()),
+                }
+            })
+            .collect())
+    }
+
+    /// Collect statistics of the knowledge base in `namespace` (default:
+    /// [`DEFAULT_NAMESPACE`])
+    pub async fn stats(&self, namespace: Option<&str>) -> Result<KnowledgeStats> {
+        let namespace = checked_namespace(namespace)?;
+        let mut documents = self.list_documents(Some(namespace)).await?;
+
+        Ok(KnowledgeStats {
+            total_chunks: self.chunk_count(Some(namespace)).await?,
+            total_documents: documents.len(),
+            chunks_by_source: self.chunk_counts_by_metadata("source", namespace).await?,
+            chunks_by_category: self.chunk_counts_by_metadata("category", namespace).await?,
+            // list_documents is sorted newest first
+            oldest_document: documents.pop(),
+            newest_document: documents.into_iter().next(),
+        })
+    }
+
+    /// Count the chunks in `namespace` grouped by one metadata key
+    async fn chunk_counts_by_metadata(&self, key: &str, namespace: &str) -> Result<HashMap<String, i64>> {
+        let query = format!(
+            "SELECT COALESCE(metadata->>$1, 'unknown') AS value, COUNT(*) AS chunks FROM {} \
+             WHERE {} GROUP BY 1",
+            self.config.embeddings_table,
+            live_chunks_in("$2")
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(key)
+            .bind(namespace)
+            .fetch_all(&self.db_pool)
+            .await
+            .with_context(|| format!("Failed to count chunks by {}", key))?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("value"), row.get("chunks")))
+            .collect())
+    }
+
+    /// Remove a document and all of its chunks from the knowledge base
+    ///
+    /// Matches on the `document` metadata key rather than the id prefix, so
+    /// deleting "faq" never touches "faq_extra".
+    ///
+    /// This is a soft delete: the chunks get a `deleted_at` timestamp and are
+    /// skipped by retrieval, but stay in the table until
+    /// [`RAGSystem::purge_deleted`] removes them, so
+    /// [`RAGSystem::restore_document`] can undo it.
+    ///
+    /// Only the document in `namespace` (default: [`DEFAULT_NAMESPACE`]) is
+    /// removed.
+    ///
+    /// # Returns
+    /// Number of chunks removed (0 if the document did not exist)
+    pub async fn delete_document(&self, document_name: &str, namespace: Option<&str>) -> Result<usize> {
+        let namespace = checked_namespace(namespace)?;
+        let query = format!(
+            r#"
+            UPDATE {} SET deleted_at = CURRENT_TIMESTAMP
+            WHERE metadata->>'document' = $1 AND {}
+            "#,
+            self.config.embeddings_table,
+            live_chunks_in("$2")
+        );
+
+        let removed = sqlx::query(&query)
+            .bind(document_name)
+            .bind(namespace)
+            .execute(&self.db_pool)
+            .await
+            .context("Failed to delete document")?
+            .rows_affected();
+
+        if removed > 0 {
+            self.clear_answer_cache();
+        }
+
+        log::info!("Deleted document {} from {} ({} chunks)", document_name, namespace, removed);
+        Ok(removed as usize)
+    }
+
+    /// Bring back a document removed with [`RAGSystem::delete_document`]
+    /// that hasn't been purged yet
+    ///
+    /// # Returns
+    /// Number of chunks restored (0 if there was nothing to restore)
+    pub async fn restore_document(&self, document_name: &str, namespace: Option<&str>) -> Result<usize> {
+        let namespace = checked_namespace(namespace)?;
+        let query = format!(
+            r#"
+            UPDATE {} SET deleted_at = NULL
+            WHERE metadata->>'document' = $1 AND namespace = $2 AND deleted_at IS NOT NULL
+            "#,
+            self.config.embeddings_table
+        );
+
+        let restored = sqlx::query(&query)
+            .bind(document_name)
+            .bind(namespace)
+            .execute(&self.db_pool)
+            .await
+            .context("Failed to restore document")?
+            .rows_affected();
+
+        if restored > 0 {
+            self.clear_answer_cache();
+        }
+
+        log::info!("Restored document {} in {} ({} chunks)", document_name, namespace, restored);
+        Ok(restored as usize)
+    }
+
+    /// Permanently remove chunks soft-deleted more than `older_than` ago,
+    /// along with parent chunks no remaining chunk points at
+    ///
+    /// # Returns
+    /// Number of chunks removed
+    pub async fn purge_deleted(&self, older_than: Duration) -> Result<usize> {
+        let mut tx = self
+            .db_pool
+            .begin()
+            .await
+            .context("Failed to start purge transaction")?;
+
+        let purged = sqlx::query(&format!(
+            r#"
+            DELETE FROM {}
+            WHERE deleted_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+            "#,
+            self.config.embeddings_table
+        ))
+        .bind(older_than.as_secs_f64())
+        .execute(&mut *tx)
+        .await
+        .context("Failed to purge deleted chunks")?
+        .rows_affected();
+
+        sqlx::query(&format!(
+            r#"
+            DELETE FROM {} AS parents
+            WHERE NOT EXISTS (SELECT 1 FROM {} AS chunks WHERE chunks.parent_id = parents.id)
+            "#,
+            self.parents_table(),
+            self.config.embeddings_table
+        ))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to purge orphaned parent chunks")?;
+
+        tx.commit().await.context("Failed to commit purge")?;
+
+        log::info!("Purged {} deleted chunks", purged);
+        Ok(purged as usize)
+    }
+
+    /// Ingest seed documents from a JSONL file or a directory of `.md`/`.txt` files
+    ///
+    /// Each document's content hash is stored in its chunk metadata, so a
+    /// document is only (re-)embedded when it is new or its content changed.
+    /// Restarting with the same seed data therefore costs no OpenAI calls.
+    ///
+    /// # Returns
+    /// Number of documents that were ingested (unchanged ones are skipped)
+    pub async fn seed_documents(&self, path: &Path) -> Result<usize> {
+        let documents = load_seed_documents(path)?;
+        log::info!("Found {} seed documents in {}", documents.len(), path.display());
+
+        let mut ingested = 0;
+        for document in documents {
+            if self.ingest_if_changed(document, "seed").await? {
+                ingested += 1;
+            }
+        }
+
+        log::info!("Seeding complete: {} documents ingested", ingested);
+        Ok(ingested)
+    }
+
+    /// Ingest every file under `path` (recursively) whose extension is in
+    /// `extensions` (e.g. `&["md", "txt"]`), tagged `source=file`
+    ///
+    /// Files are named by their path relative to `path` without the
+    /// extension (`guides/setup.md` becomes `guides/setup`), and unchanged
+    /// files are skipped the same way as seed documents, so re-running this
+    /// over a docs checkout only embeds what changed.
+    ///
+    /// # Returns
+    /// Number of files that were ingested
+    pub async fn add_documents_from_dir(&self, path: impl AsRef<Path>, extensions: &[&str]) -> Result<usize> {
+        let path = path.as_ref();
+        let mut documents = Vec::new();
+        collect_text_files(path, path, extensions, true, &mut documents)?;
+        log::info!("Found {} files in {}", documents.len(), path.display());
+
+        let mut ingested = 0;
+        for document in documents {
+            if self.ingest_if_changed(document, "file").await? {
+                ingested += 1;
+            }
+        }
+
+        log::info!("Directory ingestion complete: {} files ingested", ingested);
+        Ok(ingested)
+    }
+
+    /// Fetch a web page and add its readable text as a document named after
+    /// the URL, with `source=web`, `url` and (if present) `title` metadata
+    ///
+    /// Only `text/html` and `text/plain` responses up to `MAX_WEB_PAGE_BYTES`
+    /// are accepted. Re-ingesting a URL replaces its previous version.
+    ///
+    /// # Returns
+    /// Number of chunks the page was split into
+    pub async fn add_document_from_url(&self, url: &str) -> Result<usize> {
+        let parsed = reqwest::Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            anyhow::bail!("Only http(s) URLs can be ingested, got {}", parsed.scheme());
+        }
+
+        log::info!("Fetching {} for ingestion", url);
+        let mut response = self
+            .http_client
+            .get(parsed)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {}", url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("Fetching {} failed with status {}", url, status);
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let is_html = content_type.starts_with("text/html") || content_type.starts_with("application/xhtml");
+        if !is_html && !content_type.starts_with("text/plain") {
+            anyhow::bail!(
+                "Unsupported content type '{}' at {} (only HTML and plain text pages can be ingested)",
+                content_type,
+                url
+            );
         }
+        if response.content_length().is_some_and(|length| length > MAX_WEB_PAGE_BYTES as u64) {
+            anyhow::bail!("Page at {} is larger than {} bytes", url, MAX_WEB_PAGE_BYTES);
+        }
+
+        // Content-Length may be missing or wrong, so cap while reading too
+        let mut body = Vec::new();
+        while let Some(bytes) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed to read {}", url))?
+        {
+            if body.len() + bytes.len() > MAX_WEB_PAGE_BYTES {
+                anyhow::bail!("Page at {} is larger than {} bytes", url, MAX_WEB_PAGE_BYTES);
+            }
+            body.extend_from_slice(&bytes);
+        }
+        let body = String::from_utf8_lossy(&body);
+
+        let mut metadata = HashMap::from([
+            ("source".to_string(), "web".to_string()),
+            ("url".to_string(), url.to_string()),
+        ]);
+        let text = if is_html {
+            if let Some(title) = extract::html_title(&body) {
+                metadata.insert("title".to_string(), title);
+            }
+            extract::html_to_text(&body)
+        } else {
+            body.into_owned()
+        };
+        if text.trim().is_empty() {
+            anyhow::bail!("No readable text found at {}", url);
+        }
+
+        let stats = self.add_document(url, &text, metadata).await?;
+        Ok(stats.total())
+    }
+
+    /// Extract the text of a PDF (a file path or its bytes) and add it as
+    /// document `name` with `source=pdf`
+    ///
+    /// Encrypted PDFs and PDFs without a text layer fail with a clear error.
+    ///
+    /// # Returns
+    /// Number of chunks the PDF was split into
+    pub async fn add_document_from_pdf(&self, source: impl Into<PdfSource>, name: &str) -> Result<usize> {
+        let text = extract::pdf_to_text(&source.into())
+            .await
+            .with_context(|| format!("Failed to extract text from PDF '{}'", name))?;
+        log::info!("Extracted {} characters from PDF '{}'", text.len(), name);
+
+        let metadata = HashMap::from([("source".to_string(), "pdf".to_string())]);
+        let stats = self.add_document(name, &text, metadata).await?;
+        Ok(stats.total())
+    }
+
+    /// Add `document` unless a document of that name with the same content
+    /// hash is already stored; `source` is used when its metadata has none
+    ///
+    /// # Returns
+    /// Whether the document was (re-)ingested
+    async fn ingest_if_changed(&self, document: SeedDocument, source: &str) -> Result<bool> {
+        let content_hash = content_hash(&document.content);
+
+        let exists_query = format!(
+                r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM {}
+                    WHERE metadata->>'document' = $1 AND metadata->>'content_hash' = $2
+                      AND {}
+                )
+                "#,
+                self.config.embeddings_table,
+                live_chunks_in("$3")
+            );
+
+        let unchanged: bool = sqlx::query_scalar(&exists_query)
+            .bind(&document.name)
+            .bind(&content_hash)
+            .bind(DEFAULT_NAMESPACE)
+            .fetch_one(&self.db_pool)
+            .await
+            .context("Failed to check document hash")?;
+
+        if unchanged {
+            log::debug!("Document '{}' unchanged, skipping", document.name);
+            return Ok(false);
+        }
+
+        let mut metadata = document.metadata;
+        metadata.entry("source".to_string()).or_insert_with(|| source.to_string());
+        metadata.insert("content_hash".to_string(), content_hash);
+
+        match self.add_document(&document.name, &document.content, metadata).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.downcast_ref::<DocumentTooShort>().is_some() => {
+                log::warn!("Skipping {}", e);
+                Ok(false)
+            }
+            Err(e) => Err(e.context(format!("Failed to ingest document '{}'", document.name))),
+        }
+    }
+
+    /// Retrieve relevant document chunks for a query
+    /// 
+    /// # Arguments
+    /// * `query` - User's question or query
+    /// 
+    /// # Returns
+    /// Vector of relevant text chunks
+    pub async fn retrieve_relevant_chunks(&self, query: &str) -> Result<Vec<String>> {
+        let chunks = self.retrieve_relevant_chunks_with_metadata(query).await?;
+        Ok(chunks.into_iter().map(|chunk| chunk.content).collect())
+    }
+
+    /// Retrieve relevant document chunks together with their cosine similarity
+    /// 
+    /// # Returns
+    /// `(content, similarity)` pairs, most similar first
+    pub async fn retrieve_scored_chunks(&self, query: &str) -> Result<Vec<(String, f32)>> {
+        let chunks = self.retrieve_relevant_chunks_with_metadata(query).await?;
+        Ok(chunks
+            .into_iter()
+            .map(|chunk| (chunk.content, chunk.similarity))
+            .collect())
+    }
+
+    /// Retrieve relevant document chunks with their metadata and similarity
+    /// 
+    /// Chunks below `retrieval_min_similarity` are dropped, so an off-topic
+    /// query can come back empty and trigger the fallback.
+    /// 
+    /// # Returns
+    /// Retrieved chunks, most similar first
+    pub async fn retrieve_relevant_chunks_with_metadata(
+        &self,
+        query: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        self.retrieve_relevant_chunks_filtered(query, HashMap::new(), None).await
+    }
+
+    /// Retrieve relevant document chunks in `namespace` (default:
+    /// [`DEFAULT_NAMESPACE`]) whose metadata contains every key/value pair in
+    /// `filters` (e.g. `source=twitter`)
+    /// 
+    /// An empty filter map runs exactly the unfiltered search.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rag.retrieve_relevant_chunks",
+            skip_all,
+            fields(
+                mode = ?self.config.retrieval_mode,
+                chunks = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty
+            )
+        )
+    )]
+    pub async fn retrieve_relevant_chunks_filtered(
+        &self,
+        query: &str,
+        filters: HashMap<String, String>,
+        namespace: Option<&str>,
+    ) -> Result<Vec<RetrievedChunk>> {
+        self.retrieve_chunks(query, &filters, self.config.top_k_chunks, checked_namespace(namespace)?)
+            .await
+    }
+
+    /// [`RAGSystem::retrieve_relevant_chunks_with_metadata`] searching only
+    /// `namespace` (`None` is [`DEFAULT_NAMESPACE`])
+    pub async fn retrieve_relevant_chunks_in_namespace(
+        &self,
+        query: &str,
+        namespace: Option<&str>,
+    ) -> Result<Vec<RetrievedChunk>> {
+        self.retrieve_relevant_chunks_filtered(query, HashMap::new(), namespace)
+            .await
+    }
+
+    /// [`RAGSystem::retrieve_relevant_chunks_with_metadata`] returning up to
+    /// `k` chunks from `namespace` instead of `top_k_chunks` from the default
+    /// namespace
+    /// 
+    /// `k` must be at least 1 and is capped at [`MAX_TOP_K`].
+    pub async fn retrieve_relevant_chunks_with_k(
+        &self,
+        query: &str,
+        k: usize,
+        namespace: Option<&str>,
+    ) -> Result<Vec<RetrievedChunk>> {
+        self.retrieve_chunks(query, &HashMap::new(), checked_top_k(k)?, checked_namespace(namespace)?)
+            .await
+    }
+
+    async fn retrieve_chunks(
+        &self,
+        query: &str,
+        filters: &HashMap<String, String>,
+        top_k: usize,
+        namespace: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        let _timer = SpanTimer::start();
+        log::info!(
+            "Retrieving up to {} relevant chunks from {} for query: {}",
+            top_k,
+            namespace,
+            query
+        );
+
+        let chunks = match self.config.retrieval_mode {
+            RetrievalMode::Vector => {
+                // Generate embedding for the query
+                let query_embedding = self.generate_embedding(query).await?;
+                self.retrieve_with_embedding(query_embedding, filters, top_k, namespace).await
+            }
+            RetrievalMode::Keyword => {
+                self.retrieve_keyword(query, None, filters, top_k, namespace).await
+            }
+            RetrievalMode::Hybrid => {
+                self.retrieve_hybrid_filtered(query, filters, top_k, namespace).await
+            }
+            RetrievalMode::Hyde => {
+                self.retrieve_hyde_filtered(query, filters, top_k, namespace).await
+            }
+        }?;
+        let chunks = self.expand_to_parents(chunks).await?;
+        telemetry::record("chunks", chunks.len() as u64);
+        Ok(chunks)
+    }
+
+    /// Swap matched child chunks for their parent chunks (parent-child chunking)
+    /// 
+    /// Children of the same parent collapse into one entry carrying the best
+    /// child's similarity; chunks without a parent are kept as they are.
+    async fn expand_to_parents(&self, chunks: Vec<RetrievedChunk>) -> Result<Vec<RetrievedChunk>> {
+        let parent_ids: Vec<&str> = chunks.iter().filter_map(|c| c.parent_id.as_deref()).collect();
+        if parent_ids.is_empty() {
+            return Ok(chunks);
+        }
+
+        let parents: HashMap<String, String> = sqlx::query_as(&format!(
+            "SELECT id, content FROM {} WHERE id = ANY($1)",
+            self.parents_table()
+        ))
+        .bind(&parent_ids)
+        .fetch_all(&self.db_pool)
+        .await
+        .context("Failed to load parent chunks")?
+        .into_iter()
+        .collect();
+
+        let expanded = with_parent_content(chunks, &parents);
+        log::info!("Expanded matches to {} parent chunks", expanded.len());
+        Ok(expanded)
+    }
+
+    /// Retrieve chunks similar to a hypothetical answer to `query` (HyDE)
+    /// 
+    /// GPT drafts a short answer and the question plus that draft is
+    /// embedded, which lands closer to answer-shaped chunks than the bare
+    /// question does in sparse knowledge bases. Drafts are cached per query.
+    pub async fn retrieve_hyde(&self, query: &str, namespace: Option<&str>) -> Result<Vec<RetrievedChunk>> {
+        let namespace = checked_namespace(namespace)?;
+        self.retrieve_hyde_filtered(query, &HashMap::new(), self.config.top_k_chunks, namespace)
+            .await
+    }
+
+    async fn retrieve_hyde_filtered(
+        &self,
+        query: &str,
+        filters: &HashMap<String, String>,
+        top_k: usize,
+        namespace: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        let hypothetical = self.hypothetical_answer(query).await?;
+        let query_embedding = self
+            .generate_embedding(&format!("{}\n\n{}", query, hypothetical))
+            .await?;
+        self.retrieve_with_embedding(query_embedding, filters, top_k, namespace).await
+    }
+
+    /// Short GPT-written answer to `query` used as the HyDE search text
+    async fn hypothetical_answer(&self, query: &str) -> Result<String> {
+        let key = content_hash(&format!("{}\n{}", self.config.gpt_model, query));
+        if let Some(answer) = self.hyde_cache.lock().unwrap().get(&key) {
+            log::debug!("HyDE cache hit");
+            return Ok(answer.clone());
+        }
+
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: vec![
+                ConversationMessage {
+                    role: "system".to_string(),
+                    content: HYDE_PROMPT.to_string(),
+                },
+                ConversationMessage {
+                    role: "user".to_string(),
+                    content: query.to_string(),
+                },
+            ],
+            temperature: 0.0,
+            max_tokens: 150,
+            stream: false,
+            stream_options: None,
+        };
+        let answer = self
+            .chat_completion(&request)
+            .await
+            .context("Failed to generate hypothetical answer")?
+            .answer;
+
+        log::debug!("HyDE hypothetical answer: {}", answer);
+        self.hyde_cache.lock().unwrap().put(key, answer.clone());
+        Ok(answer)
+    }
+
+    /// Retrieve chunks by fusing vector and full-text search results
+    /// 
+    /// Each list is ranked independently and merged with reciprocal rank
+    /// fusion, so exact-term matches (function names, tickers) surface even
+    /// when their embeddings score below `retrieval_min_similarity`.
+    pub async fn retrieve_hybrid(&self, query: &str, namespace: Option<&str>) -> Result<Vec<RetrievedChunk>> {
+        let namespace = checked_namespace(namespace)?;
+        self.retrieve_hybrid_filtered(query, &HashMap::new(), self.config.top_k_chunks, namespace)
+            .await
+    }
+
+    async fn retrieve_hybrid_filtered(
+        &self,
+        query: &str,
+        filters: &HashMap<String, String>,
+        top_k: usize,
+        namespace: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        let query_embedding = self.generate_embedding(query).await?;
+
+        let vector_chunks = self
+            .retrieve_with_embedding(query_embedding.clone(), filters, top_k, namespace)
+            .await?;
+        let keyword_chunks = self
+            .retrieve_keyword(query, Some(query_embedding), filters, top_k, namespace)
+            .await?;
+
+        let fused = reciprocal_rank_fusion(vec![vector_chunks, keyword_chunks], top_k);
+        log::info!("Hybrid retrieval fused {} chunks", fused.len());
+        Ok(fused)
+    }
+
+    /// Full-text search over chunk content with `plainto_tsquery`
+    /// 
+    /// When `query_embedding` is given, each hit's similarity is its vector
+    /// similarity to the query (see [`similarity_sql`]); otherwise it is the
+    /// `ts_rank` score.
+    async fn retrieve_keyword(
+        &self,
+        query: &str,
+        query_embedding: Option<Vec<f32>>,
+        filters: &HashMap<String, String>,
+        top_k: usize,
+        namespace: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        let mut next_param = 4;
+        let similarity_expr = if query_embedding.is_some() {
+            next_param += 1;
+            similarity_sql(self.config.distance_metric, "$4")
+        } else {
+            "ts_rank(content_tsv, plainto_tsquery('english', $1))".to_string()
+        };
+        let filter_clause = if filters.is_empty() {
+            String::new()
+        } else {
+            log::info!("Filtering retrieval by metadata: {:?}", filters);
+            format!("AND metadata @> ${}", next_param)
+        };
+
+        let search_query = format!(
+            r#"
+            SELECT content, metadata, parent_id, ({})::float8 AS similarity
+            FROM {}
+            WHERE content_tsv @@ plainto_tsquery('english', $1) AND {}
+            {}
+            ORDER BY ts_rank(content_tsv, plainto_tsquery('english', $1)) DESC
+            LIMIT $2
+            "#,
+            similarity_expr, self.config.embeddings_table, live_chunks_in("$3"), filter_clause
+        );
+
+        let mut search = sqlx::query(&search_query)
+            .bind(query)
+            .bind(top_k as i64)
+            .bind(namespace);
+        if let Some(embedding) = query_embedding {
+            search = search.bind(Vector::from(embedding));
+        }
+        if !filters.is_empty() {
+            let filters_json =
+                serde_json::to_value(filters).context("Failed to serialize metadata filters")?;
+            search = search.bind(filters_json);
+        }
+
+        let rows = search
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to run full-text search")?;
+
+        let chunks: Vec<RetrievedChunk> = rows
+            .into_iter()
+            .map(|row| RetrievedChunk {
+                content: row.get("content"),
+                metadata: metadata_from_json(row.try_get("metadata").ok().flatten()),
+                similarity: row.get::<f64, _>("similarity") as f32,
+                parent_id: row.get("parent_id"),
+            })
+            .collect();
+
+        log::info!("Full-text search matched {} chunks", chunks.len());
+        Ok(chunks)
+    }
+
+    /// Vector search with an already-computed query embedding
+    async fn retrieve_with_embedding(
+        &self,
+        query_embedding: Vec<f32>,
+        filters: &HashMap<String, String>,
+        top_k: usize,
+        namespace: &str,
+    ) -> Result<Vec<RetrievedChunk>> {
+        if !filters.is_empty() {
+            log::info!("Filtering retrieval by metadata: {:?}", filters);
+        }
+
+        // With MMR or the recency boost, over-fetch candidates to re-rank
+        // (MMR also needs their embeddings)
+        let recency_weight = self.config.recency_weight;
+        let (limit, with_embeddings) = match self.config.mmr_lambda {
+            Some(_) => (top_k * MMR_CANDIDATE_MULTIPLIER, true),
+            None if recency_weight > 0.0 => (top_k * RECENCY_CANDIDATE_MULTIPLIER, false),
+            None => (top_k, false),
+        };
+
+        let search_query = vector_search_sql(
+            &self.config.embeddings_table,
+            self.config.distance_metric,
+            !filters.is_empty(),
+            with_embeddings,
+        );
+
+        let mut search = sqlx::query(&search_query)
+            .bind(Vector::from(query_embedding))
+            .bind(limit as i64)
+            .bind(namespace);
+        if !filters.is_empty() {
+            let filters_json =
+                serde_json::to_value(filters).context("Failed to serialize metadata filters")?;
+            search = search.bind(filters_json);
+        }
+
+        let rows = search
+            .fetch_all(&self.db_pool)
+            .await
+            .context("Failed to search for similar vectors")?;
+
+        let retrieved = rows.len();
+        let min_similarity = self.config.retrieval_min_similarity;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or_default();
+        let mut candidates: Vec<(RetrievedChunk, Vec<f32>)> = rows
+            .into_iter()
+            .map(|row| {
+                let embedding = row
+                    .try_get::<Vector, _>("embedding")
+                    .map(|vector| vector.to_vec())
+                    .unwrap_or_default();
+                let chunk = RetrievedChunk {
+                    content: row.get("content"),
+                    metadata: metadata_from_json(row.try_get("metadata").ok().flatten()),
+                    similarity: row.get::<f64, _>("similarity") as f32,
+                    parent_id: row.get("parent_id"),
+                };
+                let created_epoch: Option<f64> = row.try_get("created_epoch").ok().flatten();
+                (chunk, embedding, created_epoch)
+            })
+            .filter(|(chunk, _, _)| chunk.similarity >= min_similarity)
+            .map(|(mut chunk, embedding, created_epoch)| {
+                if recency_weight > 0.0 {
+                    let created = metadata_date(&chunk.metadata).or(created_epoch);
+                    chunk.similarity =
+                        recency_boosted(chunk.similarity, created, now, recency_weight);
+                }
+                (chunk, embedding)
+            })
+            .collect();
+        let kept = candidates.len();
+
+        if recency_weight > 0.0 {
+            candidates.sort_by(|a, b| {
+                b.0.similarity
+                    .partial_cmp(&a.0.similarity)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+        let chunks = match self.config.mmr_lambda {
+            Some(lambda) => maximal_marginal_relevance(candidates, top_k, lambda),
+            None => candidates
+                .into_iter()
+                .take(top_k)
+                .map(|(chunk, _)| chunk)
+                .collect(),
+        };
+
+        let dropped = retrieved - kept;
+        if dropped > 0 {
+            log::info!(
+                "Dropped {} of {} chunks below similarity {}",
+                dropped,
+                retrieved,
+                min_similarity
+            );
+        }
+
+        log::info!("Retrieved {} relevant chunks", chunks.len());
+        Ok(chunks)
+    }
+
+    /// Generate a response using GPT-4o-mini with retrieved context
+    /// 
+    /// # Arguments
+    /// * `query` - User's question
+    /// * `context_chunks` - Retrieved relevant document chunks
+    /// * `conversation_history` - Previous messages in the conversation
+    /// 
+    /// # Returns
+    /// Generated response from GPT-4o-mini and the tokens it cost
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rag.generate_response",
+            skip_all,
+            fields(
+                chunks = context_chunks.len(),
+                prompt_tokens = tracing::field::Empty,
+                completion_tokens = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty
+            )
+        )
+    )]
+    pub async fn generate_response(
+        &self,
+        query: &str,
+        context_chunks: &[RetrievedChunk],
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+    ) -> Result<GeneratedAnswer> {
+        let _timer = SpanTimer::start();
+        log::info!("Generating response using GPT-4o-mini");
+
+        let messages =
+            self.build_response_messages(query, context_chunks, conversation_history, language);
+
+        // Call OpenAI API
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages,
+            temperature: 0.3, // Low temperature for factual responses
+            max_tokens: 500,
+            stream: false,
+            stream_options: None,
+        };
+
+        let mut answer = self.chat_completion(&request).await?;
+        (answer.answer, answer.refused) = parse_refusal(&answer.answer);
+        telemetry::record("prompt_tokens", answer.usage.prompt_tokens);
+        telemetry::record("completion_tokens", answer.usage.completion_tokens);
+
+        log::info!("Response generated successfully");
+        Ok(answer)
+    }
+
+    /// Build the chat messages for a context-grounded answer:
+    /// system prompt with context, recent history, then the query
+    fn build_response_messages(
+        &self,
+        query: &str,
+        context_chunks: &[RetrievedChunk],
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+    ) -> Vec<ConversationMessage> {
+        // Build context from retrieved chunks
+        let context = format_context(context_chunks);
+
+        // Build system message with instructions
+        let system_message = ConversationMessage {
+            role: "system".to_string(),
+            content: with_language_instruction(
+                format!(
+                    "{}\n\n{}",
+                    render_prompt(
+                        self.config.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT),
+                        &context,
+                    ),
+                    refusal_instruction()
+                ),
+                language,
+            ),
+        };
+
+        // Build messages array: system + history + current query
+        let mut messages = vec![system_message];
+        
+        // Add conversation history (limited to max_conversation_history)
+        let history_start = conversation_history.len()
+            .saturating_sub(self.config.max_conversation_history);
+        messages.extend_from_slice(&conversation_history[history_start..]);
+        
+        // Add current query
+        messages.push(ConversationMessage {
+            role: "user".to_string(),
+            content: query.to_string(),
+        });
+
+        messages
+    }
+
+    /// Streaming variant of [`RAGSystem::generate_response`]
+    /// 
+    /// Returns a stream yielding answer text pieces as GPT produces them.
+    /// An `Err` item means the stream broke off mid-way; nothing is sent
+    /// after it.
+    pub async fn generate_response_streaming(
+        &self,
+        query: &str,
+        context_chunks: &[RetrievedChunk],
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+    ) -> Result<ResponseStream> {
+        log::info!("Generating streamed response using GPT-4o-mini");
+
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: self.build_response_messages(
+                query,
+                context_chunks,
+                conversation_history,
+                language,
+            ),
+            temperature: 0.3, // Low temperature for factual responses
+            max_tokens: 500,
+            stream: true,
+            stream_options: Some(OpenAIStreamOptions { include_usage: true }),
+        };
+
+        let mut response = self
+            .post_with_retry("chat/completions", &request)
+            .await
+            .context("Failed to send chat completion request")?;
+
+        let usage = Arc::clone(&self.usage);
+        let stream_usage = Arc::new(Mutex::new(TokenUsage::default()));
+        let task_usage = Arc::clone(&stream_usage);
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            // Server-sent events: `data: {json}` lines, terminated by `data: [DONE]`.
+            // Buffer raw bytes so multibyte characters split across network
+            // chunks are only decoded once the full line has arrived.
+            let mut buffer: Vec<u8> = Vec::new();
+            loop {
+                let bytes = match response.chunk().await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(anyhow::anyhow!("Response stream failed: {}", e))).await;
+                        return;
+                    }
+                };
+                buffer.extend_from_slice(&bytes);
+
+                while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let Some(data) = line.trim().strip_prefix("data:") else {
+                        continue;
+                    };
+                    let data = data.trim();
+                    if data == "[DONE]" {
+                        break;
+                    }
+
+                    let piece = serde_json::from_str::<OpenAIChatStreamChunk>(data)
+                        .map(|chunk| {
+                            usage.record(chunk.usage.as_ref());
+                            task_usage.lock().unwrap().record(chunk.usage.as_ref());
+                            chunk
+                                .choices
+                                .into_iter()
+                                .filter_map(|c| c.delta.content)
+                                .collect::<String>()
+                        })
+                        .context("Failed to parse streamed chat completion");
+                    let failed = piece.is_err();
+                    if tx.send(piece).await.is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(ResponseStream {
+            pieces: rx,
+            usage: stream_usage,
+        })
+    }
+
+    /// Retrieve ALL documents from database (for comprehensive fallback context)
+    ///
+    /// Takes the newest chunks first and stops before the total would exceed
+    /// `fallback_token_budget` tokens, so the prompt fits the model window
+    /// however large the knowledge base grows.
+    async fn retrieve_all_documents(&self, namespace: &str) -> Result<Vec<String>> {
+        let budget = self.config.fallback_token_budget;
+        log::info!(
+            "Retrieving documents from {} for comprehensive context (budget: {} tokens)",
+            namespace,
+            budget
+        );
+
+        let query = format!(
+            r#"
+            SELECT content FROM {} WHERE {}
+            ORDER BY created_at DESC, id LIMIT $1 OFFSET $2
+            "#,
+            self.config.embeddings_table,
+            live_chunks_in("$3")
+        );
+
+        let tokenizer = tiktoken_rs::o200k_base_singleton();
+        let mut chunks = Vec::new();
+        let mut used_tokens = 0;
+        let mut offset = 0;
+
+        'pages: loop {
+            let rows = sqlx::query(&query)
+                .bind(FALLBACK_PAGE_SIZE)
+                .bind(offset)
+                .bind(namespace)
+                .fetch_all(&self.db_pool)
+                .await
+                .context("Failed to retrieve all documents")?;
+
+            if rows.is_empty() {
+                break;
+            }
+            offset += rows.len() as i64;
+
+            for row in rows {
+                let content: String = row.get("content");
+                let tokens = tokenizer.lock().encode_with_special_tokens(&content).len()
+                    + FALLBACK_SEPARATOR_TOKENS;
+                if used_tokens + tokens > budget {
+                    break 'pages;
+                }
+                used_tokens += tokens;
+                chunks.push(content);
+            }
+        }
+
+        log::info!("Retrieved {} chunks ({} tokens) for context", chunks.len(), used_tokens);
+        Ok(chunks)
+    }
+
+    /// Generate a fallback response using ChatGPT with full knowledge base context
+    /// Used when no relevant information is found via similarity search
+    /// 
+    /// Only documents in `namespace` (default: [`DEFAULT_NAMESPACE`]) are
+    /// sent as context.
+    pub async fn generate_fallback_response(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        namespace: Option<&str>,
+    ) -> Result<GeneratedAnswer> {
+        log::info!("Generating fallback response using ChatGPT with full Pollinet knowledge base");
+
+        // Retrieve all documents for comprehensive context
+        let all_chunks = self.retrieve_all_documents(checked_namespace(namespace)?).await?;
+        
+        // Build comprehensive context
+        let full_context = if all_chunks.is_empty() {
+            "No documents in knowledge base yet.".to_string()
+        } else {
+            all_chunks.join("\n\n---\n\n")
+        };
+
+        // Build system message with full Pollinet knowledge base
+        let system_message = ConversationMessage {
+            role: "system".to_string(),
+            content: with_language_instruction(
+                render_prompt(
+                    self.config.fallback_prompt.as_deref().unwrap_or(DEFAULT_FALLBACK_PROMPT),
+                    &full_context,
+                ),
+                language,
+            ),
+        };
+
+        // Build messages array
+        let mut messages = vec![system_message];
+        
+        // Add conversation history
+        let history_start = conversation_history.len()
+            .saturating_sub(self.config.max_conversation_history);
+        messages.extend_from_slice(&conversation_history[history_start..]);
+        
+        // Add current query
+        messages.push(ConversationMessage {
+            role: "user".to_string(),
+            content: query.to_string(),
+        });
+
+        // Call OpenAI API
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages,
+            temperature: 0.7, // Higher temperature for general responses
+            max_tokens: 500,
+            stream: false,
+            stream_options: None,
+        };
+
+        self.chat_completion(&request).await
+    }
+
+    /// Send a non-streamed chat completion request and return GPT's reply
+    async fn chat_completion(&self, request: &OpenAIChatRequest) -> Result<GeneratedAnswer> {
+        let response = self
+            .post_with_retry("chat/completions", request)
+            .await
+            .context("Failed to send chat completion request")?;
+
+        let response: OpenAIChatResponse = response
+            .json()
+            .await
+            .context("Failed to parse chat completion response")?;
+        self.usage.record(response.usage.as_ref());
+        let mut usage = TokenUsage::default();
+        usage.record(response.usage.as_ref());
+
+        let answer = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message.content)
+            .context("No response from GPT")?;
+
+        Ok(GeneratedAnswer {
+            answer,
+            refused: false,
+            usage,
+        })
+    }
+
+    /// Ask GPT whether every claim in `answer` is supported by `context_chunks`
+    pub async fn check_grounding(
+        &self,
+        answer: &str,
+        context_chunks: &[RetrievedChunk],
+    ) -> Result<GroundingVerdict> {
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: vec![
+                ConversationMessage {
+                    role: "system".to_string(),
+                    content: GROUNDING_CHECK_PROMPT.to_string(),
+                },
+                ConversationMessage {
+                    role: "user".to_string(),
+                    content: format!(
+                        "Context:\n{}\n\nAnswer:\n{}",
+                        format_context(context_chunks),
+                        answer
+                    ),
+                },
+            ],
+            temperature: 0.0,
+            max_tokens: 5,
+            stream: false,
+            stream_options: None,
+        };
+
+        let verdict = self
+            .chat_completion(&request)
+            .await
+            .context("Failed to verify answer grounding")?;
+        let grounded = match verdict.answer.trim().to_uppercase().as_str() {
+            v if v.starts_with("UNSUPPORTED") => false,
+            v if v.starts_with("SUPPORTED") => true,
+            other => anyhow::bail!("Unexpected grounding verdict: {}", other),
+        };
+
+        Ok(GroundingVerdict {
+            grounded,
+            usage: verdict.usage,
+        })
+    }
+
+    /// Ask GPT for up to [`MAX_FOLLOW_UPS`] short follow-up questions to
+    /// `answer`, grounded in the chunks it was generated from
+    pub async fn suggest_follow_ups(
+        &self,
+        query: &str,
+        answer: &str,
+        context_chunks: &[RetrievedChunk],
+    ) -> Result<FollowUps> {
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: vec![
+                ConversationMessage {
+                    role: "system".to_string(),
+                    content: FOLLOW_UP_PROMPT.to_string(),
+                },
+                ConversationMessage {
+                    role: "user".to_string(),
+                    content: format!(
+                        "Context:\n{}\n\nQuestion:\n{}\n\nAnswer:\n{}",
+                        format_context(context_chunks),
+                        query,
+                        answer
+                    ),
+                },
+            ],
+            temperature: 0.7,
+            max_tokens: 120,
+            stream: false,
+            stream_options: None,
+        };
+
+        let suggestions = self
+            .chat_completion(&request)
+            .await
+            .context("Failed to suggest follow-up questions")?;
+
+        Ok(FollowUps {
+            questions: parse_follow_ups(&suggestions.answer),
+            usage: suggestions.usage,
+        })
+    }
+
+    /// [`Self::check_grounding`] when `ENABLE_GROUNDING_CHECK` is set
+    ///
+    /// Answers count as grounded when the check is disabled or fails, so a
+    /// verifier outage never hides a real answer.
+    pub async fn verify_grounding(
+        &self,
+        answer: &str,
+        context_chunks: &[RetrievedChunk],
+    ) -> GroundingVerdict {
+        let grounded = GroundingVerdict {
+            grounded: true,
+            usage: TokenUsage::default(),
+        };
+        if !self.config.enable_grounding_check {
+            return grounded;
+        }
+
+        match self.check_grounding(answer, context_chunks).await {
+            Ok(verdict) => {
+                if !verdict.grounded {
+                    log::info!("Answer failed the grounding check");
+                }
+                verdict
+            }
+            Err(e) => {
+                log::warn!("Grounding check failed, keeping the answer: {:#}", e);
+                grounded
+            }
+        }
+    }
+
+    /// Rewrite the latest user message into a self-contained question
+    ///
+    /// Follow-ups like "what about the fee for that?" carry no retrieval
+    /// signal on their own; the prior turns resolve what "that" refers to.
+    pub async fn rewrite_query(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+    ) -> Result<GeneratedAnswer> {
+        let history_start = conversation_history.len()
+            .saturating_sub(self.config.max_conversation_history);
+        let transcript = conversation_history[history_start..]
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: vec![
+                ConversationMessage {
+                    role: "system".to_string(),
+                    content: QUERY_REWRITE_PROMPT.to_string(),
+                },
+                ConversationMessage {
+                    role: "user".to_string(),
+                    content: format!("Conversation:\n{}\n\nLatest message: {}", transcript, query),
+                },
+            ],
+            temperature: 0.0,
+            max_tokens: 100,
+            stream: false,
+            stream_options: None,
+        };
+
+        let mut rewritten = self.chat_completion(&request).await?;
+        rewritten.answer = rewritten.answer.trim().to_string();
+        if rewritten.answer.is_empty() {
+            anyhow::bail!("GPT returned an empty rewritten query");
+        }
+        Ok(rewritten)
+    }
+
+    /// The text to embed for retrieval: `query` rewritten to stand alone when
+    /// `ENABLE_QUERY_REWRITE` is set and there is history, otherwise `query`
+    ///
+    /// Rewrite failures fall back to the original query.
+    pub async fn retrieval_query(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+    ) -> GeneratedAnswer {
+        let original = GeneratedAnswer {
+            answer: query.to_string(),
+            ..GeneratedAnswer::default()
+        };
+        if !self.config.enable_query_rewrite || conversation_history.is_empty() {
+            return original;
+        }
+
+        match self.rewrite_query(query, conversation_history).await {
+            Ok(rewritten) => {
+                log::info!("Rewrote query for retrieval: {:?} -> {:?}", query, rewritten.answer);
+                rewritten
+            }
+            Err(e) => {
+                log::warn!("Query rewrite failed, retrieving with the original query: {:#}", e);
+                original
+            }
+        }
+    }
+
+    /// Name the language `query` is written in (e.g. "Spanish")
+    pub async fn detect_language(&self, query: &str) -> Result<GeneratedAnswer> {
+        let request = OpenAIChatRequest {
+            model: self.config.gpt_model.clone(),
+            messages: vec![
+                ConversationMessage {
+                    role: "system".to_string(),
+                    content: LANGUAGE_DETECTION_PROMPT.to_string(),
+                },
+                ConversationMessage {
+                    role: "user".to_string(),
+                    content: query.to_string(),
+                },
+            ],
+            temperature: 0.0,
+            max_tokens: 10,
+            stream: false,
+            stream_options: None,
+        };
+
+        let mut detected = self.chat_completion(&request).await?;
+        detected.answer = detected
+            .answer
+            .trim()
+            .trim_end_matches('.')
+            .to_string();
+        if detected.answer.is_empty() || detected.answer.len() > MAX_LANGUAGE_NAME_LEN {
+            anyhow::bail!("GPT returned no usable language name: {:?}", detected.answer);
+        }
+        Ok(detected)
+    }
+
+    /// The language to answer `query` in: the chat's `chat_language` setting
+    /// if any, else the detected language when `ENABLE_MULTILINGUAL` is set
+    ///
+    /// Detection failures leave the language to the model.
+    pub async fn answer_language(&self, query: &str, chat_language: Option<&str>) -> AnswerLanguage {
+        if let Some(language) = chat_language {
+            return AnswerLanguage {
+                language: Some(language.to_string()),
+                usage: TokenUsage::default(),
+            };
+        }
+        if !self.config.enable_multilingual {
+            return AnswerLanguage::default();
+        }
+
+        match self.detect_language(query).await {
+            Ok(detected) => {
+                log::info!("Detected query language: {}", detected.answer);
+                AnswerLanguage {
+                    language: Some(detected.answer),
+                    usage: detected.usage,
+                }
+            }
+            Err(e) => {
+                log::warn!("Language detection failed, not forcing an answer language: {:#}", e);
+                AnswerLanguage::default()
+            }
+        }
+    }
+
+    /// A cached answer to `query` in this conversation, if one is still fresh
+    /// 
+    /// Cached results report no token usage, since answering them was free.
+    pub fn cached_answer(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        top_k: Option<usize>,
+        namespace: Option<&str>,
+    ) -> Option<QueryResult> {
+        let cache = self.answer_cache.as_ref()?;
+        let key = self.answer_cache_key(query, conversation_history, language, top_k, namespace);
+        let mut cache = cache.lock().unwrap();
+
+        let ttl = Duration::from_secs(self.config.answer_cache_ttl_secs);
+        let (cached_at, result) = cache.get(&key)?;
+        if cached_at.elapsed() >= ttl {
+            cache.pop(&key);
+            return None;
+        }
+
+        log::info!("Answer cache hit");
+        Some(QueryResult {
+            usage: TokenUsage::default(),
+            ..result.clone()
+        })
+    }
+
+    /// Remember `result` as the answer to `query` in this conversation
+    pub fn cache_answer(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        top_k: Option<usize>,
+        namespace: Option<&str>,
+        result: &QueryResult,
+    ) {
+        if let Some(cache) = &self.answer_cache {
+            let key = self.answer_cache_key(query, conversation_history, language, top_k, namespace);
+            cache.lock().unwrap().put(key, (Instant::now(), result.clone()));
+        }
+    }
+
+    /// Drop every cached answer (the knowledge base changed)
+    pub fn clear_answer_cache(&self) {
+        if let Some(cache) = &self.answer_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Hash of the normalized query, answer language, chunk count, namespace
+    /// and the history window sent to GPT
+    fn answer_cache_key(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        top_k: Option<usize>,
+        namespace: Option<&str>,
+    ) -> String {
+        let history_start = conversation_history.len()
+            .saturating_sub(self.config.max_conversation_history);
+        let mut key = normalize_query(query);
+        if let Some(language) = language {
+            key.push_str(&format!("\nlanguage: {}", language.to_lowercase()));
+        }
+        if let Some(top_k) = top_k {
+            key.push_str(&format!("\ntop_k: {}", top_k));
+        }
+        if let Some(namespace) = namespace.filter(|namespace| *namespace != DEFAULT_NAMESPACE) {
+            key.push_str(&format!("\nnamespace: {}", namespace));
+        }
+        for message in &conversation_history[history_start..] {
+            key.push_str(&format!("\n{}: {}", message.role, message.content));
+        }
+        content_hash(&key)
+    }
+
+    /// Main query method that combines retrieval and generation
+    /// 
+    /// # Arguments
+    /// * `query` - User's question
+    /// * `conversation_history` - Previous messages
+    /// 
+    /// # Returns
+    /// Generated answer based on retrieved context or fallback to general ChatGPT
+    pub async fn query(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+    ) -> Result<String> {
+        Ok(self.query_detailed(query, conversation_history, None, None).await?.answer)
+    }
+
+    /// Same as [`RAGSystem::query`], but also reports where the answer came from
+    /// 
+    /// `top_k` overrides `top_k_chunks` for this query only; see
+    /// [`RAGSystem::retrieve_relevant_chunks_with_k`]. `namespace` picks the
+    /// knowledge base searched (default: [`DEFAULT_NAMESPACE`]).
+    pub async fn query_detailed(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        top_k: Option<usize>,
+        namespace: Option<&str>,
+    ) -> Result<QueryResult> {
+        self.query_in_language(query, conversation_history, None, top_k, namespace)
+            .await
+    }
+
+    /// [`RAGSystem::query_detailed`] answering in `language` (e.g. a chat's
+    /// language setting) when given, or else in the query's detected language
+    /// when `ENABLE_MULTILINGUAL` is set
+    pub async fn query_in_language(
+        &self,
+        query: &str,
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        top_k: Option<usize>,
+        namespace: Option<&str>,
+    ) -> Result<QueryResult> {
+        let top_k = top_k.map(checked_top_k).transpose()?;
+        let namespace = checked_namespace(namespace)?;
+        if let Some(cached) =
+            self.cached_answer(query, conversation_history, language, top_k, Some(namespace))
+        {
+            return Ok(cached);
+        }
+
+        let answer_language = self.answer_language(query, language).await;
+
+        // Step 1: Retrieve relevant chunks (with a standalone rewrite of
+        // follow-ups if enabled; the original query is still what gets answered)
+        let retrieval_query = self.retrieval_query(query, conversation_history).await;
+        let chunks = self
+            .retrieve_chunks(
+                &retrieval_query.answer,
+                &HashMap::new(),
+                top_k.unwrap_or(self.config.top_k_chunks),
+                namespace,
+            )
+            .await?;
+
+        let mut result = self
+            .answer_from_chunks(
+                query,
+                &chunks,
+                conversation_history,
+                answer_language.language.as_deref(),
+                Some(namespace),
+            )
+            .await?;
+        result.usage = retrieval_query
+            .usage
+            .combined(answer_language.usage)
+            .combined(result.usage);
+        self.cache_answer(query, conversation_history, language, top_k, Some(namespace), &result);
+        Ok(result)
+    }
+
+    /// Answer a query from chunks that were already retrieved for it
+    /// 
+    /// Lets callers that retrieved chunks themselves (e.g. the streaming
+    /// path) fall back without embedding the query a second time; the
+    /// fallback uses the documents in `namespace`.
+    pub async fn answer_from_chunks(
+        &self,
+        query: &str,
+        chunks: &[RetrievedChunk],
+        conversation_history: &[ConversationMessage],
+        language: Option<&str>,
+        namespace: Option<&str>,
+    ) -> Result<QueryResult> {
+        let scores: Vec<f32> = chunks.iter().map(|chunk| chunk.similarity).collect();
+
+        // Step 2: Check if we have relevant context
+        if chunks.is_empty() {
+            log::info!("No relevant chunks found, using ChatGPT fallback with full knowledge base");
+            
+            // Use ChatGPT with full knowledge base as fallback
+            let fallback = self
+                .generate_fallback_response(query, conversation_history, language, namespace)
+                .await?;
+            
+            return Ok(QueryResult::fallback(fallback.answer, scores, fallback.usage));
+        }
+
+        // Step 3: Generate response with context from knowledge base
+        let response = self
+            .generate_response(query, chunks, conversation_history, language)
+            .await?;
+
+        // Check if GPT said it doesn't know, or claimed things the context doesn't support
+        let mut usage = response.usage;
+        let refused = response.refused || {
+            let verdict = self.verify_grounding(&response.answer, chunks).await;
+            usage = usage.combined(verdict.usage);
+            !verdict.grounded
+        };
+        if refused {
+            log::info!("GPT couldn't answer from context, using ChatGPT fallback with full knowledge base");
+            
+            // Use ChatGPT with full knowledge base as fallback
+            let fallback = self
+                .generate_fallback_response(query, conversation_history, language, namespace)
+                .await?;
+            let usage = usage.combined(fallback.usage);
+            
+            return Ok(QueryResult::fallback(fallback.answer, scores, usage));
+        }
+
+        Ok(QueryResult {
+            answer: response.answer,
+            sources: source_names(chunks),
+            used_fallback: false,
+            scores,
+            usage,
+            chunks: chunks.to_vec(),
+        })
+    }
+}
+
+/// File extension Whisper expects for an audio MIME type (it detects the
+/// format from the file name)
+fn audio_extension(mime: &str) -> &'static str {
+    match mime.split(';').next().unwrap_or_default().trim() {
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => "m4a",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/webm" => "webm",
+        "audio/flac" => "flac",
+        // Telegram voice notes are Opus in an Ogg container
+        _ => "ogg",
+    }
+}
+
+/// "Document: ...\nSection: ...\n\n" header giving a chunk its place in the
+/// knowledge base
+fn contextual_header(document_name: &str, metadata: &HashMap<String, String>) -> String {
+    let mut header = format!("Document: {}\n", document_name);
+    if let Some(section) = metadata.get("section").or_else(|| metadata.get("heading")) {
+        header.push_str(&format!("Section: {}\n", section));
+    }
+    header.push('\n');
+    header
+}
+
+/// Replace each chunk's content with its parent's, keeping only the first
+/// (most similar) chunk per parent
+fn with_parent_content(
+    chunks: Vec<RetrievedChunk>,
+    parents: &HashMap<String, String>,
+) -> Vec<RetrievedChunk> {
+    let mut seen = HashSet::new();
+    chunks
+        .into_iter()
+        .filter_map(|mut chunk| {
+            let Some((parent_id, parent)) = chunk
+                .parent_id
+                .as_ref()
+                .and_then(|id| parents.get_key_value(id))
+            else {
+                return Some(chunk);
+            };
+            if !seen.insert(parent_id) {
+                return None;
+            }
+            chunk.content = parent.clone();
+            Some(chunk)
+        })
+        .collect()
+}
+
+/// Append a "respond in `language`" instruction to a system prompt
+fn with_language_instruction(prompt: String, language: Option<&str>) -> String {
+    match language {
+        Some(language) => format!(
+            "{}\n\nAlways write your answer in {}, whatever language the context is in.",
+            prompt, language
+        ),
+        None => prompt,
+    }
+}
+
+/// Whether `database_url` points at a connection pooler (pgBouncer / Supabase pooler port)
+pub(crate) fn uses_connection_pooler(database_url: &str) -> bool {
+    database_url.contains(":6543") || database_url.contains("pgbouncer=true")
+}
+
+/// Fail with an actionable message when the stored and configured
+/// embedding dimensions differ
+fn check_embedding_dimensions(existing: i32, configured: usize) -> Result<()> {
+    if existing as usize != configured {
+        anyhow::bail!(
+            "Embedding column has {} dimensions but EMBEDDING_DIMENSIONS is {}; \
+            set EMBEDDING_DIMENSIONS to match EMBEDDING_MODEL, or re-embed the \
+            knowledge base via POST /admin/reembed",
+            existing,
+            configured
+        );
+    }
+    Ok(())
+}
+
+/// pgvector operator ordering rows by `metric` (smallest = most similar)
+fn distance_operator(metric: DistanceMetric) -> &'static str {
+    match metric {
+        DistanceMetric::Cosine => "<=>",
+        DistanceMetric::InnerProduct => "<#>",
+        DistanceMetric::L2 => "<->",
+    }
+}
+
+/// pgvector operator class an index needs to serve [`distance_operator`]
+fn index_opclass(metric: DistanceMetric) -> &'static str {
+    match metric {
+        DistanceMetric::Cosine => "vector_cosine_ops",
+        DistanceMetric::InnerProduct => "vector_ip_ops",
+        DistanceMetric::L2 => "vector_l2_ops",
+    }
+}
+
+/// SQL similarity between `embedding` and the vector bound to `param`
+///
+/// Every metric is mapped onto cosine similarity for normalized embeddings
+/// (`<#>` is the negative inner product, and cos = 1 - d²/2 for L2), so
+/// `RETRIEVAL_MIN_SIMILARITY` and `DEDUP_SIMILARITY` mean the same thing
+/// whichever metric orders the search.
+fn similarity_sql(metric: DistanceMetric, param: &str) -> String {
+    match metric {
+        DistanceMetric::Cosine => format!("1 - (embedding <=> {})", param),
+        DistanceMetric::InnerProduct => format!("-(embedding <#> {})", param),
+        DistanceMetric::L2 => format!("1 - (embedding <-> {})^2 / 2", param),
+    }
+}
+
+/// SQL condition selecting the chunks retrieval may see: those in the
+/// namespace bound to `namespace_param` that haven't been soft-deleted
+fn live_chunks_in(namespace_param: &str) -> String {
+    format!("namespace = {} AND deleted_at IS NULL", namespace_param)
+}
+
+/// Nearest-neighbour search over live chunks with the configured distance
+///
+/// Binds `$1` = query embedding, `$2` = row limit, `$3` = namespace and,
+/// when `filtered`, `$4` = JSONB metadata the chunks must contain. With
+/// `with_embeddings` the stored embeddings are selected too (for MMR).
+fn vector_search_sql(table: &str, metric: DistanceMetric, filtered: bool, with_embeddings: bool) -> String {
+    format!(
+        r#"
+        SELECT content, metadata, parent_id, ({})::float8 AS similarity,
+               EXTRACT(EPOCH FROM created_at)::float8 AS created_epoch{}
+        FROM {}
+        WHERE {} {}
+        ORDER BY embedding {} $1
+        LIMIT $2
+        "#,
+        similarity_sql(metric, "$1"),
+        if with_embeddings { ", embedding" } else { "" },
+        table,
+        live_chunks_in("$3"),
+        if filtered { "AND metadata @> $4" } else { "" },
+        distance_operator(metric)
+    )
+}
+
+/// Fail with an actionable message when an existing vector index was built
+/// for a different distance than `DISTANCE_METRIC`
+fn check_index_metric(existing_opclasses: &[String], metric: DistanceMetric) -> Result<()> {
+    let expected = index_opclass(metric);
+    if let Some(other) = existing_opclasses.iter().find(|opclass| *opclass != expected) {
+        anyhow::bail!(
+            "The vector index uses {} but DISTANCE_METRIC needs {}; set DISTANCE_METRIC \
+            to match, or drop the index so it is rebuilt on startup",
+            other,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Candidates fetched per requested chunk when MMR re-ranking is enabled
+const MMR_CANDIDATE_MULTIPLIER: usize = 4;
+
+/// Candidates fetched per requested chunk when the recency boost is on, so
+/// newer chunks ranked just below the cut can move up
+const RECENCY_CANDIDATE_MULTIPLIER: usize = 4;
+
+/// Age at which a chunk's recency boost has halved
+const RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+
+/// Blend `similarity` with an exponential decay on the chunk's age
+/// 
+/// Chunks without a date keep their plain similarity.
+fn recency_boosted(similarity: f32, created: Option<f64>, now: f64, weight: f32) -> f32 {
+    let Some(created) = created else {
+        return similarity;
+    };
+    let age_days = (now - created).max(0.0) / 86_400.0;
+    let decay = 0.5_f64.powf(age_days / RECENCY_HALF_LIFE_DAYS) as f32;
+    (1.0 - weight) * similarity + weight * decay
+}
+
+/// Unix timestamp of a chunk's `created_at` metadata (RFC 3339, Twitter's
+/// `Wed Oct 10 20:19:24 +0000 2018` format, or a bare `YYYY-MM-DD`)
+fn metadata_date(metadata: &HashMap<String, String>) -> Option<f64> {
+    let value = metadata.get("created_at")?.trim();
+    let timestamp = chrono::DateTime::parse_from_rfc3339(value)
+        .or_else(|_| chrono::DateTime::parse_from_str(value, "%a %b %d %H:%M:%S %z %Y"))
+        .map(|date| date.timestamp())
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp())
+        })
+        .ok()?;
+    Some(timestamp as f64)
+}
+
+/// Greedily pick `limit` chunks by maximal marginal relevance
+/// 
+/// Each step takes the candidate maximising
+/// `lambda * sim(query) - (1 - lambda) * max sim(already selected)`,
+/// so near-duplicate passages don't crowd out the rest of the context.
+fn maximal_marginal_relevance(
+    mut candidates: Vec<(RetrievedChunk, Vec<f32>)>,
+    limit: usize,
+    lambda: f32,
+) -> Vec<RetrievedChunk> {
+    let mut selected: Vec<(RetrievedChunk, Vec<f32>)> = Vec::with_capacity(limit);
+
+    while selected.len() < limit && !candidates.is_empty() {
+        let mut best_index = 0;
+        let mut best_score = f32::NEG_INFINITY;
+
+        for (index, (chunk, embedding)) in candidates.iter().enumerate() {
+            let redundancy = selected
+                .iter()
+                .map(|(_, chosen)| cosine_similarity(embedding, chosen))
+                .fold(0.0_f32, f32::max);
+            let score = lambda * chunk.similarity - (1.0 - lambda) * redundancy;
+            if score > best_score {
+                best_score = score;
+                best_index = index;
+            }
+        }
+
+        selected.push(candidates.remove(best_index));
+    }
+
+    selected.into_iter().map(|(chunk, _)| chunk).collect()
+}
+
+/// Cosine similarity of two vectors (0 when either is empty or zero)
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// How an API key is attached to OpenAI-style requests
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ApiAuth<'a> {
+    /// No credentials (e.g. a local model server)
+    None,
+    /// `Authorization: Bearer <key>` (OpenAI and most compatible servers)
+    Bearer(&'a str),
+    /// `api-key: <key>` (Azure OpenAI)
+    AzureApiKey(&'a str),
+}
+
+impl ApiAuth<'_> {
+    pub(crate) fn apply(self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            ApiAuth::None => request,
+            ApiAuth::Bearer(key) => request.header("Authorization", format!("Bearer {}", key)),
+            ApiAuth::AzureApiKey(key) => request.header("api-key", key),
+        }
+    }
+}
+
+/// Join an API base URL and an endpoint path, keeping any query string on
+/// the base (e.g. Azure's `?api-version=...`) at the end
+pub(crate) fn api_url(base: &str, path: &str) -> String {
+    let (root, query) = match base.split_once('?') {
+        Some((root, query)) => (root, Some(query)),
+        None => (base, None),
+    };
+    let url = format!("{}/{}", root.trim_end_matches('/'), path.trim_start_matches('/'));
+    match query {
+        Some(query) => format!("{}?{}", url, query),
+        None => url,
+    }
+}
+
+/// List the model IDs behind an OpenAI-style API's `GET /models`
+///
+/// A 401 gets its own message, since it almost always means a bad API key.
+pub(crate) async fn list_models(
+    client: &reqwest::Client,
+    base_url: &str,
+    auth: ApiAuth<'_>,
+) -> Result<Vec<String>> {
+    let request = client.get(api_url(base_url, "models"));
+    let response = auth
+        .apply(request)
+        .send()
+        .await
+        .context("Failed to send models request")?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(anyhow::anyhow!(
+            "OpenAI rejected the API key (401 Unauthorized); check OPENAI_API_KEY"
+        ));
+    }
+    if !status.is_success() {
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unable to read error response".to_string());
+        return Err(anyhow::anyhow!(
+            "OpenAI API error (status {}): {}",
+            status,
+            error_text
+        ));
+    }
+
+    let response: OpenAIModelsResponse = response
+        .json()
+        .await
+        .context("Failed to parse models response")?;
+
+    Ok(response.data.into_iter().map(|m| m.id).collect())
+}
+
+/// POST a JSON body to an OpenAI-style API, retrying transient failures
+///
+/// Network errors, 429s and 5xx responses are retried up to
+/// `max_retries` times with exponential backoff (honoring
+/// `Retry-After` on 429). Other error statuses fail immediately.
+///
+/// With a `breaker`, nothing is sent while its circuit is open (the error
+/// is a [`crate::circuit_breaker::CircuitOpen`]), and requests that still fail after their
+/// retries count towards opening it.
+///
+/// # Returns
+/// The first successful response
+pub(crate) async fn post_with_retry<T: Serialize + ?Sized>(
+    http_client: &reqwest::Client,
+    url: &str,
+    auth: ApiAuth<'_>,
+    max_retries: u32,
+    breaker: Option<&CircuitBreaker>,
+    body: &T,
+) -> Result<reqwest::Response> {
+    send_with_retry(auth, max_retries, breaker, || Ok(http_client.post(url).json(body))).await
+}
+
+/// Send a request built by `build`, with the retry and circuit breaker
+/// policy of [`post_with_retry`]
+///
+/// `build` runs once per attempt, so bodies that `send` consumes (such as
+/// multipart forms) are rebuilt for every retry.
+async fn send_with_retry(
+    auth: ApiAuth<'_>,
+    max_retries: u32,
+    breaker: Option<&CircuitBreaker>,
+    build: impl Fn() -> Result<reqwest::RequestBuilder>,
+) -> Result<reqwest::Response> {
+    if let Some(breaker) = breaker {
+        breaker.check()?;
+    }
+    let mut attempt = 0;
+
+    loop {
+        let result = auth.apply(build()?).send().await;
+
+        let backoff = Duration::from_millis(500 * 2u64.pow(attempt.min(6)));
+        let retry_delay = match result {
+            Ok(response) if response.status().is_success() => {
+                if let Some(breaker) = breaker {
+                    breaker.record_success();
+                }
+                return Ok(response);
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                if !retryable || attempt >= max_retries {
+                    // A 4xx other than 429 means OpenAI itself is up
+                    if let Some(breaker) = breaker {
+                        if retryable {
+                            breaker.record_failure();
+                        } else {
+                            breaker.record_success();
+                        }
+                    }
+                    let error_text = response
+                        .text()
+                        .await
+                        .unwrap_or_else(|_| "Unable to read error response".to_string());
+                    METRICS.record_openai_error();
+                    return Err(anyhow::anyhow!(
+                        "OpenAI API error (status {}): {}",
+                        status,
+                        error_text
+                    ));
+                }
+
+                log::warn!("OpenAI returned {}, retrying (attempt {})", status, attempt + 1);
+                response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or(backoff)
+            }
+            Err(e) if attempt < max_retries => {
+                log::warn!("OpenAI request failed: {}, retrying (attempt {})", e, attempt + 1);
+                backoff
+            }
+            Err(e) => {
+                METRICS.record_openai_error();
+                if let Some(breaker) = breaker {
+                    breaker.record_failure();
+                }
+                return Err(e.into());
+            }
+        };
+
+        tokio::time::sleep(retry_delay).await;
+        attempt += 1;
+    }
+}
+
+/// Smoothing constant for reciprocal rank fusion (the value from the original paper)
+const RRF_K: f32 = 60.0;
+
+/// Merge ranked chunk lists with reciprocal rank fusion
+/// 
+/// Each chunk scores `sum(1 / (RRF_K + rank))` over the lists it appears in;
+/// chunks are matched by content and keep their first-seen similarity.
+fn reciprocal_rank_fusion(lists: Vec<Vec<RetrievedChunk>>, limit: usize) -> Vec<RetrievedChunk> {
+    let mut fused: Vec<(RetrievedChunk, f32)> = Vec::new();
+
+    for list in lists {
+        for (rank, chunk) in list.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match fused.iter_mut().find(|(existing, _)| existing.content == chunk.content) {
+                Some((_, total)) => *total += score,
+                None => fused.push((chunk, score)),
+            }
+        }
+    }
+
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    fused.into_iter().take(limit).map(|(chunk, _)| chunk).collect()
+}
+
+/// Split GPT's follow-up suggestions into questions: one per line, with
+/// list markers and quotes stripped, duplicates and overlong lines dropped
+pub fn parse_follow_ups(text: &str) -> Vec<String> {
+    let mut questions: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim().trim_start_matches(['-', '*', '•']);
+        // "1." / "2)" numbering, but not a question starting with a number
+        let unnumbered = line.trim_start_matches(|c: char| c.is_ascii_digit());
+        let line = match unnumbered.strip_prefix(['.', ')']) {
+            Some(rest) if unnumbered.len() < line.len() => rest,
+            _ => line,
+        };
+        let question = line.trim_matches(|c: char| c == '"' || c == '\'' || c.is_whitespace());
+        if question.is_empty() || question.chars().count() > MAX_FOLLOW_UP_CHARS {
+            continue;
+        }
+        if !questions.iter().any(|q| q.eq_ignore_ascii_case(question)) {
+            questions.push(question.to_string());
+        }
+    }
+    questions.truncate(MAX_FOLLOW_UPS);
+    questions
+}
+
+/// Write one export record as a JSON line
+fn write_export_record<W: std::io::Write>(writer: &mut W, record: &ExportRecord) -> Result<()> {
+    serde_json::to_writer(&mut *writer, record).context("Failed to serialize export record")?;
+    writer
+        .write_all(b"\n")
+        .context("Failed to write knowledge base export")
+}
+
+/// Validate a namespace name, defaulting to [`DEFAULT_NAMESPACE`]
+///
+/// Names are 1-64 ASCII letters, digits, `-` or `_`.
+pub fn checked_namespace(namespace: Option<&str>) -> Result<&str> {
+    let Some(namespace) = namespace else {
+        return Ok(DEFAULT_NAMESPACE);
+    };
+    if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+        anyhow::bail!("namespace must be 1-{} characters long", MAX_NAMESPACE_LEN);
+    }
+    if !namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        anyhow::bail!(
+            "namespace '{}' may only contain letters, digits, '-' and '_'",
+            namespace
+        );
+    }
+    Ok(namespace)
+}
+
+/// Chunk or parent id of `id` in `namespace`; ids in the default namespace
+/// keep their pre-namespace form so existing rows still match
+fn namespaced_id(namespace: &str, id: String) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        id
+    } else {
+        format!("{}:{}", namespace, id)
+    }
+}
+
+/// Validate a per-query chunk count, capping it at [`MAX_TOP_K`]
+fn checked_top_k(k: usize) -> Result<usize> {
+    if k == 0 {
+        anyhow::bail!("top_k must be at least 1");
+    }
+    if k > MAX_TOP_K {
+        log::info!("Capping requested top_k {} at {}", k, MAX_TOP_K);
+    }
+    Ok(k.min(MAX_TOP_K))
+}
+
+/// Clean text before it is chunked or embedded
+///
+/// Drops zero-width and control characters (keeping newlines), normalizes
+/// Unicode to NFC and line endings to `\n`, collapses runs of spaces and
+/// tabs, trims every line and keeps at most one blank line between
+/// paragraphs. Applying it twice gives the same result.
+pub fn normalize_text(text: &str) -> String {
+    let cleaned: String = text
+        .replace("\r\n", "\n")
+        .chars()
+        .map(|c| if c == '\r' { '\n' } else { c })
+        .filter(|c| *c == '\n' || *c == '\t' || !(c.is_control() || is_zero_width(*c)))
+        .nfc()
+        .collect();
+
+    let mut normalized = String::with_capacity(cleaned.len());
+    let mut blank_lines = 0;
+    for line in cleaned.split('\n') {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+        if !normalized.is_empty() {
+            normalized.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+        normalized.push_str(&line);
+        blank_lines = 0;
+    }
+    normalized
+}
+
+/// Invisible formatting characters that tweets and web pages are full of
+fn is_zero_width(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}')
+}
+
+/// Lowercase, collapse whitespace and drop trailing punctuation, so trivially
+/// different phrasings of a question share an answer cache entry
+fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['?', '!', '.'])
+        .to_lowercase()
+}
+
+/// Number the chunks as `[Context N]` sections for a prompt
+fn format_context(chunks: &[RetrievedChunk]) -> String {
+    if chunks.is_empty() {
+        return "No relevant information found in the knowledge base.".to_string();
+    }
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("[Context {}]\n{}", i + 1, chunk.content))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Fill a prompt template's `{context}` placeholder
+fn render_prompt(template: &str, context: &str) -> String {
+    template.replace("{context}", context)
+}
+
+/// Exact reply GPT is told to give when the context doesn't cover a question
+///
+/// A token rather than prose, so paraphrased refusals are still caught and
+/// answers that merely quote "I don't have that information" are not.
+pub const REFUSAL_SENTINEL: &str = "[[NO_ANSWER]]";
+
+/// Appended to every answer prompt, so custom `SYSTEM_PROMPT`s refuse the same way
+fn refusal_instruction() -> String {
+    format!(
+        "If the context does not contain the answer, reply with {} and nothing else.",
+        REFUSAL_SENTINEL
+    )
+}
+
+/// Whether GPT answered that the retrieved context doesn't cover the question
+pub fn is_refusal(response: &str) -> bool {
+    response.contains(REFUSAL_SENTINEL)
+}
+
+/// Split a raw answer into its text (sentinel removed) and whether GPT refused
+pub fn parse_refusal(response: &str) -> (String, bool) {
+    if is_refusal(response) {
+        (response.replace(REFUSAL_SENTINEL, "").trim().to_string(), true)
+    } else {
+        (response.to_string(), false)
+    }
+}
+
+/// Flatten a chunk's metadata JSON into string pairs
+///
+/// NULL or non-object metadata yields an empty map; non-string values are
+/// kept in their JSON form.
+fn metadata_from_json(value: Option<serde_json::Value>) -> HashMap<String, String> {
+    match value {
+        Some(serde_json::Value::Object(map)) => map
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(s) => (key, s),
+                other => (key, other.to_string()),
+            })
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+/// Distinct document (or source) names behind the chunks, in retrieval order
+pub fn source_names(chunks: &[RetrievedChunk]) -> Vec<String> {
+    let mut sources: Vec<String> = Vec::new();
+    for chunk in chunks {
+        let name = chunk
+            .metadata
+            .get("document")
+            .or_else(|| chunk.metadata.get("source"));
+        if let Some(name) = name {
+            if !sources.contains(name) {
+                sources.push(name.clone());
+            }
+        }
+    }
+    sources
+}
+
+/// Hex-encoded SHA-256 of a piece of content
+fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Split text into sentences ending in `.`, `!` or `?` followed by whitespace
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((idx, c)) = chars.next() {
+        let at_boundary = matches!(c, '.' | '!' | '?')
+            && chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        if at_boundary {
+            let end = idx + c.len_utf8();
+            let sentence = text[start..end].trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+            start = end;
+        }
+    }
+
+    let rest = text[start..].trim();
+    if !rest.is_empty() {
+        sentences.push(rest);
+    }
+
+    sentences
+}
+
+/// Read the files in `dir` with one of `extensions`, in path order, naming
+/// each by its path relative to `root` without the extension
+fn collect_text_files(
+    root: &Path,
+    dir: &Path,
+    extensions: &[&str],
+    recursive: bool,
+    documents: &mut Vec<SeedDocument>,
+) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.path());
+
+    for entry in entries {
+        let file_path = entry.path();
+        if file_path.is_dir() {
+            if recursive {
+                collect_text_files(root, &file_path, extensions, recursive, documents)?;
+            }
+            continue;
+        }
+
+        let has_extension = file_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| extensions.iter().any(|wanted| wanted.eq_ignore_ascii_case(ext)));
+        if !file_path.is_file() || !has_extension {
+            continue;
+        }
+
+        let name = file_path
+            .strip_prefix(root)
+            .unwrap_or(&file_path)
+            .with_extension("")
+            .to_str()
+            .context("File name is not valid UTF-8")?
+            .replace(std::path::MAIN_SEPARATOR, "/");
+        let content = std::fs::read_to_string(&file_path)
+            .with_context(|| format!("Failed to read {}", file_path.display()))?;
+
+        documents.push(SeedDocument {
+            name,
+            content,
+            metadata: HashMap::new(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Read seed documents from a JSONL file, or from `.md`/`.txt` files in a directory
+///
+/// For directories the file name (without extension) becomes the document name.
+fn load_seed_documents(path: &Path) -> Result<Vec<SeedDocument>> {
+    if path.is_dir() {
+        let mut documents = Vec::new();
+        collect_text_files(path, path, &["md", "txt"], false, &mut documents)?;
+        return Ok(documents);
+    }
+
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read seed file {}", path.display()))?;
+
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid seed document on line {}", idx + 1))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A fresh directory under the system temp dir, unique to this process
+    /// and `name`
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("pollinet-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn seed_documents_load_from_jsonl() {
+        let path = temp_dir("seed-jsonl").join("seed.jsonl");
+        std::fs::write(
+            &path,
+            "{\"name\": \"intro\", \"content\": \"Pollinet basics\"}\n\n\
+             {\"name\": \"faq\", \"content\": \"Answers\", \"metadata\": {\"category\": \"faq\"}}\n",
+        )
+        .unwrap();
+
+        let documents = load_seed_documents(&path).unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].name, "intro");
+        assert!(documents[0].metadata.is_empty());
+        assert_eq!(documents[1].metadata["category"], "faq");
+    }
+
+    #[test]
+    fn text_files_are_named_by_relative_path() {
+        let dir = temp_dir("text-files");
+        std::fs::create_dir_all(dir.join("guides")).unwrap();
+        std::fs::write(dir.join("intro.md"), "# Pollinet").unwrap();
+        std::fs::write(dir.join("guides").join("setup.TXT"), "Install the SDK").unwrap();
+        std::fs::write(dir.join("logo.png"), "not text").unwrap();
+
+        let mut documents = Vec::new();
+        collect_text_files(&dir, &dir, &["md", "txt"], true, &mut documents).unwrap();
+        let names: Vec<&str> = documents.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["guides/setup", "intro"]);
+        assert_eq!(documents[1].content, "# Pollinet");
+
+        let mut top_level = Vec::new();
+        collect_text_files(&dir, &dir, &["md", "txt"], false, &mut top_level).unwrap();
+        assert_eq!(top_level.len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn directories_are_ingested_once_per_change() {
+        let system = test_system("ingest_dir", &[]).await;
+        let dir = temp_dir("ingest-dir");
+        std::fs::write(dir.join("relays.md"), "Pollinet relays transactions over Bluetooth.").unwrap();
+        std::fs::write(dir.join("nonces.txt"), "Durable nonces keep signed transactions valid.").unwrap();
+
+        assert_eq!(system.add_documents_from_dir(&dir, &["md", "txt"]).await.unwrap(), 2);
+        assert_eq!(system.add_documents_from_dir(&dir, &["md", "txt"]).await.unwrap(), 0);
+
+        std::fs::write(dir.join("relays.md"), "Pollinet relays transactions over a mesh network.").unwrap();
+        assert_eq!(system.add_documents_from_dir(&dir, &["md", "txt"]).await.unwrap(), 1);
+
+        let documents = system.list_documents(None).await.unwrap();
+        assert_eq!(documents.len(), 2);
+        assert!(documents.iter().all(|d| d.source.as_deref() == Some("file")));
+
+        drop_tables(&system).await;
+    }
+
+    /// Serve `body` with `content_type` at `/page` on a local port, returning its URL
+    async fn page_server(content_type: &'static str, body: &'static str) -> String {
+        let app = axum::Router::new().route(
+            "/page",
+            axum::routing::get(move || async move { ([("content-type", content_type)], body) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn web_pages_are_ingested_as_text() {
+        let system = test_system("ingest_url", &[]).await;
+        let url = page_server(
+            "text/html; charset=utf-8",
+            "<html><head><title>Tokenomics</title><script>var x = 1;</script></head>\
+             <body><h1>Tokenomics</h1><p>Relayers earn a fee for each transaction they submit.</p></body></html>",
+        )
+        .await;
+
+        assert_eq!(system.add_document_from_url(&url).await.unwrap(), 1);
+
+        let (content, metadata): (String, serde_json::Value) = sqlx::query_as(&format!(
+            "SELECT content, metadata FROM {}",
+            system.config.embeddings_table
+        ))
+        .fetch_one(&system.db_pool)
+        .await
+        .unwrap();
+        assert!(content.ends_with("\nRelayers earn a fee for each transaction they submit."), "{}", content);
+        assert!(!content.contains("var x"), "{}", content);
+        assert_eq!(metadata["source"], "web");
+        assert_eq!(metadata["url"], url.as_str());
+        assert_eq!(metadata["title"], "Tokenomics");
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    async fn only_html_and_text_pages_can_be_ingested() {
+        let system = offline_system(&[], Box::new(TestEmbedder::default()));
+        let url = page_server("application/json", "{\"fee\": 1}").await;
+
+        let error = system.add_document_from_url(&url).await.unwrap_err();
+        assert!(error.to_string().contains("Unsupported content type 'application/json'"), "{}", error);
+
+        let error = system.add_document_from_url("ftp://pollinet.xyz/tokenomics").await.unwrap_err();
+        assert!(error.to_string().contains("http(s)"), "{}", error);
+    }
+
+    #[test]
+    fn seed_documents_report_the_bad_line() {
+        let path = temp_dir("seed-bad").join("seed.jsonl");
+        std::fs::write(&path, "{\"name\": \"intro\", \"content\": \"ok\"}\nnot json\n").unwrap();
+
+        let error = load_seed_documents(&path).unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn content_hash_is_stable_sha256() {
+        assert_eq!(
+            content_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_ne!(content_hash("abc"), content_hash("abd"));
+    }
+
+    #[test]
+    fn chunk_text_never_splits_multibyte_characters() {
+        let text = "🚀é漢字🌐".repeat(50);
+        let chunks = RAGSystem::chunk_text(&text, 7, 2);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 7);
+        }
+        // Consecutive chunks share `overlap` characters
+        let first: Vec<char> = chunks[0].chars().collect();
+        assert!(chunks[1].starts_with(&first[5..].iter().collect::<String>()));
+        assert_eq!(chunks[0], "🚀é漢字🌐🚀é");
+    }
+
+    #[test]
+    fn chunk_text_counts_characters_not_bytes() {
+        let text = "ñ".repeat(10);
+        assert_eq!(RAGSystem::chunk_text(&text, 10, 2), vec![text.clone()]);
+        assert_eq!(RAGSystem::chunk_text("  short  ", 100, 10), vec!["short"]);
+    }
+
+    #[test]
+    fn split_sentences_needs_whitespace_after_punctuation() {
+        assert_eq!(
+            split_sentences("Version 1.5 ships soon. Really? Yes!  Trailing text"),
+            ["Version 1.5 ships soon.", "Really?", "Yes!", "Trailing text"]
+        );
+        assert!(split_sentences("   ").is_empty());
+    }
+
+    #[test]
+    fn sentence_chunks_end_on_sentence_boundaries() {
+        let text = "One two three. Four five six. Seven eight nine. Ten eleven twelve.";
+        let chunks = RAGSystem::chunk_text_by_sentence(text, 32, 0);
+        assert_eq!(
+            chunks,
+            ["One two three. Four five six.", "Seven eight nine.", "Ten eleven twelve."]
+        );
+    }
+
+    #[test]
+    fn sentence_chunks_carry_overlapping_sentences() {
+        let text = "Alpha beta. Gamma delta. Epsilon zeta.";
+        let chunks = RAGSystem::chunk_text_by_sentence(text, 26, 13);
+        assert_eq!(chunks, ["Alpha beta. Gamma delta.", "Gamma delta. Epsilon zeta."]);
+    }
+
+    #[test]
+    fn overlong_sentences_fall_back_to_character_chunks() {
+        let long = "x".repeat(30);
+        let text = format!("Short one. {}.", long);
+        let chunks = RAGSystem::chunk_text_by_sentence(&text, 20, 0);
+        assert_eq!(chunks[0], "Short one.");
+        assert!(chunks[1..].iter().all(|chunk| chunk.chars().count() <= 20));
+        assert_eq!(chunks[1..].concat(), format!("{}.", long));
+    }
+
+    /// Embedder for tests: text becomes counts of letters in three ranges
+    /// (padded with ones to `dimensions`), so the vectors are deterministic
+    /// and never zero
+    ///
+    /// Clones share the recorded inputs and call count, so a test can keep
+    /// one while the system under test owns another.
+    #[derive(Clone)]
+    struct TestEmbedder {
+        dimensions: usize,
+        /// `with_model` takes the new dimension from the model name, so
+        /// switching models can change the vector size
+        dimensions_from_model: bool,
+        /// Every text after the first of a batch gets one dimension too many
+        uneven: bool,
+        inputs: Arc<Mutex<Vec<String>>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Default for TestEmbedder {
+        fn default() -> Self {
+            Self {
+                dimensions: 3,
+                dimensions_from_model: false,
+                uneven: false,
+                inputs: Arc::default(),
+                calls: Arc::default(),
+            }
+        }
+    }
+
+    impl TestEmbedder {
+        fn with_dimensions_from_model(mut self) -> Self {
+            self.dimensions_from_model = true;
+            self
+        }
+
+        fn with_uneven_lengths(mut self) -> Self {
+            self.uneven = true;
+            self
+        }
+
+        /// Every text embedded so far, in order
+        fn inputs(&self) -> Vec<String> {
+            self.inputs.lock().unwrap().clone()
+        }
+
+        /// Number of `embed` calls so far
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for TestEmbedder {
+        async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inputs.lock().unwrap().extend_from_slice(texts);
+            let mut embeddings: Vec<Vec<f32>> = texts.iter().map(|text| stub_embedding(text)).collect();
+            for (i, embedding) in embeddings.iter_mut().enumerate() {
+                let extra = usize::from(self.uneven && i > 0);
+                embedding.resize(self.dimensions + extra, 1.0);
+            }
+            Ok(embeddings)
+        }
+
+        fn model(&self) -> &str {
+            "test"
+        }
+
+        fn with_model(&self, model: &str) -> Box<dyn EmbeddingProvider> {
+            let mut embedder = self.clone();
+            if self.dimensions_from_model {
+                embedder.dimensions = model.parse().expect("model named by its dimension");
+            }
+            Box::new(embedder)
+        }
+    }
+
+    fn stub_embedding(text: &str) -> Vec<f32> {
+        let mut vector = vec![1.0; 3];
+        for c in text.to_lowercase().chars().filter(char::is_ascii_lowercase) {
+            vector[match c {
+                'a'..='i' => 0,
+                'j'..='r' => 1,
+                _ => 2,
+            }] += 1.0;
+        }
+        vector
+    }
+
+    /// A RAG system on its own embeddings table in `DATABASE_URL`
+    async fn test_system(name: &str, vars: &[(&str, &str)]) -> RAGSystem {
+        std::env::var("DATABASE_URL").expect("database tests need DATABASE_URL");
+        let table = format!("test_{}_{}", name, std::process::id());
+        let mut vars = vars.to_vec();
+        vars.extend([("EMBEDDINGS_TABLE", table.as_str()), ("EMBEDDING_DIMENSIONS", "3")]);
+
+        let system = RAGSystem::new(Config::for_tests(&vars))
+            .await
+            .expect("connect to DATABASE_URL")
+            .with_embedding_provider(Box::new(TestEmbedder::default()));
+        drop_tables(&system).await;
+        system.initialize_collection().await.expect("initialize test tables");
+        system
+    }
+
+    async fn drop_tables(system: &RAGSystem) {
+        for table in [system.config.embeddings_table.clone(), system.parents_table()] {
+            sqlx::query(&format!("DROP TABLE IF EXISTS {}", table))
+                .execute(&system.db_pool)
+                .await
+                .expect("drop test table");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn deleted_documents_drop_out_of_the_knowledge_base() {
+        let system = test_system("delete", &[]).await;
+        let content = "Pollinet relays signed Solana transactions over a mesh network.";
+        system.add_document("mesh", content, HashMap::new()).await.unwrap();
+        system.add_document("other", content, HashMap::new()).await.unwrap();
+
+        assert_eq!(system.delete_document("mesh", None).await.unwrap(), 1);
+        assert_eq!(system.delete_document("mesh", None).await.unwrap(), 0);
+        assert_eq!(system.delete_document("missing", None).await.unwrap(), 0);
+        assert_eq!(system.chunk_count(None).await.unwrap(), 1);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn list_documents_summarizes_each_document() {
+        let system = test_system("list", &[("CHUNK_SIZE", "40"), ("CHUNK_OVERLAP", "0")]).await;
+        let metadata = HashMap::from([("source".to_string(), "docs".to_string())]);
+        system
+            .add_document("long", &"Pollinet relays transactions offline. ".repeat(3), metadata)
+            .await
+            .unwrap();
+        system
+            .add_document("short", "Bluetooth mesh relays for Solana.", HashMap::new())
+            .await
+            .unwrap();
+
+        let mut documents = system.list_documents(None).await.unwrap();
+        documents.sort_by(|a, b| a.document_name.cmp(&b.document_name));
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].document_name, "long");
+        assert_eq!(documents[0].chunk_count, 3);
+        assert_eq!(documents[0].source.as_deref(), Some("docs"));
+        assert_eq!(documents[1].source, None);
+        assert!(!documents[1].last_updated.is_empty());
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn weak_matches_are_dropped_below_the_threshold() {
+        let system = test_system("threshold", &[("RETRIEVAL_MIN_SIMILARITY", "0.9")]).await;
+        system
+            .add_document("close", "A bad cab faced a big deaf chef.", HashMap::new())
+            .await
+            .unwrap();
+        system
+            .add_document("far", "Rusty sturdy trusty tutus.", HashMap::new())
+            .await
+            .unwrap();
+
+        let scored = system.retrieve_scored_chunks("a bad chef").await.unwrap();
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].0, "A bad cab faced a big deaf chef.");
+        assert!(scored[0].1 >= 0.9);
+
+        assert_eq!(system.retrieve_scored_chunks("trusty tutus").await.unwrap().len(), 1);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn metadata_filters_restrict_retrieval() {
+        let system = test_system("filters", &[("RETRIEVAL_MIN_SIMILARITY", "0")]).await;
+        let category = |value: &str| HashMap::from([("category".to_string(), value.to_string())]);
+        system
+            .add_document("news", "Pollinet mainnet relays launched today.", category("announcement"))
+            .await
+            .unwrap();
+        system
+            .add_document("guide", "Pollinet relays work without internet access.", category("docs"))
+            .await
+            .unwrap();
+
+        let chunks = system
+            .retrieve_relevant_chunks_filtered("Pollinet relays", category("announcement"), None)
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].metadata["category"], "announcement");
+
+        let unfiltered = system
+            .retrieve_relevant_chunks_filtered("Pollinet relays", HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(unfiltered.len(), 2);
+
+        drop_tables(&system).await;
+    }
+
+    /// A RAG system whose database is never connected to, for code paths
+    /// that don't touch it
+    fn offline_system(vars: &[(&str, &str)], embedder: Box<dyn EmbeddingProvider>) -> RAGSystem {
+        let config = Config::for_tests(vars);
+        let pool = PgPool::connect_lazy(&config.database_url).expect("valid database URL");
+        RAGSystem::with_pool(config, pool).with_embedding_provider(embedder)
+    }
+
+    #[tokio::test]
+    async fn repeated_embeddings_come_from_the_cache() {
+        let embedder = TestEmbedder::default();
+        let system = offline_system(&[], Box::new(embedder.clone()));
+
+        let first = system.generate_embedding("What is Pollinet?").await.unwrap();
+        // Same text after normalization
+        let second = system.generate_embedding("  What is   Pollinet? ").await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(embedder.calls(), 1);
+
+        system.generate_embedding("Who runs relays?").await.unwrap();
+        assert_eq!(embedder.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn embedding_cache_can_be_disabled() {
+        let embedder = TestEmbedder::default();
+        let system = offline_system(
+            &[("EMBEDDING_CACHE_SIZE", "0")],
+            Box::new(embedder.clone()),
+        );
+
+        system.generate_embedding("What is Pollinet?").await.unwrap();
+        system.generate_embedding("What is Pollinet?").await.unwrap();
+        assert_eq!(embedder.calls(), 2);
+    }
+
+    /// Answer POSTs to the returned URL with `statuses` in order (200 once
+    /// they run out), each with `Retry-After: 0`, counting the requests
+    async fn scripted_server(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let handler = move || {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            let status = statuses.get(attempt).copied().unwrap_or(200);
+            async move {
+                (
+                    axum::http::StatusCode::from_u16(status).unwrap(),
+                    [("retry-after", "0")],
+                    "{}",
+                )
+            }
+        };
+        let app = axum::Router::new().route("/v1/test", axum::routing::post(handler));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/test", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn transient_api_errors_are_retried() {
+        let (url, hits) = scripted_server(vec![429, 503]).await;
+        let client = reqwest::Client::new();
+
+        let response = post_with_retry(&client, &url, ApiAuth::None, 3, None, &serde_json::json!({}))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_max_retries() {
+        let (url, hits) = scripted_server(vec![500, 500, 500, 500]).await;
+        let client = reqwest::Client::new();
+
+        let error = post_with_retry(&client, &url, ApiAuth::None, 2, None, &serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("status 500"), "{}", error);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, hits) = scripted_server(vec![400]).await;
+        let client = reqwest::Client::new();
+
+        let error = post_with_retry(&client, &url, ApiAuth::None, 3, None, &serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("status 400"), "{}", error);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn open_circuits_fail_without_sending() {
+        let (url, hits) = scripted_server(vec![500, 500, 500]).await;
+        let client = reqwest::Client::new();
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(30));
+        let body = serde_json::json!({});
+        let post = || post_with_retry(&client, &url, ApiAuth::None, 0, Some(&breaker), &body);
+
+        post().await.unwrap_err();
+        post().await.unwrap_err();
+        let error = post().await.unwrap_err();
+        assert!(error.downcast_ref::<crate::circuit_breaker::CircuitOpen>().is_some(), "{}", error);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(breaker.is_open());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stats_count_chunks_by_source_and_category() {
+        let system = test_system("stats", &[]).await;
+        let metadata = HashMap::from([
+            ("source".to_string(), "docs".to_string()),
+            ("category".to_string(), "faq".to_string()),
+        ]);
+        system
+            .add_document("faq", "Pollinet relays transactions offline.", metadata)
+            .await
+            .unwrap();
+        system
+            .add_document("notes", "Bluetooth mesh relays for Solana.", HashMap::new())
+            .await
+            .unwrap();
+
+        let stats = system.stats(None).await.unwrap();
+        assert_eq!(stats.total_chunks, 2);
+        assert_eq!(stats.total_documents, 2);
+        assert_eq!(stats.chunks_by_source, HashMap::from([("docs".to_string(), 1), ("unknown".to_string(), 1)]));
+        assert_eq!(stats.chunks_by_category["faq"], 1);
+        let mut ends = [stats.oldest_document.unwrap().document_name, stats.newest_document.unwrap().document_name];
+        ends.sort();
+        assert_eq!(ends, ["faq", "notes"]);
+
+        drop_tables(&system).await;
+    }
+
+    fn chunk(content: &str, metadata: &[(&str, &str)], similarity: f32) -> RetrievedChunk {
+        RetrievedChunk {
+            content: content.to_string(),
+            metadata: metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            similarity,
+            parent_id: None,
+        }
+    }
+
+    #[test]
+    fn children_of_one_parent_collapse_into_it() {
+        let child = |content: &str, parent: Option<&str>, similarity: f32| RetrievedChunk {
+            parent_id: parent.map(str::to_string),
+            ..chunk(content, &[], similarity)
+        };
+        let parents = HashMap::from([("p1".to_string(), "whole first section".to_string())]);
+
+        let expanded = with_parent_content(
+            vec![
+                child("first child", Some("p1"), 0.9),
+                child("standalone", None, 0.8),
+                child("second child", Some("p1"), 0.7),
+                child("orphan", Some("missing"), 0.6),
+            ],
+            &parents,
+        );
+        let contents: Vec<&str> = expanded.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, ["whole first section", "standalone", "orphan"]);
+        assert_eq!(expanded[0].similarity, 0.9);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn retrieval_returns_each_parent_once() {
+        let vars = [("CHUNK_SIZE", "40"), ("CHUNK_OVERLAP", "0"), ("PARENT_CHUNK_SIZE", "400")];
+        let system = test_system("parents", &vars).await;
+        let content = "Pollinet relays transactions offline. Bluetooth mesh nodes pass them along. \
+            Online relays submit them to Solana.";
+        system.add_document("relays", content, HashMap::new()).await.unwrap();
+        assert!(system.chunk_count(None).await.unwrap() > 1);
+
+        let chunks = system.retrieve_relevant_chunks("How do Pollinet relays work?").await.unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].contains("Bluetooth mesh") && chunks[0].contains("Solana"), "{}", chunks[0]);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    async fn contextual_headers_name_the_document_and_section() {
+        let metadata = HashMap::from([("section".to_string(), "Nonce Account Management".to_string())]);
+        let chunk = "Durable nonces let transactions wait offline.";
+
+        let system = offline_system(&[], Box::new(TestEmbedder::default()));
+        assert_eq!(system.embedding_text(chunk, "whitepaper", &metadata), chunk);
+
+        let system = offline_system(&[("CONTEXTUAL_CHUNK_HEADERS", "true")], Box::new(TestEmbedder::default()));
+        assert_eq!(
+            system.embedding_text(chunk, "whitepaper", &metadata),
+            format!("Document: whitepaper\nSection: Nonce Account Management\n\n{}", chunk)
+        );
+        assert_eq!(
+            system.embedding_text(chunk, "whitepaper", &HashMap::new()),
+            format!("Document: whitepaper\n\n{}", chunk)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn stored_chunks_keep_their_text_without_the_header() {
+        let system = test_system("headers", &[("CONTEXTUAL_CHUNK_HEADERS", "true")]).await;
+        let embedder = TestEmbedder::default();
+        let system = system.with_embedding_provider(Box::new(embedder.clone()));
+        let content = "Durable nonces let transactions wait offline.";
+        system.add_document("whitepaper", content, HashMap::new()).await.unwrap();
+
+        let stored: String = sqlx::query_scalar(&format!("SELECT content FROM {}", system.config.embeddings_table))
+            .fetch_one(&system.db_pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, content);
+        assert_eq!(embedder.inputs(), [format!("Document: whitepaper\n\n{}", content)]);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn per_query_top_k_is_validated_and_capped() {
+        assert!(checked_top_k(0).is_err());
+        assert_eq!(checked_top_k(3).unwrap(), 3);
+        assert_eq!(checked_top_k(MAX_TOP_K + 1).unwrap(), MAX_TOP_K);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn soft_deleted_documents_are_hidden_until_restored_or_purged() {
+        let system = test_system("soft_delete", &[("RETRIEVAL_MIN_SIMILARITY", "0")]).await;
+        system.add_document("mesh", "Bluetooth mesh nodes relay transactions.", HashMap::new()).await.unwrap();
+        system.add_document("nonces", "Durable nonces keep transactions valid.", HashMap::new()).await.unwrap();
+        let retrieved_documents = || async {
+            let chunks = system.retrieve_relevant_chunks_with_k("relay transactions", 10, None).await.unwrap();
+            let mut names: Vec<String> = chunks.into_iter().map(|c| c.metadata["document"].clone()).collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(system.delete_document("mesh", None).await.unwrap(), 1);
+        assert_eq!(retrieved_documents().await, ["nonces"]);
+        assert_eq!(system.chunk_count(None).await.unwrap(), 1);
+        let listed = system.list_documents(None).await.unwrap();
+        assert_eq!(listed.iter().map(|d| d.document_name.as_str()).collect::<Vec<_>>(), ["nonces"]);
+        // Deleting twice finds nothing left to delete
+        assert_eq!(system.delete_document("mesh", None).await.unwrap(), 0);
+
+        assert_eq!(system.restore_document("mesh", None).await.unwrap(), 1);
+        assert_eq!(retrieved_documents().await, ["mesh", "nonces"]);
+        assert_eq!(system.restore_document("mesh", None).await.unwrap(), 0);
+
+        // Only chunks deleted longer ago than the grace period are purged
+        system.delete_document("mesh", None).await.unwrap();
+        assert_eq!(system.purge_deleted(Duration::from_secs(3600)).await.unwrap(), 0);
+        assert_eq!(system.purge_deleted(Duration::ZERO).await.unwrap(), 1);
+        assert_eq!(system.restore_document("mesh", None).await.unwrap(), 0);
+        assert_eq!(retrieved_documents().await, ["nonces"]);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn namespaces_are_validated_and_prefix_non_default_ids() {
+        assert_eq!(checked_namespace(None).unwrap(), DEFAULT_NAMESPACE);
+        assert_eq!(checked_namespace(Some("support-kb_2")).unwrap(), "support-kb_2");
+        assert!(checked_namespace(Some("")).is_err());
+        assert!(checked_namespace(Some("docs; DROP TABLE")).is_err());
+        assert!(checked_namespace(Some(&"n".repeat(MAX_NAMESPACE_LEN + 1))).is_err());
+
+        assert_eq!(namespaced_id(DEFAULT_NAMESPACE, "faq_0".into()), "faq_0");
+        assert_eq!(namespaced_id("support", "faq_0".into()), "support:faq_0");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn retrieval_stays_inside_its_namespace() {
+        let system = test_system("namespaces", &[("RETRIEVAL_MIN_SIMILARITY", "0")]).await;
+        // The same document name in both namespaces must not collide
+        let docs = "Pollinet relays signed transactions over a mesh network.";
+        let support = "Restart the app if relaying transactions stalls.";
+        system.add_document_in_namespace("faq", docs, HashMap::new(), None).await.unwrap();
+        system.add_document_in_namespace("faq", support, HashMap::new(), Some("support")).await.unwrap();
+        let contents = |namespace| {
+            let system = &system;
+            async move {
+                let chunks = system.retrieve_relevant_chunks_with_k("relay transactions", 10, namespace).await.unwrap();
+                chunks.into_iter().map(|c| c.content).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(contents(None).await, [docs]);
+        assert_eq!(contents(Some("support")).await, [support]);
+        assert!(contents(Some("empty")).await.is_empty());
+        assert_eq!(system.chunk_count(Some("support")).await.unwrap(), 1);
+
+        assert_eq!(system.delete_document("faq", Some("support")).await.unwrap(), 1);
+        assert!(contents(Some("support")).await.is_empty());
+        assert_eq!(contents(None).await, [docs]);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn each_metric_selects_a_matching_operator_and_opclass() {
+        for (metric, operator, opclass) in [
+            (DistanceMetric::Cosine, "<=>", "vector_cosine_ops"),
+            (DistanceMetric::InnerProduct, "<#>", "vector_ip_ops"),
+            (DistanceMetric::L2, "<->", "vector_l2_ops"),
+        ] {
+            assert_eq!(distance_operator(metric), operator);
+            assert_eq!(index_opclass(metric), opclass);
+            assert!(similarity_sql(metric, "$1").contains(&format!("embedding {} $1", operator)));
+        }
+    }
+
+    #[test]
+    fn vector_search_only_sees_live_chunks_in_its_namespace() {
+        assert_eq!(live_chunks_in("$3"), "namespace = $3 AND deleted_at IS NULL");
+        for metric in [DistanceMetric::Cosine, DistanceMetric::InnerProduct, DistanceMetric::L2] {
+            let sql = vector_search_sql("kb", metric, false, false);
+            assert!(sql.contains("FROM kb\n"), "{}", sql);
+            assert!(sql.contains("WHERE namespace = $3 AND deleted_at IS NULL"), "{}", sql);
+            assert!(sql.contains(&format!("ORDER BY embedding {} $1", distance_operator(metric))), "{}", sql);
+            assert!(!sql.contains("$4") && !sql.contains("created_epoch, embedding"), "{}", sql);
+        }
+
+        let sql = vector_search_sql("kb", DistanceMetric::Cosine, true, true);
+        assert!(sql.contains("deleted_at IS NULL AND metadata @> $4"), "{}", sql);
+        assert!(sql.contains("created_epoch, embedding"), "{}", sql);
+    }
+
+    #[test]
+    fn index_built_for_another_metric_is_rejected() {
+        assert!(check_index_metric(&[], DistanceMetric::InnerProduct).is_ok());
+        assert!(check_index_metric(&["vector_l2_ops".into()], DistanceMetric::L2).is_ok());
+
+        let error = check_index_metric(&["vector_cosine_ops".into()], DistanceMetric::InnerProduct).unwrap_err();
+        assert!(error.to_string().contains("vector_cosine_ops"), "{}", error);
+        assert!(error.to_string().contains("vector_ip_ops"), "{}", error);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn every_metric_ranks_the_nearest_chunk_first() {
+        for metric in ["cosine", "ip", "l2"] {
+            let vars = [("DISTANCE_METRIC", metric), ("RETRIEVAL_MIN_SIMILARITY", "-100")];
+            let system = test_system(&format!("metric_{}", metric), &vars).await;
+            system.add_document("near", "abcd", HashMap::new()).await.unwrap();
+            system.add_document("far", "stuv", HashMap::new()).await.unwrap();
+
+            let chunks = system.retrieve_relevant_chunks_with_k("abc", 2, None).await.unwrap();
+            assert_eq!(chunks[0].content, "abcd", "{}", metric);
+
+            // Restarting with a different metric trips the index guard
+            let other = if metric == "cosine" { "l2" } else { "cosine" };
+            let table = system.config.embeddings_table.clone();
+            let mismatched = RAGSystem::new(Config::for_tests(&[
+                ("EMBEDDINGS_TABLE", table.as_str()),
+                ("EMBEDDING_DIMENSIONS", "3"),
+                ("DISTANCE_METRIC", other),
+            ]))
+            .await
+            .unwrap();
+            assert!(mismatched.initialize_collection().await.is_err(), "{}", metric);
+
+            drop_tables(&system).await;
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn retrieval_returns_the_requested_number_of_chunks() {
+        let vars = [("TOP_K_CHUNKS", "1"), ("RETRIEVAL_MIN_SIMILARITY", "0")];
+        let system = test_system("top_k", &vars).await;
+        for (name, content) in [
+            ("relays", "Pollinet relays transactions offline."),
+            ("mesh", "Bluetooth mesh nodes pass transactions along."),
+            ("solana", "Online relays submit transactions to Solana."),
+            ("nonces", "Durable nonces keep signed transactions valid."),
+        ] {
+            system.add_document(name, content, HashMap::new()).await.unwrap();
+        }
+
+        assert_eq!(system.retrieve_relevant_chunks("relay transactions").await.unwrap().len(), 1);
+        let chunks = system.retrieve_relevant_chunks_with_k("relay transactions", 3, None).await.unwrap();
+        assert_eq!(chunks.len(), 3);
+        let chunks = system.retrieve_relevant_chunks_with_k("relay transactions", 10, None).await.unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert!(system.retrieve_relevant_chunks_with_k("relay transactions", 0, None).await.is_err());
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn recency_boost_decays_with_age() {
+        let now = 1_700_000_000.0;
+        let day = 86_400.0;
+        assert_eq!(recency_boosted(0.8, None, now, 0.5), 0.8);
+        assert!((recency_boosted(0.8, Some(now), now, 0.5) - 0.9).abs() < 1e-6);
+        // One half-life old
+        assert!((recency_boosted(0.8, Some(now - 30.0 * day), now, 0.5) - 0.65).abs() < 1e-6);
+        // A slightly weaker but newer match wins the near-tie
+        assert!(recency_boosted(0.78, Some(now - day), now, 0.3) > recency_boosted(0.8, Some(now - 700.0 * day), now, 0.3));
+    }
+
+    #[test]
+    fn chunk_dates_parse_from_metadata() {
+        let date = |value: &str| metadata_date(&HashMap::from([("created_at".to_string(), value.to_string())]));
+        assert_eq!(date("2018-10-10T20:19:24Z"), Some(1_539_202_764.0));
+        assert_eq!(date("Wed Oct 10 20:19:24 +0000 2018"), Some(1_539_202_764.0));
+        assert_eq!(date("2018-10-10"), Some(1_539_129_600.0));
+        assert_eq!(date("last week"), None);
+        assert_eq!(metadata_date(&HashMap::new()), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn newer_near_ties_rank_first_with_a_recency_boost() {
+        let system = test_system("recency", &[("RECENCY_WEIGHT", "0.5")]).await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let yesterday = chrono::DateTime::from_timestamp(now - 86_400, 0).unwrap().to_rfc3339();
+        let dated = |date: &str| HashMap::from([("created_at".to_string(), date.to_string())]);
+        // The old chunk matches the query exactly, the new one nearly
+        system
+            .add_document("old", "Pollinet latest update", dated("2022-01-01"))
+            .await
+            .unwrap();
+        system
+            .add_document("new", "Pollinet latest updates", dated(&yesterday))
+            .await
+            .unwrap();
+
+        let chunks = system.retrieve_relevant_chunks("Pollinet latest update").await.unwrap();
+        assert_eq!(chunks, ["Pollinet latest updates", "Pollinet latest update"]);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn latest_chunks_filter_by_category_and_date() {
+        let system = test_system("latest", &[]).await;
+        for (name, category, created_at) in [
+            ("launch", "pollinet_announcement", "2024-03-01T09:00:00Z"),
+            ("roadmap", "pollinet_announcement", "Mon Jan 15 12:00:00 +0000 2024"),
+            ("teaser", "pollinet_announcement", "2023-11-20"),
+            ("meme", "community", "2024-04-01"),
+            ("typo", "pollinet_announcement", "not a date"),
+        ] {
+            let metadata = HashMap::from([
+                ("category".to_string(), category.to_string()),
+                ("created_at".to_string(), created_at.to_string()),
+            ]);
+            system
+                .add_document(name, &format!("Pollinet tweet about the {} news.", name), metadata)
+                .await
+                .unwrap();
+        }
+        let filters = HashMap::from([("category".to_string(), "pollinet_announcement".to_string())]);
+        let documents = |chunks: Vec<DatedChunk>| -> Vec<String> {
+            chunks.into_iter().map(|chunk| chunk.metadata["document"].clone()).collect()
+        };
+
+        let since = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let recent = system.query_by_metadata(&filters, Some(since), 10, None).await.unwrap();
+        assert_eq!(recent[1].created_at.as_deref(), Some("2024-03-01T09:00:00+00:00"));
+        // The malformed date falls back to when the chunk was stored (now)
+        assert_eq!(documents(recent), ["typo", "launch", "roadmap"]);
+
+        let newest = system.query_by_metadata(&filters, None, 2, None).await.unwrap();
+        assert_eq!(documents(newest), ["typo", "launch"]);
+        assert_eq!(system.query_by_metadata(&HashMap::new(), None, 10, None).await.unwrap().len(), 5);
+
+        drop_tables(&system).await;
+    }
+
+    /// Every record of `system`'s export, in export order
+    async fn exported_records(system: &RAGSystem) -> Vec<ExportRecord> {
+        let mut export = Vec::new();
+        system.export_json(&mut export).await.unwrap();
+        export
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn exports_reimport_into_a_fresh_table() {
+        let vars = [("CHUNK_SIZE", "40"), ("CHUNK_OVERLAP", "0"), ("PARENT_CHUNK_SIZE", "200")];
+        let source = test_system("export", &vars).await;
+        let metadata = HashMap::from([("category".to_string(), "faq".to_string())]);
+        source
+            .add_document("relays", "Pollinet relays transactions offline. Mesh nodes pass them on.", metadata)
+            .await
+            .unwrap();
+        source
+            .add_document("retired", "Old relay fees were flat per transaction.", HashMap::new())
+            .await
+            .unwrap();
+        source.delete_document("retired", None).await.unwrap();
+        let records = exported_records(&source).await;
+
+        let target = test_system("import", &vars).await;
+        let mut export = Vec::new();
+        source.export_json(&mut export).await.unwrap();
+        let stats = target.import_json(export.as_slice()).await.unwrap();
+        assert_eq!(stats.chunks + stats.parents, records.len());
+        assert!(stats.parents > 0);
+
+        // Embeddings, metadata, timestamps and soft deletes all survive
+        assert_eq!(exported_records(&target).await, records);
+        assert_eq!(target.chunk_count(None).await.unwrap(), source.chunk_count(None).await.unwrap());
+
+        drop_tables(&source).await;
+        drop_tables(&target).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn bad_import_lines_import_nothing() {
+        let system = test_system("import_bad", &[]).await;
+        let record = ExportRecord::Chunk {
+            id: "faq_0".to_string(),
+            content: "Pollinet relays transactions offline.".to_string(),
+            embedding: Some(vec![0.25, 0.5, 0.75]),
+            metadata: None,
+            content_hash: None,
+            parent_id: None,
+            created_at: None,
+            deleted_at: None,
+            namespace: None,
+        };
+        let export = format!("{}\nnot json\n", serde_json::to_string(&record).unwrap());
+
+        let error = system.import_json(export.as_bytes()).await.unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+        assert_eq!(system.chunk_count(None).await.unwrap(), 0);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn repeated_paragraphs_are_deduplicated() {
+        let system = test_system("dedup", &[("DEDUP_SIMILARITY", "0.99")]).await;
+        let paragraph = "Pollinet relays signed transactions over a Bluetooth mesh.";
+
+        let first = system.add_document("original", paragraph, HashMap::new()).await.unwrap();
+        assert_eq!((first.inserted, first.deduplicated), (1, 0));
+        let second = system.add_document("repost", paragraph, HashMap::new()).await.unwrap();
+        assert_eq!((second.inserted, second.deduplicated), (0, 1));
+        let distinct = system.add_document("other", "Trusty sturdy tutus stay put.", HashMap::new()).await.unwrap();
+        assert_eq!((distinct.inserted, distinct.deduplicated), (1, 0));
+
+        // A document is never a duplicate of its own previous version
+        let again = system.add_document("original", paragraph, HashMap::new()).await.unwrap();
+        assert_eq!((again.skipped, again.deduplicated), (1, 0));
+        assert_eq!(system.chunk_count(None).await.unwrap(), 2);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    async fn empty_and_blank_documents_are_rejected() {
+        let embedder = TestEmbedder::default();
+        let system = offline_system(&[], Box::new(embedder.clone()));
+
+        for (content, chars) in [("", 0), ("  \n\t  ", 0), ("  too short  ", 9)] {
+            let error = system.add_document("tweet", content, HashMap::new()).await.unwrap_err();
+            let too_short = error.downcast_ref::<DocumentTooShort>().expect("DocumentTooShort");
+            assert_eq!((too_short.chars, too_short.min_chars), (chars, 20));
+        }
+        assert!(embedder.inputs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn documents_whose_chunks_are_all_tiny_are_rejected() {
+        let vars = [("MIN_DOCUMENT_CHARS", "10"), ("MIN_CHUNK_CHARS", "20")];
+        let embedder = TestEmbedder::default();
+        let system = offline_system(&vars, Box::new(embedder.clone()));
+
+        // Long enough as a document, but its only chunk is under MIN_CHUNK_CHARS
+        let error = system.add_document("tweet", "Relays sync now.", HashMap::new()).await.unwrap_err();
+        let too_short = error.downcast_ref::<DocumentTooShort>().expect("DocumentTooShort");
+        assert!(too_short.no_chunks);
+        assert_eq!((too_short.chars, too_short.min_chars), (16, 20));
+        assert!(error.to_string().contains("no chunk reaching MIN_CHUNK_CHARS"), "{}", error);
+        assert!(embedder.inputs().is_empty());
+
+        let error = system.add_document("tweet", "Relays.", HashMap::new()).await.unwrap_err();
+        assert!(!error.downcast_ref::<DocumentTooShort>().unwrap().no_chunks);
+        assert!(error.to_string().contains("is too short (7 characters, minimum 10)"), "{}", error);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn sub_threshold_trailing_chunks_are_dropped() {
+        let vars = [("CHUNK_SIZE", "50"), ("CHUNK_OVERLAP", "0"), ("MIN_CHUNK_CHARS", "20")];
+        let system = test_system("min_chunk", &vars).await;
+        let content = "Pollinet relays signed transactions offline today. Thanks!";
+        let chunks = RAGSystem::chunk_text(content, 50, 0);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].trim().chars().count() < 20);
+
+        let stats = system.add_document("announcement", content, HashMap::new()).await.unwrap();
+        assert_eq!(stats.total(), 1);
+        assert_eq!(system.chunk_count(None).await.unwrap(), 1);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn text_is_normalized_idempotently() {
+        let dirty = "  Pollinet\u{200B} relays\t\ttransactions\r\n\r\n\r\n\u{FEFF}offline  \u{0007}\rCafe\u{301} ";
+        let clean = normalize_text(dirty);
+        assert_eq!(clean, "Pollinet relays transactions\n\noffline\nCaf\u{E9}");
+        assert_eq!(normalize_text(&clean), clean);
+        assert_eq!(normalize_text(" \u{200D}\n\t "), "");
+    }
+
+    #[tokio::test]
+    async fn dirty_and_clean_text_embed_the_same_input() {
+        let embedder = TestEmbedder::default();
+        let system = offline_system(
+            &[("EMBEDDING_CACHE_SIZE", "0")],
+            Box::new(embedder.clone()),
+        );
+
+        let dirty = system.generate_embedding("What\u{200B} is\u{00A0}Pollinet’s  fee?\r\n").await.unwrap();
+        let clean = system.generate_embedding("What is Pollinet’s fee?").await.unwrap();
+        assert_eq!(dirty, clean);
+        let embedded = embedder.inputs();
+        assert_eq!(embedded.len(), 2);
+        assert_eq!(embedded[0], embedded[1]);
+    }
+
+    #[test]
+    fn source_names_are_distinct_and_in_retrieval_order() {
+        let chunks = [
+            chunk("a", &[("document", "faq"), ("source", "docs")], 0.9),
+            chunk("b", &[("source", "blog")], 0.8),
+            chunk("c", &[("document", "faq")], 0.7),
+            chunk("d", &[], 0.6),
+        ];
+        assert_eq!(source_names(&chunks), ["faq", "blog"]);
+    }
+
+    #[test]
+    fn fallback_results_have_no_sources() {
+        let result = QueryResult::fallback("answer".to_string(), vec![0.2], TokenUsage::default());
+        assert!(result.used_fallback);
+        assert!(result.sources.is_empty() && result.chunks.is_empty());
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["used_fallback"], true);
+        assert!(json.get("chunks").is_none());
+    }
+
+    /// An OpenAI-style API answering POSTs to `path` with `body`, for use as
+    /// `OPENAI_BASE_URL`; also returns the request bodies it received
+    async fn openai_server(path: &str, body: serde_json::Value) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        let handler = move |axum::Json(request): axum::Json<serde_json::Value>| {
+            received.lock().unwrap().push(request);
+            let body = body.clone();
+            async move { axum::Json(body) }
+        };
+        let app = axum::Router::new().route(path, axum::routing::post(handler));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, requests)
+    }
+
+    /// [`openai_server`] for chat completions that always reply with `reply`
+    async fn chat_server(reply: &str) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let body = serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": reply}}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+        });
+        openai_server("/chat/completions", body).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn fallback_answers_embed_the_query_once() {
+        let (openai, requests) = chat_server("Pollinet is an offline relay network.").await;
+        let system = test_system(
+            "fallback_embed",
+            &[("OPENAI_BASE_URL", openai.as_str()), ("RETRIEVAL_MIN_SIMILARITY", "0.99")],
+        )
+        .await;
+        let embedder = TestEmbedder::default();
+        let system = system.with_embedding_provider(Box::new(embedder.clone()));
+        system
+            .add_document("guide", "A bad cab faced a big deaf chef.", HashMap::new())
+            .await
+            .unwrap();
+        let before = embedder.inputs().len();
+
+        let result = system.query_detailed("Rusty sturdy tutus?", &[], None, None).await.unwrap();
+        assert!(result.used_fallback);
+        assert_eq!(result.answer, "Pollinet is an offline relay network.");
+        assert_eq!(embedder.inputs().len() - before, 1);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn fallback_context_takes_the_newest_chunks_within_budget() {
+        let contents = ["Relay note one.", "Relay note two.", "Relay note three."];
+        let tokens = |text: &str| {
+            tiktoken_rs::o200k_base_singleton().lock().encode_with_special_tokens(text).len()
+                + FALLBACK_SEPARATOR_TOKENS
+        };
+        let budget = (tokens(contents[2]) + tokens(contents[1])).to_string();
+        let system = test_system("fallback_budget", &[("FALLBACK_TOKEN_BUDGET", budget.as_str())]).await;
+        for (i, content) in contents.iter().enumerate() {
+            system.add_document(&format!("note{}", i), content, HashMap::new()).await.unwrap();
+        }
+
+        let chunks = system.retrieve_all_documents(DEFAULT_NAMESPACE).await.unwrap();
+        assert_eq!(chunks, [contents[2], contents[1]]);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn hnsw_index_is_created_when_configured() {
+        let system = test_system("hnsw", &[("INDEX_TYPE", "hnsw")]).await;
+        let indexes: Vec<String> = sqlx::query_scalar("SELECT indexdef FROM pg_indexes WHERE tablename = $1")
+            .bind(&system.config.embeddings_table)
+            .fetch_all(&system.db_pool)
+            .await
+            .unwrap();
+        assert!(indexes.iter().any(|index| index.contains("USING hnsw")), "{:?}", indexes);
+
+        drop_tables(&system).await;
+    }
+
+    fn contents(chunks: &[RetrievedChunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.content.as_str()).collect()
+    }
+
+    #[test]
+    fn rank_fusion_favours_chunks_ranked_by_both_lists() {
+        let vector = vec![chunk("a", &[], 0.9), chunk("b", &[], 0.8), chunk("c", &[], 0.7)];
+        let keyword = vec![chunk("c", &[], 0.5), chunk("b", &[], 0.4), chunk("d", &[], 0.3)];
+
+        let fused = reciprocal_rank_fusion(vec![vector, keyword], 3);
+        assert_eq!(contents(&fused), ["c", "b", "a"]);
+        // The first-seen similarity is kept
+        assert_eq!(fused[0].similarity, 0.7);
+    }
+
+    #[test]
+    fn cosine_similarity_handles_zero_vectors() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn mmr_skips_near_duplicates() {
+        let candidates = vec![
+            (chunk("relay", &[], 0.9), vec![1.0, 0.0]),
+            (chunk("relay again", &[], 0.89), vec![1.0, 0.01]),
+            (chunk("mesh", &[], 0.7), vec![0.0, 1.0]),
+        ];
+
+        assert_eq!(contents(&maximal_marginal_relevance(candidates.clone(), 2, 0.5)), ["relay", "mesh"]);
+        // lambda 1 is plain relevance order
+        assert_eq!(contents(&maximal_marginal_relevance(candidates, 2, 1.0)), ["relay", "relay again"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn reembedding_resizes_the_embedding_column() {
+        let system = test_system("reembed", &[]).await;
+        let system = system.with_embedding_provider(Box::new(TestEmbedder::default().with_dimensions_from_model()));
+        system
+            .add_document("guide", "Pollinet relays transactions offline.", HashMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(system.reembed_all("4").await.unwrap(), 1);
+        assert_eq!(system.embedding_column_dimensions().await.unwrap(), Some(4));
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn mismatched_embedding_dimensions_are_rejected() {
+        assert!(check_embedding_dimensions(1536, 1536).is_ok());
+        let error = check_embedding_dimensions(1536, 3072).unwrap_err().to_string();
+        assert!(error.contains("1536") && error.contains("3072"), "{}", error);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn existing_tables_must_match_the_configured_dimension() {
+        let system = test_system("dimensions", &[]).await;
+        let mismatched = RAGSystem::new(Config::for_tests(&[
+            ("EMBEDDINGS_TABLE", system.config.embeddings_table.as_str()),
+            ("EMBEDDING_DIMENSIONS", "4"),
+        ]))
+        .await
+        .unwrap();
+        let error = mismatched.initialize_collection().await.unwrap_err();
+        assert!(error.to_string().contains("EMBEDDING_DIMENSIONS"), "{:#}", error);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn api_urls_keep_the_base_query_string_last() {
+        assert_eq!(api_url("https://api.openai.com/v1", "chat/completions"), "https://api.openai.com/v1/chat/completions");
+        assert_eq!(api_url("http://proxy/v1/", "/embeddings"), "http://proxy/v1/embeddings");
+        assert_eq!(
+            api_url("https://x.openai.azure.com/openai/deployments/gpt?api-version=2024-02-01", "chat/completions"),
+            "https://x.openai.azure.com/openai/deployments/gpt/chat/completions?api-version=2024-02-01"
+        );
+    }
+
+    #[tokio::test]
+    async fn moderation_blocks_flagged_or_listed_categories() {
+        let (openai, _) = openai_server(
+            "/moderations",
+            serde_json::json!({
+                "results": [{"flagged": true, "categories": {"harassment": true, "violence": false}}]
+            }),
+        )
+        .await;
+
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+        assert!(system.moderate_text("some text").await.unwrap());
+
+        let system = offline_system(
+            &[("OPENAI_BASE_URL", openai.as_str()), ("MODERATION_CATEGORIES", "violence")],
+            Box::new(TestEmbedder::default()),
+        );
+        assert!(!system.moderate_text("some text").await.unwrap());
+    }
+
+    async fn stored_contents(system: &RAGSystem) -> Vec<String> {
+        sqlx::query_scalar(&format!("SELECT content FROM {} ORDER BY id", system.config.embeddings_table))
+            .fetch_all(&system.db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn failed_ingestion_leaves_the_previous_version() {
+        let vars = [("CHUNK_SIZE", "40"), ("CHUNK_OVERLAP", "0"), ("EMBEDDING_CACHE_SIZE", "0")];
+        let system = test_system("transaction", &vars).await;
+        let first = "Pollinet relays transactions offline. Peers sync when back online.";
+        system.add_document("guide", first, HashMap::new()).await.unwrap();
+        let before = stored_contents(&system).await;
+        assert_eq!(before.len(), 2);
+
+        let system = system.with_embedding_provider(Box::new(TestEmbedder::default().with_uneven_lengths()));
+        let second = "Relays forward signed transactions. Validators settle them later on.";
+        assert!(system.add_document("guide", second, HashMap::new()).await.is_err());
+        assert_eq!(stored_contents(&system).await, before);
+
+        drop_tables(&system).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn unchanged_chunks_are_not_re_embedded() {
+        let vars = [("CHUNK_SIZE", "40"), ("CHUNK_OVERLAP", "0"), ("EMBEDDING_CACHE_SIZE", "0")];
+        let system = test_system("idempotent", &vars).await;
+        let embedder = TestEmbedder::default();
+        let system = system.with_embedding_provider(Box::new(embedder.clone()));
+        let content = "Pollinet relays transactions offline. Peers sync when back online.";
+
+        let stats = system.add_document("guide", content, HashMap::new()).await.unwrap();
+        assert_eq!((stats.inserted, stats.skipped), (2, 0));
+        assert_eq!(embedder.inputs().len(), 2);
+
+        let stats = system.add_document("guide", content, HashMap::new()).await.unwrap();
+        assert_eq!((stats.inserted, stats.updated, stats.skipped), (0, 0, 2));
+        assert_eq!(embedder.inputs().len(), 2);
+
+        let changed = "Pollinet relays transactions offline. Peers sync once reconnected.";
+        let stats = system.add_document("guide", changed, HashMap::new()).await.unwrap();
+        assert_eq!((stats.updated, stats.skipped), (1, 1));
+        assert_eq!(embedder.inputs().len(), 3);
+
+        drop_tables(&system).await;
+    }
+
+    #[test]
+    fn token_usage_adds_up_across_requests() {
+        let reported: OpenAIUsage =
+            serde_json::from_value(serde_json::json!({"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}))
+                .unwrap();
+        let mut usage = TokenUsage::default();
+        usage.record(Some(&reported));
+        usage.record(None);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (10, 5, 15));
+
+        let total = usage.combined(usage);
+        assert_eq!((total.prompt_tokens, total.completion_tokens, total.total_tokens), (20, 10, 30));
+    }
+
+    #[tokio::test]
+    async fn follow_ups_are_rewritten_only_with_history() {
+        let (openai, requests) = chat_server("  What is the Pollinet relay fee?  ").await;
+        let vars = [("OPENAI_BASE_URL", openai.as_str()), ("ENABLE_QUERY_REWRITE", "true")];
+        let system = offline_system(&vars, Box::new(TestEmbedder::default()));
+        let history = [ConversationMessage {
+            role: "user".to_string(),
+            content: "How do Pollinet relays work?".to_string(),
+        }];
+
+        assert_eq!(system.retrieval_query("What about the fee?", &[]).await.answer, "What about the fee?");
+        assert!(requests.lock().unwrap().is_empty());
+
+        let rewritten = system.retrieval_query("What about the fee?", &history).await;
+        assert_eq!(rewritten.answer, "What is the Pollinet relay fee?");
+        assert_eq!(rewritten.usage.total_tokens, 15);
+        let sent = requests.lock().unwrap()[0]["messages"][1]["content"].as_str().unwrap().to_string();
+        assert!(sent.contains("user: How do Pollinet relays work?") && sent.contains("What about the fee?"));
+    }
+
+    #[tokio::test]
+    async fn failed_rewrites_keep_the_original_query() {
+        let (openai, _) = openai_server("/chat/completions", serde_json::json!({"choices": []})).await;
+        let vars = [("OPENAI_BASE_URL", openai.as_str()), ("ENABLE_QUERY_REWRITE", "true")];
+        let system = offline_system(&vars, Box::new(TestEmbedder::default()));
+        let history = [ConversationMessage {
+            role: "user".to_string(),
+            content: "How do Pollinet relays work?".to_string(),
+        }];
+
+        assert_eq!(system.retrieval_query("What about the fee?", &history).await.answer, "What about the fee?");
+    }
+
+    #[tokio::test]
+    async fn detected_languages_reach_the_system_prompt() {
+        let (openai, requests) = chat_server(" Spanish. ").await;
+        let vars = [("OPENAI_BASE_URL", openai.as_str()), ("ENABLE_MULTILINGUAL", "true")];
+        let system = offline_system(&vars, Box::new(TestEmbedder::default()));
+
+        let detected = system.answer_language("¿Qué es Pollinet?", None).await;
+        assert_eq!(detected.language.as_deref(), Some("Spanish"));
+        assert_eq!(detected.usage.total_tokens, 15);
+
+        let context = [chunk("Pollinet relays transactions offline.", &[], 0.9)];
+        let messages = system.build_response_messages("¿Qué es Pollinet?", &context, &[], detected.language.as_deref());
+        assert!(messages[0].content.ends_with("Always write your answer in Spanish, whatever language the context is in."));
+
+        // A chat's language setting wins without a detection call
+        let setting = system.answer_language("¿Qué es Pollinet?", Some("French")).await;
+        assert_eq!(setting.language.as_deref(), Some("French"));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn languages_are_not_detected_unless_enabled() {
+        let (openai, requests) = chat_server("Spanish").await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+
+        assert_eq!(system.answer_language("¿Qué es Pollinet?", None).await.language, None);
+        assert!(requests.lock().unwrap().is_empty());
+        let messages = system.build_response_messages("What is Pollinet?", &[], &[], None);
+        assert!(!messages[0].content.contains("Always write your answer in"));
+    }
+
+    #[tokio::test]
+    async fn voice_notes_are_uploaded_to_whisper() {
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&uploads);
+        let handler = move |headers: axum::http::HeaderMap, body: axum::body::Bytes| {
+            let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+            received.lock().unwrap().push((
+                header("authorization"),
+                header("content-type"),
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+            async { axum::Json(serde_json::json!({"text": "  What is Pollinet?\n"})) }
+        };
+        let app = axum::Router::new().route("/audio/transcriptions", axum::routing::post(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let openai = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+
+        let transcript = system.transcribe_audio(b"OggS-voice".to_vec(), "audio/ogg").await.unwrap();
+        assert_eq!(transcript, "What is Pollinet?");
+
+        let uploads = uploads.lock().unwrap();
+        let (authorization, content_type, body) = &uploads[0];
+        assert_eq!(authorization, "Bearer sk-test");
+        assert!(content_type.starts_with("multipart/form-data; boundary="), "{}", content_type);
+        assert!(body.contains("name=\"model\"") && body.contains(WHISPER_MODEL), "{}", body);
+        assert!(body.contains("filename=\"audio.ogg\""), "{}", body);
+        assert!(body.contains("audio/ogg") && body.contains("OggS-voice"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn transcription_retries_and_respects_the_circuit_breaker() {
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&uploads);
+        let handler = move |body: axum::body::Bytes| {
+            let mut uploads = received.lock().unwrap();
+            uploads.push(String::from_utf8_lossy(&body).into_owned());
+            let status = if uploads.len() == 1 { 503 } else { 200 };
+            async move {
+                (
+                    axum::http::StatusCode::from_u16(status).unwrap(),
+                    [("retry-after", "0")],
+                    axum::Json(serde_json::json!({"text": "What is Pollinet?"})),
+                )
+            }
+        };
+        let app = axum::Router::new().route("/audio/transcriptions", axum::routing::post(handler));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let openai = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let system = offline_system(
+            &[("OPENAI_BASE_URL", openai.as_str()), ("OPENAI_MAX_RETRIES", "2")],
+            Box::new(TestEmbedder::default()),
+        );
+
+        let transcript = system.transcribe_audio(b"OggS-voice".to_vec(), "audio/ogg").await.unwrap();
+        assert_eq!(transcript, "What is Pollinet?");
+        {
+            let uploads = uploads.lock().unwrap();
+            assert_eq!(uploads.len(), 2);
+            assert!(uploads.iter().all(|body| body.contains("OggS-voice")), "{:?}", uploads);
+        }
+
+        for _ in 0..system.config.circuit_breaker_threshold {
+            system.circuit_breaker.record_failure();
+        }
+        let error = system.transcribe_audio(b"OggS-voice".to_vec(), "audio/ogg").await.unwrap_err();
+        assert!(error.downcast_ref::<crate::circuit_breaker::CircuitOpen>().is_some(), "{:#}", error);
+        assert_eq!(uploads.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn audio_extensions_follow_the_mime_type() {
+        assert_eq!(audio_extension("audio/mpeg"), "mp3");
+        assert_eq!(audio_extension("audio/x-m4a"), "m4a");
+        assert_eq!(audio_extension("audio/wav; codecs=1"), "wav");
+        assert_eq!(audio_extension("audio/ogg; codecs=opus"), "ogg");
+        assert_eq!(audio_extension("application/octet-stream"), "ogg");
+    }
+
+    #[test]
+    fn follow_up_suggestions_are_cleaned_up() {
+        let text = "1. What is a relay?\n\n- \"How are fees paid?\"\n2) what is a relay?\n\
+                    3 nodes or more?\nFourth question?\n";
+        assert_eq!(
+            parse_follow_ups(text),
+            ["What is a relay?", "How are fees paid?", "3 nodes or more?"]
+        );
+        assert!(parse_follow_ups(&"Why? ".repeat(30)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stubbed_follow_ups_become_questions() {
+        let (openai, requests) = chat_server("1. How do relays earn fees?\n2. Which wallets are supported?").await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+        let context = [chunk("Relays earn a fee per transaction.", &[], 0.9)];
+
+        let suggestions = system
+            .suggest_follow_ups("What do relays do?", "They forward transactions.", &context)
+            .await
+            .unwrap();
+        assert_eq!(suggestions.questions, ["How do relays earn fees?", "Which wallets are supported?"]);
+        assert_eq!(suggestions.usage.total_tokens, 15);
+        let sent = requests.lock().unwrap()[0]["messages"][1]["content"].as_str().unwrap().to_string();
+        assert!(sent.contains("Relays earn a fee per transaction.") && sent.contains("They forward transactions."));
+    }
+
+    #[tokio::test]
+    async fn hypothetical_answers_are_cached_per_query() {
+        let (openai, requests) = chat_server("Pollinet relays transactions over Bluetooth.").await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+
+        let first = system.hypothetical_answer("How do relays work?").await.unwrap();
+        assert_eq!(first, "Pollinet relays transactions over Bluetooth.");
+        assert_eq!(system.hypothetical_answer("How do relays work?").await.unwrap(), first);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        system.hypothetical_answer("Who runs relays?").await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn grounding_verdicts_are_parsed() {
+        let context = [chunk("Pollinet relays transactions offline.", &[], 0.9)];
+        for (reply, grounded) in [("SUPPORTED", true), ("unsupported.", false)] {
+            let (openai, _) = chat_server(reply).await;
+            let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+            assert_eq!(system.check_grounding("answer", &context).await.unwrap().grounded, grounded);
+        }
+
+        let (openai, _) = chat_server("Maybe").await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+        assert!(system.check_grounding("answer", &context).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn grounding_check_only_runs_when_enabled_and_never_hides_answers() {
+        let (openai, requests) = chat_server("Maybe").await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+        assert!(system.verify_grounding("answer", &[]).await.grounded);
+        assert!(requests.lock().unwrap().is_empty());
+
+        let vars = [("OPENAI_BASE_URL", openai.as_str()), ("ENABLE_GROUNDING_CHECK", "true")];
+        let system = offline_system(&vars, Box::new(TestEmbedder::default()));
+        // An unusable verdict keeps the answer
+        assert!(system.verify_grounding("answer", &[]).await.grounded);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn only_the_sentinel_counts_as_a_refusal() {
+        assert_eq!(parse_refusal(REFUSAL_SENTINEL), (String::new(), true));
+        assert_eq!(parse_refusal(&format!("{} ", REFUSAL_SENTINEL)), (String::new(), true));
+
+        let incidental = "I don't have that information yet, but relays sync once peers reconnect.";
+        assert_eq!(parse_refusal(incidental), (incidental.to_string(), false));
+        assert!(!is_refusal(incidental));
+
+        assert!(refusal_instruction().contains(REFUSAL_SENTINEL));
+    }
+
+    #[tokio::test]
+    async fn answers_report_refusals_and_prompts_ask_for_the_sentinel() {
+        let (openai, requests) = chat_server(REFUSAL_SENTINEL).await;
+        let system = offline_system(&[("OPENAI_BASE_URL", openai.as_str())], Box::new(TestEmbedder::default()));
+        let context = [chunk("Pollinet relays transactions offline.", &[], 0.9)];
+
+        let response = system.generate_response("Who founded Pollinet?", &context, &[], None).await.unwrap();
+        assert!(response.refused);
+        assert_eq!(response.answer, "");
+
+        let system_prompt = requests.lock().unwrap()[0]["messages"][0]["content"].as_str().unwrap().to_string();
+        assert!(system_prompt.contains(REFUSAL_SENTINEL));
+    }
+
+    #[tokio::test]
+    async fn cached_answers_match_normalized_queries_in_the_same_context() {
+        let system = offline_system(&[("ANSWER_CACHE_TTL_SECS", "60")], Box::new(TestEmbedder::default()));
+        let usage = TokenUsage { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15 };
+        let result = QueryResult {
+            answer: "An offline relay network.".to_string(),
+            usage,
+            ..QueryResult::default()
+        };
+        system.cache_answer("What is Pollinet?", &[], None, None, None, &result);
+
+        let cached = system.cached_answer("  what is   POLLINET ", &[], None, None, None).unwrap();
+        assert_eq!(cached.answer, "An offline relay network.");
+        // Cache hits cost nothing
+        assert_eq!(cached.usage.total_tokens, 0);
+
+        let history = [ConversationMessage { role: "user".to_string(), content: "Hi".to_string() }];
+        assert!(system.cached_answer("What is Pollinet?", &history, None, None, None).is_none());
+        assert!(system.cached_answer("What is Pollinet?", &[], Some("Spanish"), None, None).is_none());
+        assert!(system.cached_answer("What is Pollinet?", &[], None, Some(3), None).is_none());
+        assert!(system.cached_answer("What is Pollinet?", &[], None, None, Some("team")).is_none());
+        // The default namespace shares entries with no namespace
+        assert!(system.cached_answer("What is Pollinet?", &[], None, None, Some(DEFAULT_NAMESPACE)).is_some());
+
+        system.clear_answer_cache();
+        assert!(system.cached_answer("What is Pollinet?", &[], None, None, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn answer_cache_is_off_without_a_ttl() {
+        let system = offline_system(&[], Box::new(TestEmbedder::default()));
+        system.cache_answer("What is Pollinet?", &[], None, None, None, &QueryResult::default());
+        assert!(system.cached_answer("What is Pollinet?", &[], None, None, None).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn migrations_can_run_again() {
+        let pool = test_pool().await;
+        let system = RAGSystem::with_pool(Config::for_tests(&[]), pool);
+        system.run_migrations().await.unwrap();
+
+        for table in ["conversations", "feedback", "chats", "query_logs", "chat_settings"] {
+            let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+                .bind(table)
+                .fetch_one(&system.db_pool)
+                .await
+                .unwrap();
+            assert!(exists, "{} missing", table);
+        }
+    }
 
-        Ok(response)
+    #[test]
+    fn poolers_are_detected_from_the_database_url() {
+        assert!(uses_connection_pooler("postgres://u:p@db.supabase.co:6543/postgres"));
+        assert!(uses_connection_pooler("postgres://u:p@host/db?pgbouncer=true"));
+        assert!(!uses_connection_pooler("postgres://u:p@host:5432/db"));
     }
 }
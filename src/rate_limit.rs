@@ -0,0 +1,157 @@
+//! Per-user rate limiting module
+//!
+//! This module handles:
+//! - Token buckets that cap how many questions each user can ask per minute
+//! - Separate limits for private chats and groups
+//! - Periodic cleanup of idle buckets
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// How often idle buckets are swept from memory
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Buckets untouched for this long have refilled completely and can be dropped
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limiter keyed by Telegram user id
+///
+/// Each user holds up to `per_minute` tokens, refilled continuously at
+/// `per_minute` tokens per minute; every answered message spends one.
+/// A limit of 0 disables limiting for that chat type.
+#[derive(Debug)]
+pub struct RateLimiter {
+    private_per_minute: u32,
+    group_per_minute: u32,
+    /// Keyed by (user id, is group chat) so the two limits don't share tokens
+    buckets: Mutex<HashMap<(i64, bool), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(private_per_minute: u32, group_per_minute: u32) -> Self {
+        Self {
+            private_per_minute,
+            group_per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.rate_limit_private_per_minute,
+            config.rate_limit_group_per_minute,
+        )
+    }
+
+    /// Spend a token for `user_id` if one is available
+    ///
+    /// # Returns
+    /// `true` if the message may be answered, `false` if the user is over the limit
+    pub fn check(&self, user_id: i64, is_group: bool) -> bool {
+        self.check_at(user_id, is_group, Instant::now())
+    }
+
+    /// [`Self::check`] against an explicit clock
+    pub fn check_at(&self, user_id: i64, is_group: bool, now: Instant) -> bool {
+        let per_minute = if is_group {
+            self.group_per_minute
+        } else {
+            self.private_per_minute
+        };
+        if per_minute == 0 {
+            return true;
+        }
+
+        let capacity = per_minute as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((user_id, is_group)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop buckets that have been idle long enough to be full again
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_BUCKET_TTL);
+
+        let removed = before - buckets.len();
+        if removed > 0 {
+            log::debug!("Removed {} idle rate limit buckets", removed);
+        }
+    }
+
+    /// Sweep idle buckets every few minutes for the life of the process
+    pub fn spawn_cleanup(self: &Arc<Self>) {
+        let limiter = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                limiter.cleanup();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_call_past_the_limit_is_rejected() {
+        let limiter = RateLimiter::new(3, 1);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(1, false, now));
+        }
+        assert!(!limiter.check_at(1, false, now));
+
+        // Other users and the group bucket are unaffected
+        assert!(limiter.check_at(2, false, now));
+        assert!(limiter.check_at(1, true, now));
+        assert!(!limiter.check_at(1, true, now));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limiter = RateLimiter::new(2, 0);
+        let now = Instant::now();
+        assert!(limiter.check_at(1, false, now));
+        assert!(limiter.check_at(1, false, now));
+        assert!(!limiter.check_at(1, false, now));
+
+        // One token every 30 seconds at 2 per minute
+        assert!(!limiter.check_at(1, false, now + Duration::from_secs(20)));
+        assert!(limiter.check_at(1, false, now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn a_zero_limit_disables_limiting() {
+        let limiter = RateLimiter::new(0, 0);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.check_at(1, true, now)));
+    }
+}
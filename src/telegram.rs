@@ -0,0 +1,121 @@
+//! Telegram request module
+//!
+//! This module handles:
+//! - Retrying outbound Telegram requests (messages, edits, chat actions) on
+//!   transient errors, honoring `retry_after` on flood control
+//! - Telling transient errors apart from permanent ones (blocked bot,
+//!   missing chat, bad markup)
+
+use std::future::Future;
+use std::time::Duration;
+use teloxide::requests::{Output, Request};
+use teloxide::{ApiError, RequestError};
+
+/// Retries after the first attempt of an outbound request
+const SEND_MAX_RETRIES: u32 = 3;
+
+/// Wait before the first retry; doubled after each further failure
+const SEND_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Outbound Telegram requests that can be retried on transient errors
+pub trait SendWithRetry: Request<Err = RequestError> + Send + Sized
+where
+    Output<Self>: Send,
+{
+    /// Send the request, retrying network errors, Telegram 5xx responses and
+    /// flood control (after its `retry_after`) with exponential backoff
+    ///
+    /// Permanent errors such as "chat not found" or "bot was blocked" are
+    /// returned straight away.
+    fn send_with_retry(self) -> impl Future<Output = Result<Output<Self>, RequestError>> + Send;
+}
+
+impl<R> SendWithRetry for R
+where
+    R: Request<Err = RequestError> + Send + Sync,
+    Output<R>: Send,
+{
+    async fn send_with_retry(self) -> Result<Output<Self>, RequestError> {
+        let mut backoff = SEND_INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.send_ref().await {
+                Err(e) if attempt < SEND_MAX_RETRIES && is_retryable(&e) => {
+                    let delay = match &e {
+                        RequestError::RetryAfter(retry_after) => *retry_after,
+                        _ => backoff,
+                    };
+                    attempt += 1;
+                    log::warn!(
+                        "Telegram request failed ({}), retry {}/{} in {:?}",
+                        e,
+                        attempt,
+                        SEND_MAX_RETRIES,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a failed Telegram request may succeed if sent again
+///
+/// Flood control, network and I/O errors, unparseable responses (usually a
+/// proxy's HTML error page) and Telegram's own 5xx errors are transient;
+/// every other API error is permanent.
+pub fn is_retryable(error: &RequestError) -> bool {
+    match error {
+        RequestError::RetryAfter(_)
+        | RequestError::Network(_)
+        | RequestError::Io(_)
+        | RequestError::InvalidJson { .. } => true,
+        RequestError::Api(ApiError::Unknown(description)) => is_server_error(description),
+        RequestError::Api(_) | RequestError::MigrateToChatId(_) => false,
+    }
+}
+
+/// Whether an unrecognised API error description is one of Telegram's 5xx errors
+fn is_server_error(description: &str) -> bool {
+    [
+        "Internal Server Error",
+        "Bad Gateway",
+        "Service Unavailable",
+        "Gateway Timeout",
+    ]
+    .iter()
+    .any(|server_error| description.contains(server_error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_errors_are_retried() {
+        assert!(is_retryable(&RequestError::RetryAfter(Duration::from_secs(3))));
+        assert!(is_retryable(&RequestError::Io(std::io::ErrorKind::ConnectionReset.into())));
+        assert!(is_retryable(&RequestError::InvalidJson {
+            source: serde_json::from_str::<serde_json::Value>("<html>").unwrap_err(),
+            raw: "<html>Bad Gateway</html>".into(),
+        }));
+        assert!(is_retryable(&RequestError::Api(ApiError::Unknown(
+            "Internal Server Error: restart".to_string()
+        ))));
+        assert!(is_retryable(&RequestError::Api(ApiError::Unknown("Bad Gateway".to_string()))));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        assert!(!is_retryable(&RequestError::Api(ApiError::BotBlocked)));
+        assert!(!is_retryable(&RequestError::Api(ApiError::ChatNotFound)));
+        assert!(!is_retryable(&RequestError::Api(ApiError::MessageIsTooLong)));
+        assert!(!is_retryable(&RequestError::Api(ApiError::Unknown(
+            "Bad Request: can't parse entities".to_string()
+        ))));
+        assert!(!is_retryable(&RequestError::MigrateToChatId(-1001234567890)));
+    }
+}
@@ -0,0 +1,87 @@
+//! Tracing helpers module
+//!
+//! With the `tracing` Cargo feature enabled, the hot paths are wrapped in
+//! `tracing` spans (forwarded to `log` unless a subscriber is installed, as
+//! `LOG_FORMAT=json` does, which puts the span fields on every JSON line):
+//! - `telegram.handle_message`: one answered message (`request_id`, `chat_id`)
+//! - `rag.retrieve_relevant_chunks`: retrieval (`chunks`)
+//! - `rag.generate_embedding`: one query embedding (`cache_hit`)
+//! - `rag.generate_response`: the context-grounded completion (`prompt_tokens`, `completion_tokens`)
+//!
+//! Every span also records `elapsed_ms`. Without the feature these helpers
+//! compile to nothing and logging stays plain `log`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Process-unique id correlating everything done for one incoming message
+pub fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Records `elapsed_ms` on the current span when dropped
+///
+/// Create one at the top of an instrumented function.
+pub struct SpanTimer {
+    start: Instant,
+}
+
+impl SpanTimer {
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for SpanTimer {
+    fn drop(&mut self) {
+        record("elapsed_ms", self.start.elapsed().as_millis() as u64);
+    }
+}
+
+/// Record a numeric field declared on the current span
+#[cfg(feature = "tracing")]
+pub fn record(field: &'static str, value: u64) {
+    tracing::Span::current().record(field, value);
+}
+
+/// Record a numeric field declared on the current span
+#[cfg(not(feature = "tracing"))]
+pub fn record(_field: &'static str, _value: u64) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_are_unique() {
+        let first = next_request_id();
+        let second = next_request_id();
+        assert!(second > first);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn span_timers_record_elapsed_ms_on_close() {
+        use crate::test_support::CapturedOutput;
+
+        let output = CapturedOutput::default();
+        let subscriber = crate::logging::json_subscriber()
+            .with_env_filter("trace")
+            .with_writer(output.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("test.work", elapsed_ms = tracing::field::Empty);
+            let _entered = span.enter();
+            let _timer = SpanTimer::start();
+        });
+
+        let lines = output.lines();
+        let closed: serde_json::Value = serde_json::from_str(lines.last().expect("close event")).unwrap();
+        assert_eq!(closed["span"]["name"], "test.work");
+        assert!(closed["span"]["elapsed_ms"].is_u64(), "{}", closed);
+    }
+}
@@ -0,0 +1,49 @@
+//! Shared test helpers
+//!
+//! Tests that need Postgres are `#[ignore = "needs DATABASE_URL"]`; run them
+//! with `DATABASE_URL` set and `cargo test -- --ignored`. Log output can be
+//! captured with `CapturedOutput`.
+
+use sqlx::PgPool;
+use std::sync::{Arc, Mutex};
+
+/// Migrated pool for `DATABASE_URL`
+///
+/// # Panics
+/// If `DATABASE_URL` is unset or the database can't be reached or migrated
+pub async fn test_pool() -> PgPool {
+    let url = std::env::var("DATABASE_URL").expect("database tests need DATABASE_URL");
+    let pool = PgPool::connect(&url).await.expect("connect to DATABASE_URL");
+    sqlx::migrate!("./migrations").run(&pool).await.expect("run migrations");
+    pool
+}
+
+/// A `tracing-subscriber` writer collecting output in memory
+#[derive(Clone, Default)]
+pub struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedOutput {
+    /// Everything written so far, one entry per line
+    pub fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+    }
+}
+
+impl std::io::Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedOutput {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}